@@ -1,7 +1,6 @@
 use std::collections::HashMap;
 use std::path::PathBuf;
 use std::time::{Duration, Instant};
-use std::thread;
 
 use fanotify_rs::{Fanotify, FanotifyFlags, MaskFlags};
 
@@ -128,7 +127,7 @@ impl MonitorStats {
 
 /// Advanced file system monitor
 struct AdvancedMonitor {
-    fanotify: Fanotify,
+    fanotify: Option<Fanotify>,
     config: MonitorConfig,
     stats: MonitorStats,
     recent_files: HashMap<PathBuf, Instant>,
@@ -141,7 +140,7 @@ impl AdvancedMonitor {
         )?;
         
         Ok(Self {
-            fanotify,
+            fanotify: Some(fanotify),
             config,
             stats: MonitorStats::new(),
             recent_files: HashMap::new(),
@@ -158,10 +157,12 @@ impl AdvancedMonitor {
         println!("  Track symlinks: {}", self.config.track_symlinks);
         println!();
         
+        let mut fanotify = self.fanotify.take().ok_or("monitor already started")?;
+        
         // Add watches for all configured paths
         for path in &self.config.paths {
             if path.exists() {
-                self.fanotify.add_watch(path, MaskFlags::ALL_EVENTS)?;
+                fanotify.add_watch(path, MaskFlags::ALL_EVENTS)?;
                 println!("Added watch for: {}", path.display());
             } else {
                 eprintln!("Warning: Path does not exist: {}", path.display());
@@ -170,13 +171,14 @@ impl AdvancedMonitor {
         
         println!("Monitoring started. Press Ctrl+C to stop.");
         
+        // Read events on a background thread
+        let watcher = fanotify.spawn_watcher()?;
         let mut last_stats_time = Instant::now();
         
         // Main event loop
         loop {
-            let events = self.fanotify.read_events()?;
-            
-            for event in events {
+            if let Some(event) = watcher.recv_timeout(Duration::from_secs(1)) {
+                let event = event?;
                 if self.should_process_event(&event) {
                     self.process_event(&event);
                     self.stats.record_event(&event);
@@ -188,8 +190,6 @@ impl AdvancedMonitor {
                 self.stats.print_summary();
                 last_stats_time = Instant::now();
             }
-            
-            thread::sleep(Duration::from_millis(100));
         }
     }
     
@@ -269,27 +269,21 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Simple argument parsing
     for (i, arg) in args.iter().enumerate() {
         match arg.as_str() {
-            "--paths" => {
-                if i + 1 < args.len() {
-                    config.paths = args[i + 1]
-                        .split(',')
-                        .map(|s| PathBuf::from(s.trim()))
-                        .collect();
-                }
+            "--paths" if i + 1 < args.len() => {
+                config.paths = args[i + 1]
+                    .split(',')
+                    .map(|s| PathBuf::from(s.trim()))
+                    .collect();
             },
-            "--extensions" => {
-                if i + 1 < args.len() {
-                    config.extensions = args[i + 1]
-                        .split(',')
-                        .map(|s| s.trim().to_lowercase())
-                        .collect();
-                }
+            "--extensions" if i + 1 < args.len() => {
+                config.extensions = args[i + 1]
+                    .split(',')
+                    .map(|s| s.trim().to_lowercase())
+                    .collect();
             },
-            "--min-size" => {
-                if i + 1 < args.len() {
-                    if let Ok(size) = args[i + 1].parse::<u64>() {
-                        config.min_file_size = size;
-                    }
+            "--min-size" if i + 1 < args.len() => {
+                if let Ok(size) = args[i + 1].parse::<u64>() {
+                    config.min_file_size = size;
                 }
             },
             "--no-dirs" => {
@@ -1,59 +1,84 @@
-use std::collections::HashMap;
-use std::time::Duration;
-use std::thread;
+use fanotify_rs::policy::{Action, Policy, Rule};
+use fanotify_rs::{Decision, Event, Fanotify, FanotifyFlags, Handler, MaskFlags};
 
-use fanotify_rs::{Fanotify, FanotifyFlags, MaskFlags};
-
-/// Simple access control based on process ID
+/// Access control driven by a rule-based policy, with statistics
 struct AccessController {
-    allowed_pids: HashMap<u32, String>,
-    denied_paths: Vec<String>,
+    policy: Policy,
+    event_count: u64,
+    allowed_count: u64,
+    denied_count: u64,
 }
 
 impl AccessController {
     fn new() -> Self {
-        let mut controller = Self {
-            allowed_pids: HashMap::new(),
-            denied_paths: Vec::new(),
-        };
-        
-        // Add some example allowed processes
-        controller.allowed_pids.insert(1, "systemd".to_string());
-        controller.allowed_pids.insert(1000, "user_process".to_string());
-        
-        // Add some denied paths
-        controller.denied_paths.push("/etc/shadow".to_string());
-        controller.denied_paths.push("/etc/passwd".to_string());
-        
-        controller
+        // Rules are checked in order and the first match wins
+        let policy = Policy::new(Action::Allow)
+            // Some example trusted processes
+            .rule(Rule::allow().pid(1).named("systemd"))
+            .rule(Rule::allow().uid(1000).named("user_process"))
+            // Some denied paths
+            .rule(Rule::deny().path_prefix("/etc/shadow").named("shadow"))
+            .rule(Rule::deny().path_prefix("/etc/passwd").named("passwd"));
+
+        Self {
+            policy,
+            event_count: 0,
+            allowed_count: 0,
+            denied_count: 0,
+        }
     }
     
-    fn should_allow(&self, event: &fanotify_rs::Event) -> bool {
-        let pid = event.info.pid;
+    fn print_statistics(&self) {
+        println!("Statistics:");
+        println!("  Total events: {}", self.event_count);
+        println!("  Allowed: {}", self.allowed_count);
+        println!("  Denied: {}", self.denied_count);
+        println!("  Allow rate: {:.1}%", 
+            (self.allowed_count as f64 / self.event_count as f64) * 100.0);
+        println!();
+    }
+}
+
+impl Handler for AccessController {
+    fn on_event(&mut self, _event: &Event) {
+        // Only permission events are requested
+    }
+    
+    fn on_permission(&mut self, event: &Event) -> Decision {
+        self.event_count += 1;
         
-        // Check if process is explicitly allowed
-        if self.allowed_pids.contains_key(&pid) {
-            return true;
-        }
+        let path_str = event.info.path_str().unwrap_or("unknown");
+        
+        println!("Permission request #{}:", self.event_count);
+        println!("  PID: {}", event.info.pid);
+        println!("  Path: {}", path_str);
+        println!("  Event type: {}", event.event_type());
         
-        // Check if path is explicitly denied
-        if let Some(path) = &event.info.path {
-            let path_str = path.to_string_lossy();
-            for denied_path in &self.denied_paths {
-                if path_str.contains(denied_path) {
-                    return false;
-                }
+        // Decide whether to allow or deny; the event loop sends the answer
+        let verdict = self.policy.evaluate(event);
+        let rule = verdict
+            .rule
+            .and_then(|index| self.policy.rules()[index].name())
+            .unwrap_or("default");
+        let decision = verdict.action.decision();
+        match decision {
+            Decision::Allow => {
+                self.allowed_count += 1;
+                println!("  Decision: ALLOWED ({})", rule);
+            }
+            Decision::Deny | Decision::DenyWith(_) => {
+                self.denied_count += 1;
+                println!("  Decision: DENIED ({})", rule);
             }
         }
+        println!();
         
-        // Default to allowing access
-        true
-    }
-    
-    fn get_process_name(&self, pid: u32) -> String {
-        self.allowed_pids.get(&pid)
-            .cloned()
-            .unwrap_or_else(|| format!("unknown_pid_{}", pid))
+        // Print statistics every 100 events
+        if self.event_count.is_multiple_of(100) {
+            self.print_statistics();
+        }
+        
+        decision
     }
 }
 
@@ -65,9 +90,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Create access controller
     let controller = AccessController::new();
     
-    // Create fanotify instance
+    // Permission events require a content class group
     let mut fanotify = Fanotify::with_flags(
-        FanotifyFlags::CLOEXEC
+        FanotifyFlags::CLASS_CONTENT | FanotifyFlags::CLOEXEC
     )?;
     
     // Get the directory to monitor from command line args or use root
@@ -83,51 +108,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     
     println!("Permission monitoring active. Access attempts will be logged and controlled.");
     
-    let mut event_count = 0;
-    let mut allowed_count = 0;
-    let mut denied_count = 0;
+    // The event loop answers every permission request with the controller's decision
+    fanotify.run(controller)?;
     
-    // Event monitoring loop
-    loop {
-        let events = fanotify.read_events()?;
-        
-        for event in events {
-            event_count += 1;
-            
-            if event.is_permission() {
-                let process_name = controller.get_process_name(event.info.pid);
-                let path_str = event.info.path_str().unwrap_or("unknown");
-                
-                println!("Permission request #{}:", event_count);
-                println!("  Process: {} (PID: {})", process_name, event.info.pid);
-                println!("  Path: {}", path_str);
-                println!("  Event type: {}", event.event_type());
-                
-                // Decide whether to allow or deny
-                if controller.should_allow(&event) {
-                    fanotify.allow(&event)?;
-                    allowed_count += 1;
-                    println!("  Decision: ALLOWED");
-                } else {
-                    fanotify.deny(&event)?;
-                    denied_count += 1;
-                    println!("  Decision: DENIED");
-                }
-                println!();
-            }
-        }
-        
-        // Print statistics every 10 seconds
-        if event_count % 100 == 0 && event_count > 0 {
-            println!("Statistics:");
-            println!("  Total events: {}", event_count);
-            println!("  Allowed: {}", allowed_count);
-            println!("  Denied: {}", denied_count);
-            println!("  Allow rate: {:.1}%", 
-                (allowed_count as f64 / event_count as f64) * 100.0);
-            println!();
-        }
-        
-        thread::sleep(Duration::from_millis(100));
-    }
-} 
\ No newline at end of file
+    Ok(())
+}
@@ -0,0 +1,528 @@
+//! Executable allowlisting
+//!
+//! An [`ExecAllowlist`] answers `OPEN_EXEC_PERM` events: the executable is
+//! hashed with SHA-256 through the event's file descriptor and allowed only
+//! if the [`Allowlist`] has an entry with the same path and hash (and size,
+//! when the entry records one). Reading through the event descriptor hashes
+//! exactly the file the kernel is about to execute, even if the path has
+//! been replaced since.
+//!
+//! In [learn mode](ExecAllowlist::learn) every executable is allowed and
+//! recorded instead, producing an allowlist for a known-good system:
+//!
+//! ```no_run
+//! use fanotify_rs::allowlist::ExecAllowlist;
+//! use fanotify_rs::{Fanotify, FanotifyFlags};
+//!
+//! # fn main() -> fanotify_rs::Result<()> {
+//! let mut fanotify = Fanotify::with_flags(FanotifyFlags::CLASS_CONTENT)?;
+//! ExecAllowlist::watch(&mut fanotify, "/")?;
+//!
+//! let mut learner = ExecAllowlist::learn();
+//! fanotify.run(&mut learner)?;
+//! learner.allowlist().save("/etc/exec.allow")?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! # File format
+//!
+//! One executable per line: the path, its SHA-256 in hex and optionally its
+//! size in bytes, separated by whitespace. Paths may contain spaces; the hash
+//! and size are taken from the end of the line. Blank lines and lines
+//! starting with `#` are ignored.
+//!
+//! ```text
+//! # path                 sha256                                                            size
+//! /usr/bin/ls            5a1fa1fbbab4d4ad5a4fa8ae8a10b83dd1e8e4ae5b08d3b1a6e7e1b6bd8e4e33  142312
+//! /opt/my tool/run.sh    9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::os::fd::RawFd;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use sha2::{Digest as _, Sha256};
+
+use crate::{
+    error::{FanotifyError, Result},
+    event::Event,
+    fanotify::Fanotify,
+    flags::MaskFlags,
+    handler::{Decision, Handler},
+    sys,
+};
+
+/// A SHA-256 digest
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Digest([u8; 32]);
+
+impl Digest {
+    /// Hash everything readable from `fd`, starting at offset 0
+    ///
+    /// Uses `pread(2)`, so the file offset of `fd` is left alone.
+    pub fn of_fd(fd: RawFd) -> Result<(Self, u64)> {
+        let mut hasher = Sha256::new();
+        let size = sys::read_chunks(fd, 64 * 1024, |chunk| {
+            hasher.update(chunk);
+            Ok(true)
+        })?;
+
+        Ok((Self(hasher.finalize().into()), size))
+    }
+
+    /// Wrap a finished hash
+    pub(crate) fn from_bytes(digest: [u8; 32]) -> Self {
+        Self(digest)
+    }
+
+    /// Hash `data`
+    pub fn of_bytes(data: &[u8]) -> Self {
+        Self(Sha256::digest(data).into())
+    }
+
+    /// The raw digest
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Digest({})", self)
+    }
+}
+
+impl FromStr for Digest {
+    type Err = FanotifyError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || FanotifyError::invalid_policy(format!("invalid SHA-256 digest: {:?}", s));
+
+        // from_str_radix alone would also take a sign
+        if s.len() != 64 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+
+        let mut digest = [0u8; 32];
+        for (byte, pair) in digest.iter_mut().zip(s.as_bytes().chunks_exact(2)) {
+            let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+        }
+        Ok(Self(digest))
+    }
+}
+
+/// One allowed executable
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowEntry {
+    /// Where the executable lives
+    pub path: PathBuf,
+    /// SHA-256 of its contents
+    pub digest: Digest,
+    /// Its size in bytes, if recorded
+    pub size: Option<u64>,
+}
+
+impl AllowEntry {
+    /// Whether this entry allows an executable with `digest` and `size`
+    pub fn matches(&self, digest: &Digest, size: u64) -> bool {
+        self.digest == *digest && self.size.is_none_or(|expected| expected == size)
+    }
+}
+
+/// The set of allowed executables
+#[derive(Debug, Clone, Default)]
+pub struct Allowlist {
+    entries: HashMap<PathBuf, Vec<AllowEntry>>,
+}
+
+impl Allowlist {
+    /// An empty allowlist
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse an allowlist in the [file format](self#file-format)
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut allowlist = Self::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let entry = parse_line(line)
+                .map_err(|message| FanotifyError::invalid_policy(format!("line {}: {}", index + 1, message)))?;
+            allowlist.insert(entry);
+        }
+
+        Ok(allowlist)
+    }
+
+    /// Read an allowlist file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)?;
+
+        Self::parse(&text).map_err(|e| match e {
+            FanotifyError::InvalidPolicy { message } => {
+                FanotifyError::invalid_policy(format!("{}: {}", path.display(), message))
+            }
+            e => e,
+        })
+    }
+
+    /// Write the allowlist in the [file format](self#file-format), sorted by path
+    pub fn write_to<W: Write>(&self, mut writer: W) -> Result<()> {
+        let mut paths: Vec<&PathBuf> = self.entries.keys().collect();
+        paths.sort();
+
+        for path in paths {
+            for entry in &self.entries[path] {
+                write!(writer, "{} {}", path.display(), entry.digest)?;
+                if let Some(size) = entry.size {
+                    write!(writer, " {}", size)?;
+                }
+                writeln!(writer)?;
+            }
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Write the allowlist to the file at `path`
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.write_to(BufWriter::new(File::create(path)?))
+    }
+
+    /// Allow an executable
+    ///
+    /// A path may have several entries, e.g. for the versions of a binary
+    /// during an upgrade. Returns `false` if the entry was already present.
+    pub fn insert(&mut self, entry: AllowEntry) -> bool {
+        let entries = self.entries.entry(entry.path.clone()).or_default();
+        if entries.contains(&entry) {
+            return false;
+        }
+        entries.push(entry);
+        true
+    }
+
+    /// Drop every entry for `path`
+    pub fn remove<P: AsRef<Path>>(&mut self, path: P) -> bool {
+        self.entries.remove(path.as_ref()).is_some()
+    }
+
+    /// Whether the executable at `path` with `digest` and `size` is allowed
+    pub fn allows(&self, path: &Path, digest: &Digest, size: u64) -> bool {
+        self.entries
+            .get(path)
+            .is_some_and(|entries| entries.iter().any(|entry| entry.matches(digest, size)))
+    }
+
+    /// The entries for `path`
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> &[AllowEntry] {
+        self.entries.get(path.as_ref()).map_or(&[], Vec::as_slice)
+    }
+
+    /// Every entry, in no particular order
+    pub fn entries(&self) -> impl Iterator<Item = &AllowEntry> {
+        self.entries.values().flatten()
+    }
+
+    /// Number of entries
+    pub fn len(&self) -> usize {
+        self.entries.values().map(Vec::len).sum()
+    }
+
+    /// Whether the allowlist is empty
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Parse `path sha256 [size]`, taking fields from the end
+fn parse_line(line: &str) -> std::result::Result<AllowEntry, String> {
+    let (rest, last) = split_last(line).ok_or("expected a path and a SHA-256 digest")?;
+
+    let (rest, digest, size) = match last.parse::<u64>() {
+        Ok(size) if last.len() != 64 => {
+            let (rest, digest) = split_last(rest).ok_or("expected a path and a SHA-256 digest")?;
+            (rest, digest, Some(size))
+        }
+        _ => (rest, last, None),
+    };
+
+    let digest = digest.parse::<Digest>().map_err(|_| format!("invalid SHA-256 digest {:?}", digest))?;
+    if !rest.starts_with('/') {
+        return Err(format!("path {:?} is not absolute", rest));
+    }
+
+    Ok(AllowEntry {
+        path: PathBuf::from(rest),
+        digest,
+        size,
+    })
+}
+
+/// Split off the last whitespace-separated field
+fn split_last(s: &str) -> Option<(&str, &str)> {
+    let (rest, last) = s.trim_end().rsplit_once(char::is_whitespace)?;
+    Some((rest.trim_end(), last))
+}
+
+/// Whether an [`ExecAllowlist`] enforces or learns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Deny executables that are not on the allowlist
+    Enforce,
+    /// Allow everything and add it to the allowlist
+    Learn,
+}
+
+/// Counters kept by an [`ExecAllowlist`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ExecStats {
+    /// Executions allowed
+    pub allowed: u64,
+    /// Executions denied
+    pub denied: u64,
+    /// Executables added in learn mode
+    pub learned: u64,
+    /// Executables that could not be hashed
+    pub errors: u64,
+}
+
+/// A [`Handler`] allowing only allowlisted executables
+pub struct ExecAllowlist {
+    allowlist: Allowlist,
+    mode: Mode,
+    stats: ExecStats,
+}
+
+impl ExecAllowlist {
+    /// Enforce `allowlist`
+    pub fn enforce(allowlist: Allowlist) -> Self {
+        Self {
+            allowlist,
+            mode: Mode::Enforce,
+            stats: ExecStats::default(),
+        }
+    }
+
+    /// Record every executable into a new allowlist
+    pub fn learn() -> Self {
+        Self {
+            allowlist: Allowlist::new(),
+            mode: Mode::Learn,
+            stats: ExecStats::default(),
+        }
+    }
+
+    /// Mark the mount containing `path` for `OPEN_EXEC_PERM`
+    ///
+    /// The group must be created with `CLASS_CONTENT` or `CLASS_PRE_CONTENT`.
+    pub fn watch<P: AsRef<Path>>(fanotify: &mut Fanotify, path: P) -> Result<()> {
+        fanotify.add_mount_watch(path, MaskFlags::OPEN_EXEC_PERM)
+    }
+
+    /// The current mode
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Switch between enforcing and learning
+    ///
+    /// Learning on top of an existing allowlist extends it.
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+
+    /// The allowlist, including what was learned
+    pub fn allowlist(&self) -> &Allowlist {
+        &self.allowlist
+    }
+
+    /// The allowlist, e.g. to add entries while running
+    pub fn allowlist_mut(&mut self) -> &mut Allowlist {
+        &mut self.allowlist
+    }
+
+    /// Take the allowlist
+    pub fn into_allowlist(self) -> Allowlist {
+        self.allowlist
+    }
+
+    /// A snapshot of the counters
+    pub fn stats(&self) -> ExecStats {
+        self.stats
+    }
+
+    /// Decide an `OPEN_EXEC_PERM` event
+    fn decide(&mut self, event: &Event) -> Result<Decision> {
+        let fd = event.info.fd.ok_or_else(|| {
+            FanotifyError::invalid_event_data("Permission event has no file descriptor")
+        })?;
+        let path = event
+            .info
+            .path
+            .as_deref()
+            .ok_or_else(|| FanotifyError::invalid_event_data("Executable has no path"))?;
+        let (digest, size) = Digest::of_fd(fd)?;
+
+        match self.mode {
+            Mode::Learn => {
+                let entry = AllowEntry {
+                    path: path.to_path_buf(),
+                    digest,
+                    size: Some(size),
+                };
+                if self.allowlist.insert(entry) {
+                    self.stats.learned += 1;
+                    log::info!("learned {} {}", path.display(), digest);
+                }
+                Ok(Decision::Allow)
+            }
+            Mode::Enforce if self.allowlist.allows(path, &digest, size) => Ok(Decision::Allow),
+            Mode::Enforce => {
+                log::warn!("denied execution of {} ({}) by pid {}", path.display(), digest, event.info.pid);
+                Ok(Decision::Deny)
+            }
+        }
+    }
+}
+
+impl Handler for ExecAllowlist {
+    fn on_event(&mut self, _event: &Event) {}
+
+    fn on_permission(&mut self, event: &Event) -> Decision {
+        if !event.info.mask.contains(MaskFlags::OPEN_EXEC_PERM) {
+            return Decision::Allow;
+        }
+
+        let decision = match self.decide(event) {
+            Ok(decision) => decision,
+            Err(e) => {
+                self.stats.errors += 1;
+                log::error!("failed to check executable {:?}: {}", event.info.path, e);
+                match self.mode {
+                    Mode::Learn => Decision::Allow,
+                    Mode::Enforce => Decision::Deny,
+                }
+            }
+        };
+
+        match decision {
+            Decision::Allow => self.stats.allowed += 1,
+            _ => self.stats.denied += 1,
+        }
+        decision
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::decide_next;
+    use crate::FanotifyFlags;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process::Command;
+    use std::thread;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_digest_round_trip() {
+        let empty = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert_eq!(Digest::of_bytes(b"").to_string(), empty);
+        assert_eq!(empty.parse::<Digest>().unwrap(), Digest::of_bytes(b""));
+        assert_eq!(
+            Digest::of_bytes(b"abc").to_string(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        // Every pair must be two hex digits, not a signed number
+        let signed = format!("+{}", &empty[1..]);
+        assert!(signed.parse::<Digest>().is_err());
+        assert!(empty[..62].parse::<Digest>().is_err());
+    }
+
+    #[test]
+    fn test_parse_and_write_allowlist() {
+        let digest = Digest::of_bytes(b"abc");
+        let text = format!(
+            "# comment\n\n/usr/bin/tool {digest} 3\n/opt/my tool/run.sh   {digest}\n"
+        );
+        let allowlist = Allowlist::parse(&text).unwrap();
+
+        assert_eq!(allowlist.len(), 2);
+        assert!(allowlist.allows(Path::new("/usr/bin/tool"), &digest, 3));
+        assert!(!allowlist.allows(Path::new("/usr/bin/tool"), &digest, 4));
+        assert!(allowlist.allows(Path::new("/opt/my tool/run.sh"), &digest, 99));
+        assert!(!allowlist.allows(Path::new("/usr/bin/other"), &digest, 3));
+
+        let mut written = Vec::new();
+        allowlist.write_to(&mut written).unwrap();
+        let reparsed = Allowlist::parse(std::str::from_utf8(&written).unwrap()).unwrap();
+        assert_eq!(reparsed.get("/usr/bin/tool"), allowlist.get("/usr/bin/tool"));
+        assert_eq!(reparsed.get("/opt/my tool/run.sh"), allowlist.get("/opt/my tool/run.sh"));
+
+        let err = Allowlist::parse("/bin/ls nothex").unwrap_err();
+        assert!(err.to_string().contains("line 1"), "{}", err);
+        assert!(Allowlist::parse(&format!("relative {digest}")).is_err());
+    }
+
+    /// Run `script` while `handler` decides the exec permission events
+    fn run_script(fanotify: &mut Fanotify, handler: &mut ExecAllowlist, script: &Path) -> bool {
+        let script = script.to_path_buf();
+        let runner = thread::spawn(move || Command::new(script).status().map(|s| s.success()));
+
+        // The shell is executed from outside the test directory and not watched
+        decide_next(fanotify, handler);
+
+        runner.join().unwrap().unwrap_or(false)
+    }
+
+    #[test]
+    fn test_learn_then_enforce() {
+        let temp_dir = tempdir().unwrap();
+        let script = temp_dir.path().join("hello.sh");
+        std::fs::write(&script, "#!/bin/sh\nexit 0\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        // An inode mark keeps the test to this one file; watch() marks the mount
+        let mut fanotify = Fanotify::with_flags(FanotifyFlags::CLASS_CONTENT).unwrap();
+        fanotify.add_watch(&script, MaskFlags::OPEN_EXEC_PERM).unwrap();
+
+        let mut learner = ExecAllowlist::learn();
+        assert!(run_script(&mut fanotify, &mut learner, &script));
+        assert_eq!(learner.stats().learned, 1);
+
+        let list_file = temp_dir.path().join("exec.allow");
+        learner.allowlist().save(&list_file).unwrap();
+
+        let mut enforcer = ExecAllowlist::enforce(Allowlist::load(&list_file).unwrap());
+        assert!(run_script(&mut fanotify, &mut enforcer, &script));
+
+        // Same path, different contents
+        std::fs::write(&script, "#!/bin/sh\n# tampered\nexit 0\n").unwrap();
+        assert!(!run_script(&mut fanotify, &mut enforcer, &script));
+        assert_eq!(enforcer.stats().allowed, 1);
+        assert_eq!(enforcer.stats().denied, 1);
+    }
+}
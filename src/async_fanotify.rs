@@ -7,12 +7,14 @@ use std::pin::Pin;
 #[cfg(feature = "tokio")]
 use std::task::{Context, Poll};
 #[cfg(feature = "tokio")]
-use std::os::fd::{AsRawFd, FromRawFd};
-
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+#[cfg(feature = "tokio")]
+use std::path::PathBuf;
 #[cfg(feature = "tokio")]
-use tokio::fs::File;
+use std::sync::{Arc, Mutex, OnceLock};
+
 #[cfg(feature = "tokio")]
-use tokio::io::AsyncReadExt;
+use tokio::io::unix::AsyncFd;
 
 use crate::{
     error::{FanotifyError, Result},
@@ -21,20 +23,32 @@ use crate::{
 
 #[cfg(feature = "tokio")]
 use crate::{
+    decode::EventBuffer,
     flags::{MaskFlags, EventFlags},
     event::Event,
-    linux::{fanotify_init, fanotify_mark, fanotify_response, FAN_MARK_ADD, FAN_MARK_REMOVE, errno},
+    handler::{self, Decision, Handler},
+    linux::{FAN_MARK_ADD, FAN_MARK_REMOVE},
+    pending::{self, PendingPermissions},
+    permission::{Incoming, Responder},
+    response::Response,
+    sys::{self, mark_path},
 };
+#[cfg(feature = "tokio")]
+use tokio_util::sync::CancellationToken;
 
 /// An asynchronous fanotify instance for monitoring filesystem events
 #[cfg(feature = "tokio")]
 pub struct AsyncFanotify {
     /// The file descriptor for the fanotify instance
-    fd: File,
+    fd: AsyncFd<OwnedFd>,
     /// Buffer for reading events
-    buffer: Vec<u8>,
+    buffer: EventBuffer,
     /// Watched paths and their masks
     watched_paths: HashMap<std::path::PathBuf, MaskFlags>,
+    /// Shared responder, created the first time it is needed
+    responder: OnceLock<Responder>,
+    /// Decision used by permission requests dropped without an answer
+    default_decision: Decision,
 }
 
 #[cfg(feature = "tokio")]
@@ -45,53 +59,38 @@ impl AsyncFanotify {
     }
 
     /// Create a new asynchronous fanotify instance with custom flags
+    ///
+    /// Must be called from within a tokio runtime; outside one it fails
+    /// with an I/O error. The group is always
+    /// switched to non-blocking mode so it can be registered with the
+    /// reactor, whether or not `NONBLOCK` was requested.
     pub fn with_flags(flags: FanotifyFlags) -> Result<Self> {
-        let result = unsafe {
-            fanotify_init(
-                flags.bits(),
-                libc::O_RDONLY as u32,
-            )
-        };
-
-        if result < 0 {
-            return Err(FanotifyError::from(errno()));
-        }
+        Self::with_event_flags(flags, libc::O_RDONLY)
+    }
 
-        // SAFETY: result is a valid file descriptor
-        let std_file = unsafe { std::fs::File::from_raw_fd(result) };
-        let fd = File::from_std(std_file);
+    /// Create a new asynchronous fanotify instance whose event file
+    /// descriptors are opened with the `open(2)` flags `event_f_flags`
+    ///
+    /// See [`Fanotify::with_event_flags`](crate::Fanotify::with_event_flags).
+    pub fn with_event_flags(flags: FanotifyFlags, event_f_flags: i32) -> Result<Self> {
+        // AsyncFd panics without a reactor to register with
+        tokio::runtime::Handle::try_current().map_err(|e| FanotifyError::Io(std::io::Error::other(e)))?;
+        let fd = sys::init(flags, event_f_flags as u32)?;
+        sys::set_nonblocking(fd.as_raw_fd())?;
 
         Ok(Self {
-            fd,
-            buffer: vec![0u8; 4096],
+            fd: AsyncFd::new(fd)?,
+            buffer: EventBuffer::new(),
             watched_paths: HashMap::new(),
+            responder: OnceLock::new(),
+            default_decision: Decision::default(),
         })
     }
 
     /// Add a watch for a path with the specified mask
     pub async fn add_watch<P: AsRef<Path>>(&mut self, path: P, mask: MaskFlags) -> Result<()> {
         let path = path.as_ref();
-        
-        // Convert path to C string
-        let path_cstr = match std::ffi::CString::new(path.to_string_lossy().as_bytes()) {
-            Ok(s) => s,
-            Err(_) => return Err(FanotifyError::invalid_path(path.to_string_lossy().to_string())),
-        };
-
-        let result = unsafe {
-            fanotify_mark(
-                self.fd.as_raw_fd(),
-                FAN_MARK_ADD,
-                mask.bits(),
-                libc::AT_SYMLINK_NOFOLLOW,
-                path_cstr.as_ptr(),
-            )
-        };
-
-        if result < 0 {
-            return Err(FanotifyError::from(errno()));
-        }
-
+        mark_path(self.fd.as_raw_fd(), FAN_MARK_ADD, mask, path)?;
         self.watched_paths.insert(path.to_path_buf(), mask);
         Ok(())
     }
@@ -99,58 +98,29 @@ impl AsyncFanotify {
     /// Remove a watch for a path
     pub async fn remove_watch<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
         let path = path.as_ref();
-        
-        let path_cstr = match std::ffi::CString::new(path.to_string_lossy().as_bytes()) {
-            Ok(s) => s,
-            Err(_) => return Err(FanotifyError::invalid_path(path.to_string_lossy().to_string())),
-        };
-
         let mask = self.watched_paths.get(path).copied().unwrap_or(MaskFlags::empty());
-
-        let result = unsafe {
-            fanotify_mark(
-                self.fd.as_raw_fd(),
-                FAN_MARK_REMOVE,
-                mask.bits(),
-                libc::AT_SYMLINK_NOFOLLOW,
-                path_cstr.as_ptr(),
-            )
-        };
-
-        if result < 0 {
-            return Err(FanotifyError::from(errno()));
-        }
-
+        mark_path(self.fd.as_raw_fd(), FAN_MARK_REMOVE, mask, path)?;
         self.watched_paths.remove(path);
         Ok(())
     }
 
     /// Read a single event asynchronously
     pub async fn read_event(&mut self) -> Result<Option<Event>> {
-        let bytes_read = match self.fd.read(&mut self.buffer).await {
-            Ok(n) => n,
-            Err(e) => return Err(FanotifyError::Io(e)),
-        };
-
-        if bytes_read == 0 {
-            return Ok(None);
-        }
-
-        let event = Event::from_raw_data(&self.buffer[..bytes_read])?;
-        Ok(Some(event))
+        read_one(&self.fd, &mut self.buffer).await
     }
 
     /// Read all available events asynchronously
+    ///
+    /// Waits for the next batch and returns every event decoded from it,
+    /// together with anything still buffered from an earlier read.
     pub async fn read_events(&mut self) -> Result<Vec<Event>> {
         let mut events = Vec::new();
-        
-        loop {
-            match self.read_event().await? {
-                Some(event) => events.push(event),
-                None => break,
-            }
+
+        if let Some(event) = self.read_event().await? {
+            events.push(event);
+            events.extend(self.buffer.drain());
         }
-        
+
         Ok(events)
     }
 
@@ -170,34 +140,116 @@ impl AsyncFanotify {
         }
     }
 
-    /// Respond to a permission event asynchronously
-    pub async fn respond(&mut self, event: &Event, response: EventFlags) -> Result<()> {
-        if !event.is_permission() {
-            return Err(FanotifyError::invalid_event_data("Event is not a permission event"));
+    /// Run an event loop that hands every event to `handler`
+    ///
+    /// Behaves like [`Fanotify::run`](crate::Fanotify::run): permission
+    /// events are answered with the handler's decision and event file
+    /// descriptors are closed once the callbacks return.
+    pub async fn run<H: Handler>(&mut self, mut handler: H) -> Result<()> {
+        while !handler.should_stop() {
+            let read = tokio::time::timeout(handler::STOP_CHECK_INTERVAL, self.read_event());
+            let first = match read.await {
+                // Idle: go back and check should_stop
+                Err(_elapsed) => continue,
+                Ok(Ok(Some(event))) => event,
+                Ok(Ok(None)) => continue,
+                Ok(Err(e)) => {
+                    if handler.on_error(&e).is_break() {
+                        return Err(e);
+                    }
+                    continue;
+                }
+            };
+
+            let group_fd = self.fd.as_raw_fd();
+            let pending = self.buffer.permissions().clone();
+            let events = std::iter::once(first).chain(self.buffer.drain());
+            handler::dispatch_batch(&mut handler, group_fd, &pending, events)?;
         }
 
-        let fd = event.info.fd.ok_or_else(|| {
-            FanotifyError::invalid_event_data("Permission event has no file descriptor")
-        })?;
+        Ok(())
+    }
 
-        let response_struct = fanotify_response {
-            fd,
-            response: response.bits(),
+    /// Run an event loop until `token` is cancelled, then shut the group down
+    ///
+    /// Combines [`run`](Self::run) with [`shutdown`](Self::shutdown): once
+    /// the token is cancelled (or the handler stops the loop) the marks are
+    /// flushed and every permission event still outstanding is answered
+    /// with `default` before the group is closed.
+    pub async fn run_until_cancelled<H: Handler>(
+        mut self,
+        mut handler: H,
+        token: &CancellationToken,
+        default: Decision,
+    ) -> Result<()> {
+        let outcome = tokio::select! {
+            result = self.run(&mut handler) => result,
+            _ = token.cancelled() => Ok(()),
         };
 
-        let result = unsafe {
-            libc::write(
-                self.fd.as_raw_fd(),
-                &response_struct as *const _ as *const libc::c_void,
-                std::mem::size_of::<fanotify_response>(),
-            )
+        let shutdown = self.shutdown(default).await;
+        outcome.and(shutdown)
+    }
+
+    /// Shut the group down without leaving any process waiting on it
+    ///
+    /// Behaves like [`Fanotify::shutdown`](crate::Fanotify::shutdown).
+    pub async fn shutdown(mut self, default: Decision) -> Result<()> {
+        let group_fd = self.fd.as_raw_fd();
+        let flushed = sys::flush_marks(group_fd);
+        self.watched_paths.clear();
+
+        let (unread, read_result) = match self.buffer.drain_fd(group_fd) {
+            Ok(events) => (events, Ok(())),
+            Err(e) => (self.buffer.drain().collect(), Err(e)),
         };
+        let answered = pending::finish(group_fd, self.buffer.permissions(), unread, default.response());
 
-        if result < 0 {
-            return Err(FanotifyError::from(errno()));
+        flushed.and(read_result).and(answered)
+    }
+
+    /// Respond to a permission event asynchronously
+    pub async fn respond(&mut self, event: &Event, response: EventFlags) -> Result<()> {
+        self.respond_with(event, &Response::new(response)).await
+    }
+
+    /// Respond to a permission event with an extended [`Response`]
+    pub async fn respond_with(&mut self, event: &Event, response: &Response) -> Result<()> {
+        self.buffer.permissions().respond(self.fd.as_raw_fd(), event, response)
+    }
+
+    /// Wait for the next event and sort it into a notification or a permission request
+    ///
+    /// See [`Fanotify::read_incoming`](crate::Fanotify::read_incoming).
+    pub async fn next_incoming(&mut self) -> Result<Incoming> {
+        let event = self.wait_for_event().await?;
+        Ok(self.responder()?.incoming(event, self.default_decision))
+    }
+
+    /// A thread-safe responder for this group's permission events
+    pub fn responder(&self) -> Result<Responder> {
+        if let Some(responder) = self.responder.get() {
+            return Ok(responder.clone());
         }
 
-        Ok(())
+        let fd = self.fd.get_ref().try_clone()?;
+        let responder = Responder::new(fd, self.buffer.permissions().clone());
+        Ok(self.responder.get_or_init(|| responder).clone())
+    }
+
+    /// The decision used by permission requests dropped without an answer
+    pub fn default_decision(&self) -> Decision {
+        self.default_decision
+    }
+
+    /// Change the decision used by permission requests dropped without an answer
+    pub fn set_default_decision(&mut self, decision: Decision) {
+        self.default_decision = decision;
+    }
+
+    /// Number of permission events that were read but not answered yet
+    pub fn pending_permissions(&self) -> usize {
+        self.buffer.permissions().len()
     }
 
     /// Allow a permission event asynchronously
@@ -227,7 +279,7 @@ impl AsyncFanotify {
 
     /// Set the buffer size for reading events
     pub fn set_buffer_size(&mut self, size: usize) {
-        self.buffer.resize(size, 0);
+        self.buffer.resize(size);
     }
 
     /// Get the current buffer size
@@ -236,28 +288,240 @@ impl AsyncFanotify {
     }
 
     /// Create an event stream for use with tokio streams
-    pub fn event_stream(&mut self) -> EventStream {
+    pub fn event_stream(&mut self) -> EventStream<'_> {
         EventStream { fanotify: self }
     }
+
+    /// Split the instance into an event reader and a control handle
+    ///
+    /// The reader owns the read side of the group and is meant to live in a
+    /// single task. The [`AsyncFanotifyHandle`] is `Send + Sync` and can be
+    /// cloned freely, so other tasks can add or remove watches and answer
+    /// permission events while the reader is waiting for the next event.
+    ///
+    /// The group stays open until both halves (and every handle clone) have
+    /// been dropped.
+    pub fn into_split(self) -> Result<(AsyncEventReader, AsyncFanotifyHandle)> {
+        let control_fd = self.fd.get_ref().try_clone()?;
+        let permissions = self.buffer.permissions().clone();
+
+        let reader = AsyncEventReader {
+            fd: self.fd,
+            buffer: self.buffer,
+        };
+        let handle = AsyncFanotifyHandle {
+            inner: Arc::new(HandleInner {
+                fd: control_fd,
+                watched_paths: Mutex::new(self.watched_paths),
+                permissions,
+            }),
+        };
+
+        Ok((reader, handle))
+    }
 }
 
 #[cfg(feature = "tokio")]
-impl Drop for AsyncFanotify {
-    fn drop(&mut self) {
-        // The file will be closed automatically when dropped
+impl AsRawFd for AsyncFanotify {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// The reading half of an [`AsyncFanotify`], created by [`AsyncFanotify::into_split`]
+#[cfg(feature = "tokio")]
+pub struct AsyncEventReader {
+    /// The file descriptor for the fanotify instance
+    fd: AsyncFd<OwnedFd>,
+    /// Buffer for reading events
+    buffer: EventBuffer,
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncEventReader {
+    /// Read a single event asynchronously
+    pub async fn read_event(&mut self) -> Result<Option<Event>> {
+        read_one(&self.fd, &mut self.buffer).await
+    }
+
+    /// Read all available events asynchronously
+    ///
+    /// Waits for the next batch and returns every event decoded from it,
+    /// together with anything still buffered from an earlier read.
+    pub async fn read_events(&mut self) -> Result<Vec<Event>> {
+        let mut events = Vec::new();
+
+        if let Some(event) = self.read_event().await? {
+            events.push(event);
+            events.extend(self.buffer.drain());
+        }
+
+        Ok(events)
+    }
+
+    /// Get the next event (returns None when no events are available)
+    pub async fn next_event(&mut self) -> Result<Option<Event>> {
+        self.read_event().await
+    }
+
+    /// Set the buffer size for reading events
+    pub fn set_buffer_size(&mut self, size: usize) {
+        self.buffer.resize(size);
+    }
+
+    /// Get the current buffer size
+    pub fn buffer_size(&self) -> usize {
+        self.buffer.len()
     }
 }
 
 #[cfg(feature = "tokio")]
-impl std::os::unix::io::AsRawFd for AsyncFanotify {
-    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+impl AsRawFd for AsyncEventReader {
+    fn as_raw_fd(&self) -> RawFd {
         self.fd.as_raw_fd()
     }
 }
 
+/// The control half of an [`AsyncFanotify`], created by [`AsyncFanotify::into_split`]
+///
+/// Cloning the handle is cheap; all clones share the same group and the same
+/// set of watched paths.
+#[cfg(feature = "tokio")]
+#[derive(Clone)]
+pub struct AsyncFanotifyHandle {
+    inner: Arc<HandleInner>,
+}
+
+#[cfg(feature = "tokio")]
+struct HandleInner {
+    /// A duplicate of the group file descriptor
+    fd: OwnedFd,
+    /// Watched paths and their masks
+    watched_paths: Mutex<HashMap<PathBuf, MaskFlags>>,
+    /// Permission events read by the reader half that await a response
+    permissions: PendingPermissions,
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncFanotifyHandle {
+    /// Add a watch for a path with the specified mask
+    pub async fn add_watch<P: AsRef<Path>>(&self, path: P, mask: MaskFlags) -> Result<()> {
+        let path = path.as_ref();
+        let mut watched_paths = self.inner.watched_paths.lock().unwrap();
+        mark_path(self.inner.fd.as_raw_fd(), FAN_MARK_ADD, mask, path)?;
+        watched_paths.insert(path.to_path_buf(), mask);
+        Ok(())
+    }
+
+    /// Remove a watch for a path
+    pub async fn remove_watch<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut watched_paths = self.inner.watched_paths.lock().unwrap();
+        let mask = watched_paths.get(path).copied().unwrap_or(MaskFlags::empty());
+        mark_path(self.inner.fd.as_raw_fd(), FAN_MARK_REMOVE, mask, path)?;
+        watched_paths.remove(path);
+        Ok(())
+    }
+
+    /// Respond to a permission event asynchronously
+    pub async fn respond(&self, event: &Event, response: EventFlags) -> Result<()> {
+        self.respond_with(event, &Response::new(response)).await
+    }
+
+    /// Respond to a permission event with an extended [`Response`]
+    pub async fn respond_with(&self, event: &Event, response: &Response) -> Result<()> {
+        self.inner.permissions.respond(self.inner.fd.as_raw_fd(), event, response)
+    }
+
+    /// Number of permission events that were read but not answered yet
+    pub fn pending_permissions(&self) -> usize {
+        self.inner.permissions.len()
+    }
+
+    /// A thread-safe responder for this group's permission events
+    ///
+    /// Use it to turn events from the reader half into
+    /// [`PermissionRequest`](crate::PermissionRequest)s with
+    /// [`Responder::incoming`].
+    pub fn responder(&self) -> Result<Responder> {
+        let fd = self.inner.fd.try_clone()?;
+        Ok(Responder::new(fd, self.inner.permissions.clone()))
+    }
+
+    /// Flush every mark and answer every outstanding permission event
+    ///
+    /// Stop the reader half first (for example by cancelling the task that
+    /// owns it): events it reads afterwards are not answered here. Events
+    /// still queued in the kernel are answered by the kernel once the group
+    /// is closed, i.e. when the reader and every handle have been dropped.
+    pub async fn shutdown(&self, default: Decision) -> Result<()> {
+        let group_fd = self.inner.fd.as_raw_fd();
+        let flushed = sys::flush_marks(group_fd);
+        self.inner.watched_paths.lock().unwrap().clear();
+
+        let answered = self.inner.permissions.answer_all(group_fd, default.response());
+        flushed.and(answered)
+    }
+
+    /// Allow a permission event asynchronously
+    pub async fn allow(&self, event: &Event) -> Result<()> {
+        self.respond(event, EventFlags::ALLOW).await
+    }
+
+    /// Deny a permission event asynchronously
+    pub async fn deny(&self, event: &Event) -> Result<()> {
+        self.respond(event, EventFlags::DENY).await
+    }
+
+    /// Get a snapshot of the watched paths
+    pub fn watched_paths(&self) -> HashMap<PathBuf, MaskFlags> {
+        self.inner.watched_paths.lock().unwrap().clone()
+    }
+
+    /// Check if a path is being watched
+    pub fn is_watched<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.inner.watched_paths.lock().unwrap().contains_key(path.as_ref())
+    }
+
+    /// Get the mask for a watched path
+    pub fn get_mask<P: AsRef<Path>>(&self, path: P) -> Option<MaskFlags> {
+        self.inner.watched_paths.lock().unwrap().get(path.as_ref()).copied()
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl AsRawFd for AsyncFanotifyHandle {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.fd.as_raw_fd()
+    }
+}
+
+/// Return the next buffered event, waiting for the group if none is left
+#[cfg(feature = "tokio")]
+async fn read_one(fd: &AsyncFd<OwnedFd>, buffer: &mut EventBuffer) -> Result<Option<Event>> {
+    if let Some(event) = buffer.pop() {
+        return Ok(Some(event));
+    }
+
+    let bytes_read = loop {
+        let mut guard = fd.readable().await?;
+        match guard.try_io(|inner| sys::read_fd(inner.as_raw_fd(), buffer.space())) {
+            Ok(result) => break result?,
+            // Readiness was stale, wait for the next notification
+            Err(_would_block) => continue,
+        }
+    };
+
+    if bytes_read == 0 {
+        return Ok(None);
+    }
+
+    buffer.fill(fd.as_raw_fd(), bytes_read)?;
+    Ok(buffer.pop())
+}
+
 /// A stream of fanotify events for use with tokio streams
 #[cfg(feature = "tokio")]
-#[allow(dead_code)]
 pub struct EventStream<'a> {
     fanotify: &'a mut AsyncFanotify,
 }
@@ -266,11 +530,33 @@ pub struct EventStream<'a> {
 impl<'a> futures::Stream for EventStream<'a> {
     type Item = Result<Event>;
 
-    fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        // This is a simplified implementation. In a real implementation,
-        // you would need to handle the async reading properly with tokio's
-        // async I/O primitives.
-        Poll::Pending
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let fanotify = &mut *self.get_mut().fanotify;
+
+        loop {
+            if let Some(event) = fanotify.buffer.pop() {
+                return Poll::Ready(Some(Ok(event)));
+            }
+
+            let mut guard = match fanotify.fd.poll_read_ready(cx) {
+                Poll::Ready(Ok(guard)) => guard,
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(FanotifyError::Io(e)))),
+                Poll::Pending => return Poll::Pending,
+            };
+
+            let buffer = &mut fanotify.buffer;
+            match guard.try_io(|inner| sys::read_fd(inner.as_raw_fd(), buffer.space())) {
+                Ok(Ok(0)) => return Poll::Ready(None),
+                Ok(Ok(n)) => {
+                    if let Err(e) = buffer.fill(fanotify.fd.as_raw_fd(), n) {
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                }
+                Ok(Err(e)) => return Poll::Ready(Some(Err(FanotifyError::Io(e)))),
+                // Readiness was stale, wait for the next notification
+                Err(_would_block) => {}
+            }
+        }
     }
 }
 
@@ -295,11 +581,16 @@ impl AsyncFanotify {
     pub fn with_flags(_flags: FanotifyFlags) -> Result<Self> {
         Err(FanotifyError::NotSupported)
     }
+
+    pub fn with_event_flags(_flags: FanotifyFlags, _event_f_flags: i32) -> Result<Self> {
+        Err(FanotifyError::NotSupported)
+    }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "tokio"))]
 mod tests {
     use super::*;
+    use crate::test_support::{assert_denied, open_in_thread};
     use tempfile::tempdir;
 
     #[cfg(feature = "tokio")]
@@ -309,6 +600,12 @@ mod tests {
         assert!(fanotify.is_ok());
     }
 
+    #[test]
+    fn test_creation_outside_runtime_fails() {
+        let err = AsyncFanotify::new().err().unwrap();
+        assert!(err.to_string().contains("runtime"), "{}", err);
+    }
+
     #[cfg(feature = "tokio")]
     #[tokio::test]
     async fn test_async_add_watch() {
@@ -344,4 +641,111 @@ mod tests {
         assert!(result.is_ok(), "remove_watch failed: {:?}", result.err());
         assert!(!fanotify.is_watched(temp_dir.path()));
     }
-} 
\ No newline at end of file
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_async_into_split() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<AsyncFanotifyHandle>();
+
+        let fanotify = AsyncFanotify::new().unwrap();
+        let (mut reader, handle) = fanotify.into_split().unwrap();
+        let temp_dir = tempdir().unwrap();
+        let test_file = temp_dir.path().join("split.txt");
+
+        std::fs::write(&test_file, "").unwrap();
+
+        let control = handle.clone();
+        let watch_file = test_file.clone();
+        tokio::spawn(async move {
+            control.add_watch(&watch_file, MaskFlags::MODIFY).await
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(handle.is_watched(&test_file));
+
+        std::fs::write(&test_file, "split").unwrap();
+
+        let event = tokio::time::timeout(std::time::Duration::from_secs(5), reader.read_event())
+            .await
+            .expect("timed out waiting for event")
+            .unwrap()
+            .unwrap();
+        assert!(event.is_modify());
+
+        handle.remove_watch(&test_file).await.unwrap();
+        assert!(!handle.is_watched(&test_file));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_async_run_handler() {
+        struct StopAfterModify(bool);
+
+        impl Handler for StopAfterModify {
+            fn on_event(&mut self, event: &Event) {
+                self.0 |= event.is_modify();
+            }
+
+            fn should_stop(&self) -> bool {
+                self.0
+            }
+        }
+
+        let mut fanotify = AsyncFanotify::new().unwrap();
+        let temp_dir = tempdir().unwrap();
+        let test_file = temp_dir.path().join("run.txt");
+        std::fs::write(&test_file, "").unwrap();
+        fanotify.add_watch(&test_file, MaskFlags::MODIFY).await.unwrap();
+        std::fs::write(&test_file, "run").unwrap();
+
+        let mut handler = StopAfterModify(false);
+        tokio::time::timeout(std::time::Duration::from_secs(5), fanotify.run(&mut handler))
+            .await
+            .expect("timed out waiting for event")
+            .unwrap();
+        assert!(handler.0);
+    }
+
+    #[tokio::test]
+    async fn test_async_shutdown_answers_pending_permissions() {
+        let mut fanotify = AsyncFanotify::with_flags(FanotifyFlags::CLASS_CONTENT).unwrap();
+        let temp_dir = tempdir().unwrap();
+        let test_file = temp_dir.path().join("pending.txt");
+        std::fs::write(&test_file, "pending").unwrap();
+        fanotify.add_watch(&test_file, MaskFlags::OPEN_PERM).await.unwrap();
+        let opener = open_in_thread(&test_file);
+
+        let event = fanotify.wait_for_event().await.unwrap();
+        assert!(event.is_permission());
+        assert_eq!(fanotify.pending_permissions(), 1);
+
+        fanotify.shutdown(Decision::Deny).await.unwrap();
+        sys::close_fd(event.info.fd.unwrap());
+        assert_denied(opener);
+    }
+
+    #[tokio::test]
+    async fn test_run_until_cancelled_flushes_marks() {
+        struct Ignore;
+
+        impl Handler for Ignore {
+            fn on_event(&mut self, _event: &Event) {}
+        }
+
+        let mut fanotify = AsyncFanotify::with_flags(FanotifyFlags::CLASS_CONTENT).unwrap();
+        let temp_dir = tempdir().unwrap();
+        fanotify.add_watch(temp_dir.path(), MaskFlags::OPEN_PERM).await.unwrap();
+
+        let token = CancellationToken::new();
+        let child = token.child_token();
+        let task = tokio::spawn(async move {
+            fanotify.run_until_cancelled(Ignore, &child, Decision::Allow).await
+        });
+
+        token.cancel();
+        let result = tokio::time::timeout(std::time::Duration::from_secs(5), task).await;
+        result.unwrap().unwrap().unwrap();
+    }
+}
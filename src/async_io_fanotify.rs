@@ -0,0 +1,380 @@
+//! Runtime-agnostic asynchronous fanotify support built on `async-io`
+//!
+//! [`AsyncIoFanotify`] offers the same reader and stream API as
+//! [`AsyncFanotify`](crate::async_fanotify::AsyncFanotify) but is driven by
+//! [`async_io::Async`], so it works with smol, async-std or any executor that
+//! can poll plain futures.
+
+use std::collections::HashMap;
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::task::{Context, Poll};
+
+use async_io::Async;
+use futures::future::Either;
+
+use crate::{
+    decode::EventBuffer,
+    error::{FanotifyError, Result},
+    event::Event,
+    flags::{EventFlags, FanotifyFlags, MaskFlags},
+    handler::{self, Decision, Handler},
+    linux::{FAN_MARK_ADD, FAN_MARK_REMOVE},
+    pending,
+    permission::{Incoming, Responder},
+    response::Response,
+    sys::{self, mark_path},
+};
+
+/// An asynchronous fanotify instance driven by the `async-io` reactor
+pub struct AsyncIoFanotify {
+    /// The file descriptor for the fanotify instance
+    fd: Async<OwnedFd>,
+    /// Buffer for reading events
+    buffer: EventBuffer,
+    /// Watched paths and their masks
+    watched_paths: HashMap<PathBuf, MaskFlags>,
+    /// Shared responder, created the first time it is needed
+    responder: OnceLock<Responder>,
+    /// Decision used by permission requests dropped without an answer
+    default_decision: Decision,
+}
+
+impl AsyncIoFanotify {
+    /// Create a new asynchronous fanotify instance with default flags
+    pub fn new() -> Result<Self> {
+        Self::with_flags(FanotifyFlags::default())
+    }
+
+    /// Create a new asynchronous fanotify instance with custom flags
+    ///
+    /// The group is always switched to non-blocking mode so it can be
+    /// registered with the reactor, whether or not `NONBLOCK` was requested.
+    pub fn with_flags(flags: FanotifyFlags) -> Result<Self> {
+        Self::with_event_flags(flags, libc::O_RDONLY)
+    }
+
+    /// Create a new asynchronous fanotify instance whose event file
+    /// descriptors are opened with the `open(2)` flags `event_f_flags`
+    ///
+    /// See [`Fanotify::with_event_flags`](crate::Fanotify::with_event_flags).
+    pub fn with_event_flags(flags: FanotifyFlags, event_f_flags: i32) -> Result<Self> {
+        let fd = sys::init(flags, event_f_flags as u32)?;
+
+        Ok(Self {
+            fd: Async::new(fd)?,
+            buffer: EventBuffer::new(),
+            watched_paths: HashMap::new(),
+            responder: OnceLock::new(),
+            default_decision: Decision::default(),
+        })
+    }
+
+    /// Add a watch for a path with the specified mask
+    pub async fn add_watch<P: AsRef<Path>>(&mut self, path: P, mask: MaskFlags) -> Result<()> {
+        let path = path.as_ref();
+        mark_path(self.fd.as_raw_fd(), FAN_MARK_ADD, mask, path)?;
+        self.watched_paths.insert(path.to_path_buf(), mask);
+        Ok(())
+    }
+
+    /// Remove a watch for a path
+    pub async fn remove_watch<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mask = self.watched_paths.get(path).copied().unwrap_or(MaskFlags::empty());
+        mark_path(self.fd.as_raw_fd(), FAN_MARK_REMOVE, mask, path)?;
+        self.watched_paths.remove(path);
+        Ok(())
+    }
+
+    /// Read a single event asynchronously
+    ///
+    /// Waits until the group becomes readable.
+    pub async fn read_event(&mut self) -> Result<Option<Event>> {
+        if let Some(event) = self.buffer.pop() {
+            return Ok(Some(event));
+        }
+
+        let buffer = &mut self.buffer;
+        let bytes_read = self
+            .fd
+            .read_with(|fd| sys::read_fd(fd.as_raw_fd(), buffer.space()))
+            .await?;
+
+        if bytes_read == 0 {
+            return Ok(None);
+        }
+
+        buffer.fill(self.fd.as_raw_fd(), bytes_read)?;
+        Ok(buffer.pop())
+    }
+
+    /// Read all available events asynchronously
+    ///
+    /// Waits for the next batch and returns every event decoded from it,
+    /// together with anything still buffered from an earlier read.
+    pub async fn read_events(&mut self) -> Result<Vec<Event>> {
+        let mut events = Vec::new();
+
+        if let Some(event) = self.read_event().await? {
+            events.push(event);
+            events.extend(self.buffer.drain());
+        }
+
+        Ok(events)
+    }
+
+    /// Get the next event
+    pub async fn next_event(&mut self) -> Result<Option<Event>> {
+        self.read_event().await
+    }
+
+    /// Wait for the next event
+    pub async fn wait_for_event(&mut self) -> Result<Event> {
+        loop {
+            if let Some(event) = self.read_event().await? {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Run an event loop that hands every event to `handler`
+    ///
+    /// Behaves like [`Fanotify::run`](crate::Fanotify::run): permission
+    /// events are answered with the handler's decision and event file
+    /// descriptors are closed once the callbacks return.
+    pub async fn run<H: Handler>(&mut self, mut handler: H) -> Result<()> {
+        while !handler.should_stop() {
+            let read = {
+                let read = self.read_event();
+                let idle = async_io::Timer::after(handler::STOP_CHECK_INTERVAL);
+                futures::pin_mut!(read);
+                match futures::future::select(read, idle).await {
+                    Either::Left((read, _)) => Some(read),
+                    Either::Right(_) => None,
+                }
+            };
+            let first = match read {
+                // Idle: go back and check should_stop
+                None => continue,
+                Some(Ok(Some(event))) => event,
+                Some(Ok(None)) => continue,
+                Some(Err(e)) => {
+                    if handler.on_error(&e).is_break() {
+                        return Err(e);
+                    }
+                    continue;
+                }
+            };
+
+            let group_fd = self.fd.as_raw_fd();
+            let pending = self.buffer.permissions().clone();
+            let events = std::iter::once(first).chain(self.buffer.drain());
+            handler::dispatch_batch(&mut handler, group_fd, &pending, events)?;
+        }
+
+        Ok(())
+    }
+
+    /// Shut the group down without leaving any process waiting on it
+    ///
+    /// Behaves like [`Fanotify::shutdown`](crate::Fanotify::shutdown).
+    pub async fn shutdown(mut self, default: Decision) -> Result<()> {
+        let group_fd = self.fd.as_raw_fd();
+        let flushed = sys::flush_marks(group_fd);
+        self.watched_paths.clear();
+
+        let (unread, read_result) = match self.buffer.drain_fd(group_fd) {
+            Ok(events) => (events, Ok(())),
+            Err(e) => (self.buffer.drain().collect(), Err(e)),
+        };
+        let answered = pending::finish(group_fd, self.buffer.permissions(), unread, default.response());
+
+        flushed.and(read_result).and(answered)
+    }
+
+    /// Respond to a permission event asynchronously
+    pub async fn respond(&mut self, event: &Event, response: EventFlags) -> Result<()> {
+        self.respond_with(event, &Response::new(response)).await
+    }
+
+    /// Respond to a permission event with an extended [`Response`]
+    pub async fn respond_with(&mut self, event: &Event, response: &Response) -> Result<()> {
+        self.buffer.permissions().respond(self.fd.as_raw_fd(), event, response)
+    }
+
+    /// Wait for the next event and sort it into a notification or a permission request
+    ///
+    /// See [`Fanotify::read_incoming`](crate::Fanotify::read_incoming).
+    pub async fn next_incoming(&mut self) -> Result<Incoming> {
+        let event = self.wait_for_event().await?;
+        Ok(self.responder()?.incoming(event, self.default_decision))
+    }
+
+    /// A thread-safe responder for this group's permission events
+    pub fn responder(&self) -> Result<Responder> {
+        if let Some(responder) = self.responder.get() {
+            return Ok(responder.clone());
+        }
+
+        let fd = self.fd.get_ref().try_clone()?;
+        let responder = Responder::new(fd, self.buffer.permissions().clone());
+        Ok(self.responder.get_or_init(|| responder).clone())
+    }
+
+    /// The decision used by permission requests dropped without an answer
+    pub fn default_decision(&self) -> Decision {
+        self.default_decision
+    }
+
+    /// Change the decision used by permission requests dropped without an answer
+    pub fn set_default_decision(&mut self, decision: Decision) {
+        self.default_decision = decision;
+    }
+
+    /// Number of permission events that were read but not answered yet
+    pub fn pending_permissions(&self) -> usize {
+        self.buffer.permissions().len()
+    }
+
+    /// Allow a permission event asynchronously
+    pub async fn allow(&mut self, event: &Event) -> Result<()> {
+        self.respond(event, EventFlags::ALLOW).await
+    }
+
+    /// Deny a permission event asynchronously
+    pub async fn deny(&mut self, event: &Event) -> Result<()> {
+        self.respond(event, EventFlags::DENY).await
+    }
+
+    /// Get the list of watched paths
+    pub fn watched_paths(&self) -> &HashMap<PathBuf, MaskFlags> {
+        &self.watched_paths
+    }
+
+    /// Check if a path is being watched
+    pub fn is_watched<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.watched_paths.contains_key(path.as_ref())
+    }
+
+    /// Get the mask for a watched path
+    pub fn get_mask<P: AsRef<Path>>(&self, path: P) -> Option<MaskFlags> {
+        self.watched_paths.get(path.as_ref()).copied()
+    }
+
+    /// Set the buffer size for reading events
+    pub fn set_buffer_size(&mut self, size: usize) {
+        self.buffer.resize(size);
+    }
+
+    /// Get the current buffer size
+    pub fn buffer_size(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Create a stream of events
+    pub fn event_stream(&mut self) -> EventStream<'_> {
+        EventStream { fanotify: self }
+    }
+}
+
+impl AsRawFd for AsyncIoFanotify {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// A stream of fanotify events driven by the `async-io` reactor
+pub struct EventStream<'a> {
+    fanotify: &'a mut AsyncIoFanotify,
+}
+
+impl<'a> futures::Stream for EventStream<'a> {
+    type Item = Result<Event>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let fanotify = &mut *self.get_mut().fanotify;
+
+        loop {
+            if let Some(event) = fanotify.buffer.pop() {
+                return Poll::Ready(Some(Ok(event)));
+            }
+
+            match fanotify.fd.poll_readable(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(FanotifyError::Io(e)))),
+                Poll::Pending => return Poll::Pending,
+            }
+
+            let raw_fd = fanotify.fd.as_raw_fd();
+            match sys::read_fd(raw_fd, fanotify.buffer.space()) {
+                Ok(0) => return Poll::Ready(None),
+                Ok(n) => {
+                    if let Err(e) = fanotify.buffer.fill(raw_fd, n) {
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                }
+                // Spurious wakeup, wait for the next readiness notification
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Poll::Ready(Some(Err(FanotifyError::Io(e)))),
+            }
+        }
+    }
+}
+
+impl<'a> EventStream<'a> {
+    /// Create a new event stream
+    pub fn new(fanotify: &'a mut AsyncIoFanotify) -> Self {
+        Self { fanotify }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_async_io_read_event() {
+        async_io::block_on(async {
+            let mut fanotify = AsyncIoFanotify::new().unwrap();
+            let temp_dir = tempdir().unwrap();
+            let test_file = temp_dir.path().join("async_io.txt");
+            std::fs::write(&test_file, "").unwrap();
+
+            fanotify.add_watch(&test_file, MaskFlags::MODIFY).await.unwrap();
+            std::fs::write(&test_file, "async-io").unwrap();
+
+            let read = fanotify.read_event();
+            let timeout = async_io::Timer::after(Duration::from_secs(5));
+            futures::pin_mut!(read);
+            let event = match futures::future::select(read, timeout).await {
+                futures::future::Either::Left((event, _)) => event.unwrap().unwrap(),
+                futures::future::Either::Right(_) => panic!("timed out waiting for event"),
+            };
+            assert!(event.is_modify());
+        });
+    }
+
+    #[test]
+    fn test_async_io_event_stream() {
+        async_io::block_on(async {
+            let mut fanotify = AsyncIoFanotify::new().unwrap();
+            let temp_dir = tempdir().unwrap();
+            let test_file = temp_dir.path().join("stream.txt");
+            std::fs::write(&test_file, "").unwrap();
+
+            fanotify.add_watch(&test_file, MaskFlags::MODIFY).await.unwrap();
+            std::fs::write(&test_file, "stream").unwrap();
+
+            let mut stream = fanotify.event_stream();
+            let event = stream.next().await.unwrap().unwrap();
+            assert!(event.is_modify());
+        });
+    }
+}
@@ -0,0 +1,607 @@
+//! In-process event queue with backpressure policies
+//!
+//! The kernel queue of a fanotify group is large but not unbounded, and when
+//! it overflows all the consumer gets is a single `Q_OVERFLOW` event. A
+//! [`BufferedReader`] drains the group from a background task into a bounded
+//! queue of its own, where an [`OverflowPolicy`] decides what happens when
+//! the consumer falls behind. [`QueueMetrics`] report how the queue is doing
+//! and [`BufferedReader::lag_signal`] flips to `true` while the consumer is
+//! behind.
+//!
+//! Events are handed over unchanged, including their file descriptors, and
+//! permission events still have to be answered through
+//! [`BufferedReader::handle`]. Permission events are never dropped or
+//! coalesced: when the queue is full and the policy cannot make room by
+//! discarding a notification event, the reader waits as with
+//! [`OverflowPolicy::Block`]. The file descriptors of dropped and coalesced
+//! events are closed by the queue.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinHandle;
+
+use crate::{
+    async_fanotify::{AsyncEventReader, AsyncFanotify, AsyncFanotifyHandle},
+    error::{FanotifyError, Result},
+    event::Event,
+    handler::Decision,
+    permission::Responder,
+    response::Response,
+    sys,
+};
+
+/// What to do with a new event when the queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum OverflowPolicy {
+    /// Stop reading the group until the consumer makes room
+    ///
+    /// Nothing is lost in-process, but events pile up in the kernel queue,
+    /// which may eventually overflow.
+    #[default]
+    Block,
+    /// Discard the oldest queued notification event
+    DropOldest,
+    /// Discard the new event, unless it is a permission event
+    DropNewest,
+    /// Merge the new event into a queued event for the same path
+    ///
+    /// The masks are combined and the queued event keeps its place. If no
+    /// queued event has the same path, the oldest one is discarded.
+    CoalesceByPath,
+}
+
+/// Configuration of a [`BufferedReader`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueConfig {
+    capacity: usize,
+    policy: OverflowPolicy,
+    lag_threshold: usize,
+}
+
+impl QueueConfig {
+    /// A queue holding up to `capacity` events, using [`OverflowPolicy::Block`]
+    ///
+    /// The lag threshold defaults to three quarters of the capacity.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "queue capacity must be non-zero");
+
+        Self {
+            capacity,
+            policy: OverflowPolicy::default(),
+            lag_threshold: (capacity * 3 / 4).max(1),
+        }
+    }
+
+    /// Set the overflow policy
+    pub fn with_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Report the consumer as lagging once this many events are queued
+    ///
+    /// The signal is cleared again when the queue has drained to half the
+    /// threshold. The value is clamped to `1..=capacity`.
+    pub fn with_lag_threshold(mut self, threshold: usize) -> Self {
+        self.lag_threshold = threshold.clamp(1, self.capacity);
+        self
+    }
+
+    /// Maximum number of queued events
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The overflow policy
+    pub fn policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+
+    /// Queue depth at which the consumer is reported as lagging
+    pub fn lag_threshold(&self) -> usize {
+        self.lag_threshold
+    }
+}
+
+/// A snapshot of the queue's counters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct QueueMetrics {
+    /// Events currently queued
+    pub depth: usize,
+    /// Highest depth seen so far
+    pub high_watermark: usize,
+    /// Events read from the group
+    pub received: u64,
+    /// Events handed to the consumer
+    pub delivered: u64,
+    /// Events discarded by the overflow policy
+    pub dropped: u64,
+    /// Events merged into an already queued event
+    pub coalesced: u64,
+}
+
+/// State shared by the reader task and the consumer
+struct Shared {
+    config: QueueConfig,
+    state: Mutex<State>,
+    /// Woken when an event is queued or the reader stops
+    readable: Notify,
+    /// Woken when the consumer takes an event
+    writable: Notify,
+    lagging: watch::Sender<bool>,
+    /// Answers the permission events left over when the reader is dropped
+    responder: Responder,
+    default_decision: Decision,
+}
+
+#[derive(Default)]
+struct State {
+    /// Events read from the group that are waiting for room in the queue
+    incoming: VecDeque<Event>,
+    events: VecDeque<Event>,
+    metrics: QueueMetrics,
+    /// A read error waiting to be handed to the consumer
+    error: Option<FanotifyError>,
+    /// The reader task has stopped, or the reader was dropped
+    closed: bool,
+}
+
+impl Shared {
+    /// Take over `events` read from the group
+    ///
+    /// Returns `false` if the reader was dropped in the meantime, in which
+    /// case the events are settled here.
+    fn receive(&self, events: Vec<Event>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            drop(state);
+            events.into_iter().for_each(|event| self.settle(event));
+            return false;
+        }
+
+        state.metrics.received += events.len() as u64;
+        state.incoming.extend(events);
+        true
+    }
+
+    /// Queue the received events according to the overflow policy, waiting
+    /// for room where the policy says so
+    async fn admit(&self) {
+        loop {
+            let writable = self.writable.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                while let Some(event) = state.incoming.pop_front() {
+                    if let Err(event) = self.place(&mut state, event) {
+                        state.incoming.push_front(event);
+                        break;
+                    }
+                }
+                if state.incoming.is_empty() {
+                    return;
+                }
+            }
+            writable.await;
+        }
+    }
+
+    /// Queue, merge or drop `event`; hands it back if it has to wait for room
+    fn place(&self, state: &mut State, event: Event) -> std::result::Result<(), Event> {
+        if state.events.len() < self.config.capacity {
+            state.events.push_back(event);
+            self.queued(state);
+            return Ok(());
+        }
+
+        match (self.config.policy, event.is_permission()) {
+            (OverflowPolicy::Block, _) | (OverflowPolicy::DropNewest, true) => Err(event),
+            (OverflowPolicy::DropNewest, false) => {
+                discard(event);
+                state.metrics.dropped += 1;
+                Ok(())
+            }
+            (OverflowPolicy::CoalesceByPath, false) if coalesce(&mut state.events, &event) => {
+                discard(event);
+                state.metrics.coalesced += 1;
+                Ok(())
+            }
+            (OverflowPolicy::DropOldest | OverflowPolicy::CoalesceByPath, _) => {
+                // Only permission events queued: wait rather than grow
+                if !drop_oldest(state) {
+                    return Err(event);
+                }
+                state.events.push_back(event);
+                self.queued(state);
+                Ok(())
+            }
+        }
+    }
+
+    /// Answer `event` with the default decision if it is a permission event,
+    /// and close its file descriptor
+    fn settle(&self, event: Event) {
+        if event.is_permission() {
+            let response = Response::new(self.default_decision.response());
+            if let Err(e) = self.responder.respond_with(&event, &response) {
+                log::warn!("failed to answer a permission event left in the queue: {}", e);
+            }
+        }
+        discard(event);
+    }
+
+    /// Update metrics and wake the consumer after an event was queued
+    fn queued(&self, state: &mut State) {
+        let depth = state.events.len();
+        state.metrics.depth = depth;
+        state.metrics.high_watermark = state.metrics.high_watermark.max(depth);
+
+        if depth >= self.config.lag_threshold {
+            self.lagging.send_if_modified(|lagging| !std::mem::replace(lagging, true));
+        }
+        self.readable.notify_one();
+    }
+
+    /// Take the next event, if any
+    fn pop(&self, state: &mut State) -> Option<Event> {
+        let event = state.events.pop_front()?;
+        let depth = state.events.len();
+        state.metrics.depth = depth;
+        state.metrics.delivered += 1;
+
+        if depth <= self.config.lag_threshold / 2 {
+            self.lagging.send_if_modified(|lagging| std::mem::replace(lagging, false));
+        }
+        self.writable.notify_one();
+        Some(event)
+    }
+
+    /// Record that the reader task stopped because of `error`
+    fn close(&self, error: FanotifyError) {
+        let mut state = self.state.lock().unwrap();
+        state.error = Some(error);
+        state.closed = true;
+        self.readable.notify_one();
+    }
+}
+
+/// Discard the oldest notification event to make room
+///
+/// Returns `false` if every queued event is a permission event.
+fn drop_oldest(state: &mut State) -> bool {
+    let position = state.events.iter().position(|event| !event.is_permission());
+    match position.and_then(|i| state.events.remove(i)) {
+        Some(event) => {
+            discard(event);
+            state.metrics.dropped += 1;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Merge `event` into a queued notification event for the same path
+fn coalesce(events: &mut VecDeque<Event>, event: &Event) -> bool {
+    let Some(path) = event.info.path.as_deref() else {
+        return false;
+    };
+
+    match events
+        .iter_mut()
+        .find(|queued| !queued.is_permission() && queued.info.path.as_deref() == Some(path))
+    {
+        Some(queued) => {
+            queued.info.mask |= event.info.mask;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Close the file descriptor of an event that will never be delivered
+fn discard(event: Event) {
+    if let Some(fd) = event.info.fd {
+        sys::close_fd(fd);
+    }
+}
+
+/// Reads a group into a bounded in-process queue
+///
+/// Dropping the reader stops the background task, answers the permission
+/// events still queued with the group's
+/// [default decision](AsyncFanotify::default_decision) and closes the file
+/// descriptors of every event still queued.
+pub struct BufferedReader {
+    handle: AsyncFanotifyHandle,
+    shared: Arc<Shared>,
+    lagging: watch::Receiver<bool>,
+    task: JoinHandle<()>,
+}
+
+impl BufferedReader {
+    /// Start reading `fanotify` into a queue configured by `config`
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn spawn(fanotify: AsyncFanotify, config: QueueConfig) -> Result<Self> {
+        let default_decision = fanotify.default_decision();
+        let (reader, handle) = fanotify.into_split()?;
+        let (lagging_tx, lagging) = watch::channel(false);
+        let shared = Arc::new(Shared {
+            config,
+            state: Mutex::new(State::default()),
+            readable: Notify::new(),
+            writable: Notify::new(),
+            lagging: lagging_tx,
+            responder: handle.responder()?,
+            default_decision,
+        });
+
+        let task = tokio::spawn(fill(reader, Arc::clone(&shared)));
+
+        Ok(Self {
+            handle,
+            shared,
+            lagging,
+            task,
+        })
+    }
+
+    /// Wait for the next event
+    ///
+    /// A read error is returned once, after every event queued before it.
+    /// Returns `None` after that, or once the queue is empty and the reader
+    /// task has stopped.
+    pub async fn recv(&mut self) -> Option<Result<Event>> {
+        loop {
+            let readable = self.shared.readable.notified();
+            if let Some(item) = self.take() {
+                return item;
+            }
+            readable.await;
+        }
+    }
+
+    /// Return the next event if one is queued, without waiting
+    pub fn try_recv(&mut self) -> Option<Result<Event>> {
+        self.take().flatten()
+    }
+
+    /// Take the next item; the outer `None` means "nothing yet, keep waiting"
+    fn take(&self) -> Option<Option<Result<Event>>> {
+        let mut state = self.shared.state.lock().unwrap();
+
+        if let Some(event) = self.shared.pop(&mut state) {
+            return Some(Some(Ok(event)));
+        }
+        if let Some(error) = state.error.take() {
+            return Some(Some(Err(error)));
+        }
+        if state.closed {
+            return Some(None);
+        }
+
+        None
+    }
+
+    /// The control handle of the underlying group
+    pub fn handle(&self) -> &AsyncFanotifyHandle {
+        &self.handle
+    }
+
+    /// The queue configuration
+    pub fn config(&self) -> &QueueConfig {
+        &self.shared.config
+    }
+
+    /// A snapshot of the queue's counters
+    pub fn metrics(&self) -> QueueMetrics {
+        self.shared.state.lock().unwrap().metrics
+    }
+
+    /// Check whether the consumer is currently falling behind
+    pub fn is_lagging(&self) -> bool {
+        *self.lagging.borrow()
+    }
+
+    /// A receiver that is notified whenever the lagging state changes
+    ///
+    /// The value is `true` from the moment the queue depth reaches the lag
+    /// threshold until it has drained to half of it.
+    pub fn lag_signal(&self) -> watch::Receiver<bool> {
+        self.lagging.clone()
+    }
+}
+
+impl Drop for BufferedReader {
+    fn drop(&mut self) {
+        // Closing first makes the task settle whatever it reads from now on
+        let mut state = self.shared.state.lock().unwrap();
+        state.closed = true;
+        let mut left: Vec<Event> = state.incoming.drain(..).collect();
+        left.extend(state.events.drain(..));
+        drop(state);
+
+        self.task.abort();
+        for event in left {
+            self.shared.settle(event);
+        }
+    }
+}
+
+/// Body of the reader task
+///
+/// Read events go straight into the shared state, so they are never lost
+/// when the task is aborted.
+async fn fill(mut reader: AsyncEventReader, shared: Arc<Shared>) {
+    loop {
+        match reader.read_events().await {
+            Ok(events) => {
+                if !shared.receive(events) {
+                    return;
+                }
+                shared.admit().await;
+            }
+            Err(e) => {
+                shared.close(e);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::open_in_thread;
+    use crate::MaskFlags;
+    use std::fs::File;
+    use std::path::Path;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    /// Open `path` `count` times, giving the reader task time to catch up
+    async fn open_repeatedly(path: &Path, count: usize) {
+        for _ in 0..count {
+            drop(File::open(path).unwrap());
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Wait until the queue has seen `received` events
+    async fn wait_for_received(reader: &BufferedReader, received: u64) {
+        for _ in 0..500 {
+            if reader.metrics().received >= received {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("queue only received {:?}", reader.metrics());
+    }
+
+    async fn spawn_watching(path: &Path, config: QueueConfig) -> BufferedReader {
+        let mut fanotify = AsyncFanotify::new().unwrap();
+        fanotify.add_watch(path, MaskFlags::OPEN).await.unwrap();
+        BufferedReader::spawn(fanotify, config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_keeps_newest_events() {
+        let temp_dir = tempdir().unwrap();
+        let test_file = temp_dir.path().join("oldest.txt");
+        std::fs::write(&test_file, "").unwrap();
+
+        let config = QueueConfig::new(2).with_policy(OverflowPolicy::DropOldest);
+        let mut reader = spawn_watching(&test_file, config).await;
+        open_repeatedly(&test_file, 5).await;
+        wait_for_received(&reader, 5).await;
+
+        let metrics = reader.metrics();
+        assert_eq!(metrics.depth, 2);
+        assert_eq!(metrics.high_watermark, 2);
+        assert_eq!(metrics.dropped, 3);
+        assert!(reader.is_lagging());
+
+        assert!(reader.recv().await.unwrap().unwrap().is_open());
+        assert!(reader.recv().await.unwrap().unwrap().is_open());
+        assert!(reader.try_recv().is_none());
+        assert!(!reader.is_lagging());
+        assert_eq!(reader.metrics().delivered, 2);
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_by_path_merges_events() {
+        let temp_dir = tempdir().unwrap();
+        let test_file = temp_dir.path().join("coalesce.txt");
+        std::fs::write(&test_file, "").unwrap();
+
+        let config = QueueConfig::new(1).with_policy(OverflowPolicy::CoalesceByPath);
+        let mut reader = spawn_watching(&test_file, config).await;
+        open_repeatedly(&test_file, 3).await;
+        wait_for_received(&reader, 3).await;
+
+        let metrics = reader.metrics();
+        assert_eq!(metrics.depth, 1);
+        assert_eq!(metrics.coalesced, 2);
+        assert_eq!(metrics.dropped, 0);
+
+        let event = reader.recv().await.unwrap().unwrap();
+        assert_eq!(event.info.path.as_deref(), Some(test_file.as_path()));
+    }
+
+    #[tokio::test]
+    async fn test_block_stops_reading_until_consumed() {
+        let temp_dir = tempdir().unwrap();
+        let test_file = temp_dir.path().join("block.txt");
+        std::fs::write(&test_file, "").unwrap();
+
+        let mut reader = spawn_watching(&test_file, QueueConfig::new(1)).await;
+        let mut lag_signal = reader.lag_signal();
+        open_repeatedly(&test_file, 3).await;
+        wait_for_received(&reader, 1).await;
+        lag_signal.wait_for(|lagging| *lagging).await.unwrap();
+
+        for _ in 0..3 {
+            let event = tokio::time::timeout(Duration::from_secs(5), reader.recv()).await;
+            assert!(event.unwrap().unwrap().unwrap().is_open());
+        }
+
+        let metrics = reader.metrics();
+        assert_eq!(metrics.received, 3);
+        assert_eq!(metrics.delivered, 3);
+        assert_eq!(metrics.dropped, 0);
+    }
+
+    /// A reader queueing the `OPEN_PERM` events of `path`
+    async fn spawn_permission_watching(path: &Path, config: QueueConfig) -> BufferedReader {
+        let mut fanotify = AsyncFanotify::with_flags(crate::FanotifyFlags::CLASS_CONTENT).unwrap();
+        fanotify.add_watch(path, MaskFlags::OPEN_PERM).await.unwrap();
+        BufferedReader::spawn(fanotify, config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_permission_events_wait_for_room() {
+        let temp_dir = tempdir().unwrap();
+        let test_file = temp_dir.path().join("permission.txt");
+        std::fs::write(&test_file, "").unwrap();
+
+        let config = QueueConfig::new(1).with_policy(OverflowPolicy::DropOldest);
+        let mut reader = spawn_permission_watching(&test_file, config).await;
+        let openers: Vec<_> = (0..2).map(|_| open_in_thread(&test_file)).collect();
+        wait_for_received(&reader, 2).await;
+
+        let metrics = reader.metrics();
+        assert_eq!((metrics.depth, metrics.high_watermark, metrics.dropped), (1, 1, 0));
+
+        for _ in 0..2 {
+            let event = tokio::time::timeout(Duration::from_secs(5), reader.recv()).await;
+            let event = event.unwrap().unwrap().unwrap();
+            reader.handle().allow(&event).await.unwrap();
+            sys::close_fd(event.info.fd.unwrap());
+        }
+        for opener in openers {
+            opener.join().unwrap().unwrap();
+        }
+        assert_eq!(reader.metrics().high_watermark, 1);
+    }
+
+    #[tokio::test]
+    async fn test_drop_answers_queued_permission_events() {
+        let temp_dir = tempdir().unwrap();
+        let test_file = temp_dir.path().join("dropped.txt");
+        std::fs::write(&test_file, "").unwrap();
+
+        let reader = spawn_permission_watching(&test_file, QueueConfig::new(4)).await;
+        let opener = open_in_thread(&test_file);
+        wait_for_received(&reader, 1).await;
+
+        let handle = reader.handle().clone();
+        drop(reader);
+        assert_eq!(handle.pending_permissions(), 0);
+        opener.join().unwrap().unwrap();
+    }
+}
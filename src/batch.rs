@@ -0,0 +1,433 @@
+//! Answering permission events in batches
+//!
+//! Every [`Fanotify::respond`](crate::Fanotify::respond) is one `write(2)`.
+//! A [`BatchResponder`] collects responses instead and writes them together
+//! with a single `writev(2)` once enough have accumulated or the oldest has
+//! waited long enough, whichever comes first.
+//!
+//! The kernel checks every response on its own, so one it rejects (for
+//! example because the event was already answered) does not affect the
+//! others: the error is reported for that event and the rest are written.
+
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::{
+    error::{FanotifyError, Result},
+    event::Event,
+    flags::EventFlags,
+    permission::Responder,
+    response::Response,
+    sys,
+};
+
+/// Configuration of a [`BatchResponder`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchConfig {
+    max_batch: usize,
+    max_delay: Duration,
+}
+
+impl BatchConfig {
+    /// Flush once `max_batch` responses are queued, or after one millisecond
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_batch` is zero.
+    pub fn new(max_batch: usize) -> Self {
+        assert!(max_batch > 0, "batch size must be non-zero");
+
+        Self {
+            max_batch,
+            max_delay: Duration::from_millis(1),
+        }
+    }
+
+    /// Flush once the oldest queued response has waited `delay`
+    pub fn with_max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    /// Number of queued responses that triggers a flush
+    pub fn max_batch(&self) -> usize {
+        self.max_batch
+    }
+
+    /// Longest time a response is held back
+    pub fn max_delay(&self) -> Duration {
+        self.max_delay
+    }
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self::new(64)
+    }
+}
+
+/// A response the kernel did not accept
+#[derive(Debug)]
+pub struct ResponseError {
+    /// File descriptor of the event that was answered
+    pub fd: RawFd,
+    /// Process that triggered the event
+    pub pid: u32,
+    /// Why the response failed
+    pub error: FanotifyError,
+}
+
+/// The outcome of a flush
+#[derive(Debug, Default)]
+pub struct FlushReport {
+    /// Responses written successfully
+    pub answered: usize,
+    /// Responses skipped because a watchdog had already answered the event
+    pub expired: usize,
+    /// Responses that failed
+    pub errors: Vec<ResponseError>,
+}
+
+/// A snapshot of the responder's counters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BatchStats {
+    /// Number of flushes that wrote at least one response
+    pub flushes: u64,
+    /// Responses written successfully
+    pub answered: u64,
+    /// Responses skipped because the event had already expired
+    pub expired: u64,
+    /// Responses that failed
+    pub failed: u64,
+}
+
+/// State shared with the flushing thread
+struct Shared {
+    responder: Responder,
+    config: BatchConfig,
+    queue: Mutex<Queue>,
+    /// Woken when the first response is queued or the responder stops
+    wake: Condvar,
+    flushes: AtomicU64,
+    answered: AtomicU64,
+    expired: AtomicU64,
+    failed: AtomicU64,
+}
+
+#[derive(Default)]
+struct Queue {
+    events: Vec<(Event, Response)>,
+    /// When the oldest queued response was added
+    since: Option<Instant>,
+    /// Failures of background flushes nobody has collected yet
+    errors: Vec<ResponseError>,
+    stopped: bool,
+}
+
+impl Shared {
+    /// Write everything queued
+    fn flush(&self) -> FlushReport {
+        let events = {
+            let mut queue = self.queue.lock().unwrap();
+            queue.since = None;
+            std::mem::take(&mut queue.events)
+        };
+        self.write(events)
+    }
+
+    /// Write `events` and close their file descriptors
+    fn write(&self, events: Vec<(Event, Response)>) -> FlushReport {
+        let mut report = FlushReport::default();
+        if events.is_empty() {
+            return report;
+        }
+
+        // Events were checked for a file descriptor when they were queued
+        let responses: Vec<(RawFd, Response)> = events
+            .iter()
+            .map(|(event, response)| (event.info.fd.unwrap_or(-1), *response))
+            .collect();
+        let results = self
+            .responder
+            .permissions()
+            .respond_all(self.responder.as_raw_fd(), &responses);
+
+        for ((event, _), result) in events.into_iter().zip(results) {
+            let fd = event.info.fd.unwrap_or(-1);
+            match result {
+                Ok(true) => report.answered += 1,
+                Ok(false) => report.expired += 1,
+                Err(error) => report.errors.push(ResponseError {
+                    fd,
+                    pid: event.info.pid,
+                    error,
+                }),
+            }
+            sys::close_fd(fd);
+        }
+
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        self.answered.fetch_add(report.answered as u64, Ordering::Relaxed);
+        self.expired.fetch_add(report.expired as u64, Ordering::Relaxed);
+        self.failed.fetch_add(report.errors.len() as u64, Ordering::Relaxed);
+        report
+    }
+}
+
+/// Collects permission responses and writes them in batches
+///
+/// The responder takes ownership of each event and closes its file
+/// descriptor once the response is written. A background thread flushes
+/// responses that have waited for the configured delay; dropping the
+/// responder flushes whatever is left.
+pub struct BatchResponder {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl BatchResponder {
+    /// Batch responses written through `responder`
+    pub fn spawn(responder: Responder, config: BatchConfig) -> Result<Self> {
+        let shared = Arc::new(Shared {
+            responder,
+            config,
+            queue: Mutex::new(Queue::default()),
+            wake: Condvar::new(),
+            flushes: AtomicU64::new(0),
+            answered: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+        });
+
+        let thread_shared = Arc::clone(&shared);
+        let thread = thread::Builder::new()
+            .name("fanotify-batch".to_string())
+            .spawn(move || flush_periodically(&thread_shared))?;
+
+        Ok(Self {
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    /// Queue a response to `event`
+    ///
+    /// If this fills the batch, the batch is written before returning and
+    /// its failures are returned in the report; otherwise the report is
+    /// empty. Fails if `event` is not a permission event.
+    pub fn respond(&self, event: Event, response: impl Into<Response>) -> Result<FlushReport> {
+        if !event.is_permission() {
+            return Err(FanotifyError::invalid_event_data("Event is not a permission event"));
+        }
+        if event.info.fd.is_none() {
+            return Err(FanotifyError::invalid_event_data("Permission event has no file descriptor"));
+        }
+
+        let full = {
+            let mut queue = self.shared.queue.lock().unwrap();
+            if queue.events.is_empty() {
+                queue.since = Some(Instant::now());
+                self.shared.wake.notify_all();
+            }
+            queue.events.push((event, response.into()));
+
+            if queue.events.len() >= self.shared.config.max_batch {
+                queue.since = None;
+                Some(std::mem::take(&mut queue.events))
+            } else {
+                None
+            }
+        };
+
+        Ok(full.map(|events| self.shared.write(events)).unwrap_or_default())
+    }
+
+    /// Queue an answer allowing `event`
+    pub fn allow(&self, event: Event) -> Result<FlushReport> {
+        self.respond(event, EventFlags::ALLOW)
+    }
+
+    /// Queue an answer denying `event`
+    pub fn deny(&self, event: Event) -> Result<FlushReport> {
+        self.respond(event, EventFlags::DENY)
+    }
+
+    /// Write every queued response now
+    pub fn flush(&self) -> FlushReport {
+        self.shared.flush()
+    }
+
+    /// Number of responses waiting to be written
+    pub fn queued(&self) -> usize {
+        self.shared.queue.lock().unwrap().events.len()
+    }
+
+    /// Take the failures of flushes done by the background thread
+    pub fn take_errors(&self) -> Vec<ResponseError> {
+        std::mem::take(&mut self.shared.queue.lock().unwrap().errors)
+    }
+
+    /// The responder's configuration
+    pub fn config(&self) -> &BatchConfig {
+        &self.shared.config
+    }
+
+    /// A snapshot of the flush counters
+    pub fn stats(&self) -> BatchStats {
+        BatchStats {
+            flushes: self.shared.flushes.load(Ordering::Relaxed),
+            answered: self.shared.answered.load(Ordering::Relaxed),
+            expired: self.shared.expired.load(Ordering::Relaxed),
+            failed: self.shared.failed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for BatchResponder {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().stopped = true;
+        self.shared.wake.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+
+        let report = self.shared.flush();
+        for failure in report.errors {
+            log::error!("failed to answer permission event fd {}: {}", failure.fd, failure.error);
+        }
+    }
+}
+
+/// Body of the flushing thread
+fn flush_periodically(shared: &Shared) {
+    let mut queue = shared.queue.lock().unwrap();
+
+    while !queue.stopped {
+        let Some(since) = queue.since else {
+            queue = shared.wake.wait(queue).unwrap();
+            continue;
+        };
+
+        let due = since + shared.config.max_delay;
+        let now = Instant::now();
+        if now < due {
+            queue = shared.wake.wait_timeout(queue, due - now).unwrap().0;
+            continue;
+        }
+
+        queue.since = None;
+        let events = std::mem::take(&mut queue.events);
+        drop(queue);
+
+        let report = shared.write(events);
+        queue = shared.queue.lock().unwrap();
+        queue.errors.extend(report.errors);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_denied, open_in_thread, permission_event};
+    use crate::{Fanotify, FanotifyFlags, MaskFlags};
+    use std::fs::File;
+    use std::os::fd::IntoRawFd;
+    use tempfile::tempdir;
+
+    fn watched(count: usize) -> (Fanotify, tempfile::TempDir, Vec<std::path::PathBuf>) {
+        let mut fanotify = Fanotify::with_flags(FanotifyFlags::CLASS_CONTENT).unwrap();
+        let temp_dir = tempdir().unwrap();
+        let files = (0..count)
+            .map(|i| {
+                let path = temp_dir.path().join(format!("batch{}.txt", i));
+                std::fs::write(&path, "batch").unwrap();
+                fanotify.add_watch(&path, MaskFlags::OPEN_PERM).unwrap();
+                path
+            })
+            .collect();
+        (fanotify, temp_dir, files)
+    }
+
+    fn read_permissions(fanotify: &mut Fanotify, count: usize) -> Vec<Event> {
+        let mut events = Vec::new();
+        while events.len() < count {
+            let event = fanotify.read_blocking().unwrap();
+            if event.is_permission() {
+                events.push(event);
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn test_flush_on_size_skips_bad_fd() {
+        let (mut fanotify, dir, files) = watched(2);
+        let openers: Vec<_> = files.iter().map(|path| open_in_thread(path)).collect();
+        let mut events = read_permissions(&mut fanotify, 2);
+
+        // A descriptor that does not belong to any permission event
+        let stray = File::create(dir.path().join("stray")).unwrap().into_raw_fd();
+        let mut bogus = permission_event(MaskFlags::OPEN_PERM, "stray");
+        bogus.info.fd = Some(stray);
+        // Tracked like a real event, so its failed response must untrack it
+        let responder = fanotify.responder().unwrap();
+        responder.permissions().track([&bogus]);
+        events.insert(1, bogus);
+
+        let config = BatchConfig::new(3).with_max_delay(Duration::from_secs(60));
+        let batch = BatchResponder::spawn(responder, config).unwrap();
+        let mut reports: Vec<FlushReport> = events
+            .into_iter()
+            .map(|event| batch.deny(event).unwrap())
+            .collect();
+
+        let report = reports.pop().unwrap();
+        assert!(reports.iter().all(|r| r.answered == 0 && r.errors.is_empty()));
+        assert_eq!(report.answered, 2);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].fd, stray);
+        assert_eq!(batch.stats(), BatchStats { flushes: 1, answered: 2, expired: 0, failed: 1 });
+
+        openers.into_iter().for_each(assert_denied);
+        assert_eq!(fanotify.pending_permissions(), 0);
+    }
+
+    #[test]
+    fn test_flush_on_interval() {
+        let (mut fanotify, _dir, files) = watched(1);
+        let opener = open_in_thread(&files[0]);
+        let event = read_permissions(&mut fanotify, 1).pop().unwrap();
+
+        let config = BatchConfig::new(100).with_max_delay(Duration::from_millis(20));
+        let batch = BatchResponder::spawn(fanotify.responder().unwrap(), config).unwrap();
+        let report = batch.deny(event).unwrap();
+        assert_eq!(report.answered, 0);
+
+        assert_denied(opener);
+        assert_eq!(batch.queued(), 0);
+        assert!(batch.take_errors().is_empty());
+    }
+
+    #[test]
+    fn test_expired_events_are_counted_apart() {
+        let (mut fanotify, _dir, files) = watched(1);
+        let opener = open_in_thread(&files[0]);
+        let event = read_permissions(&mut fanotify, 1).pop().unwrap();
+
+        let responder = fanotify.responder().unwrap();
+        let deny = Response::new(EventFlags::DENY);
+        let (expired, result) = responder.expire(Duration::ZERO, &deny);
+        result.unwrap();
+        assert_eq!(expired, 1);
+
+        let batch = BatchResponder::spawn(responder, BatchConfig::new(1)).unwrap();
+        let report = batch.allow(event).unwrap();
+        assert_eq!((report.answered, report.expired), (0, 1));
+        assert_eq!(batch.stats(), BatchStats { flushes: 1, answered: 0, expired: 1, failed: 0 });
+
+        assert_denied(opener);
+    }
+}
@@ -0,0 +1,524 @@
+//! Caching allow decisions in the kernel
+//!
+//! Once a file has been allowed, asking again on every open is wasted work.
+//! A [`DecisionCache`] remembers allowed files and adds an ignore mask for
+//! the allowed permission events on the file's inode, so the kernel stops
+//! reporting them altogether: the common path costs no round-trip to
+//! userspace.
+//!
+//! The ignore mask does not survive modification: as soon as the file is
+//! written, the kernel clears it and the next open is decided again. The
+//! cache holds at most [`CacheConfig::capacity`] files; the least recently
+//! used entry is evicted, and its ignore mask removed, to make room.
+//!
+//! Each entry keeps a duplicate of the event's file descriptor so the mark
+//! can be removed later; size the capacity with the file descriptor limit
+//! in mind.
+
+use std::collections::{BTreeMap, HashMap};
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use crate::{
+    error::Result,
+    event::Event,
+    flags::MaskFlags,
+    handler::{Decision, Handler},
+    linux::{FAN_MARK_ADD, FAN_MARK_IGNORED_MASK, FAN_MARK_REMOVE},
+    permission::Responder,
+    sys,
+};
+
+/// Configuration of a [`DecisionCache`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheConfig {
+    capacity: usize,
+    mask: MaskFlags,
+}
+
+impl CacheConfig {
+    /// A cache of up to `capacity` files, caching `OPEN_PERM` decisions
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "cache capacity must be non-zero");
+
+        Self {
+            capacity,
+            mask: MaskFlags::OPEN_PERM,
+        }
+    }
+
+    /// Cache decisions for these permission events
+    ///
+    /// Only the permission event types in `mask` are cached. An allowed event
+    /// only ever suppresses events of its own type, so allowing an open does
+    /// not skip the decision on executing the same file.
+    pub fn with_mask(mut self, mask: MaskFlags) -> Self {
+        self.mask = mask
+            & (MaskFlags::OPEN_PERM | MaskFlags::ACCESS_PERM | MaskFlags::OPEN_EXEC_PERM);
+        self
+    }
+
+    /// Maximum number of cached files
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The permission events whose decisions are cached
+    pub fn mask(&self) -> MaskFlags {
+        self.mask
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self::new(1024)
+    }
+}
+
+/// A snapshot of the cache's counters
+///
+/// Events suppressed by the kernel never reach userspace and are not
+/// counted; `hits` are events for cached files that were reported anyway
+/// and answered from the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    /// Events answered from the cache
+    pub hits: u64,
+    /// Events that had to be decided
+    pub misses: u64,
+    /// Files added to the cache
+    pub inserts: u64,
+    /// Entries dropped because the file changed since it was cached
+    pub stale: u64,
+    /// Entries dropped to make room
+    pub evictions: u64,
+    /// Entries dropped by [`DecisionCache::invalidate`] or [`DecisionCache::clear`]
+    pub invalidations: u64,
+}
+
+impl CacheStats {
+    /// Share of looked-up events answered from the cache
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / lookups as f64
+    }
+}
+
+/// Identifies a file: device and inode number
+type Key = (u64, u64);
+
+/// Identifies one version of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    size: i64,
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+}
+
+/// Identity and version of the file `fd` refers to
+fn identify(fd: RawFd) -> Option<(Key, Stamp)> {
+    let stat = sys::fstat(fd).ok()?;
+
+    let key = (stat.st_dev, stat.st_ino);
+    let stamp = Stamp {
+        size: stat.st_size,
+        mtime: (stat.st_mtime, stat.st_mtime_nsec),
+        ctime: (stat.st_ctime, stat.st_ctime_nsec),
+    };
+    Some((key, stamp))
+}
+
+struct Entry {
+    /// Keeps a handle on the inode to remove the ignore mask later
+    fd: OwnedFd,
+    stamp: Stamp,
+    /// Ignored permission events
+    mask: MaskFlags,
+    /// Position in the LRU order
+    used: u64,
+}
+
+/// Remembers allowed files and suppresses their permission events in the kernel
+pub struct DecisionCache {
+    group: Responder,
+    config: CacheConfig,
+    entries: HashMap<Key, Entry>,
+    /// Entries by last use, oldest first
+    order: BTreeMap<u64, Key>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl DecisionCache {
+    /// A cache adding ignore marks to `group`'s group
+    pub fn new(group: Responder, config: CacheConfig) -> Self {
+        Self {
+            group,
+            config,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            clock: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// The cached decision for `event`, if its file was allowed and has not
+    /// changed since
+    pub fn lookup(&mut self, event: &Event) -> Option<Decision> {
+        let cacheable = self.cacheable(event);
+        let identified = event.info.fd.and_then(identify);
+        let Some((key, stamp)) = identified.filter(|_| !cacheable.is_empty()) else {
+            self.stats.misses += 1;
+            return None;
+        };
+
+        let cached = self.entries.get(&key).map(|entry| (entry.stamp, entry.mask));
+        match cached {
+            // Allowing one event type says nothing about the others
+            Some((_, mask)) if !mask.contains(cacheable) => {
+                self.stats.misses += 1;
+                None
+            }
+            Some((cached, _)) if cached == stamp => {
+                self.touch(key);
+                self.stats.hits += 1;
+                Some(Decision::Allow)
+            }
+            Some(_) => {
+                self.remove(key);
+                self.stats.stale += 1;
+                self.stats.misses += 1;
+                None
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Record the decision for `event`
+    ///
+    /// Allowed events of a cached type install an ignore mask on the file;
+    /// other decisions are not cached. Returns whether the file is now
+    /// cached.
+    pub fn insert(&mut self, event: &Event, decision: Decision) -> Result<bool> {
+        let mask = self.cacheable(event);
+        if decision != Decision::Allow || mask.is_empty() {
+            return Ok(false);
+        }
+        let Some(fd) = event.info.fd else {
+            return Ok(false);
+        };
+        let Some((key, stamp)) = identify(fd) else {
+            return Ok(false);
+        };
+
+        let mask = mask | self.entries.get(&key).map_or(MaskFlags::empty(), |entry| entry.mask);
+        sys::mark_fd(self.group.as_raw_fd(), FAN_MARK_ADD | FAN_MARK_IGNORED_MASK, mask, fd)?;
+
+        if let Some(entry) = self.entries.get_mut(&key) {
+            entry.stamp = stamp;
+            entry.mask = mask;
+            self.touch(key);
+            return Ok(true);
+        }
+
+        // SAFETY: duplicating a descriptor the event owns for as long as we use it
+        let held = unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?;
+        while self.entries.len() >= self.config.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            self.remove(oldest);
+            self.stats.evictions += 1;
+        }
+
+        self.clock += 1;
+        self.order.insert(self.clock, key);
+        self.entries.insert(
+            key,
+            Entry {
+                fd: held,
+                stamp,
+                mask,
+                used: self.clock,
+            },
+        );
+        self.stats.inserts += 1;
+        Ok(true)
+    }
+
+    /// Forget the decision for the file at `path`
+    ///
+    /// Returns whether the file was cached.
+    pub fn invalidate<P: AsRef<Path>>(&mut self, path: P) -> Result<bool> {
+        let metadata = std::fs::metadata(path)?;
+        let key = (metadata.dev(), metadata.ino());
+
+        if !self.entries.contains_key(&key) {
+            return Ok(false);
+        }
+
+        self.remove(key);
+        self.stats.invalidations += 1;
+        Ok(true)
+    }
+
+    /// Forget every cached decision
+    pub fn clear(&mut self) {
+        let keys: Vec<Key> = self.entries.keys().copied().collect();
+        self.stats.invalidations += keys.len() as u64;
+        for key in keys {
+            self.remove(key);
+        }
+    }
+
+    /// Number of cached files
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether no file is cached
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The cache's configuration
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    /// A snapshot of the counters
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// The cached event types among the permission events of `event`
+    fn cacheable(&self, event: &Event) -> MaskFlags {
+        event.info.mask & self.config.mask
+    }
+
+    /// Mark `key` as most recently used
+    fn touch(&mut self, key: Key) {
+        if let Some(entry) = self.entries.get_mut(&key) {
+            self.order.remove(&entry.used);
+            self.clock += 1;
+            entry.used = self.clock;
+            self.order.insert(self.clock, key);
+        }
+    }
+
+    /// Drop `key` and its ignore mask
+    fn remove(&mut self, key: Key) {
+        let Some(entry) = self.entries.remove(&key) else {
+            return;
+        };
+        self.order.remove(&entry.used);
+
+        // The kernel already dropped the ignore mask if the file was modified
+        let flags = FAN_MARK_REMOVE | FAN_MARK_IGNORED_MASK;
+        if let Err(e) = sys::mark_fd(self.group.as_raw_fd(), flags, entry.mask, entry.fd.as_raw_fd()) {
+            log::debug!("removing ignore mask: {}", e);
+        }
+    }
+}
+
+impl Drop for DecisionCache {
+    fn drop(&mut self) {
+        let keys: Vec<Key> = self.entries.keys().copied().collect();
+        for key in keys {
+            self.remove(key);
+        }
+    }
+}
+
+/// A [`Handler`] whose allow decisions are cached
+///
+/// Permission events for cached files are answered without consulting the
+/// inner handler; every other callback is passed through.
+pub struct Cached<H> {
+    cache: DecisionCache,
+    handler: H,
+}
+
+impl<H: Handler> Cached<H> {
+    /// Cache the decisions of `handler` in `cache`
+    pub fn new(cache: DecisionCache, handler: H) -> Self {
+        Self { cache, handler }
+    }
+
+    /// The cache
+    pub fn cache(&self) -> &DecisionCache {
+        &self.cache
+    }
+
+    /// The cache, e.g. to invalidate entries
+    pub fn cache_mut(&mut self) -> &mut DecisionCache {
+        &mut self.cache
+    }
+
+    /// The inner handler
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    /// Split into the cache and the inner handler
+    pub fn into_parts(self) -> (DecisionCache, H) {
+        (self.cache, self.handler)
+    }
+}
+
+impl<H: Handler> Handler for Cached<H> {
+    fn on_event(&mut self, event: &Event) {
+        self.handler.on_event(event)
+    }
+
+    fn on_permission(&mut self, event: &Event) -> Decision {
+        if let Some(decision) = self.cache.lookup(event) {
+            return decision;
+        }
+
+        let decision = self.handler.on_permission(event);
+        if let Err(e) = self.cache.insert(event, decision) {
+            log::warn!("failed to cache decision for {:?}: {}", event.info.path, e);
+        }
+        decision
+    }
+
+    fn on_overflow(&mut self) {
+        self.handler.on_overflow()
+    }
+
+    fn on_error(&mut self, error: &crate::FanotifyError) -> std::ops::ControlFlow<()> {
+        self.handler.on_error(error)
+    }
+
+    fn should_stop(&self) -> bool {
+        self.handler.should_stop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{decide_next, open_in_thread, permission_event, watched_file};
+    use std::os::fd::AsRawFd;
+    use crate::{Fanotify, FanotifyFlags};
+    use std::fs::File;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    struct AllowCounter(usize);
+
+    impl Handler for AllowCounter {
+        fn on_event(&mut self, _event: &Event) {}
+
+        fn on_permission(&mut self, _event: &Event) -> Decision {
+            self.0 += 1;
+            Decision::Allow
+        }
+    }
+
+    /// Open `path` on another thread and answer the permission event
+    fn open_and_decide(fanotify: &mut Fanotify, handler: &mut Cached<AllowCounter>, path: &Path) {
+        let opener = open_in_thread(path);
+        decide_next(fanotify, handler);
+        opener.join().unwrap().unwrap();
+    }
+
+    fn quiet(fanotify: &Fanotify) -> bool {
+        !fanotify.poll_readable(Some(Duration::from_millis(100))).unwrap()
+    }
+
+    #[test]
+    fn test_allowed_file_is_ignored_until_modified() {
+        let (mut fanotify, _dir, test_file) = watched_file("cached.txt", "v1", MaskFlags::OPEN_PERM);
+
+        let cache = DecisionCache::new(fanotify.responder().unwrap(), CacheConfig::new(8));
+        let mut handler = Cached::new(cache, AllowCounter(0));
+
+        open_and_decide(&mut fanotify, &mut handler, &test_file);
+        assert_eq!(handler.cache().len(), 1);
+
+        // Cached in the kernel: no event at all
+        File::open(&test_file).unwrap();
+        File::open(&test_file).unwrap();
+        assert!(quiet(&fanotify));
+
+        // Writing clears the ignore mask, so the next open is decided again
+        std::fs::write(&test_file, "v2").unwrap();
+        open_and_decide(&mut fanotify, &mut handler, &test_file);
+        assert_eq!(handler.handler().0, 2);
+
+        // Invalidation removes the ignore mask as well
+        assert!(handler.cache_mut().invalidate(&test_file).unwrap());
+        open_and_decide(&mut fanotify, &mut handler, &test_file);
+        assert_eq!(handler.handler().0, 3);
+
+        let stats = handler.cache().stats();
+        assert_eq!(stats.misses, 3);
+        assert_eq!(stats.stale, 1);
+        assert_eq!(stats.invalidations, 1);
+    }
+
+    #[test]
+    fn test_allowed_open_does_not_allow_exec() {
+        let mask = MaskFlags::OPEN_PERM | MaskFlags::OPEN_EXEC_PERM;
+        for config in [CacheConfig::new(8), CacheConfig::new(8).with_mask(mask)] {
+            let (mut fanotify, _dir, test_file) = watched_file("tool.sh", "#!/bin/sh\n", mask);
+            let cache = DecisionCache::new(fanotify.responder().unwrap(), config);
+            let mut handler = Cached::new(cache, AllowCounter(0));
+            open_and_decide(&mut fanotify, &mut handler, &test_file);
+
+            let file = File::open(&test_file).unwrap();
+            let mut exec = permission_event(MaskFlags::OPEN_EXEC_PERM, test_file.to_str().unwrap());
+            exec.info.fd = Some(file.as_raw_fd());
+            let mut open = permission_event(MaskFlags::OPEN_PERM, test_file.to_str().unwrap());
+            open.info.fd = Some(file.as_raw_fd());
+
+            // The exec is decided and the open stays cached
+            assert_eq!(handler.cache_mut().lookup(&exec), None);
+            assert_eq!(handler.cache_mut().lookup(&open), Some(Decision::Allow));
+            assert_eq!(handler.cache().len(), 1);
+            assert_eq!(handler.cache().stats().stale, 0);
+        }
+    }
+
+    #[test]
+    fn test_lru_eviction_rearms_file() {
+        let mut fanotify = Fanotify::with_flags(FanotifyFlags::CLASS_CONTENT).unwrap();
+        let temp_dir = tempdir().unwrap();
+        let files: Vec<_> = (0..3)
+            .map(|i| {
+                let path = temp_dir.path().join(format!("lru{}.txt", i));
+                std::fs::write(&path, "lru").unwrap();
+                fanotify.add_watch(&path, MaskFlags::OPEN_PERM).unwrap();
+                path
+            })
+            .collect();
+
+        let cache = DecisionCache::new(fanotify.responder().unwrap(), CacheConfig::new(2));
+        let mut handler = Cached::new(cache, AllowCounter(0));
+
+        for file in &files {
+            open_and_decide(&mut fanotify, &mut handler, file);
+        }
+        assert_eq!(handler.cache().len(), 2);
+        assert_eq!(handler.cache().stats().evictions, 1);
+
+        // The oldest file was evicted and is reported again
+        File::open(&files[2]).unwrap();
+        assert!(quiet(&fanotify));
+        open_and_decide(&mut fanotify, &mut handler, &files[0]);
+        assert_eq!(handler.handler().0, 4);
+    }
+}
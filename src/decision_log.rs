@@ -0,0 +1,611 @@
+//! A tamper-evident record of permission decisions
+//!
+//! A [`DecisionLog`] appends one JSON object per line for every permission
+//! event a group answers: when, which process (pid, executable, uid), which
+//! file, the kind of access, the decision, the rule that made it and how long
+//! the event waited for its answer. Attach it with
+//! [`Fanotify::set_decision_log`](crate::Fanotify::set_decision_log) and every
+//! response is recorded, whichever way it was written: directly, through a
+//! [`Responder`](crate::Responder), in a batch, by a watchdog or at shutdown.
+//!
+//! ```json
+//! {"seq":7,"prev":"9c1e…","time":"2024-05-01T12:00:00.123456789Z","pid":4242,"exe":"/usr/bin/cat","uid":1000,"path":"/srv/secret.key","kind":"open","decision":"deny","rule":2,"latency_us":180,"hash":"5b0f…"}
+//! ```
+//!
+//! Paths are written as UTF-8, lossily if need be; a path or executable
+//! that is not valid UTF-8 gets an extra `path_bytes` or `exe_bytes` field
+//! with its exact bytes in hex.
+//!
+//! Every record carries the hash of the record before it (`prev`) and its own
+//! hash, the SHA-256 of the line up to the `hash` field. Editing, removing
+//! or reordering records breaks the chain, which [`verify`] detects. The
+//! chain continues across rotated files, so they can be verified together
+//! with [`verify_chain`].
+//!
+//! When the file would grow beyond [`LogConfig::max_size`] it is renamed to
+//! `<path>.1`, older files move up to `<path>.2` and so on, and the oldest
+//! beyond [`LogConfig::keep`] is deleted.
+
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::{FanotifyError, Result},
+    handler::{Decision, Errno},
+    json::{push_number, push_path, push_path_bytes, push_string},
+    policy::EventKind,
+};
+
+/// The `prev` hash of the very first record
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Length of the `,"hash":"<64 hex digits>"}` suffix of every line
+const HASH_SUFFIX_LEN: usize = 75;
+
+/// Rotation settings of a [`DecisionLog`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogConfig {
+    max_size: u64,
+    keep: usize,
+}
+
+impl LogConfig {
+    /// Rotate once the file would grow beyond `max_size` bytes, keeping 5
+    /// rotated files
+    pub fn new(max_size: u64) -> Self {
+        Self { max_size, keep: 5 }
+    }
+
+    /// Keep `keep` rotated files; zero deletes the old file on rotation
+    pub fn with_keep(mut self, keep: usize) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    /// Size at which the file is rotated
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    /// Number of rotated files kept
+    pub fn keep(&self) -> usize {
+        self.keep
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self::new(64 * 1024 * 1024)
+    }
+}
+
+/// One permission decision
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecisionRecord {
+    /// When the response was written
+    pub time: DateTime<Utc>,
+    /// Process that triggered the event
+    pub pid: u32,
+    /// Executable of that process, if it could be read
+    pub exe: Option<PathBuf>,
+    /// Effective user id of that process, if it could be read
+    pub uid: Option<u32>,
+    /// The file accessed
+    pub path: Option<PathBuf>,
+    /// Kind of access
+    pub kind: Option<EventKind>,
+    /// The decision
+    pub decision: Decision,
+    /// Number of the rule that made the decision, if known
+    pub rule: Option<u32>,
+    /// Time from reading the event to answering it
+    pub latency: Option<Duration>,
+}
+
+/// Appends hash-chained decision records to a JSON-lines file
+pub struct DecisionLog {
+    path: PathBuf,
+    config: LogConfig,
+    file: File,
+    size: u64,
+    seq: u64,
+    prev: String,
+}
+
+impl DecisionLog {
+    /// Open or create the log at `path`
+    ///
+    /// An existing log is appended to; its last record (or that of the most
+    /// recent rotated file) is the start of the chain. A partial last line,
+    /// left by a crash in the middle of a write, is cut off first.
+    pub fn open<P: AsRef<Path>>(path: P, config: LogConfig) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).read(true).append(true).open(&path)?;
+        let size = repair_tail(&path, &file)?;
+
+        let last = match last_record(&path)? {
+            Some(last) => Some(last),
+            None => last_record(&rotated(&path, 1))?,
+        };
+        let (seq, prev) = last.unwrap_or((0, GENESIS.to_string()));
+
+        Ok(Self {
+            path,
+            config,
+            file,
+            size,
+            seq,
+            prev,
+        })
+    }
+
+    /// Append `record`
+    pub fn append(&mut self, record: &DecisionRecord) -> Result<()> {
+        let mut line = self.body(self.seq + 1, record);
+        let hash = hash_hex(line.as_bytes());
+        let _ = writeln!(line, ",\"hash\":\"{}\"}}", hash);
+
+        if self.size > 0 && self.size + line.len() as u64 > self.config.max_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        self.seq += 1;
+        self.prev = hash;
+        Ok(())
+    }
+
+    /// Flush the file to disk
+    pub fn sync(&self) -> Result<()> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// The log's path
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The rotation settings
+    pub fn config(&self) -> &LogConfig {
+        &self.config
+    }
+
+    /// Sequence number of the last record written
+    pub fn last_seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Hash of the last record written
+    pub fn last_hash(&self) -> &str {
+        &self.prev
+    }
+
+    /// The line for `record` up to its hash
+    fn body(&self, seq: u64, record: &DecisionRecord) -> String {
+        let mut line = String::with_capacity(256);
+        let _ = write!(line, "{{\"seq\":{},\"prev\":\"{}\",\"time\":", seq, self.prev);
+        push_string(&mut line, &record.time.to_rfc3339_opts(SecondsFormat::Nanos, true));
+        let _ = write!(line, ",\"pid\":{},\"exe\":", record.pid);
+        push_path(&mut line, record.exe.as_deref());
+        push_path_bytes(&mut line, "exe", record.exe.as_deref());
+        line.push_str(",\"uid\":");
+        push_number(&mut line, record.uid);
+        line.push_str(",\"path\":");
+        push_path(&mut line, record.path.as_deref());
+        push_path_bytes(&mut line, "path", record.path.as_deref());
+        line.push_str(",\"kind\":");
+        match record.kind {
+            Some(EventKind::Open) => line.push_str("\"open\""),
+            Some(EventKind::Access) => line.push_str("\"access\""),
+            Some(EventKind::Exec) => line.push_str("\"exec\""),
+            None => line.push_str("null"),
+        }
+        match record.decision {
+            Decision::Allow => line.push_str(",\"decision\":\"allow\""),
+            Decision::Deny => line.push_str(",\"decision\":\"deny\""),
+            Decision::DenyWith(errno) => {
+                let _ = write!(line, ",\"decision\":\"deny\",\"errno\":{}", errno.raw());
+            }
+        }
+        line.push_str(",\"rule\":");
+        push_number(&mut line, record.rule);
+        line.push_str(",\"latency_us\":");
+        push_number(&mut line, record.latency.map(|latency| latency.as_micros()));
+        line
+    }
+
+    /// Move the current file to `<path>.1` and start a new one
+    fn rotate(&mut self) -> Result<()> {
+        self.file.sync_data()?;
+
+        if self.config.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            let _ = std::fs::remove_file(rotated(&self.path, self.config.keep));
+            for index in (1..self.config.keep).rev() {
+                let from = rotated(&self.path, index);
+                if from.exists() {
+                    std::fs::rename(&from, rotated(&self.path, index + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, rotated(&self.path, 1))?;
+        }
+
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Path of the `index`th rotated file
+fn rotated(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+/// Cut off everything after the last complete line of `file`
+///
+/// Returns the resulting size.
+fn repair_tail(path: &Path, file: &File) -> Result<u64> {
+    let size = file.metadata()?.len();
+    let mut end = size;
+    let mut buf = [0u8; 4096];
+
+    while end > 0 {
+        let start = end.saturating_sub(buf.len() as u64);
+        let chunk = &mut buf[..(end - start) as usize];
+        file.read_exact_at(chunk, start)?;
+        if let Some(newline) = chunk.iter().rposition(|&byte| byte == b'\n') {
+            end = start + newline as u64 + 1;
+            break;
+        }
+        end = start;
+    }
+
+    if end < size {
+        log::warn!(
+            "{}: dropping a partial record of {} bytes at the end",
+            path.display(),
+            size - end
+        );
+        file.set_len(end)?;
+    }
+    Ok(end)
+}
+
+/// Sequence number and hash of the last record in the file at `path`
+fn last_record(path: &Path) -> Result<Option<(u64, String)>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut last = None;
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let record = parse_line(&line).map_err(|message| line_error(path, index, &message))?;
+        last = Some((record.seq, record.hash.to_string()));
+    }
+    Ok(last)
+}
+
+/// The chain fields of one line
+struct ChainFields<'a> {
+    seq: u64,
+    prev: &'a str,
+    hash: &'a str,
+    body: &'a str,
+}
+
+/// Split a line into its chain fields
+fn parse_line(line: &str) -> std::result::Result<ChainFields<'_>, String> {
+    if line.len() < HASH_SUFFIX_LEN || !line.is_char_boundary(line.len() - HASH_SUFFIX_LEN) {
+        return Err("truncated record".to_string());
+    }
+    let (body, suffix) = line.split_at(line.len() - HASH_SUFFIX_LEN);
+    let hash = suffix
+        .strip_prefix(",\"hash\":\"")
+        .and_then(|rest| rest.strip_suffix("\"}"))
+        .ok_or("record does not end with its hash")?;
+
+    let rest = body.strip_prefix("{\"seq\":").ok_or("record does not start with its sequence number")?;
+    let (seq, rest) = rest.split_once(',').ok_or("missing sequence number")?;
+    let seq = seq.parse().map_err(|_| format!("invalid sequence number {:?}", seq))?;
+    let prev = rest
+        .strip_prefix("\"prev\":\"")
+        .and_then(|rest| rest.get(..64))
+        .ok_or("missing previous hash")?;
+
+    Ok(ChainFields { seq, prev, hash, body })
+}
+
+fn line_error(path: &Path, index: usize, message: &str) -> FanotifyError {
+    FanotifyError::invalid_log(format!("{}: line {}: {}", path.display(), index + 1, message))
+}
+
+/// Check the hash chain of a single log file
+///
+/// The first record is trusted to link to whatever came before it, e.g. a
+/// rotated file. Returns the number of records.
+///
+/// The chain cannot tell that records were cut from the end of the log. To
+/// detect that, keep [`DecisionLog::last_hash`] somewhere the log's writer
+/// cannot change and check against it with [`verify_anchored`].
+pub fn verify<P: AsRef<Path>>(path: P) -> Result<u64> {
+    verify_chain([path])
+}
+
+/// Check the hash chain across several log files, oldest first
+///
+/// Pass rotated files before the current one, e.g. `log.2`, `log.1`, `log`.
+/// Returns the total number of records. Like [`verify`], this does not
+/// notice records cut from the end.
+pub fn verify_chain<P: AsRef<Path>>(paths: impl IntoIterator<Item = P>) -> Result<u64> {
+    check_chain(paths).map(|(records, _)| records)
+}
+
+/// Check the hash chain across several log files, oldest first, and that
+/// it ends with the record hashed `last_hash`
+///
+/// `last_hash` is a value of [`DecisionLog::last_hash`] saved elsewhere, so
+/// records removed from the end are noticed too. Returns the total number of
+/// records.
+pub fn verify_anchored<P: AsRef<Path>>(paths: impl IntoIterator<Item = P>, last_hash: &str) -> Result<u64> {
+    let (records, last) = check_chain(paths)?;
+    match last {
+        Some(hash) if hash == last_hash => Ok(records),
+        Some(hash) => Err(FanotifyError::invalid_log(format!(
+            "log ends with record {} instead of {}",
+            hash, last_hash
+        ))),
+        None if last_hash == GENESIS => Ok(records),
+        None => Err(FanotifyError::invalid_log(format!("log is empty, expected record {}", last_hash))),
+    }
+}
+
+/// Number of records and hash of the last one across `paths`
+fn check_chain<P: AsRef<Path>>(paths: impl IntoIterator<Item = P>) -> Result<(u64, Option<String>)> {
+    let mut previous: Option<(u64, String)> = None;
+    let mut records = 0;
+
+    for path in paths {
+        let path = path.as_ref();
+        let reader = BufReader::new(File::open(path)?);
+
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let fields = parse_line(&line).map_err(|message| line_error(path, index, &message))?;
+
+            if hash_hex(fields.body.as_bytes()) != fields.hash {
+                return Err(line_error(path, index, "record does not match its hash"));
+            }
+            if let Some((seq, hash)) = &previous {
+                if fields.prev != hash {
+                    return Err(line_error(path, index, "record does not follow the previous one"));
+                }
+                if fields.seq != seq + 1 {
+                    return Err(line_error(path, index, &format!("expected record {}, found {}", seq + 1, fields.seq)));
+                }
+            }
+
+            previous = Some((fields.seq, fields.hash.to_string()));
+            records += 1;
+        }
+    }
+
+    Ok((records, previous.map(|(_, hash)| hash)))
+}
+
+fn hash_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The decision a response stands for
+pub(crate) fn decision_of(response: &crate::response::Response) -> Decision {
+    let flags = response.flags();
+    if flags.contains(crate::flags::EventFlags::ALLOW) {
+        return Decision::Allow;
+    }
+    match flags.errno().and_then(|errno| Errno::new(errno).ok()) {
+        Some(errno) => Decision::DenyWith(errno),
+        None => Decision::Deny,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{open_in_thread, respond, watched_file};
+    use crate::MaskFlags;
+    use tempfile::tempdir;
+
+    fn record(path: &str, decision: Decision) -> DecisionRecord {
+        DecisionRecord {
+            time: Utc::now(),
+            pid: 42,
+            exe: Some(PathBuf::from("/usr/bin/cat")),
+            uid: Some(1000),
+            path: Some(PathBuf::from(path)),
+            kind: Some(EventKind::Open),
+            decision,
+            rule: Some(3),
+            latency: Some(Duration::from_micros(250)),
+        }
+    }
+
+    #[test]
+    fn test_chain_detects_edits() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("decisions.log");
+
+        let mut log = DecisionLog::open(&path, LogConfig::default()).unwrap();
+        log.append(&record("/srv/a \"quoted\"\n", Decision::Allow)).unwrap();
+        log.append(&record("/srv/b", Decision::Deny)).unwrap();
+        log.append(&record("/srv/c", Decision::DenyWith(Errno::EIO))).unwrap();
+        drop(log);
+
+        // Reopening continues the chain
+        let mut log = DecisionLog::open(&path, LogConfig::default()).unwrap();
+        assert_eq!(log.last_seq(), 3);
+        log.append(&record("/srv/d", Decision::Allow)).unwrap();
+        assert_eq!(verify(&path).unwrap(), 4);
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.contains(r#""path":"/srv/a \"quoted\"\n""#), "{}", text);
+        assert!(text.contains(r#""decision":"deny","errno":5"#), "{}", text);
+        assert!(text.contains(r#""rule":3,"latency_us":250"#), "{}", text);
+
+        let tampered = text.replacen("\"decision\":\"deny\"", "\"decision\":\"allow\"", 1);
+        std::fs::write(&path, tampered).unwrap();
+        let err = verify(&path).unwrap_err();
+        assert!(err.to_string().contains("line 2: record does not match its hash"), "{}", err);
+
+        let mut lines: Vec<&str> = text.lines().collect();
+        lines.remove(1);
+        std::fs::write(&path, lines.join("\n")).unwrap();
+        let err = verify(&path).unwrap_err();
+        assert!(err.to_string().contains("does not follow"), "{}", err);
+    }
+
+    #[test]
+    fn test_non_utf8_paths_are_told_apart() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("decisions.log");
+        let mut log = DecisionLog::open(&path, LogConfig::default()).unwrap();
+        for name in [&b"/srv/caf\xe9"[..], b"/srv/caf\xe8"] {
+            let mut record = record("", Decision::Allow);
+            record.path = Some(PathBuf::from(std::ffi::OsStr::from_bytes(name)));
+            log.append(&record).unwrap();
+        }
+        assert_eq!(verify(&path).unwrap(), 2);
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.contains(r#""path_bytes":"2f7372762f636166e9""#), "{}", text);
+        assert!(text.contains(r#""path_bytes":"2f7372762f636166e8""#), "{}", text);
+        assert!(!text.contains("exe_bytes"), "{}", text);
+    }
+
+    #[test]
+    fn test_rotation_keeps_chain() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("decisions.log");
+
+        let mut log = DecisionLog::open(&path, LogConfig::new(600).with_keep(2)).unwrap();
+        for i in 0..12 {
+            log.append(&record(&format!("/srv/file{}", i), Decision::Allow)).unwrap();
+        }
+
+        assert!(rotated(&path, 2).exists());
+        assert!(!rotated(&path, 3).exists());
+        for file in [rotated(&path, 2), rotated(&path, 1), path.clone()] {
+            assert!(std::fs::metadata(&file).unwrap().len() <= 600);
+        }
+
+        let records = verify_chain([rotated(&path, 2), rotated(&path, 1), path.clone()]).unwrap();
+        assert!(records > 0 && records < 12);
+        assert!(verify_chain([rotated(&path, 1), rotated(&path, 2)]).is_err());
+
+        drop(log);
+        let log = DecisionLog::open(&path, LogConfig::new(600)).unwrap();
+        assert_eq!(log.last_seq(), 12);
+
+        // Without a current file the chain continues from the rotated one
+        drop(log);
+        let (rotated_seq, rotated_hash) = last_record(&rotated(&path, 1)).unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let log = DecisionLog::open(&path, LogConfig::new(600)).unwrap();
+        assert_eq!((log.last_seq(), log.last_hash()), (rotated_seq, rotated_hash.as_str()));
+    }
+
+    #[test]
+    fn test_torn_last_line_is_dropped_on_open() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("decisions.log");
+
+        let mut log = DecisionLog::open(&path, LogConfig::default()).unwrap();
+        log.append(&record("/srv/a", Decision::Allow)).unwrap();
+        let anchor = log.last_hash().to_string();
+        log.append(&record("/srv/b", Decision::Deny)).unwrap();
+        drop(log);
+
+        // A crash halfway through the second record
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, &text[..text.len() - 40]).unwrap();
+
+        let mut log = DecisionLog::open(&path, LogConfig::default()).unwrap();
+        assert_eq!((log.last_seq(), log.last_hash()), (1, anchor.as_str()));
+        log.append(&record("/srv/c", Decision::Allow)).unwrap();
+        assert_eq!(verify(&path).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_anchored_verify_detects_cut_tail() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("decisions.log");
+
+        let mut log = DecisionLog::open(&path, LogConfig::default()).unwrap();
+        for name in ["/srv/a", "/srv/b", "/srv/c"] {
+            log.append(&record(name, Decision::Allow)).unwrap();
+        }
+        let anchor = log.last_hash().to_string();
+        drop(log);
+        assert_eq!(verify_anchored([&path], &anchor).unwrap(), 3);
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let kept: Vec<&str> = text.lines().take(2).collect();
+        std::fs::write(&path, kept.join("\n") + "\n").unwrap();
+        assert_eq!(verify(&path).unwrap(), 2);
+        let err = verify_anchored([&path], &anchor).unwrap_err();
+        assert!(err.to_string().contains("instead of"), "{}", err);
+    }
+
+    #[test]
+    fn test_corrupt_rotated_file_fails_open() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("decisions.log");
+        std::fs::write(rotated(&path, 1), "{\"seq\":1,garbage\n").unwrap();
+
+        let err = DecisionLog::open(&path, LogConfig::default()).err().unwrap();
+        assert!(err.to_string().contains("line 1"), "{}", err);
+    }
+
+    #[test]
+    fn test_group_logs_responses() {
+        let (mut fanotify, temp_dir, test_file) = watched_file("logged.txt", "logged", MaskFlags::OPEN_PERM);
+        let log_path = temp_dir.path().join("decisions.log");
+        fanotify
+            .set_decision_log(DecisionLog::open(&log_path, LogConfig::default()).unwrap());
+
+        let opener = open_in_thread(&test_file);
+        let event = fanotify.read_blocking().unwrap();
+        respond(&fanotify, &event, &crate::Response::deny().rule(7));
+        assert!(opener.join().unwrap().is_err());
+
+        let text = std::fs::read_to_string(&log_path).unwrap();
+        let line = text.lines().next().unwrap();
+        assert!(line.contains(&format!("\"pid\":{}", std::process::id())), "{}", line);
+        assert!(line.contains(&format!("\"path\":\"{}\"", test_file.display())), "{}", line);
+        assert!(line.contains(r#""kind":"open","decision":"deny","rule":7,"latency_us":"#), "{}", line);
+        assert!(line.contains(&format!("\"uid\":{}", unsafe { libc::geteuid() })), "{}", line);
+        assert_eq!(verify(&log_path).unwrap(), 1);
+    }
+}
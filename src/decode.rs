@@ -0,0 +1,107 @@
+//! Read buffer shared by the synchronous and asynchronous readers
+//!
+//! A single `read` from a fanotify group can return several events. The
+//! buffer keeps the ones that have not been handed out yet so no event (and
+//! no permission request) is lost between calls.
+
+use std::collections::VecDeque;
+use std::os::fd::RawFd;
+
+use crate::{error::Result, event::Event, pending::PendingPermissions, response::Response, sys};
+
+/// Default size of the read buffer in bytes
+pub(crate) const DEFAULT_BUFFER_SIZE: usize = 4096;
+
+/// Raw read buffer plus the queue of decoded, not yet consumed events
+pub(crate) struct EventBuffer {
+    buffer: Vec<u8>,
+    pending: VecDeque<Event>,
+    /// Permission events decoded from this buffer that are not answered yet
+    permissions: PendingPermissions,
+}
+
+impl EventBuffer {
+    /// Create a buffer of the default size
+    pub(crate) fn new() -> Self {
+        Self {
+            buffer: vec![0u8; DEFAULT_BUFFER_SIZE],
+            pending: VecDeque::new(),
+            permissions: PendingPermissions::default(),
+        }
+    }
+
+    /// Take the next decoded event, if any
+    pub(crate) fn pop(&mut self) -> Option<Event> {
+        self.pending.pop_front()
+    }
+
+    /// Take every decoded event
+    pub(crate) fn drain(&mut self) -> impl Iterator<Item = Event> + '_ {
+        self.pending.drain(..)
+    }
+
+    /// The space the next `read` should fill
+    pub(crate) fn space(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+
+    /// Decode the first `len` bytes of the buffer after a successful `read`
+    /// from the group `group_fd`
+    ///
+    /// Events that fail to decode are dropped: permission events among them
+    /// are denied and their file descriptors closed, so nothing is left
+    /// waiting on them. Every event that did decode is kept and tracked
+    /// before the first decoding error is returned.
+    pub(crate) fn fill(&mut self, group_fd: RawFd, len: usize) -> Result<()> {
+        let decoded = Event::decode_all(&self.buffer[..len]);
+
+        for (fd, mask) in decoded.rejected {
+            if mask.has_permission_events() {
+                if let Err(e) = sys::write_raw_response(group_fd, fd, &Response::deny()) {
+                    log::error!("failed to deny undecodable permission event fd {}: {}", fd, e);
+                }
+            }
+            sys::close_fd(fd);
+        }
+
+        self.permissions.track(&decoded.events);
+        self.pending.extend(decoded.events);
+
+        match decoded.error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// The permission events read through this buffer that await a response
+    pub(crate) fn permissions(&self) -> &PendingPermissions {
+        &self.permissions
+    }
+
+    /// Read everything queued on the non-blocking group `fd` without waiting
+    ///
+    /// Returns the decoded events together with the ones already buffered.
+    #[cfg(any(feature = "tokio", feature = "async-io"))]
+    pub(crate) fn drain_fd(&mut self, fd: std::os::fd::RawFd) -> Result<Vec<Event>> {
+        loop {
+            match crate::sys::read_fd(fd, self.space()) {
+                Ok(0) => break,
+                Ok(n) => self.fill(fd, n)?,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(self.pending.drain(..).collect())
+    }
+
+    /// Resize the raw buffer
+    pub(crate) fn resize(&mut self, size: usize) {
+        self.buffer.resize(size, 0);
+    }
+
+    /// Size of the raw buffer
+    pub(crate) fn len(&self) -> usize {
+        self.buffer.len()
+    }
+}
@@ -0,0 +1,452 @@
+//! Broadcast one fanotify group to many subscribers
+//!
+//! A [`Dispatcher`] takes ownership of an [`AsyncFanotify`], reads it from a
+//! background task and hands a copy of every event to each [`Subscription`]
+//! whose [`Filter`] matches. Every subscriber has its own bounded queue: a
+//! subscriber that falls behind loses events instead of stalling the others,
+//! and is told how many it missed through [`Delivery::Lagged`].
+//!
+//! Subscribers only ever see copies of an event, so the dispatcher owns the
+//! event file descriptors. They are closed before fan-out and
+//! [`EventInfo::fd`](crate::EventInfo::fd) is always `None` in a delivered
+//! event; use [`EventInfo::path`](crate::EventInfo::path) instead. Permission
+//! events are answered with the group's
+//! [default decision](AsyncFanotify::default_decision), `Allow` unless
+//! changed with [`AsyncFanotify::set_default_decision`], before they are
+//! broadcast, since no single subscriber owns the decision.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use tokio::sync::mpsc::{self, error::TryRecvError, error::TrySendError};
+use tokio::task::JoinHandle;
+
+use crate::{
+    async_fanotify::{AsyncEventReader, AsyncFanotify, AsyncFanotifyHandle},
+    error::Result,
+    event::Event,
+    flags::MaskFlags,
+    handler::Decision,
+    sys,
+};
+
+/// Selects the events a subscriber receives
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    /// Events must match at least one of these bits
+    mask: MaskFlags,
+    /// Events must have a path below this prefix
+    path_prefix: Option<PathBuf>,
+}
+
+impl Filter {
+    /// Match events with any of the bits in `mask`
+    pub fn new(mask: MaskFlags) -> Self {
+        Self {
+            mask,
+            path_prefix: None,
+        }
+    }
+
+    /// Match every event
+    pub fn all() -> Self {
+        Self::new(MaskFlags::all())
+    }
+
+    /// Only match events whose path lies below `prefix`
+    ///
+    /// Events without a resolved path never match a prefix filter.
+    pub fn with_path_prefix<P: AsRef<Path>>(mut self, prefix: P) -> Self {
+        self.path_prefix = Some(prefix.as_ref().to_path_buf());
+        self
+    }
+
+    /// The event mask of the filter
+    pub fn mask(&self) -> MaskFlags {
+        self.mask
+    }
+
+    /// The path prefix of the filter, if any
+    pub fn path_prefix(&self) -> Option<&Path> {
+        self.path_prefix.as_deref()
+    }
+
+    /// Check whether `event` passes the filter
+    pub fn matches(&self, event: &Event) -> bool {
+        if !self.mask.intersects(event.info.mask) {
+            return false;
+        }
+
+        match &self.path_prefix {
+            Some(prefix) => event
+                .info
+                .path
+                .as_deref()
+                .is_some_and(|path| path.starts_with(prefix)),
+            None => true,
+        }
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+/// An item delivered to a [`Subscription`]
+#[derive(Debug, Clone)]
+pub enum Delivery {
+    /// An event that matched the subscriber's filter
+    Event(Event),
+    /// The subscriber's queue was full and this many events were dropped
+    ///
+    /// The dropped events happened after everything delivered before this
+    /// item and before everything delivered after it.
+    Lagged(u64),
+}
+
+/// State shared by the dispatcher task and every subscription
+struct Shared {
+    subscribers: Mutex<Subscribers>,
+    /// How permission events are answered
+    default_decision: Decision,
+    failed_responses: AtomicU64,
+}
+
+#[derive(Default)]
+struct Subscribers {
+    next_id: u64,
+    entries: HashMap<u64, Subscriber>,
+}
+
+/// The dispatcher's side of a subscription
+struct Subscriber {
+    filter: Filter,
+    sender: mpsc::Sender<Delivery>,
+    /// Events dropped and not yet reported, shared with the subscription
+    lagged: Arc<AtomicU64>,
+}
+
+impl Subscriber {
+    /// Queue `delivery`, or count it as dropped if the queue is full
+    ///
+    /// Once an event has been dropped, later events are dropped as well until
+    /// the subscription has drained its queue and picked up the count, so
+    /// the loss is reported in order. Returns `false` once the subscription
+    /// has been dropped.
+    fn offer(&mut self, delivery: Delivery) -> bool {
+        let pending = self
+            .lagged
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| (n > 0).then_some(n + 1));
+        if pending.is_ok() {
+            return !self.sender.is_closed();
+        }
+
+        match self.sender.try_send(delivery) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.lagged.fetch_add(1, Ordering::AcqRel);
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+/// Reads one fanotify group and broadcasts its events to subscribers
+///
+/// Dropping the dispatcher stops the background task; every subscription
+/// then sees the end of its stream once its queue has been drained.
+pub struct Dispatcher {
+    handle: AsyncFanotifyHandle,
+    shared: Arc<Shared>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl Dispatcher {
+    /// Start broadcasting the events of `fanotify`
+    ///
+    /// Must be called from within a tokio runtime. Watches can still be
+    /// changed afterwards through [`handle`](Self::handle).
+    pub fn spawn(fanotify: AsyncFanotify) -> Result<Self> {
+        let default_decision = fanotify.default_decision();
+        let (reader, handle) = fanotify.into_split()?;
+        let shared = Arc::new(Shared {
+            subscribers: Mutex::new(Subscribers::default()),
+            default_decision,
+            failed_responses: AtomicU64::new(0),
+        });
+
+        let task = tokio::spawn(broadcast(reader, handle.clone(), Arc::clone(&shared)));
+
+        Ok(Self {
+            handle,
+            shared,
+            task,
+        })
+    }
+
+    /// Subscribe to the events matching `filter`
+    ///
+    /// At most `capacity` deliveries are queued for the subscriber; further
+    /// events are dropped and reported as [`Delivery::Lagged`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn subscribe(&self, filter: Filter, capacity: usize) -> Subscription {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let mut subscribers = self.shared.subscribers.lock().unwrap();
+
+        let lagged = Arc::new(AtomicU64::new(0));
+
+        let id = subscribers.next_id;
+        subscribers.next_id += 1;
+        subscribers.entries.insert(
+            id,
+            Subscriber {
+                filter,
+                sender,
+                lagged: Arc::clone(&lagged),
+            },
+        );
+
+        Subscription {
+            id,
+            receiver,
+            lagged,
+            shared: Arc::downgrade(&self.shared),
+        }
+    }
+
+    /// The control handle of the underlying group
+    pub fn handle(&self) -> &AsyncFanotifyHandle {
+        &self.handle
+    }
+
+    /// Number of live subscriptions
+    pub fn subscriber_count(&self) -> usize {
+        self.shared.subscribers.lock().unwrap().entries.len()
+    }
+
+    /// The decision permission events are answered with
+    pub fn default_decision(&self) -> Decision {
+        self.shared.default_decision
+    }
+
+    /// Number of permission events whose response could not be written
+    pub fn failed_responses(&self) -> u64 {
+        self.shared.failed_responses.load(Ordering::Relaxed)
+    }
+
+    /// Check whether the background task is still reading the group
+    pub fn is_running(&self) -> bool {
+        !self.task.is_finished()
+    }
+}
+
+impl Drop for Dispatcher {
+    fn drop(&mut self) {
+        self.task.abort();
+        self.shared.subscribers.lock().unwrap().entries.clear();
+    }
+}
+
+/// A subscriber's end of a [`Dispatcher`]
+///
+/// Dropping the subscription unsubscribes it.
+pub struct Subscription {
+    id: u64,
+    receiver: mpsc::Receiver<Delivery>,
+    lagged: Arc<AtomicU64>,
+    shared: Weak<Shared>,
+}
+
+impl Subscription {
+    /// Wait for the next delivery
+    ///
+    /// Returns `None` once the dispatcher has stopped and the queue is empty.
+    pub async fn recv(&mut self) -> Option<Delivery> {
+        match self.try_next() {
+            Ok(delivery) => Some(delivery),
+            Err(TryRecvError::Empty) => self.receiver.recv().await,
+            Err(TryRecvError::Disconnected) => None,
+        }
+    }
+
+    /// Return the next delivery if one is queued, without waiting
+    pub fn try_recv(&mut self) -> Option<Delivery> {
+        self.try_next().ok()
+    }
+
+    /// Take the next queued delivery, then any loss counted after it
+    fn try_next(&mut self) -> std::result::Result<Delivery, TryRecvError> {
+        match self.receiver.try_recv() {
+            Ok(delivery) => Ok(delivery),
+            Err(e) => match self.lagged.swap(0, Ordering::AcqRel) {
+                0 => Err(e),
+                n => Ok(Delivery::Lagged(n)),
+            },
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Some(shared) = self.shared.upgrade() {
+            shared.subscribers.lock().unwrap().entries.remove(&self.id);
+        }
+    }
+}
+
+/// Body of the dispatcher task
+async fn broadcast(
+    mut reader: AsyncEventReader,
+    handle: AsyncFanotifyHandle,
+    shared: Arc<Shared>,
+) -> Result<()> {
+    let result = loop {
+        let events = match reader.read_events().await {
+            Ok(events) => events,
+            Err(e) => break Err(e),
+        };
+
+        for mut event in events {
+            if event.is_permission() {
+                if let Err(e) = handle.respond(&event, shared.default_decision.response()).await {
+                    shared.failed_responses.fetch_add(1, Ordering::Relaxed);
+                    log::error!("failed to answer permission event for {:?}: {}", event.info.path, e);
+                }
+            }
+            if let Some(fd) = event.info.fd.take() {
+                sys::close_fd(fd);
+            }
+
+            let overflow = event.info.mask.contains(MaskFlags::Q_OVERFLOW);
+            let mut subscribers = shared.subscribers.lock().unwrap();
+            subscribers.entries.retain(|_, subscriber| {
+                if overflow || subscriber.filter.matches(&event) {
+                    subscriber.offer(Delivery::Event(event.clone()))
+                } else {
+                    !subscriber.sender.is_closed()
+                }
+            });
+        }
+    };
+
+    shared.subscribers.lock().unwrap().entries.clear();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_denied, open_in_thread};
+    use std::time::Duration;
+    use tempfile::tempdir;
+    use tokio::time::timeout;
+
+    async fn next_event(subscription: &mut Subscription) -> Event {
+        match timeout(Duration::from_secs(5), subscription.recv()).await {
+            Ok(Some(Delivery::Event(event))) => event,
+            other => panic!("expected an event, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscribers_receive_filtered_events() {
+        let temp_dir = tempdir().unwrap();
+        let first = temp_dir.path().join("first.txt");
+        let second = temp_dir.path().join("second.txt");
+        std::fs::write(&first, "").unwrap();
+        std::fs::write(&second, "").unwrap();
+
+        let mut fanotify = AsyncFanotify::new().unwrap();
+        fanotify.add_watch(&first, MaskFlags::MODIFY).await.unwrap();
+        fanotify.add_watch(&second, MaskFlags::MODIFY).await.unwrap();
+        let dispatcher = Dispatcher::spawn(fanotify).unwrap();
+
+        let mut everything = dispatcher.subscribe(Filter::new(MaskFlags::MODIFY), 16);
+        let mut only_second =
+            dispatcher.subscribe(Filter::new(MaskFlags::MODIFY).with_path_prefix(&second), 16);
+        assert_eq!(dispatcher.subscriber_count(), 2);
+
+        std::fs::write(&first, "first").unwrap();
+        let event = next_event(&mut everything).await;
+        assert_eq!(event.info.path.as_deref(), Some(first.as_path()));
+        assert!(event.info.fd.is_none());
+
+        std::fs::write(&second, "second").unwrap();
+        let event = next_event(&mut everything).await;
+        assert_eq!(event.info.path.as_deref(), Some(second.as_path()));
+        let event = next_event(&mut only_second).await;
+        assert_eq!(event.info.path.as_deref(), Some(second.as_path()));
+        assert!(only_second.try_recv().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_permission_events_get_the_default_decision() {
+        let temp_dir = tempdir().unwrap();
+        let guarded = temp_dir.path().join("guarded.txt");
+        std::fs::write(&guarded, "").unwrap();
+
+        let mut fanotify = AsyncFanotify::with_flags(crate::FanotifyFlags::CLASS_CONTENT).unwrap();
+        fanotify.add_watch(&guarded, MaskFlags::OPEN_PERM).await.unwrap();
+        fanotify.set_default_decision(Decision::Deny);
+        let dispatcher = Dispatcher::spawn(fanotify).unwrap();
+        assert_eq!(dispatcher.default_decision(), Decision::Deny);
+        let mut subscription = dispatcher.subscribe(Filter::all(), 16);
+
+        let opener = open_in_thread(&guarded);
+        let event = next_event(&mut subscription).await;
+        assert!(event.is_permission());
+
+        // Answered before it was handed to the subscribers
+        assert_denied(opener);
+        assert_eq!(dispatcher.failed_responses(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_slow_subscriber_is_told_it_lagged() {
+        let temp_dir = tempdir().unwrap();
+        let test_file = temp_dir.path().join("lag.txt");
+        std::fs::write(&test_file, "").unwrap();
+
+        let mut fanotify = AsyncFanotify::new().unwrap();
+        fanotify.add_watch(&test_file, MaskFlags::OPEN).await.unwrap();
+        let dispatcher = Dispatcher::spawn(fanotify).unwrap();
+        let mut slow = dispatcher.subscribe(Filter::all(), 1);
+
+        // Opens are never merged by the kernel, so each one is a new event
+        for _ in 0..4 {
+            drop(std::fs::File::open(&test_file).unwrap());
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let mut delivered = 0;
+        let mut lagged = 0;
+        while delivered + lagged < 4 {
+            match timeout(Duration::from_secs(5), slow.recv()).await {
+                Ok(Some(Delivery::Event(_))) => delivered += 1,
+                Ok(Some(Delivery::Lagged(n))) => lagged += n,
+                other => panic!("unexpected delivery {:?}", other),
+            }
+        }
+        assert!(lagged > 0);
+        assert!(delivered >= 1);
+    }
+
+    #[tokio::test]
+    async fn test_dropping_subscription_unsubscribes() {
+        let dispatcher = Dispatcher::spawn(AsyncFanotify::new().unwrap()).unwrap();
+        let subscription = dispatcher.subscribe(Filter::all(), 4);
+        assert_eq!(dispatcher.subscriber_count(), 1);
+
+        drop(subscription);
+        assert_eq!(dispatcher.subscriber_count(), 0);
+        assert!(dispatcher.is_running());
+    }
+}
@@ -0,0 +1,312 @@
+//! Trying out a policy without enforcing it
+//!
+//! [`DryRun`] evaluates a [`Policy`] for every permission event and logs the
+//! decision it would have made, together with the rule that made it, but
+//! always answers `ALLOW`. What would have been denied is collected into a
+//! [`DryRunReport`], grouped by rule, so a new policy can be checked against
+//! production traffic before it is enforced:
+//!
+//! ```no_run
+//! use fanotify_rs::dry_run::DryRun;
+//! use fanotify_rs::policy::{Action, PathPattern, Policy, Rule};
+//! use fanotify_rs::{Fanotify, FanotifyFlags, MaskFlags};
+//!
+//! # fn main() -> fanotify_rs::Result<()> {
+//! let policy = Policy::new(Action::Allow)
+//!     .rule(Rule::deny().named("no-keys").path(PathPattern::glob("/srv/**/*.key")?));
+//!
+//! let mut fanotify = Fanotify::with_flags(FanotifyFlags::CLASS_CONTENT)?;
+//! fanotify.add_mount_watch("/srv", MaskFlags::OPEN_PERM)?;
+//!
+//! let mut dry_run = DryRun::new(policy);
+//! fanotify.run(&mut dry_run)?;
+//! println!("{}", dry_run.report());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::{
+    event::Event,
+    handler::{Decision, Handler},
+    policy::{Action, EventKind, Policy, ProcessInfo, Verdict},
+    response::Response,
+};
+
+/// Distinct example accesses kept per rule
+const MAX_EXAMPLES: usize = 10;
+
+/// One access that would have been denied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeniedAccess {
+    /// Process that asked for the access
+    pub pid: u32,
+    /// Executable of that process, if it could be read
+    pub exe: Option<PathBuf>,
+    /// Kind of access
+    pub kind: Option<EventKind>,
+    /// The file accessed
+    pub path: Option<PathBuf>,
+}
+
+/// Would-be denials attributed to one rule, or to the default action
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DenialSummary {
+    /// Index of the rule, or `None` for the default action
+    pub rule: Option<usize>,
+    /// The rule's name, `rule #N` (counted from 1) for unnamed rules, or `default`
+    pub label: String,
+    /// Number of events the rule would have denied
+    pub count: u64,
+    /// The first few distinct files it would have denied access to
+    pub examples: Vec<DeniedAccess>,
+}
+
+/// What a policy would have done so far
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DryRunReport {
+    /// Permission events evaluated
+    pub evaluated: u64,
+    /// Events the policy would have allowed
+    pub allowed: u64,
+    /// Events the policy would have allowed and logged
+    pub audited: u64,
+    /// Events the policy would have denied
+    pub denied: u64,
+    /// Would-be denials by rule, most frequent first
+    pub denials: Vec<DenialSummary>,
+}
+
+impl fmt::Display for DryRunReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "dry run: {} permission events, {} allowed, {} audited, {} would have been denied",
+            self.evaluated, self.allowed, self.audited, self.denied
+        )?;
+
+        for summary in &self.denials {
+            writeln!(f, "  {}: {} denied", summary.label, summary.count)?;
+            for example in &summary.examples {
+                let path = example.path.as_ref().map_or("?".into(), |p| p.display().to_string());
+                let exe = example.exe.as_ref().map_or("?".into(), |p| p.display().to_string());
+                let kind = example.kind.map_or("access".into(), |k| format!("{:?}", k).to_lowercase());
+                writeln!(f, "    {} {} by pid {} ({})", kind, path, example.pid, exe)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A [`Handler`] that evaluates a policy but allows everything
+///
+/// When the policy has [kernel audit](Policy::set_kernel_audit) enabled,
+/// would-be denials are still reported to the audit subsystem with the number
+/// of their rule.
+pub struct DryRun {
+    policy: Policy,
+    report: DryRunReport,
+    denials: BTreeMap<Option<usize>, DenialSummary>,
+}
+
+impl DryRun {
+    /// Evaluate `policy` without enforcing it
+    pub fn new(policy: Policy) -> Self {
+        Self {
+            policy,
+            report: DryRunReport::default(),
+            denials: BTreeMap::new(),
+        }
+    }
+
+    /// The policy being tried
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    /// Replace the policy
+    ///
+    /// The counters carry on, but the denials by rule start over: rule
+    /// numbers and names refer to the new policy from here on.
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+        self.denials.clear();
+    }
+
+    /// Stop the dry run and return the policy, e.g. to enforce it
+    pub fn into_policy(self) -> Policy {
+        self.policy
+    }
+
+    /// What the policy would have done so far
+    pub fn report(&self) -> DryRunReport {
+        let mut denials: Vec<DenialSummary> = self.denials.values().cloned().collect();
+        denials.sort_by(|a, b| b.count.cmp(&a.count).then(a.rule.cmp(&b.rule)));
+
+        DryRunReport {
+            denials,
+            ..self.report.clone()
+        }
+    }
+
+    /// Start a new report
+    pub fn reset(&mut self) {
+        self.report = DryRunReport::default();
+        self.denials.clear();
+    }
+
+    /// Evaluate `event` and record the would-be decision
+    pub fn evaluate(&mut self, event: &Event) -> Verdict {
+        let verdict = self.policy.evaluate(event);
+        self.policy.log(event, verdict);
+        self.report.evaluated += 1;
+
+        match verdict.action {
+            Action::Allow => self.report.allowed += 1,
+            Action::Audit => self.report.audited += 1,
+            Action::Deny => {
+                self.report.denied += 1;
+                self.record_denial(event, verdict.rule);
+            }
+        }
+
+        verdict
+    }
+
+    fn record_denial(&mut self, event: &Event, rule: Option<usize>) {
+        let label = self.label(rule);
+        let access = DeniedAccess {
+            pid: event.info.pid,
+            exe: ProcessInfo::new(event.info.pid).exe().map(PathBuf::from),
+            kind: EventKind::of(event),
+            path: event.info.path.clone(),
+        };
+
+        log::warn!(
+            "dry run: would deny {:?} {:?} by pid {} ({:?}), rule {}",
+            access.kind,
+            access.path,
+            access.pid,
+            access.exe,
+            label
+        );
+
+        let summary = self.denials.entry(rule).or_insert_with(|| DenialSummary {
+            rule,
+            label,
+            count: 0,
+            examples: Vec::new(),
+        });
+        summary.count += 1;
+        if summary.examples.len() < MAX_EXAMPLES
+            && !summary.examples.iter().any(|example| example.path == access.path)
+        {
+            summary.examples.push(access);
+        }
+    }
+
+    /// How a rule is named in logs and reports
+    fn label(&self, rule: Option<usize>) -> String {
+        match rule {
+            None => "default".to_string(),
+            Some(index) => match self.policy.rules().get(index).and_then(|rule| rule.name()) {
+                Some(name) => name.to_string(),
+                None => format!("rule #{}", index + 1),
+            },
+        }
+    }
+}
+
+impl Handler for DryRun {
+    fn on_event(&mut self, _event: &Event) {}
+
+    fn on_permission(&mut self, event: &Event) -> Decision {
+        self.evaluate(event);
+        Decision::Allow
+    }
+
+    fn on_permission_response(&mut self, event: &Event) -> Response {
+        let verdict = self.evaluate(event);
+
+        if !self.policy.kernel_audit() || verdict.action == Action::Allow {
+            return Response::allow();
+        }
+        let rule_number = verdict.rule.map_or(0, |index| index as u32 + 1);
+        Response::allow().audit_rule(rule_number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::{PathPattern, Rule};
+    use crate::test_support::{decide_next, open_in_thread, permission_event, watched_file};
+    use crate::MaskFlags;
+
+    #[test]
+    fn test_report_groups_denials_by_rule() {
+        let policy = Policy::new(Action::Deny)
+            .rule(Rule::allow().path_prefix("/srv/public"))
+            .rule(Rule::deny().named("no-keys").path(PathPattern::glob("/**/*.key").unwrap()))
+            .rule(Rule::audit().path_prefix("/etc"));
+        let mut dry_run = DryRun::new(policy);
+
+        for path in ["/srv/a.key", "/srv/a.key", "/srv/b.key", "/var/data", "/srv/public/x", "/etc/hosts"] {
+            let decision = dry_run.on_permission(&permission_event(MaskFlags::OPEN_PERM, path));
+            assert_eq!(decision, Decision::Allow);
+        }
+
+        let report = dry_run.report();
+        assert_eq!((report.evaluated, report.allowed, report.audited, report.denied), (6, 1, 1, 4));
+        assert_eq!(report.denials.len(), 2);
+
+        let keys = &report.denials[0];
+        assert_eq!((keys.rule, keys.label.as_str(), keys.count), (Some(1), "no-keys", 3));
+        assert_eq!(keys.examples.len(), 2);
+        assert_eq!(keys.examples[0].kind, Some(EventKind::Open));
+        assert_eq!(report.denials[1].label, "default");
+
+        let text = report.to_string();
+        assert!(text.contains("4 would have been denied"), "{}", text);
+        assert!(text.contains("open /srv/b.key by pid"), "{}", text);
+
+        dry_run.reset();
+        assert_eq!(dry_run.report(), DryRunReport::default());
+    }
+
+    #[test]
+    fn test_set_policy_starts_denials_over() {
+        let mut dry_run = DryRun::new(Policy::new(Action::Allow).rule(Rule::deny().named("old").path_prefix("/srv")));
+        dry_run.on_permission(&permission_event(MaskFlags::OPEN_PERM, "/srv/a"));
+
+        dry_run.set_policy(Policy::new(Action::Allow).rule(Rule::deny().named("new").path_prefix("/var")));
+        dry_run.on_permission(&permission_event(MaskFlags::OPEN_PERM, "/var/b"));
+
+        let report = dry_run.report();
+        assert_eq!((report.evaluated, report.denied), (2, 2));
+        assert_eq!(report.denials.len(), 1);
+        assert_eq!((report.denials[0].label.as_str(), report.denials[0].count), ("new", 1));
+    }
+
+    #[test]
+    fn test_dry_run_allows_denied_access() {
+        let (mut fanotify, _dir, secret) = watched_file("secret.key", "secret", MaskFlags::OPEN_PERM);
+
+        let policy = Policy::new(Action::Allow).rule(Rule::deny().path(PathPattern::glob("/**/*.key").unwrap()));
+        let mut dry_run = DryRun::new(policy);
+
+        let opener = open_in_thread(&secret);
+        decide_next(&mut fanotify, &mut dry_run);
+
+        opener.join().unwrap().unwrap();
+        let report = dry_run.report();
+        assert_eq!(report.denied, 1);
+        assert_eq!(report.denials[0].label, "rule #1");
+        assert_eq!(report.denials[0].examples[0].path.as_deref(), Some(secret.as_path()));
+        assert!(report.denials[0].examples[0].exe.is_some());
+    }
+}
@@ -54,6 +54,22 @@ pub enum FanotifyError {
     /// Invalid mask flags
     #[error("Invalid mask flags: {message}")]
     InvalidMask { message: String },
+
+    /// Feature needs a newer kernel
+    #[error("{feature} requires Linux {kernel} or newer")]
+    UnsupportedFeature { feature: &'static str, kernel: &'static str },
+
+    /// Invalid permission policy
+    #[error("Invalid policy: {message}")]
+    InvalidPolicy { message: String },
+
+    /// Decision log that fails verification
+    #[error("Invalid decision log: {message}")]
+    InvalidLog { message: String },
+
+    /// Quarantine entry that cannot be read or fails its hash check
+    #[error("Invalid quarantine entry: {message}")]
+    InvalidQuarantine { message: String },
 }
 
 impl From<libc::c_int> for FanotifyError {
@@ -117,4 +133,30 @@ impl FanotifyError {
             message: message.into(),
         }
     }
+
+    /// Create a new unsupported feature error
+    pub fn unsupported_feature(feature: &'static str, kernel: &'static str) -> Self {
+        FanotifyError::UnsupportedFeature { feature, kernel }
+    }
+
+    /// Create a new invalid policy error
+    pub fn invalid_policy(message: impl Into<String>) -> Self {
+        FanotifyError::InvalidPolicy {
+            message: message.into(),
+        }
+    }
+
+    /// Create a new invalid decision log error
+    pub fn invalid_log(message: impl Into<String>) -> Self {
+        FanotifyError::InvalidLog {
+            message: message.into(),
+        }
+    }
+
+    /// Create a new invalid quarantine entry error
+    pub fn invalid_quarantine(message: impl Into<String>) -> Self {
+        FanotifyError::InvalidQuarantine {
+            message: message.into(),
+        }
+    }
 } 
\ No newline at end of file
@@ -1,8 +1,10 @@
-use std::ffi::OsStr;
-use std::os::unix::ffi::OsStrExt;
+use std::os::fd::RawFd;
 use std::path::PathBuf;
 use crate::{FanotifyError, MaskFlags, Result};
-use crate::linux::{fanotify_event_metadata, errno};
+use crate::linux::{
+    fanotify_event_info_header, fanotify_event_info_range, fanotify_event_metadata,
+    FAN_EVENT_INFO_TYPE_RANGE,
+};
 
 /// Information about a fanotify event
 #[derive(Debug, Clone)]
@@ -17,6 +19,52 @@ pub struct EventInfo {
     pub pid: u32,
     /// Whether this is a directory event
     pub is_directory: bool,
+    /// The byte range about to be accessed, reported with pre-content events
+    pub range: Option<FileRange>,
+}
+
+/// A byte range of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileRange {
+    /// Offset of the first byte
+    pub offset: u64,
+    /// Number of bytes
+    pub count: u64,
+}
+
+impl FileRange {
+    /// Create a range of `count` bytes starting at `offset`
+    pub fn new(offset: u64, count: u64) -> Self {
+        Self { offset, count }
+    }
+
+    /// Offset just past the last byte
+    pub fn end(&self) -> u64 {
+        self.offset.saturating_add(self.count)
+    }
+
+    /// Whether the range covers no bytes
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+}
+
+/// What could be decoded from one `read`, see [`Event::decode_all`]
+pub(crate) struct Decoded {
+    /// The events that decoded
+    pub(crate) events: Vec<Event>,
+    /// File descriptors and masks of the events that did not
+    pub(crate) rejected: Vec<(RawFd, MaskFlags)>,
+    /// The first decoding error
+    pub(crate) error: Option<FanotifyError>,
+}
+
+impl Decoded {
+    fn reject(&mut self, metadata: &fanotify_event_metadata) {
+        if metadata.fd >= 0 {
+            self.rejected.push((metadata.fd, MaskFlags::from_bits_retain(metadata.mask)));
+        }
+    }
 }
 
 /// A fanotify event
@@ -35,12 +83,14 @@ impl Event {
             return Err(FanotifyError::invalid_event_data("Data too short"));
         }
 
+        // The buffer carries no alignment guarantee, so copy the header out
         let metadata = unsafe {
-            &*(data.as_ptr() as *const fanotify_event_metadata)
+            std::ptr::read_unaligned(data.as_ptr() as *const fanotify_event_metadata)
         };
 
-        let mask = MaskFlags::from_bits(metadata.mask)
-            .ok_or_else(|| FanotifyError::invalid_mask("Invalid mask bits"))?;
+        // Keep bits this crate has no name for rather than rejecting the
+        // event: a dropped permission event would never be answered
+        let mask = MaskFlags::from_bits_retain(metadata.mask);
 
         let mut info = EventInfo {
             fd: if metadata.fd >= 0 { Some(metadata.fd) } else { None },
@@ -48,8 +98,15 @@ impl Event {
             mask,
             pid: metadata.pid as u32,
             is_directory: mask.contains(MaskFlags::ISDIR),
+            range: None,
         };
 
+        let info_start = (metadata.metadata_len as usize).max(std::mem::size_of::<fanotify_event_metadata>());
+        let info_end = (metadata.event_len as usize).min(data.len());
+        if info_start < info_end {
+            info.range = Self::parse_range(&data[info_start..info_end]);
+        }
+
         // Try to get the path from the file descriptor
         if let Some(fd) = info.fd {
             info.path = Self::get_path_from_fd(fd).ok();
@@ -61,26 +118,103 @@ impl Event {
         })
     }
 
-    /// Get the path from a file descriptor
-    fn get_path_from_fd(fd: i32) -> Result<PathBuf> {
-        let mut buf = [0u8; libc::PATH_MAX as usize];
-        
-        let result = unsafe {
-            libc::readlink(
-                format!("/proc/self/fd/{}", fd).as_ptr() as *const i8,
-                buf.as_mut_ptr() as *mut i8,
-                buf.len() - 1,
-            )
+    /// Decode every event contained in a buffer returned by a single `read`
+    ///
+    /// The kernel packs as many events as fit into the caller's buffer, each
+    /// one prefixed by its metadata header. A trailing partial event is
+    /// reported as an error rather than silently dropped.
+    pub fn parse_all(data: &[u8]) -> Result<Vec<Self>> {
+        let decoded = Self::decode_all(data);
+        match decoded.error {
+            Some(e) => Err(e),
+            None => Ok(decoded.events),
+        }
+    }
+
+    /// Decode as many events as possible from a buffer returned by `read`
+    ///
+    /// An event that fails to decode is skipped and the ones after it are
+    /// still decoded; an invalid event length ends decoding since the next
+    /// event cannot be found. Either way the first error is kept.
+    pub(crate) fn decode_all(data: &[u8]) -> Decoded {
+        let header_len = std::mem::size_of::<fanotify_event_metadata>();
+        let mut decoded = Decoded {
+            events: Vec::new(),
+            rejected: Vec::new(),
+            error: None,
         };
+        let mut offset = 0;
+
+        while offset < data.len() {
+            let remaining = &data[offset..];
+            if remaining.len() < header_len {
+                decoded.error.get_or_insert(FanotifyError::invalid_event_data("Truncated event header"));
+                break;
+            }
+
+            let metadata = unsafe {
+                std::ptr::read_unaligned(remaining.as_ptr() as *const fanotify_event_metadata)
+            };
+            let event_len = metadata.event_len as usize;
+            if event_len < header_len || event_len > remaining.len() {
+                decoded.reject(&metadata);
+                decoded.error.get_or_insert(FanotifyError::invalid_event_data(format!(
+                    "Invalid event length {}",
+                    event_len
+                )));
+                break;
+            }
+
+            match Self::from_raw_data(&remaining[..event_len]) {
+                Ok(event) => decoded.events.push(event),
+                Err(e) => {
+                    decoded.reject(&metadata);
+                    decoded.error.get_or_insert(e);
+                }
+            }
+            offset += event_len;
+        }
+
+        decoded
+    }
+
+    /// Find the range record among the information records of an event
+    ///
+    /// Records of other types are skipped. A malformed record ends the
+    /// search: the event itself is still delivered, just without a range.
+    fn parse_range(mut records: &[u8]) -> Option<FileRange> {
+        let header_len = std::mem::size_of::<fanotify_event_info_header>();
+
+        while records.len() >= header_len {
+            let header = unsafe {
+                std::ptr::read_unaligned(records.as_ptr() as *const fanotify_event_info_header)
+            };
+            let len = header.len as usize;
+            if len < header_len || len > records.len() {
+                log::debug!("ignoring information record with invalid length {}", len);
+                return None;
+            }
 
-        if result < 0 {
-            return Err(FanotifyError::from(errno()));
+            if header.info_type == FAN_EVENT_INFO_TYPE_RANGE {
+                if len < std::mem::size_of::<fanotify_event_info_range>() {
+                    log::debug!("ignoring truncated range record");
+                    return None;
+                }
+                let range = unsafe {
+                    std::ptr::read_unaligned(records.as_ptr() as *const fanotify_event_info_range)
+                };
+                return Some(FileRange::new(range.offset, range.count));
+            }
+
+            records = &records[len..];
         }
 
-        let path_str = &buf[..result as usize];
-        let path = OsStr::from_bytes(path_str);
-        
-        Ok(PathBuf::from(path))
+        None
+    }
+
+    /// Get the path from a file descriptor
+    fn get_path_from_fd(fd: i32) -> Result<PathBuf> {
+        Ok(std::fs::read_link(format!("/proc/self/fd/{}", fd))?)
     }
 
     /// Check if this is an access event
@@ -100,7 +234,7 @@ impl Event {
 
     /// Check if this is a close event
     pub fn is_close(&self) -> bool {
-        self.info.mask.contains(MaskFlags::CLOSE_WRITE | MaskFlags::CLOSE_NOWRITE)
+        self.info.mask.intersects(MaskFlags::CLOSE_WRITE | MaskFlags::CLOSE_NOWRITE)
     }
 
     /// Check if this is a create event
@@ -110,17 +244,30 @@ impl Event {
 
     /// Check if this is a delete event
     pub fn is_delete(&self) -> bool {
-        self.info.mask.contains(MaskFlags::DELETE | MaskFlags::DELETE_SELF)
+        self.info.mask.intersects(MaskFlags::DELETE | MaskFlags::DELETE_SELF)
     }
 
     /// Check if this is a move event
     pub fn is_move(&self) -> bool {
-        self.info.mask.contains(MaskFlags::MOVED_FROM | MaskFlags::MOVED_TO | MaskFlags::MOVE_SELF)
+        self.info.mask.intersects(MaskFlags::MOVED_FROM | MaskFlags::MOVED_TO | MaskFlags::MOVE_SELF)
     }
 
     /// Check if this is a permission event
     pub fn is_permission(&self) -> bool {
-        self.info.mask.contains(MaskFlags::OPEN_PERM | MaskFlags::ACCESS_PERM)
+        self.info.mask.has_permission_events()
+    }
+
+    /// Check if this is a pre-content event
+    pub fn is_pre_access(&self) -> bool {
+        self.info.mask.contains(MaskFlags::PRE_ACCESS)
+    }
+
+    /// The byte range a pre-content event is about to access
+    ///
+    /// `None` for other events, and for pre-content events that concern
+    /// the whole file.
+    pub fn range(&self) -> Option<FileRange> {
+        self.info.range
     }
 
     /// Get a human-readable description of the event
@@ -192,6 +339,7 @@ impl EventInfo {
             mask,
             pid,
             is_directory: mask.contains(MaskFlags::ISDIR),
+            range: None,
         }
     }
 
@@ -207,6 +355,12 @@ impl EventInfo {
         self
     }
 
+    /// Set the accessed byte range
+    pub fn with_range(mut self, range: FileRange) -> Self {
+        self.range = Some(range);
+        self
+    }
+
     /// Get the path as a string, if available
     pub fn path_str(&self) -> Option<&str> {
         self.path.as_ref().and_then(|p| p.to_str())
@@ -216,4 +370,128 @@ impl EventInfo {
     pub fn filename(&self) -> Option<&str> {
         self.path.as_ref().and_then(|p| p.file_name()).and_then(|n| n.to_str())
     }
-} 
\ No newline at end of file
+} 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw_event(mask: u64, pid: i32) -> Vec<u8> {
+        let metadata = fanotify_event_metadata {
+            event_len: std::mem::size_of::<fanotify_event_metadata>() as u32,
+            vers: 3,
+            reserved: 0,
+            metadata_len: std::mem::size_of::<fanotify_event_metadata>() as u16,
+            mask,
+            fd: -1,
+            pid,
+        };
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                &metadata as *const _ as *const u8,
+                std::mem::size_of::<fanotify_event_metadata>(),
+            )
+        };
+        bytes.to_vec()
+    }
+
+    #[test]
+    fn test_parse_all_multiple_events() {
+        let mut data = raw_event(MaskFlags::MODIFY.bits(), 10);
+        data.extend(raw_event(MaskFlags::ACCESS.bits(), 20));
+
+        let events = Event::parse_all(&data).unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(events[0].is_modify());
+        assert_eq!(events[0].info.pid, 10);
+        assert!(events[1].is_access());
+        assert_eq!(events[1].info.pid, 20);
+        assert_eq!(events[1].raw_data.len(), std::mem::size_of::<fanotify_event_metadata>());
+
+        assert!(Event::parse_all(&data[..data.len() - 4]).is_err());
+    }
+
+    #[test]
+    fn test_unknown_mask_bits_are_kept() {
+        // A bit a newer kernel might set that this crate has no name for
+        let unknown = 1u64 << 40;
+        let data = raw_event(MaskFlags::OPEN_PERM.bits() | unknown, 10);
+
+        let event = Event::from_raw_data(&data).unwrap();
+        assert!(event.is_permission());
+        assert_eq!(event.info.mask.bits() & unknown, unknown);
+    }
+
+    #[test]
+    fn test_permission_masks_match_the_kernel() {
+        assert_eq!(MaskFlags::OPEN_PERM.bits(), libc::FAN_OPEN_PERM);
+        assert_eq!(MaskFlags::ACCESS_PERM.bits(), libc::FAN_ACCESS_PERM);
+        assert_eq!(MaskFlags::OPEN_EXEC_PERM.bits(), libc::FAN_OPEN_EXEC_PERM);
+        assert_eq!(MaskFlags::OPEN_EXEC.bits(), libc::FAN_OPEN_EXEC);
+        assert_eq!(crate::linux::FAN_OPEN_PERM, libc::FAN_OPEN_PERM);
+        assert_eq!(crate::linux::FAN_ACCESS_PERM, libc::FAN_ACCESS_PERM);
+        assert_eq!(crate::linux::FAN_OPEN_EXEC_PERM, libc::FAN_OPEN_EXEC_PERM);
+        assert_eq!(crate::linux::FAN_OPEN_EXEC, libc::FAN_OPEN_EXEC);
+    }
+
+    #[test]
+    fn test_predicates_match_any_of_their_flags() {
+        let event = |mask: MaskFlags| Event::from_raw_data(&raw_event(mask.bits(), 10)).unwrap();
+
+        assert!(event(MaskFlags::CLOSE_WRITE).is_close());
+        assert!(event(MaskFlags::CLOSE_NOWRITE).is_close());
+        assert!(event(MaskFlags::DELETE_SELF).is_delete());
+        assert!(event(MaskFlags::MOVED_TO).is_move());
+        assert!(event(MaskFlags::OPEN_PERM).is_permission());
+        assert!(event(MaskFlags::ACCESS_PERM).is_permission());
+        assert!(!event(MaskFlags::MODIFY).is_close());
+
+        assert!(MaskFlags::OPEN.has_access_events());
+        assert!(MaskFlags::CREATE.has_modify_events());
+        assert!(MaskFlags::OPEN_EXEC_PERM.has_permission_events());
+        assert!(!MaskFlags::MODIFY.has_permission_events());
+    }
+
+    #[test]
+    fn test_decode_all_keeps_events_before_a_bad_one() {
+        let mut data = raw_event(MaskFlags::OPEN_PERM.bits(), 10);
+        let mut bad = raw_event(MaskFlags::OPEN_PERM.bits(), 20);
+        bad[..4].copy_from_slice(&1000u32.to_ne_bytes());
+        bad[16..20].copy_from_slice(&7i32.to_ne_bytes());
+        data.extend(bad);
+
+        let decoded = Event::decode_all(&data);
+        assert_eq!(decoded.events.len(), 1);
+        assert_eq!(decoded.events[0].info.pid, 10);
+        assert_eq!(decoded.rejected, vec![(7, MaskFlags::OPEN_PERM)]);
+        assert!(decoded.error.is_some());
+    }
+
+    #[test]
+    fn test_parse_range_record() {
+        let mut data = raw_event(MaskFlags::PRE_ACCESS.bits(), 30);
+        data.extend([0x01, 0, 8, 0, 0, 0, 0, 0]); // an unknown record to skip
+        data.extend([FAN_EVENT_INFO_TYPE_RANGE, 0, 24, 0, 0, 0, 0, 0]);
+        data.extend(4096u64.to_ne_bytes());
+        data.extend(8192u64.to_ne_bytes());
+        let len = data.len() as u32;
+        data[..4].copy_from_slice(&len.to_ne_bytes());
+
+        let event = Event::from_raw_data(&data).unwrap();
+        assert!(event.is_pre_access());
+        assert!(event.is_permission());
+        assert_eq!(event.range(), Some(FileRange::new(4096, 8192)));
+        assert_eq!(event.range().unwrap().end(), 12288);
+
+        // A record claiming more bytes than the event holds is ignored
+        data[metadata_len() + 10] = 40;
+        let event = Event::from_raw_data(&data).unwrap();
+        assert!(event.is_pre_access());
+        assert_eq!(event.range(), None);
+
+        assert_eq!(Event::from_raw_data(&raw_event(MaskFlags::OPEN_PERM.bits(), 1)).unwrap().range(), None);
+    }
+
+    fn metadata_len() -> usize {
+        std::mem::size_of::<fanotify_event_metadata>()
+    }
+}
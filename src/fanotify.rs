@@ -1,24 +1,44 @@
 use std::fs::File;
 use std::io::{self, Read};
-use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
 use std::path::{Path, PathBuf};
 use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 
 use crate::{
+    batch::{BatchConfig, BatchResponder},
+    cache::{CacheConfig, DecisionCache},
+    decision_log::DecisionLog,
+    decode::EventBuffer,
     error::{FanotifyError, Result},
     flags::{FanotifyFlags, MaskFlags, EventFlags},
     event::Event,
-    linux::{fanotify_init, fanotify_mark, fanotify_response, FAN_MARK_ADD, FAN_MARK_REMOVE, errno},
+    handler::{self, Decision, Handler},
+    linux::{FAN_MARK_ADD, FAN_MARK_MOUNT, FAN_MARK_REMOVE},
+    pending,
+    permission::{Incoming, Responder},
+    response::Response,
+    sys,
+    pool::{PoolConfig, WorkerPool},
+    watchdog::Watchdog,
+    watcher::WatcherHandle,
 };
 
 /// A fanotify instance for monitoring filesystem events
 pub struct Fanotify {
     /// The file descriptor for the fanotify instance
-    fd: Option<File>,
+    fd: File,
     /// Buffer for reading events
-    buffer: Vec<u8>,
+    buffer: EventBuffer,
     /// Watched paths and their masks
     watched_paths: HashMap<PathBuf, MaskFlags>,
+    /// Watched mounts and their masks
+    watched_mounts: HashMap<PathBuf, MaskFlags>,
+    /// Shared responder, created the first time it is needed
+    responder: OnceLock<Responder>,
+    /// Decision used by permission requests dropped without an answer
+    default_decision: Decision,
 }
 
 impl Fanotify {
@@ -29,49 +49,33 @@ impl Fanotify {
 
     /// Create a new fanotify instance with custom flags
     pub fn with_flags(flags: FanotifyFlags) -> Result<Self> {
-        let result = unsafe {
-            fanotify_init(
-                flags.bits(),
-                libc::O_RDONLY as u32,
-            )
-        };
+        Self::with_event_flags(flags, libc::O_RDONLY)
+    }
 
-        if result < 0 {
-            return Err(FanotifyError::from(errno()));
-        }
+    /// Create a new fanotify instance whose event file descriptors are
+    /// opened with the `open(2)` flags `event_f_flags`
+    ///
+    /// The default is `O_RDONLY`. Pass `O_RDWR` to modify files through
+    /// their events, e.g. to [quarantine](crate::quarantine) them; the kernel
+    /// then cannot report events for files that are not writable, such as
+    /// running executables.
+    pub fn with_event_flags(flags: FanotifyFlags, event_f_flags: i32) -> Result<Self> {
+        let fd = sys::init(flags, event_f_flags as u32)?;
 
-        let fd = unsafe { File::from_raw_fd(result) };
         Ok(Self {
-            fd: Some(fd),
-            buffer: vec![0u8; 4096],
+            fd: File::from(fd),
+            buffer: EventBuffer::new(),
             watched_paths: HashMap::new(),
+            watched_mounts: HashMap::new(),
+            responder: OnceLock::new(),
+            default_decision: Decision::default(),
         })
     }
 
     /// Add a watch for a path with the specified mask
     pub fn add_watch<P: AsRef<Path>>(&mut self, path: P, mask: MaskFlags) -> Result<()> {
         let path = path.as_ref();
-        
-        // Convert path to C string
-        let path_cstr = match std::ffi::CString::new(path.to_string_lossy().as_bytes()) {
-            Ok(s) => s,
-            Err(_) => return Err(FanotifyError::invalid_path(path.to_string_lossy().to_string())),
-        };
-
-        let result = unsafe {
-            fanotify_mark(
-                self.fd.as_ref().map(|f| f.as_raw_fd()).unwrap_or(-1),
-                FAN_MARK_ADD,
-                mask.bits(),
-                libc::AT_SYMLINK_NOFOLLOW,
-                path_cstr.as_ptr(),
-            )
-        };
-
-        if result < 0 {
-            return Err(FanotifyError::from(errno()));
-        }
-
+        sys::mark_path(self.as_raw_fd(), FAN_MARK_ADD, mask, path)?;
         self.watched_paths.insert(path.to_path_buf(), mask);
         Ok(())
     }
@@ -79,100 +83,251 @@ impl Fanotify {
     /// Remove a watch for a path
     pub fn remove_watch<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
         let path = path.as_ref();
-        
-        let path_cstr = match std::ffi::CString::new(path.to_string_lossy().as_bytes()) {
-            Ok(s) => s,
-            Err(_) => return Err(FanotifyError::invalid_path(path.to_string_lossy().to_string())),
-        };
-
         let mask = self.watched_paths.get(path).copied().unwrap_or(MaskFlags::empty());
+        sys::mark_path(self.as_raw_fd(), FAN_MARK_REMOVE, mask, path)?;
+        self.watched_paths.remove(path);
+        Ok(())
+    }
 
-        let result = unsafe {
-            fanotify_mark(
-                self.fd.as_ref().map(|f| f.as_raw_fd()).unwrap_or(-1),
-                FAN_MARK_REMOVE,
-                mask.bits(),
-                libc::AT_SYMLINK_NOFOLLOW,
-                path_cstr.as_ptr(),
-            )
-        };
-
-        if result < 0 {
-            return Err(FanotifyError::from(errno()));
-        }
+    /// Watch every file on the mount containing `path`
+    ///
+    /// Mount marks cover files that do not exist yet, which makes them the
+    /// natural choice for system-wide policies such as exec allowlisting.
+    pub fn add_mount_watch<P: AsRef<Path>>(&mut self, path: P, mask: MaskFlags) -> Result<()> {
+        let path = path.as_ref();
+        sys::mark_path(self.as_raw_fd(), FAN_MARK_ADD | FAN_MARK_MOUNT, mask, path)?;
+        self.watched_mounts.insert(path.to_path_buf(), mask);
+        Ok(())
+    }
 
-        self.watched_paths.remove(path);
+    /// Remove a mount watch added with [`add_mount_watch`](Self::add_mount_watch)
+    pub fn remove_mount_watch<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mask = self.watched_mounts.get(path).copied().unwrap_or(MaskFlags::empty());
+        sys::mark_path(self.as_raw_fd(), FAN_MARK_REMOVE | FAN_MARK_MOUNT, mask, path)?;
+        self.watched_mounts.remove(path);
         Ok(())
     }
 
     /// Read a single event
+    ///
+    /// Events left over from a previous `read` are returned first. Otherwise
+    /// this blocks on a group created without `NONBLOCK` and returns `None`
+    /// on a non-blocking group with nothing queued. Prefer
+    /// [`read_blocking`](Self::read_blocking), [`try_read`](Self::try_read)
+    /// or [`read_timeout`](Self::read_timeout), which behave the same way
+    /// regardless of how the group was created.
     pub fn read_event(&mut self) -> Result<Option<Event>> {
-        let bytes_read = match self.fd.as_mut().map(|f| f.read(&mut self.buffer)) {
-            Some(Ok(n)) => n,
-            Some(Err(e)) if e.kind() == io::ErrorKind::WouldBlock => {
-                return Ok(None);
-            }
-            Some(Err(e)) => return Err(FanotifyError::Io(e)),
-            None => return Ok(None),
-        };
+        if let Some(event) = self.buffer.pop() {
+            return Ok(Some(event));
+        }
 
-        if bytes_read == 0 {
+        if self.fill_buffer()? == 0 {
             return Ok(None);
         }
 
-        let event = Event::from_raw_data(&self.buffer[..bytes_read])?;
-        Ok(Some(event))
+        Ok(self.buffer.pop())
     }
 
-    /// Read all available events
-    pub fn read_events(&mut self) -> Result<Vec<Event>> {
-        let mut events = Vec::new();
-        
+    /// Wait until an event is available and return it
+    pub fn read_blocking(&mut self) -> Result<Event> {
         loop {
-            match self.read_event()? {
-                Some(event) => events.push(event),
-                None => break,
+            if let Some(event) = self.read_timeout_inner(None)? {
+                return Ok(event);
             }
         }
-        
-        Ok(events)
     }
 
-    /// Get an iterator over events
-    pub fn events(&mut self) -> EventIterator {
-        EventIterator { fanotify: self }
+    /// Return the next event if one is queued, without blocking
+    pub fn try_read(&mut self) -> Result<Option<Event>> {
+        self.read_timeout(Duration::ZERO)
     }
 
-    /// Respond to a permission event
-    pub fn respond(&self, event: &Event, response: EventFlags) -> Result<()> {
-        if !event.is_permission() {
-            return Err(FanotifyError::invalid_event_data("Event is not a permission event"));
+    /// Wait up to `timeout` for the next event
+    ///
+    /// Returns `None` if no event arrived in time.
+    pub fn read_timeout(&mut self, timeout: Duration) -> Result<Option<Event>> {
+        self.read_timeout_inner(Some(timeout))
+    }
+
+    /// Read all events that are currently queued
+    ///
+    /// Never blocks; returns an empty vector if nothing is queued.
+    pub fn read_events(&mut self) -> Result<Vec<Event>> {
+        self.read_available()
+    }
+
+    /// Read every event that is queued right now without blocking
+    ///
+    /// This works on blocking and non-blocking groups alike, which makes it
+    /// the method to call after an external poller (epoll, mio, ...)
+    /// reported the group as readable.
+    pub fn read_available(&mut self) -> Result<Vec<Event>> {
+        // The events stay buffered until every read succeeded, so an error
+        // leaves them to the next call instead of dropping them
+        while self.poll_readable(Some(Duration::ZERO))? {
+            if self.fill_buffer()? == 0 {
+                break;
+            }
         }
 
-        let fd = event.info.fd.ok_or_else(|| {
-            FanotifyError::invalid_event_data("Permission event has no file descriptor")
-        })?;
+        Ok(self.buffer.drain().collect())
+    }
 
-        let response_struct = fanotify_response {
-            fd,
-            response: response.bits(),
-        };
+    /// Wait until the group has events to read
+    ///
+    /// `None` waits indefinitely. Returns `false` if the timeout expired
+    /// before any event was queued.
+    pub fn poll_readable(&self, timeout: Option<Duration>) -> Result<bool> {
+        sys::poll_readable(self.as_raw_fd(), timeout)
+    }
 
-        let result = unsafe {
-            libc::write(
-                self.fd.as_ref().map(|f| f.as_raw_fd()).unwrap_or(-1),
-                &response_struct as *const _ as *const libc::c_void,
-                std::mem::size_of::<fanotify_response>(),
-            )
+    /// Shared implementation of the timed reads; `None` waits indefinitely
+    fn read_timeout_inner(&mut self, timeout: Option<Duration>) -> Result<Option<Event>> {
+        if let Some(event) = self.buffer.pop() {
+            return Ok(Some(event));
+        }
+
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            let remaining = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+            if !self.poll_readable(remaining)? {
+                return Ok(None);
+            }
+
+            // Another reader sharing a non-blocking group may have raced us
+            // to the events, in which case wait again until the deadline
+            if self.fill_buffer()? > 0 {
+                return Ok(self.buffer.pop());
+            }
+
+            if remaining == Some(Duration::ZERO) {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Read one batch of events from the group into the buffer
+    ///
+    /// Returns the number of bytes read, or 0 if a non-blocking group had
+    /// nothing queued.
+    fn fill_buffer(&mut self) -> Result<usize> {
+        let bytes_read = match self.fd.read(self.buffer.space()) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(0),
+            Err(e) => return Err(FanotifyError::Io(e)),
         };
 
-        if result < 0 {
-            return Err(FanotifyError::from(errno()));
+        if bytes_read > 0 {
+            self.buffer.fill(self.fd.as_raw_fd(), bytes_read)?;
+        }
+
+        Ok(bytes_read)
+    }
+
+    /// Get an iterator over the events that are currently queued
+    ///
+    /// The iterator never blocks and ends once the queue is drained.
+    pub fn events(&mut self) -> EventIterator<'_> {
+        EventIterator { fanotify: self }
+    }
+
+    /// Run an event loop that hands every event to `handler`
+    ///
+    /// Permission events are answered with the handler's decision and every
+    /// event file descriptor is closed after its callback returns. The loop
+    /// runs until [`Handler::should_stop`] returns `true` or
+    /// [`Handler::on_error`] breaks, in which case the error is returned.
+    pub fn run<H: Handler>(&mut self, mut handler: H) -> Result<()> {
+        while !handler.should_stop() {
+            let read = match self.poll_readable(Some(handler::STOP_CHECK_INTERVAL)) {
+                Ok(false) => continue,
+                ready => ready.and_then(|_| self.read_available()),
+            };
+            let events = match read {
+                Ok(events) => events,
+                Err(e) => {
+                    if handler.on_error(&e).is_break() {
+                        return Err(e);
+                    }
+                    continue;
+                }
+            };
+
+            self.dispatch(&mut handler, events)?;
         }
 
         Ok(())
     }
 
+    /// Hand `events` to `handler` the way [`run`](Self::run) does
+    pub(crate) fn dispatch<H: Handler>(&self, handler: &mut H, events: Vec<Event>) -> Result<()> {
+        handler::dispatch_batch(handler, self.as_raw_fd(), self.buffer.permissions(), events)
+    }
+
+    /// Move the group onto a background thread that delivers events over a channel
+    ///
+    /// See [`WatcherHandle`] for how to receive events and stop the thread.
+    pub fn spawn_watcher(self) -> Result<WatcherHandle> {
+        WatcherHandle::spawn(self)
+    }
+
+    /// Move the group onto a reader thread feeding a pool of worker threads
+    ///
+    /// See [`WorkerPool`] for how events are distributed and measured.
+    pub fn spawn_pool<H>(self, config: PoolConfig, handler: H) -> Result<WorkerPool>
+    where
+        H: Handler + Clone + Send + 'static,
+    {
+        WorkerPool::spawn(self, config, handler)
+    }
+
+    /// A responder that writes this group's permission responses in batches
+    ///
+    /// See [`BatchResponder`] for when batches are flushed.
+    pub fn batch_responder(&self, config: BatchConfig) -> Result<BatchResponder> {
+        BatchResponder::spawn(self.responder()?, config)
+    }
+
+    /// A cache of allow decisions that suppresses repeated permission events
+    /// in the kernel
+    ///
+    /// See [`DecisionCache`] for when cached decisions are dropped.
+    pub fn decision_cache(&self, config: CacheConfig) -> Result<DecisionCache> {
+        Ok(DecisionCache::new(self.responder()?, config))
+    }
+
+    /// Start a watchdog that answers permission events left waiting longer
+    /// than `deadline` with `default`
+    ///
+    /// See [`Watchdog`] for the timeout metric and how to stop it.
+    pub fn spawn_watchdog(&self, deadline: Duration, default: Decision) -> Result<Watchdog> {
+        Watchdog::spawn(self.responder()?, deadline, default)
+    }
+
+    /// Record every permission response of this group in `log`
+    ///
+    /// Covers responses written through this instance, its responders,
+    /// batch responders, watchdogs and [`shutdown`](Self::shutdown).
+    pub fn set_decision_log(&mut self, log: DecisionLog) {
+        self.buffer.permissions().set_log(Some(log));
+    }
+
+    /// Stop recording responses and return the decision log, if any
+    pub fn take_decision_log(&mut self) -> Option<DecisionLog> {
+        self.buffer.permissions().set_log(None)
+    }
+
+    /// Respond to a permission event
+    pub fn respond(&self, event: &Event, response: EventFlags) -> Result<()> {
+        self.respond_with(event, &Response::new(response))
+    }
+
+    /// Respond to a permission event with an extended [`Response`]
+    pub fn respond_with(&self, event: &Event, response: &Response) -> Result<()> {
+        self.buffer.permissions().respond(self.as_raw_fd(), event, response)
+    }
+
     /// Allow a permission event
     pub fn allow(&self, event: &Event) -> Result<()> {
         self.respond(event, EventFlags::ALLOW)
@@ -183,11 +338,88 @@ impl Fanotify {
         self.respond(event, EventFlags::DENY)
     }
 
+    /// Wait for the next event and sort it into a notification or a permission request
+    ///
+    /// Permission requests respond with the
+    /// [default decision](Self::set_default_decision) if they are dropped
+    /// unanswered.
+    pub fn read_incoming(&mut self) -> Result<Incoming> {
+        let event = self.read_blocking()?;
+        self.incoming(event)
+    }
+
+    /// Sort an event returned by one of the other read methods
+    pub fn incoming(&self, event: Event) -> Result<Incoming> {
+        Ok(self.responder()?.incoming(event, self.default_decision))
+    }
+
+    /// A thread-safe responder for this group's permission events
+    ///
+    /// All responders of a group share one duplicate of the group file
+    /// descriptor, created on first use.
+    pub fn responder(&self) -> Result<Responder> {
+        if let Some(responder) = self.responder.get() {
+            return Ok(responder.clone());
+        }
+
+        let fd = OwnedFd::from(self.fd.try_clone()?);
+        let responder = Responder::new(fd, self.buffer.permissions().clone());
+        Ok(self.responder.get_or_init(|| responder).clone())
+    }
+
+    /// The decision used by permission requests dropped without an answer
+    pub fn default_decision(&self) -> Decision {
+        self.default_decision
+    }
+
+    /// Change the decision used by permission requests dropped without an answer
+    ///
+    /// Defaults to [`Decision::Allow`], which is also what the kernel does
+    /// when the group is closed. Only affects requests created afterwards.
+    pub fn set_default_decision(&mut self, decision: Decision) {
+        self.default_decision = decision;
+    }
+
+    /// Number of permission events that were read but not answered yet
+    pub fn pending_permissions(&self) -> usize {
+        self.buffer.permissions().len()
+    }
+
+    /// Shut the group down without leaving any process waiting on it
+    ///
+    /// Removes every mark so no new events are generated, answers every
+    /// outstanding permission event with `default` (including the ones still
+    /// queued in the kernel or in the read buffer), and closes the group.
+    /// Events that were already handed out keep their file descriptors;
+    /// closing those is still up to the caller.
+    ///
+    /// Every step is attempted even if an earlier one fails; the first
+    /// error is returned.
+    pub fn shutdown(mut self, default: Decision) -> Result<()> {
+        let group_fd = self.as_raw_fd();
+        let flushed = sys::flush_marks(group_fd);
+        self.watched_paths.clear();
+        self.watched_mounts.clear();
+
+        let (unread, read_result) = match self.read_available() {
+            Ok(events) => (events, Ok(())),
+            Err(e) => (self.buffer.drain().collect(), Err(e)),
+        };
+        let answered = pending::finish(group_fd, self.buffer.permissions(), unread, default.response());
+
+        flushed.and(read_result).and(answered)
+    }
+
     /// Get the list of watched paths
     pub fn watched_paths(&self) -> &HashMap<PathBuf, MaskFlags> {
         &self.watched_paths
     }
 
+    /// Get the list of watched mounts
+    pub fn watched_mounts(&self) -> &HashMap<PathBuf, MaskFlags> {
+        &self.watched_mounts
+    }
+
     /// Check if a path is being watched
     pub fn is_watched<P: AsRef<Path>>(&self, path: P) -> bool {
         self.watched_paths.contains_key(path.as_ref())
@@ -200,7 +432,7 @@ impl Fanotify {
 
     /// Set the buffer size for reading events
     pub fn set_buffer_size(&mut self, size: usize) {
-        self.buffer.resize(size, 0);
+        self.buffer.resize(size);
     }
 
     /// Get the current buffer size
@@ -209,16 +441,68 @@ impl Fanotify {
     }
 }
 
-impl Drop for Fanotify {
-    fn drop(&mut self) {
-        // Let File's Drop handle closing the file descriptor
-        let _ = self.fd.take();
+impl AsRawFd for Fanotify {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
     }
 }
 
-impl AsRawFd for Fanotify {
-    fn as_raw_fd(&self) -> RawFd {
-        self.fd.as_ref().map(|f| f.as_raw_fd()).unwrap_or(-1)
+impl AsFd for Fanotify {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl IntoRawFd for Fanotify {
+    fn into_raw_fd(self) -> RawFd {
+        self.fd.into_raw_fd()
+    }
+}
+
+impl FromRawFd for Fanotify {
+    /// Wrap an existing fanotify group
+    ///
+    /// Marks that were added before the group was handed over are not known
+    /// to the new instance, so [`Fanotify::watched_paths`] starts out empty.
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        Self {
+            fd: File::from_raw_fd(fd),
+            buffer: EventBuffer::new(),
+            watched_paths: HashMap::new(),
+            watched_mounts: HashMap::new(),
+            responder: OnceLock::new(),
+            default_decision: Decision::default(),
+        }
+    }
+}
+
+/// Lets a [`Fanotify`] group be registered with a [`mio::Poll`]
+///
+/// Register the group for [`mio::Interest::READABLE`] and call
+/// [`Fanotify::read_available`] when it fires; with edge-triggered
+/// notifications every queued event must be drained before the next wakeup.
+#[cfg(feature = "mio")]
+impl mio::event::Source for Fanotify {
+    fn register(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        mio::unix::SourceFd(&self.as_raw_fd()).register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        mio::unix::SourceFd(&self.as_raw_fd()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &mio::Registry) -> io::Result<()> {
+        mio::unix::SourceFd(&self.as_raw_fd()).deregister(registry)
     }
 }
 
@@ -231,7 +515,7 @@ impl<'a> Iterator for EventIterator<'a> {
     type Item = Result<Event>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.fanotify.read_event() {
+        match self.fanotify.try_read() {
             Ok(Some(event)) => Some(Ok(event)),
             Ok(None) => None,
             Err(e) => Some(Err(e)),
@@ -249,6 +533,7 @@ impl<'a> EventIterator<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support::{assert_denied, open_in_thread, watched_file};
     use tempfile::tempdir;
 
     #[test]
@@ -284,4 +569,73 @@ mod tests {
         assert!(result.is_ok(), "remove_watch failed: {:?}", result.err());
         assert!(!fanotify.is_watched(temp_dir.path()));
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_read_available_does_not_block() {
+        let mut fanotify = Fanotify::new().unwrap();
+        let temp_dir = tempdir().unwrap();
+        let test_file = temp_dir.path().join("poll.txt");
+        std::fs::write(&test_file, "").unwrap();
+        fanotify.add_watch(&test_file, MaskFlags::MODIFY).unwrap();
+
+        assert!(!fanotify.poll_readable(Some(Duration::from_millis(10))).unwrap());
+        assert!(fanotify.read_available().unwrap().is_empty());
+
+        std::fs::write(&test_file, "poll").unwrap();
+        assert!(fanotify.poll_readable(Some(Duration::from_secs(5))).unwrap());
+
+        let events = fanotify.read_available().unwrap();
+        assert!(events.iter().any(|e| e.is_modify()));
+        assert!(fanotify.read_available().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_shutdown_answers_pending_permissions() {
+        let (mut fanotify, _dir, test_file) = watched_file("pending.txt", "pending", MaskFlags::OPEN_PERM);
+        let openers: Vec<_> = (0..2).map(|_| open_in_thread(&test_file)).collect();
+
+        // Hand out one request and leave the other one queued
+        let event = fanotify.read_blocking().unwrap();
+        assert!(event.is_permission());
+        assert!(fanotify.pending_permissions() >= 1);
+        std::thread::sleep(Duration::from_millis(50));
+
+        fanotify.shutdown(Decision::Deny).unwrap();
+        sys::close_fd(event.info.fd.unwrap());
+
+        openers.into_iter().for_each(assert_denied);
+    }
+
+    #[test]
+    fn test_raw_fd_round_trip() {
+        let fanotify = Fanotify::new().unwrap();
+        let raw = fanotify.into_raw_fd();
+        let fanotify = unsafe { Fanotify::from_raw_fd(raw) };
+        assert_eq!(fanotify.as_fd().as_raw_fd(), raw);
+        assert!(fanotify.watched_paths().is_empty());
+    }
+
+    #[cfg(feature = "mio")]
+    #[test]
+    fn test_mio_source() {
+        use mio::{Events, Interest, Poll, Token};
+
+        let mut fanotify = Fanotify::with_flags(FanotifyFlags::default() | FanotifyFlags::NONBLOCK).unwrap();
+        let temp_dir = tempdir().unwrap();
+        let test_file = temp_dir.path().join("mio.txt");
+        std::fs::write(&test_file, "").unwrap();
+        fanotify.add_watch(&test_file, MaskFlags::MODIFY).unwrap();
+
+        let mut poll = Poll::new().unwrap();
+        poll.registry().register(&mut fanotify, Token(7), Interest::READABLE).unwrap();
+
+        std::fs::write(&test_file, "mio").unwrap();
+
+        let mut events = Events::with_capacity(4);
+        poll.poll(&mut events, Some(Duration::from_secs(5))).unwrap();
+        assert!(events.iter().any(|e| e.token() == Token(7) && e.is_readable()));
+        assert!(fanotify.read_available().unwrap().iter().any(|e| e.is_modify()));
+
+        poll.registry().deregister(&mut fanotify).unwrap();
+    }
+}
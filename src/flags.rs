@@ -19,6 +19,8 @@ bitflags! {
         const UNLIMITED_QUEUE = 0x00000010;
         /// Unlimited marks (FAN_UNLIMITED_MARKS)
         const UNLIMITED_MARKS = 0x00000020;
+        /// Allow permission responses to be logged by the audit subsystem (FAN_ENABLE_AUDIT)
+        const ENABLE_AUDIT = 0x00000040;
         /// Report TID (FAN_REPORT_TID)
         const REPORT_TID = 0x00000100;
         /// Report FID (FAN_REPORT_FID)
@@ -49,10 +51,18 @@ bitflags! {
         const DELETE = 0x00000200;
         const DELETE_SELF = 0x00000400;
         const MOVE_SELF = 0x00000800;
+        const OPEN_EXEC = 0x00001000;
         
         // Permission events
-        const OPEN_PERM = 0x00001000;
-        const ACCESS_PERM = 0x00002000;
+        const OPEN_PERM = 0x00010000;
+        const ACCESS_PERM = 0x00020000;
+        const OPEN_EXEC_PERM = 0x00040000;
+        /// File content is about to be read or written (FAN_PRE_ACCESS)
+        ///
+        /// Needs Linux 6.14 or newer and a `CLASS_PRE_CONTENT` group. The
+        /// event reports the byte range being accessed, see
+        /// [`Event::range`](crate::Event::range).
+        const PRE_ACCESS = 0x00100000;
         
         // Directory events
         const ISDIR = 0x40000000;
@@ -64,6 +74,7 @@ bitflags! {
         const ONLYDIR = 0x01000000;
         const DONT_FOLLOW = 0x02000000;
         const EXCL_UNLINK = 0x04000000;
+        const EVENT_ON_CHILD = 0x08000000;
         const MASK_ADD = 0x20000000;
         const IGNORED_MASK = 0x80000000;
         
@@ -89,6 +100,14 @@ bitflags! {
         
         /// Deny the operation
         const DENY = 0x02;
+
+        /// Log the decision to the audit subsystem (FAN_AUDIT)
+        ///
+        /// The group must have been created with [`FanotifyFlags::ENABLE_AUDIT`].
+        const AUDIT = 0x10;
+
+        /// The response carries an information record (FAN_INFO)
+        const INFO = 0x20;
     }
 }
 
@@ -104,6 +123,21 @@ impl Default for MaskFlags {
     }
 }
 
+impl EventFlags {
+    /// Deny the operation, failing it with `errno` (FAN_DENY_ERRNO)
+    pub const fn deny_errno(errno: i32) -> Self {
+        Self::from_bits_retain(Self::DENY.bits() | ((errno as u32 & 0xff) << 24))
+    }
+
+    /// The error number carried by a [`deny_errno`](Self::deny_errno) response
+    pub fn errno(&self) -> Option<i32> {
+        match self.bits() >> 24 {
+            0 => None,
+            errno => Some(errno as i32),
+        }
+    }
+}
+
 impl Default for EventFlags {
     fn default() -> Self {
         EventFlags::ALLOW
@@ -113,19 +147,21 @@ impl Default for EventFlags {
 impl MaskFlags {
     /// Check if the mask contains access events
     pub fn has_access_events(&self) -> bool {
-        self.contains(MaskFlags::ACCESS | MaskFlags::OPEN | MaskFlags::ACCESS_PERM)
+        self.intersects(MaskFlags::ACCESS | MaskFlags::OPEN | MaskFlags::ACCESS_PERM)
     }
     
     /// Check if the mask contains modify events
     pub fn has_modify_events(&self) -> bool {
-        self.contains(MaskFlags::MODIFY | MaskFlags::ATTRIB | MaskFlags::CLOSE_WRITE | 
+        self.intersects(MaskFlags::MODIFY | MaskFlags::ATTRIB | MaskFlags::CLOSE_WRITE | 
                      MaskFlags::CREATE | MaskFlags::DELETE | MaskFlags::DELETE_SELF | 
                      MaskFlags::MOVE_SELF | MaskFlags::MOVED_FROM | MaskFlags::MOVED_TO)
     }
     
     /// Check if the mask contains permission events
     pub fn has_permission_events(&self) -> bool {
-        self.contains(MaskFlags::OPEN_PERM | MaskFlags::ACCESS_PERM)
+        self.intersects(
+            MaskFlags::OPEN_PERM | MaskFlags::ACCESS_PERM | MaskFlags::OPEN_EXEC_PERM | MaskFlags::PRE_ACCESS,
+        )
     }
     
     /// Check if the mask is directory-only
@@ -0,0 +1,365 @@
+//! Callback-driven event loop
+//!
+//! Implement [`Handler`] and pass it to [`Fanotify::run`](crate::Fanotify::run)
+//! (or the async equivalents) to let the crate own the read loop. Every
+//! permission event is answered with the [`Decision`] returned by
+//! [`Handler::on_permission`], and every event file descriptor is closed
+//! once the callback returns, so neither can be forgotten.
+
+use std::ops::ControlFlow;
+use std::os::fd::RawFd;
+use std::time::Duration;
+
+use crate::{
+    error::{FanotifyError, Result},
+    event::Event,
+    flags::{EventFlags, MaskFlags},
+    pending::PendingPermissions,
+    response::Response,
+    sys,
+};
+
+/// How long `run` waits for events before checking [`Handler::should_stop`] again
+pub const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The answer to a permission event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Decision {
+    /// Let the operation proceed
+    #[default]
+    Allow,
+    /// Fail the operation with `EPERM`
+    Deny,
+    /// Fail the operation with a chosen error (`FAN_DENY_ERRNO`)
+    ///
+    /// Needs Linux 6.14 or newer and a group created with
+    /// [`FanotifyFlags::CLASS_PRE_CONTENT`](crate::FanotifyFlags::CLASS_PRE_CONTENT);
+    /// answering fails with [`FanotifyError::UnsupportedFeature`] on older
+    /// kernels.
+    DenyWith(Errno),
+}
+
+impl Decision {
+    /// The response flags written to the kernel for this decision
+    pub fn response(self) -> EventFlags {
+        match self {
+            Decision::Allow => EventFlags::ALLOW,
+            Decision::Deny => EventFlags::DENY,
+            Decision::DenyWith(errno) => EventFlags::deny_errno(errno.raw()),
+        }
+    }
+
+    /// Whether the operation is refused
+    pub fn is_deny(self) -> bool {
+        !matches!(self, Decision::Allow)
+    }
+}
+
+/// An error number a denied permission event can fail with
+///
+/// The kernel accepts only the errors listed as constants here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Errno(i32);
+
+impl Errno {
+    /// Operation not permitted, the error of a plain deny
+    pub const EPERM: Errno = Errno(libc::EPERM);
+    /// I/O error
+    pub const EIO: Errno = Errno(libc::EIO);
+    /// Device or resource busy
+    pub const EBUSY: Errno = Errno(libc::EBUSY);
+    /// Text file busy
+    pub const ETXTBSY: Errno = Errno(libc::ETXTBSY);
+    /// Try again
+    pub const EAGAIN: Errno = Errno(libc::EAGAIN);
+    /// No space left on device
+    pub const ENOSPC: Errno = Errno(libc::ENOSPC);
+    /// Disk quota exceeded
+    pub const EDQUOT: Errno = Errno(libc::EDQUOT);
+
+    const ACCEPTED: [Errno; 7] = [
+        Errno::EPERM,
+        Errno::EIO,
+        Errno::EBUSY,
+        Errno::ETXTBSY,
+        Errno::EAGAIN,
+        Errno::ENOSPC,
+        Errno::EDQUOT,
+    ];
+
+    /// Wrap a raw error number
+    ///
+    /// Fails for errors the kernel does not accept in a response.
+    pub fn new(errno: i32) -> Result<Self> {
+        let errno = Errno(errno);
+        if !Self::ACCEPTED.contains(&errno) {
+            return Err(FanotifyError::invalid_flags(format!(
+                "errno {} cannot be returned for a denied permission event",
+                errno.0
+            )));
+        }
+        Ok(errno)
+    }
+
+    /// The raw error number
+    pub fn raw(self) -> i32 {
+        self.0
+    }
+}
+
+impl From<Decision> for EventFlags {
+    fn from(decision: Decision) -> Self {
+        decision.response()
+    }
+}
+
+/// Callbacks invoked by the `run` event loops
+///
+/// Only [`on_event`](Handler::on_event) is required. The event passed to a
+/// callback is only valid for the duration of the call: its file descriptor
+/// is closed as soon as the callback returns.
+pub trait Handler {
+    /// Called for every notification event
+    fn on_event(&mut self, event: &Event);
+
+    /// Called for every permission event
+    ///
+    /// The returned decision is written back to the kernel by the loop.
+    fn on_permission(&mut self, event: &Event) -> Decision {
+        let _ = event;
+        Decision::Allow
+    }
+
+    /// Called for every permission event to build the full response
+    ///
+    /// Defaults to the response for [`on_permission`](Handler::on_permission).
+    /// Override it to attach audit information to the answer.
+    fn on_permission_response(&mut self, event: &Event) -> Response {
+        self.on_permission(event).into()
+    }
+
+    /// Called when the kernel reports that its event queue overflowed
+    fn on_overflow(&mut self) {}
+
+    /// Called when reading or answering events fails
+    ///
+    /// Return `ControlFlow::Continue(())` to keep the loop running, or
+    /// `ControlFlow::Break(())` to make `run` return the error.
+    fn on_error(&mut self, error: &FanotifyError) -> ControlFlow<()> {
+        let _ = error;
+        ControlFlow::Break(())
+    }
+
+    /// Checked after every batch of events, and every
+    /// [`STOP_CHECK_INTERVAL`] while the group is idle; return `true` to
+    /// make `run` return
+    fn should_stop(&self) -> bool {
+        false
+    }
+}
+
+impl<H: Handler + ?Sized> Handler for &mut H {
+    fn on_event(&mut self, event: &Event) {
+        (**self).on_event(event)
+    }
+
+    fn on_permission(&mut self, event: &Event) -> Decision {
+        (**self).on_permission(event)
+    }
+
+    fn on_permission_response(&mut self, event: &Event) -> Response {
+        (**self).on_permission_response(event)
+    }
+
+    fn on_overflow(&mut self) {
+        (**self).on_overflow()
+    }
+
+    fn on_error(&mut self, error: &FanotifyError) -> ControlFlow<()> {
+        (**self).on_error(error)
+    }
+
+    fn should_stop(&self) -> bool {
+        (**self).should_stop()
+    }
+}
+
+/// Hand a batch of events to `handler`, answering and closing each of them
+///
+/// The whole batch is always dispatched, even if the handler asks to stop
+/// half-way, so no permission event is left unanswered.
+pub(crate) fn dispatch_batch<H: Handler + ?Sized>(
+    handler: &mut H,
+    group_fd: RawFd,
+    pending: &PendingPermissions,
+    events: impl IntoIterator<Item = Event>,
+) -> Result<()> {
+    let mut failure = None;
+
+    for event in events {
+        if let Err(e) = dispatch(handler, group_fd, pending, &event) {
+            if failure.is_none() && handler.on_error(&e).is_break() {
+                failure = Some(e);
+            }
+        }
+    }
+
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Hand a single event to `handler`
+fn dispatch<H: Handler + ?Sized>(
+    handler: &mut H,
+    group_fd: RawFd,
+    pending: &PendingPermissions,
+    event: &Event,
+) -> Result<()> {
+    let result = if event.info.mask.contains(MaskFlags::Q_OVERFLOW) {
+        handler.on_overflow();
+        Ok(())
+    } else if event.is_permission() {
+        let response = handler.on_permission_response(event);
+        pending.respond(group_fd, event, &response)
+    } else {
+        handler.on_event(event);
+        Ok(())
+    };
+
+    if let Some(fd) = event.info.fd {
+        sys::close_fd(fd);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_denied, open_in_thread, watched_file};
+    use crate::{Fanotify, FanotifyFlags};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Instant;
+    use tempfile::tempdir;
+
+    struct Counter {
+        modified: usize,
+        permissions: usize,
+        decision: Decision,
+    }
+
+    impl Handler for Counter {
+        fn on_event(&mut self, event: &Event) {
+            if event.is_modify() {
+                self.modified += 1;
+            }
+        }
+
+        fn on_permission(&mut self, event: &Event) -> Decision {
+            assert!(event.info.fd.is_some());
+            self.permissions += 1;
+            self.decision
+        }
+
+        fn should_stop(&self) -> bool {
+            self.modified > 0 || self.permissions > 0
+        }
+    }
+
+    #[test]
+    fn test_run_notification_events() {
+        let mut fanotify = Fanotify::new().unwrap();
+        let temp_dir = tempdir().unwrap();
+        let test_file = temp_dir.path().join("run.txt");
+        std::fs::write(&test_file, "").unwrap();
+        fanotify.add_watch(&test_file, MaskFlags::MODIFY).unwrap();
+
+        let writer_file = test_file.clone();
+        let writer = thread::spawn(move || std::fs::write(writer_file, "run").unwrap());
+
+        let mut handler = Counter { modified: 0, permissions: 0, decision: Decision::Allow };
+        fanotify.run(&mut handler).unwrap();
+        writer.join().unwrap();
+
+        assert!(handler.modified >= 1);
+    }
+
+    #[test]
+    fn test_run_answers_permission_events() {
+        let (mut fanotify, _dir, test_file) = watched_file("secret.txt", "secret", MaskFlags::OPEN_PERM);
+        let opener = open_in_thread(&test_file);
+
+        let mut handler = Counter { modified: 0, permissions: 0, decision: Decision::Deny };
+        fanotify.run(&mut handler).unwrap();
+
+        assert_eq!(handler.permissions, 1);
+        assert_denied(opener);
+    }
+
+    #[test]
+    fn test_run_stops_while_idle() {
+        struct StopFlag(Arc<AtomicBool>);
+
+        impl Handler for StopFlag {
+            fn on_event(&mut self, _event: &Event) {}
+
+            fn should_stop(&self) -> bool {
+                self.0.load(Ordering::SeqCst)
+            }
+        }
+
+        let mut fanotify = Fanotify::new().unwrap();
+        let temp_dir = tempdir().unwrap();
+        fanotify.add_watch(temp_dir.path(), MaskFlags::MODIFY).unwrap();
+
+        // Nothing is ever queued, so only the idle check can end the loop
+        let stop = Arc::new(AtomicBool::new(false));
+        let flag = stop.clone();
+        let stopper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            flag.store(true, Ordering::SeqCst);
+        });
+
+        let started = Instant::now();
+        fanotify.run(StopFlag(stop)).unwrap();
+        stopper.join().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_deny_with_errno() {
+        assert!(Errno::new(libc::ENOENT).is_err());
+        assert_eq!(Errno::new(libc::EBUSY).unwrap(), Errno::EBUSY);
+
+        let response = Decision::DenyWith(Errno::EAGAIN).response();
+        assert!(response.contains(EventFlags::DENY));
+        assert_eq!(response.errno(), Some(libc::EAGAIN));
+        assert_eq!(Decision::Deny.response().errno(), None);
+
+        let mut fanotify = Fanotify::with_flags(FanotifyFlags::CLASS_PRE_CONTENT).unwrap();
+        let temp_dir = tempdir().unwrap();
+        let test_file = temp_dir.path().join("busy.txt");
+        std::fs::write(&test_file, "busy").unwrap();
+        fanotify.add_watch(&test_file, MaskFlags::OPEN_PERM).unwrap();
+
+        let opener = open_in_thread(&test_file);
+
+        let decision = Decision::DenyWith(Errno::EBUSY);
+        let mut handler = Counter { modified: 0, permissions: 0, decision };
+        let result = fanotify.run(&mut handler);
+        fanotify.shutdown(Decision::Allow).unwrap();
+        let opened = opener.join().unwrap();
+
+        if sys::kernel_at_least(6, 14) {
+            result.unwrap();
+            assert_eq!(opened.unwrap_err().raw_os_error(), Some(libc::EBUSY));
+        } else {
+            assert!(matches!(result, Err(FanotifyError::UnsupportedFeature { .. })));
+            assert!(opened.is_ok());
+        }
+    }
+}
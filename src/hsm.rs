@@ -0,0 +1,545 @@
+//! Hierarchical storage management with pre-content events
+//!
+//! A placeholder is a sparse file of the right size whose content still
+//! lives in a backing store. Before the kernel lets a process read or write
+//! part of a watched placeholder it reports a `PRE_ACCESS` event carrying the
+//! byte range about to be accessed. An [`Hsm`] answers those events: the
+//! [`Filler`] copies the missing part of the range in from the backing store
+//! through the event's file descriptor, and only then is the access allowed.
+//! Writes through an event file descriptor generate no events, so filling
+//! does not recurse.
+//!
+//! Which ranges of a file were filled is kept in its
+//! [`POPULATED_XATTR`] extended attribute as well as in memory, so a
+//! restarted `Hsm` does not fetch them again over what clients have written
+//! since. Clear the attribute when turning a file back into a placeholder.
+//!
+//! Pre-content events need Linux 6.14 or newer, a `CLASS_PRE_CONTENT` group
+//! whose event file descriptors are writable (see [`group`]) and a
+//! filesystem that supports them, such as ext4, xfs or btrfs; marking a file
+//! on tmpfs fails with `EOPNOTSUPP`.
+//!
+//! ```no_run
+//! use fanotify_rs::hsm::{self, DirectoryFiller, Hsm};
+//!
+//! # fn main() -> fanotify_rs::Result<()> {
+//! let mut fanotify = hsm::group()?;
+//! hsm::watch(&mut fanotify, "/srv/cache")?;
+//!
+//! let mut hsm = Hsm::new(DirectoryFiller::new("/srv/cache", "/mnt/archive"));
+//! fanotify.run(&mut hsm)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::File;
+use std::os::fd::{BorrowedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use crate::{
+    error::{FanotifyError, Result},
+    event::{Event, FileRange},
+    fanotify::Fanotify,
+    flags::{FanotifyFlags, MaskFlags},
+    handler::{Decision, Errno, Handler},
+    linux::O_LARGEFILE,
+    sys,
+};
+
+/// Extended attribute recording the filled ranges of a placeholder
+///
+/// It holds pairs of little-endian `u64` offsets and counts.
+pub const POPULATED_XATTR: &str = "user.fanotify.populated";
+
+/// Populates placeholder files from a backing store
+pub trait Filler {
+    /// Write the bytes `range` of the placeholder at `path` into `file`
+    ///
+    /// `file` is the event's file descriptor, opened for writing; write with
+    /// `pwrite(2)` at the offsets of `range`. The range never extends past
+    /// the end of the placeholder. Returning an error fails the access.
+    fn fill(&mut self, path: &Path, range: FileRange, file: BorrowedFd<'_>) -> Result<()>;
+}
+
+impl<F: Filler + ?Sized> Filler for &mut F {
+    fn fill(&mut self, path: &Path, range: FileRange, file: BorrowedFd<'_>) -> Result<()> {
+        (**self).fill(path, range, file)
+    }
+}
+
+impl<F: Filler + ?Sized> Filler for Box<F> {
+    fn fill(&mut self, path: &Path, range: FileRange, file: BorrowedFd<'_>) -> Result<()> {
+        (**self).fill(path, range, file)
+    }
+}
+
+/// A [`Filler`] whose backing store is a local directory
+///
+/// The placeholder `<local>/a/b` is filled from `<backing>/a/b`. Every
+/// watched placeholder must have a counterpart in the backing directory;
+/// a missing one fails the access.
+#[derive(Debug, Clone)]
+pub struct DirectoryFiller {
+    local: PathBuf,
+    backing: PathBuf,
+}
+
+impl DirectoryFiller {
+    /// Fill placeholders below `local` from the same paths below `backing`
+    pub fn new(local: impl Into<PathBuf>, backing: impl Into<PathBuf>) -> Self {
+        Self {
+            local: local.into(),
+            backing: backing.into(),
+        }
+    }
+
+    /// Where the content of the placeholder `path` is kept
+    pub fn backing_path(&self, path: &Path) -> Result<PathBuf> {
+        let relative = path
+            .strip_prefix(&self.local)
+            .map_err(|_| FanotifyError::invalid_path(path.to_string_lossy().to_string()))?;
+        Ok(self.backing.join(relative))
+    }
+}
+
+impl Filler for DirectoryFiller {
+    fn fill(&mut self, path: &Path, range: FileRange, file: BorrowedFd<'_>) -> Result<()> {
+        let source = File::open(self.backing_path(path)?)?;
+        let target = File::from(file.try_clone_to_owned()?);
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut offset = range.offset;
+
+        while offset < range.end() {
+            let want = (range.end() - offset).min(buf.len() as u64) as usize;
+            let read = source.read_at(&mut buf[..want], offset)?;
+            if read == 0 {
+                // The backing file is shorter: the rest stays a hole
+                break;
+            }
+            target.write_all_at(&buf[..read], offset)?;
+            offset += read as u64;
+        }
+
+        Ok(())
+    }
+}
+
+/// Create a group for an [`Hsm`]
+///
+/// The group is `CLASS_PRE_CONTENT` and its event file descriptors are
+/// opened `O_RDWR` so fillers can write through them.
+pub fn group() -> Result<Fanotify> {
+    Fanotify::with_event_flags(
+        FanotifyFlags::CLASS_PRE_CONTENT | FanotifyFlags::CLOEXEC,
+        libc::O_RDWR | O_LARGEFILE,
+    )
+}
+
+/// Mark `path` for `PRE_ACCESS`
+///
+/// A directory covers the files directly inside it. Fails with
+/// [`FanotifyError::UnsupportedFeature`] on kernels without pre-content
+/// events.
+pub fn watch<P: AsRef<Path>>(fanotify: &mut Fanotify, path: P) -> Result<()> {
+    if !sys::kernel_at_least(6, 14) {
+        return Err(FanotifyError::unsupported_feature("Pre-content events", "6.14"));
+    }
+
+    let path = path.as_ref();
+    let mut mask = MaskFlags::PRE_ACCESS;
+    if path.is_dir() {
+        mask |= MaskFlags::EVENT_ON_CHILD;
+    }
+
+    match fanotify.add_watch(path, mask) {
+        Err(FanotifyError::InvalidFlags { .. }) => Err(FanotifyError::invalid_flags(
+            "pre-content events need a CLASS_PRE_CONTENT group, see hsm::group",
+        )),
+        result => result,
+    }
+}
+
+/// Counters kept by an [`Hsm`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HsmStats {
+    /// Pre-content events answered
+    pub requests: u64,
+    /// Calls to the filler
+    pub fills: u64,
+    /// Bytes requested from the filler
+    pub bytes_filled: u64,
+    /// Accesses failed because filling did
+    pub errors: u64,
+}
+
+/// A [`Handler`] populating placeholders on first access
+///
+/// The ranges already filled are remembered per file, in memory and in the
+/// file's [`POPULATED_XATTR`], so each byte is fetched from the backing store
+/// once. Other permission events are allowed.
+pub struct Hsm<F> {
+    filler: F,
+    /// Filled ranges by (device, inode), sorted and disjoint
+    populated: HashMap<(u64, u64), Vec<FileRange>>,
+    on_error: Errno,
+    stats: HsmStats,
+}
+
+impl<F: Filler> Hsm<F> {
+    /// Populate placeholders with `filler`
+    pub fn new(filler: F) -> Self {
+        Self {
+            filler,
+            populated: HashMap::new(),
+            on_error: Errno::EIO,
+            stats: HsmStats::default(),
+        }
+    }
+
+    /// The error an access fails with when filling fails, `EIO` by default
+    pub fn on_fill_error(mut self, errno: Errno) -> Self {
+        self.on_error = errno;
+        self
+    }
+
+    /// The filler
+    pub fn filler(&self) -> &F {
+        &self.filler
+    }
+
+    /// The filler, mutably
+    pub fn filler_mut(&mut self) -> &mut F {
+        &mut self.filler
+    }
+
+    /// The counters
+    pub fn stats(&self) -> HsmStats {
+        self.stats
+    }
+
+    /// Forget which ranges of the file at `path` were filled
+    ///
+    /// Call this after turning a populated file back into a placeholder.
+    /// Removes its [`POPULATED_XATTR`] too.
+    pub fn forget<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let meta = std::fs::metadata(path)?;
+        self.populated.remove(&(meta.dev(), meta.ino()));
+
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| FanotifyError::invalid_path(path.to_string_lossy().to_string()))?;
+        let name = CString::new(POPULATED_XATTR).expect("no NUL in the attribute name");
+        if unsafe { libc::removexattr(c_path.as_ptr(), name.as_ptr()) } < 0 {
+            let err = std::io::Error::last_os_error();
+            if !matches!(err.raw_os_error(), Some(libc::ENODATA) | Some(libc::EOPNOTSUPP)) {
+                return Err(err.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill whatever part of the range `event` is about to access is still
+    /// missing
+    ///
+    /// An event without a range concerns the whole file.
+    pub fn fill_event(&mut self, event: &Event) -> Result<()> {
+        let fd = event.info.fd.ok_or_else(|| {
+            FanotifyError::invalid_event_data("Pre-content event has no file descriptor")
+        })?;
+        let path = event.info.path.as_deref().ok_or_else(|| {
+            FanotifyError::invalid_event_data("Pre-content event has no path")
+        })?;
+        if !sys::writable(fd)? {
+            return Err(FanotifyError::invalid_flags(
+                "filling needs event file descriptors opened with O_RDWR, see hsm::group",
+            ));
+        }
+
+        let stat = sys::fstat(fd)?;
+        let size = stat.st_size as u64;
+        let requested = event.range().unwrap_or(FileRange::new(0, size));
+        let requested = FileRange::new(requested.offset, requested.end().min(size).saturating_sub(requested.offset));
+
+        let populated = self
+            .populated
+            .entry((stat.st_dev as u64, stat.st_ino as u64))
+            .or_insert_with(|| load_populated(fd, path));
+        // SAFETY: the event owns fd until the handler returns
+        let file = unsafe { BorrowedFd::borrow_raw(fd) };
+
+        let gaps = missing(populated, requested);
+        if gaps.is_empty() {
+            return Ok(());
+        }
+        let mut result = Ok(());
+        for range in gaps {
+            self.stats.fills += 1;
+            self.stats.bytes_filled += range.count;
+            result = self.filler.fill(path, range, file);
+            if result.is_err() {
+                break;
+            }
+            insert(populated, range);
+        }
+
+        // The content is in place either way; failing to record it only
+        // means it may be fetched again after a restart
+        if let Err(e) = store_populated(fd, populated) {
+            log::warn!("failed to record the populated ranges of {}: {}", path.display(), e);
+        }
+
+        result
+    }
+}
+
+impl<F: Filler> Handler for Hsm<F> {
+    fn on_event(&mut self, _event: &Event) {}
+
+    fn on_permission(&mut self, event: &Event) -> Decision {
+        if !event.is_pre_access() {
+            return Decision::Allow;
+        }
+
+        self.stats.requests += 1;
+        match self.fill_event(event) {
+            Ok(()) => Decision::Allow,
+            Err(e) => {
+                self.stats.errors += 1;
+                log::warn!(
+                    "failed to fill {}: {}",
+                    event.info.path.as_deref().unwrap_or(Path::new("<unknown>")).display(),
+                    e
+                );
+                Decision::DenyWith(self.on_error)
+            }
+        }
+    }
+}
+
+/// The filled ranges recorded in the [`POPULATED_XATTR`] of `fd`
+fn load_populated(fd: RawFd, path: &Path) -> Vec<FileRange> {
+    let name = CString::new(POPULATED_XATTR).expect("no NUL in the attribute name");
+    let mut buf = vec![0u8; 4096];
+
+    let len = loop {
+        let len = unsafe { libc::fgetxattr(fd, name.as_ptr(), buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if len >= 0 {
+            break len as usize;
+        }
+        let err = std::io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::ERANGE) => buf.resize(buf.len() * 4, 0),
+            Some(libc::ENODATA) | Some(libc::EOPNOTSUPP) => return Vec::new(),
+            _ => {
+                log::warn!("failed to read {} of {}: {}", POPULATED_XATTR, path.display(), err);
+                return Vec::new();
+            }
+        }
+    };
+
+    if len % 16 != 0 {
+        log::warn!("ignoring malformed {} of {}", POPULATED_XATTR, path.display());
+        return Vec::new();
+    }
+    let mut populated = Vec::new();
+    for pair in buf[..len].chunks_exact(16) {
+        let offset = u64::from_le_bytes(pair[..8].try_into().expect("8 bytes"));
+        let count = u64::from_le_bytes(pair[8..].try_into().expect("8 bytes"));
+        insert(&mut populated, FileRange::new(offset, count));
+    }
+    populated
+}
+
+/// Record `populated` in the [`POPULATED_XATTR`] of `fd`
+///
+/// Filesystems without user extended attributes only get the in-memory
+/// record.
+fn store_populated(fd: RawFd, populated: &[FileRange]) -> Result<()> {
+    let name = CString::new(POPULATED_XATTR).expect("no NUL in the attribute name");
+    let value: Vec<u8> = populated
+        .iter()
+        .flat_map(|range| range.offset.to_le_bytes().into_iter().chain(range.count.to_le_bytes()))
+        .collect();
+
+    let result = unsafe {
+        libc::fsetxattr(fd, name.as_ptr(), value.as_ptr() as *const libc::c_void, value.len(), 0)
+    };
+    if result < 0 {
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+            return Err(err.into());
+        }
+    }
+    Ok(())
+}
+
+/// The parts of `range` not covered by the sorted, disjoint `populated`
+fn missing(populated: &[FileRange], range: FileRange) -> Vec<FileRange> {
+    let mut gaps = Vec::new();
+    let mut offset = range.offset;
+
+    for done in populated {
+        if done.end() <= offset {
+            continue;
+        }
+        if done.offset >= range.end() {
+            break;
+        }
+        if done.offset > offset {
+            gaps.push(FileRange::new(offset, done.offset - offset));
+        }
+        offset = done.end();
+    }
+
+    if offset < range.end() {
+        gaps.push(FileRange::new(offset, range.end() - offset));
+    }
+    gaps
+}
+
+/// Add `range` to the sorted, disjoint `populated`, merging neighbours
+fn insert(populated: &mut Vec<FileRange>, range: FileRange) {
+    if range.is_empty() {
+        return;
+    }
+
+    let mut merged = range;
+    populated.retain(|done| {
+        if done.end() < merged.offset || done.offset > merged.end() {
+            return true;
+        }
+        let end = done.end().max(merged.end());
+        merged.offset = merged.offset.min(done.offset);
+        merged.count = end - merged.offset;
+        false
+    });
+
+    let at = populated.partition_point(|done| done.offset < merged.offset);
+    populated.insert(at, merged);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{decide, permission_event};
+    use std::os::fd::AsRawFd;
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_missing_and_insert() {
+        let mut populated = Vec::new();
+        insert(&mut populated, FileRange::new(100, 50));
+        insert(&mut populated, FileRange::new(300, 100));
+
+        assert_eq!(
+            missing(&populated, FileRange::new(0, 500)),
+            vec![FileRange::new(0, 100), FileRange::new(150, 150), FileRange::new(400, 100)]
+        );
+        assert_eq!(missing(&populated, FileRange::new(310, 20)), vec![]);
+
+        insert(&mut populated, FileRange::new(150, 150));
+        assert_eq!(populated, vec![FileRange::new(100, 300)]);
+        insert(&mut populated, FileRange::new(0, 10));
+        assert_eq!(populated, vec![FileRange::new(0, 10), FileRange::new(100, 300)]);
+    }
+
+    #[test]
+    fn test_unrecorded_fills_are_allowed() {
+        let temp_dir = tempdir().unwrap();
+        let local = temp_dir.path().join("local");
+        let backing = temp_dir.path().join("backing");
+        std::fs::create_dir_all(&local).unwrap();
+        std::fs::create_dir_all(&backing).unwrap();
+        std::fs::write(backing.join("data.bin"), vec![7u8; 16384]).unwrap();
+        let placeholder = local.join("data.bin");
+        File::create(&placeholder).unwrap().set_len(16384).unwrap();
+        let file = File::options().read(true).write(true).open(&placeholder).unwrap();
+
+        // Every other byte is filled already: far too many ranges to fit in
+        // the attribute on most filesystems
+        let mut hsm = Hsm::new(DirectoryFiller::new(&local, &backing));
+        let stat = sys::fstat(file.as_raw_fd()).unwrap();
+        let ranges = (0..8192).map(|i| FileRange::new(2 * i, 1)).collect();
+        hsm.populated.insert((stat.st_dev as u64, stat.st_ino as u64), ranges);
+
+        let mut event = permission_event(MaskFlags::PRE_ACCESS, placeholder.to_str().unwrap());
+        event.info.fd = Some(file.as_raw_fd());
+        event.info.range = Some(FileRange::new(0, 16));
+
+        hsm.fill_event(&event).unwrap();
+        assert_eq!(hsm.stats().fills, 8);
+        assert_eq!(std::fs::read(&placeholder).unwrap()[..16], [0, 7].repeat(8)[..]);
+    }
+
+    /// Answer events until `reader` finishes and return what it returned
+    fn serve<T: Send + 'static>(
+        fanotify: &mut Fanotify,
+        hsm: &mut Hsm<DirectoryFiller>,
+        reader: thread::JoinHandle<T>,
+    ) -> T {
+        while !reader.is_finished() {
+            if let Some(event) = fanotify.read_timeout(Duration::from_millis(50)).unwrap() {
+                decide(fanotify, hsm, &event);
+            }
+        }
+        reader.join().unwrap()
+    }
+
+    #[test]
+    fn test_fills_placeholder_on_access() {
+        // tmpfs lacks pre-content support, so stay off /dev/shm
+        let temp_dir = tempdir().unwrap();
+        let local = temp_dir.path().join("local");
+        let backing = temp_dir.path().join("backing");
+        std::fs::create_dir_all(&local).unwrap();
+        std::fs::create_dir_all(&backing).unwrap();
+
+        let content: Vec<u8> = (0..3 * 4096 + 100).map(|i| (i % 251) as u8).collect();
+        std::fs::write(backing.join("data.bin"), &content).unwrap();
+        let placeholder = local.join("data.bin");
+        File::create(&placeholder).unwrap().set_len(content.len() as u64).unwrap();
+        File::create(local.join("lost.bin")).unwrap().set_len(10).unwrap();
+
+        let mut fanotify = group().unwrap();
+        match watch(&mut fanotify, &local) {
+            Err(FanotifyError::UnsupportedFeature { .. }) => return,
+            Err(FanotifyError::Io(e)) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => return,
+            result => result.unwrap(),
+        }
+        let mut hsm = Hsm::new(DirectoryFiller::new(&local, &backing));
+
+        let path = placeholder.clone();
+        let read = serve(&mut fanotify, &mut hsm, thread::spawn(move || std::fs::read(path).unwrap()));
+        assert_eq!(read, content);
+        let stats = hsm.stats();
+        assert!(stats.fills >= 1);
+        assert_eq!(stats.bytes_filled, content.len() as u64);
+
+        // Populated ranges are not fetched again
+        let path = placeholder.clone();
+        serve(&mut fanotify, &mut hsm, thread::spawn(move || std::fs::read(path).unwrap()));
+        assert_eq!(hsm.stats().bytes_filled, content.len() as u64);
+
+        // Nor after a restart, so local changes are not overwritten
+        std::fs::write(backing.join("data.bin"), vec![0xffu8; content.len()]).unwrap();
+        let mut restarted = Hsm::new(DirectoryFiller::new(&local, &backing));
+        let path = placeholder.clone();
+        let read = serve(&mut fanotify, &mut restarted, thread::spawn(move || std::fs::read(path).unwrap()));
+        assert_eq!(read, content);
+        assert_eq!(restarted.stats().bytes_filled, 0);
+
+        // A placeholder without backing content fails with EIO
+        let lost = local.join("lost.bin");
+        let err = serve(&mut fanotify, &mut hsm, thread::spawn(move || std::fs::read(lost).unwrap_err()));
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        assert_eq!(hsm.stats().errors, 1);
+
+        fanotify.remove_watch(&local).unwrap();
+    }
+}
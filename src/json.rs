@@ -0,0 +1,79 @@
+//! Writing the JSON files this crate produces
+//!
+//! Decision log records are flat objects of strings, integers, booleans and
+//! nulls. Writing them by hand keeps the output byte-for-byte stable, which
+//! the log's hash chain relies on; reading them back, and everything else,
+//! goes through `serde_json`.
+
+use std::ffi::OsString;
+use std::fmt::Write as _;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+/// Append `value` as a JSON string
+pub(crate) fn push_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Append `path` as a JSON string, or `null`
+pub(crate) fn push_path(out: &mut String, path: Option<&Path>) {
+    match path {
+        Some(path) => push_string(out, &path.to_string_lossy()),
+        None => out.push_str("null"),
+    }
+}
+
+/// Append `,"<name>_bytes":"<hex>"` if `path` is not valid UTF-8
+///
+/// Goes after the field [`push_path`] wrote for `path`: its lossy string
+/// cannot tell such paths apart, the hex bytes can.
+pub(crate) fn push_path_bytes(out: &mut String, name: &str, path: Option<&Path>) {
+    if let Some(path) = path.filter(|path| path.to_str().is_none()) {
+        let _ = write!(out, ",\"{}_bytes\":\"{}\"", name, path_to_hex(path));
+    }
+}
+
+/// Append `value` as a JSON number, or `null`
+pub(crate) fn push_number<N: std::fmt::Display>(out: &mut String, value: Option<N>) {
+    match value {
+        Some(value) => {
+            let _ = write!(out, "{}", value);
+        }
+        None => out.push_str("null"),
+    }
+}
+
+/// The bytes of `path` as hex, for paths a JSON string cannot hold exactly
+pub(crate) fn path_to_hex(path: &Path) -> String {
+    let mut hex = String::new();
+    for byte in path.as_os_str().as_bytes() {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+/// Decode a path stored by [`path_to_hex`]
+pub(crate) fn path_from_hex(hex: &str) -> Option<PathBuf> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some(PathBuf::from(OsString::from_vec(bytes)))
+}
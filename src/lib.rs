@@ -8,7 +8,9 @@
 //!
 //! - **Safe abstractions**: All unsafe operations are wrapped in safe Rust code
 //! - **Error handling**: Comprehensive error types with detailed information
-//! - **Async support**: Both synchronous and asynchronous event monitoring
+//! - **Async support**: Both synchronous and asynchronous event monitoring, on
+//!   tokio (`tokio` feature) or any `async-io` based runtime such as smol
+//!   (`async-io` feature)
 //! - **Type safety**: Strongly typed flags and event types
 //! - **Documentation**: Extensive documentation with examples
 //!
@@ -25,12 +27,11 @@
 //!     // Monitor a directory for all events
 //!     fanotify.add_watch("/tmp", MaskFlags::ALL_EVENTS)?;
 //!     
-//!     // Read events
-//!     for event in fanotify.events() {
+//!     // Wait for events
+//!     loop {
+//!         let event = fanotify.read_blocking()?;
 //!         println!("Event: {:?}", event);
 //!     }
-//!     
-//!     Ok(())
 //! }
 //! ```
 //!
@@ -58,12 +59,67 @@ pub mod error;
 pub mod flags;
 pub mod event;
 pub mod fanotify;
+pub mod handler;
+pub mod allowlist;
 pub mod async_fanotify;
+pub mod batch;
+pub mod cache;
+pub mod decision_log;
+pub mod dry_run;
+#[cfg(feature = "tokio")]
+pub mod backpressure;
+#[cfg(feature = "tokio")]
+pub mod dispatch;
+#[cfg(feature = "async-io")]
+pub mod async_io_fanotify;
+pub mod hsm;
 pub mod linux;
+pub mod permission;
+pub mod policy;
+pub mod pool;
+pub mod quarantine;
+#[cfg(feature = "policy-file")]
+pub mod policy_file;
+pub mod response;
+pub mod sandbox;
+pub mod scan;
+pub mod watchdog;
+pub mod watcher;
+
+mod decode;
+mod json;
+mod pending;
+mod sys;
+#[cfg(test)]
+mod test_support;
 
 pub use error::{FanotifyError, Result};
 pub use flags::{FanotifyFlags, MaskFlags, EventFlags};
-pub use event::{Event, EventInfo};
+pub use event::{Event, EventInfo, FileRange};
 pub use fanotify::Fanotify;
+pub use allowlist::{Allowlist, ExecAllowlist};
+pub use batch::{BatchConfig, BatchResponder};
+pub use cache::{CacheConfig, CacheStats, Cached, DecisionCache};
+pub use decision_log::{DecisionLog, LogConfig};
+pub use dry_run::{DryRun, DryRunReport};
+pub use handler::{Decision, Errno, Handler};
+pub use hsm::{DirectoryFiller, Filler, Hsm};
+pub use permission::{Incoming, PermissionRequest, Responder};
+pub use policy::{Action, Policy, Rule};
+pub use pool::{LatencyHistogram, PoolConfig, PoolMetrics, WorkerPool};
+pub use quarantine::Quarantine;
+pub use response::Response;
+pub use sandbox::Sandbox;
+pub use scan::{Scanner, Scanning};
+pub use watchdog::Watchdog;
+pub use watcher::WatcherHandle;
+#[cfg(feature = "tokio")]
+pub use async_fanotify::{AsyncEventReader, AsyncFanotify, AsyncFanotifyHandle};
+#[cfg(feature = "tokio")]
+pub use backpressure::{BufferedReader, OverflowPolicy, QueueConfig, QueueMetrics};
 #[cfg(feature = "tokio")]
-pub use async_fanotify::AsyncFanotify; 
\ No newline at end of file
+pub use dispatch::{Delivery, Dispatcher, Filter, Subscription};
+#[cfg(feature = "async-io")]
+pub use async_io_fanotify::AsyncIoFanotify;
+#[cfg(feature = "policy-file")]
+pub use policy_file::ReloadingPolicy;
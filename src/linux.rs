@@ -18,10 +18,11 @@ pub const FAN_CREATE: u64 = 0x00000100;
 pub const FAN_DELETE: u64 = 0x00000200;
 pub const FAN_DELETE_SELF: u64 = 0x00000400;
 pub const FAN_MOVE_SELF: u64 = 0x00000800;
-pub const FAN_OPEN_PERM: u64 = 0x00001000;
-pub const FAN_ACCESS_PERM: u64 = 0x00002000;
-pub const FAN_OPEN_EXEC_PERM: u64 = 0x00004000;
-pub const FAN_OPEN_EXEC: u64 = 0x00008000;
+pub const FAN_OPEN_PERM: u64 = 0x00010000;
+pub const FAN_ACCESS_PERM: u64 = 0x00020000;
+pub const FAN_OPEN_EXEC_PERM: u64 = 0x00040000;
+pub const FAN_PRE_ACCESS: u64 = 0x00100000;
+pub const FAN_OPEN_EXEC: u64 = 0x00001000;
 pub const FAN_QUEUE_OVERFLOW: u64 = 0x00004000;
 pub const FAN_FS_ERROR: u64 = 0x00008000;
 pub const FAN_UNMOUNT: u64 = 0x00002000;
@@ -43,16 +44,24 @@ pub const FAN_MARK_MOUNT: u32 = 0x00000010;
 pub const FAN_MARK_IGNORED_MASK: u32 = 0x00000020;
 pub const FAN_MARK_IGNORED_SURV_MODIFY: u32 = 0x00000040;
 pub const FAN_MARK_FLUSH: u32 = 0x00000080;
+pub const FAN_MARK_FILESYSTEM: u32 = 0x00000100;
 
 // Fanotify response flags
 pub const FAN_ALLOW: u32 = 0x01;
 pub const FAN_DENY: u32 = 0x02;
+pub const FAN_AUDIT: u32 = 0x10;
+pub const FAN_INFO: u32 = 0x20;
+
+// Fanotify response info types
+pub const FAN_RESPONSE_INFO_NONE: u8 = 0;
+pub const FAN_RESPONSE_INFO_AUDIT_RULE: u8 = 1;
 
 // Fanotify init flags
 pub const FAN_CLOEXEC: u32 = 0x00000001;
 pub const FAN_NONBLOCK: u32 = 0x00000002;
 pub const FAN_UNLIMITED_QUEUE: u32 = 0x00000010;
 pub const FAN_UNLIMITED_MARKS: u32 = 0x00000020;
+pub const FAN_ENABLE_AUDIT: u32 = 0x00000040;
 pub const FAN_REPORT_TID: u32 = 0x00000100;
 pub const FAN_REPORT_FID: u32 = 0x00000200;
 pub const FAN_REPORT_DIR_FID: u32 = 0x00000400;
@@ -84,6 +93,25 @@ pub struct fanotify_response {
     pub response: u32,
 }
 
+// Fanotify response info header structure
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct fanotify_response_info_header {
+    pub type_: u8,
+    pub pad: u8,
+    pub len: u16,
+}
+
+// Fanotify response info audit rule structure
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct fanotify_response_info_audit_rule {
+    pub hdr: fanotify_response_info_header,
+    pub rule_number: u32,
+    pub subj_trust: u32,
+    pub obj_trust: u32,
+}
+
 // Fanotify info header structure
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
@@ -127,14 +155,27 @@ pub struct fanotify_event_info_pidfd {
     pub pidfd: i32,
 }
 
+// Fanotify info range structure, reported with pre-content events
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct fanotify_event_info_range {
+    pub hdr: fanotify_event_info_header,
+    pub pad: u32,
+    pub offset: u64,
+    pub count: u64,
+}
+
 // Fanotify info types
 pub const FAN_EVENT_INFO_TYPE_FID: u8 = 1;
 pub const FAN_EVENT_INFO_TYPE_DFID_NAME: u8 = 2;
 pub const FAN_EVENT_INFO_TYPE_DFID: u8 = 3;
 pub const FAN_EVENT_INFO_TYPE_PIDFD: u8 = 4;
 pub const FAN_EVENT_INFO_TYPE_ERROR: u8 = 5;
-pub const FAN_EVENT_INFO_TYPE_OLD_NAME: u8 = 6;
-pub const FAN_EVENT_INFO_TYPE_NEW_DFID_NAME: u8 = 7;
+pub const FAN_EVENT_INFO_TYPE_RANGE: u8 = 6;
+pub const FAN_EVENT_INFO_TYPE_OLD_DFID_NAME: u8 = 10;
+pub const FAN_EVENT_INFO_TYPE_NEW_DFID_NAME: u8 = 12;
+#[deprecated(note = "the kernel has no such info type; use FAN_EVENT_INFO_TYPE_OLD_DFID_NAME")]
+pub const FAN_EVENT_INFO_TYPE_OLD_NAME: u8 = FAN_EVENT_INFO_TYPE_OLD_DFID_NAME;
 
 // System call numbers (these may vary by architecture)
 #[cfg(target_arch = "x86_64")]
@@ -159,10 +200,22 @@ pub const SYS_FANOTIFY_INIT: i32 = 300;
 pub const SYS_FANOTIFY_MARK: i32 = 301;
 
 // Wrapper functions for system calls
+
+/// Raw `fanotify_init(2)` system call.
+///
+/// # Safety
+///
+/// The returned file descriptor is owned by the caller and must be closed
+/// exactly once.
 pub unsafe fn fanotify_init(flags: u32, event_f_flags: u32) -> i32 {
     libc::syscall(SYS_FANOTIFY_INIT.into(), flags as i64, event_f_flags as i64) as i32
 }
 
+/// Raw `fanotify_mark(2)` system call.
+///
+/// # Safety
+///
+/// `pathname` must be null or point to a valid NUL-terminated string.
 pub unsafe fn fanotify_mark(
     fanotify_fd: i32,
     flags: u32,
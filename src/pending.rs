@@ -0,0 +1,305 @@
+//! Bookkeeping of permission events that still await a response
+//!
+//! Every reader records the permission events it decodes here and every
+//! response removes them again, so whatever is left when a group shuts down
+//! can still be answered instead of leaving the triggering processes hanging.
+
+use std::collections::{HashMap, HashSet};
+use std::os::fd::RawFd;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+
+use crate::{
+    decision_log::{self, DecisionLog, DecisionRecord},
+    error::Result,
+    event::Event,
+    flags::{EventFlags, MaskFlags},
+    policy::{EventKind, ProcessInfo},
+    response::Response,
+    sys,
+};
+
+/// Event file descriptors of unanswered permission events
+///
+/// Clones share the same set, so the halves of a split group agree on what
+/// is still outstanding.
+#[derive(Clone, Default)]
+pub(crate) struct PendingPermissions {
+    state: Arc<Mutex<State>>,
+    /// Where answered events are recorded, if anywhere
+    ///
+    /// Kept apart from `state` so writing the log never holds up responses.
+    log: Arc<Mutex<Option<DecisionLog>>>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Unanswered events
+    waiting: HashMap<RawFd, Waiting>,
+    /// Events answered by [`PendingPermissions::expire`] whose owner has not
+    /// responded yet
+    expired: HashSet<RawFd>,
+}
+
+/// What is known about an unanswered event
+struct Waiting {
+    /// When the event was read
+    since: Instant,
+    pid: u32,
+    mask: MaskFlags,
+    path: Option<PathBuf>,
+}
+
+impl State {
+    /// Stop tracking the event `fd` answered with `response`
+    ///
+    /// Returns what goes into the decision log, short of the process details
+    /// that [`PendingPermissions::record`] looks up once the lock is released.
+    fn answered(&mut self, fd: RawFd, response: &Response) -> Option<DecisionRecord> {
+        let waiting = self.waiting.remove(&fd)?;
+
+        Some(DecisionRecord {
+            time: Utc::now(),
+            pid: waiting.pid,
+            exe: None,
+            uid: None,
+            kind: EventKind::from_mask(waiting.mask),
+            path: waiting.path,
+            decision: decision_log::decision_of(response),
+            rule: response.rule_number(),
+            latency: Some(waiting.since.elapsed()),
+        })
+    }
+}
+
+impl PendingPermissions {
+    /// Record the permission events among `events`
+    pub(crate) fn track<'a>(&self, events: impl IntoIterator<Item = &'a Event>) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+
+        for event in events.into_iter().filter(|event| event.is_permission()) {
+            let Some(fd) = event.info.fd else {
+                continue;
+            };
+            state.expired.remove(&fd);
+            state.waiting.insert(
+                fd,
+                Waiting {
+                    since: now,
+                    pid: event.info.pid,
+                    mask: event.info.mask,
+                    path: event.info.path.clone(),
+                },
+            );
+        }
+    }
+
+    /// Record every response from now on in `log`
+    pub(crate) fn set_log(&self, log: Option<DecisionLog>) -> Option<DecisionLog> {
+        std::mem::replace(&mut *self.log.lock().unwrap(), log)
+    }
+
+    /// Write `records` to the decision log, if there is one
+    ///
+    /// Must be called without the state lock held.
+    fn record(&self, records: Vec<DecisionRecord>) {
+        if records.is_empty() || self.log.lock().unwrap().is_none() {
+            return;
+        }
+
+        let records: Vec<DecisionRecord> = records
+            .into_iter()
+            .map(|record| {
+                let process = ProcessInfo::new(record.pid);
+                DecisionRecord {
+                    exe: process.exe().map(PathBuf::from),
+                    uid: process.uid(),
+                    ..record
+                }
+            })
+            .collect();
+
+        let mut log = self.log.lock().unwrap();
+        let Some(writer) = log.as_mut() else {
+            return;
+        };
+        for record in &records {
+            // The response is already written; losing the record must not fail it
+            if let Err(e) = writer.append(record) {
+                log::error!("failed to write decision log {}: {}", writer.path().display(), e);
+            }
+        }
+    }
+
+    /// Answer `event` and stop tracking it
+    ///
+    /// An event that already expired has been answered; responding to it
+    /// again does nothing.
+    pub(crate) fn respond(&self, group_fd: RawFd, event: &Event, response: &Response) -> Result<()> {
+        // Hold the lock while writing so an expiring event is answered once
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(fd) = event.info.fd {
+            if state.expired.remove(&fd) {
+                log::debug!("permission event fd {} was already answered after its deadline", fd);
+                return Ok(());
+            }
+        }
+
+        let result = sys::write_response(group_fd, event, response);
+
+        // The caller closes the event file descriptor either way, so a
+        // failed response must not stay behind for a reused descriptor
+        let record = match (event.info.fd, &result) {
+            (Some(fd), Ok(())) => state.answered(fd, response),
+            (Some(fd), Err(_)) => {
+                state.waiting.remove(&fd);
+                None
+            }
+            (None, _) => None,
+        };
+        drop(state);
+        self.record(record.into_iter().collect());
+
+        result
+    }
+
+    /// Answer several events at once, see [`sys::write_responses`]
+    ///
+    /// Returns the result for each response in order: `Ok(true)` if it was
+    /// written, `Ok(false)` if the event had already expired and was skipped.
+    /// Every event stops being tracked, whether or not its response failed.
+    pub(crate) fn respond_all(&self, group_fd: RawFd, responses: &[(RawFd, Response)]) -> Vec<Result<bool>> {
+        let mut state = self.state.lock().unwrap();
+
+        let mut results: Vec<Option<Result<bool>>> = Vec::with_capacity(responses.len());
+        let mut to_write = Vec::with_capacity(responses.len());
+        for (fd, response) in responses {
+            if state.expired.remove(fd) {
+                results.push(Some(Ok(false)));
+            } else {
+                results.push(None);
+                to_write.push((*fd, *response));
+            }
+        }
+
+        let mut written = sys::write_responses(group_fd, &to_write).into_iter();
+        let mut records = Vec::new();
+        for (slot, (fd, response)) in results.iter_mut().zip(responses) {
+            if slot.is_none() {
+                let result = written.next().expect("one result per response");
+                if result.is_ok() {
+                    records.extend(state.answered(*fd, response));
+                } else {
+                    state.waiting.remove(fd);
+                }
+                *slot = Some(result.map(|()| true));
+            }
+        }
+        drop(state);
+        self.record(records);
+
+        results.into_iter().map(|result| result.expect("every response answered")).collect()
+    }
+
+    /// Number of permission events still waiting for a response
+    pub(crate) fn len(&self) -> usize {
+        self.state.lock().unwrap().waiting.len()
+    }
+
+    /// How long the oldest unanswered permission event has been waiting
+    pub(crate) fn oldest(&self) -> Option<Duration> {
+        let state = self.state.lock().unwrap();
+        state.waiting.values().map(|waiting| waiting.since).min().map(|since| since.elapsed())
+    }
+
+    /// Answer every event that has been waiting longer than `deadline`
+    ///
+    /// The event file descriptors stay open: they still belong to whoever
+    /// read the events. Returns the number of events answered together with
+    /// the first failed response; all are attempted either way. An event
+    /// whose response failed stays on the list for its owner to answer.
+    pub(crate) fn expire(&self, group_fd: RawFd, deadline: Duration, response: &Response) -> (usize, Result<()>) {
+        let mut state = self.state.lock().unwrap();
+        let overdue: Vec<RawFd> = state
+            .waiting
+            .iter()
+            .filter(|(_, waiting)| waiting.since.elapsed() >= deadline)
+            .map(|(fd, _)| *fd)
+            .collect();
+        let mut expired = 0;
+        let mut result = Ok(());
+        let mut records = Vec::new();
+
+        for fd in overdue {
+            let answered = sys::write_raw_response(group_fd, fd, response);
+            if answered.is_ok() {
+                expired += 1;
+                state.expired.insert(fd);
+                records.extend(state.answered(fd, response));
+            } else if result.is_ok() {
+                result = answered;
+            }
+        }
+        drop(state);
+        self.record(records);
+
+        (expired, result)
+    }
+
+    /// Answer every outstanding permission event with `response`
+    ///
+    /// All events are answered even if some responses fail; the first
+    /// failure is returned.
+    pub(crate) fn answer_all(&self, group_fd: RawFd, response: EventFlags) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.expired.clear();
+        let fds: Vec<RawFd> = state.waiting.keys().copied().collect();
+        let response = Response::new(response);
+        let mut result = Ok(());
+        let mut records = Vec::new();
+
+        for fd in fds {
+            let answered = sys::write_raw_response(group_fd, fd, &response);
+            if answered.is_ok() {
+                records.extend(state.answered(fd, &response));
+            } else {
+                state.waiting.remove(&fd);
+            }
+            if result.is_ok() {
+                result = answered;
+            }
+        }
+        drop(state);
+        self.record(records);
+
+        result
+    }
+}
+
+/// Shut a group down without leaving any permission event unanswered
+///
+/// `unread` are the events that were read from the group but never handed
+/// out; their file descriptors are closed once everything has been
+/// answered. The caller is expected to have flushed the marks already so no
+/// new events arrive.
+pub(crate) fn finish(
+    group_fd: RawFd,
+    pending: &PendingPermissions,
+    unread: Vec<Event>,
+    response: EventFlags,
+) -> Result<()> {
+    let result = pending.answer_all(group_fd, response);
+
+    for event in unread {
+        if let Some(fd) = event.info.fd {
+            sys::close_fd(fd);
+        }
+    }
+
+    result
+}
@@ -0,0 +1,286 @@
+//! Permission events that cannot be forgotten
+//!
+//! A process that triggers a permission event is blocked until the event is
+//! answered. [`PermissionRequest`] wraps such an event together with a
+//! [`Responder`] for its group: [`allow`](PermissionRequest::allow) and
+//! [`deny`](PermissionRequest::deny) consume the request, and a request that
+//! is dropped unanswered responds with its default decision and logs a
+//! warning, so a missed code path never leaves a process hanging.
+//!
+//! Use [`Fanotify::read_incoming`](crate::Fanotify::read_incoming) (or the
+//! async equivalents) to receive events already sorted into notifications
+//! and permission requests.
+
+use std::fmt;
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{
+    error::{FanotifyError, Result},
+    event::Event,
+    flags::EventFlags,
+    handler::Decision,
+    pending::PendingPermissions,
+    response::Response,
+    sys,
+};
+
+/// A cloneable, thread-safe handle for answering a group's permission events
+///
+/// The responder holds its own duplicate of the group file descriptor, so it
+/// stays usable from other threads and tasks and keeps the group open while
+/// it is alive.
+#[derive(Clone)]
+pub struct Responder {
+    fd: Arc<OwnedFd>,
+    pending: PendingPermissions,
+}
+
+impl Responder {
+    /// Create a responder writing to `fd` and settling events in `pending`
+    pub(crate) fn new(fd: OwnedFd, pending: PendingPermissions) -> Self {
+        Self {
+            fd: Arc::new(fd),
+            pending,
+        }
+    }
+
+    /// Respond to a permission event
+    pub fn respond(&self, event: &Event, response: EventFlags) -> Result<()> {
+        self.respond_with(event, &Response::new(response))
+    }
+
+    /// Respond to a permission event with an extended [`Response`]
+    pub fn respond_with(&self, event: &Event, response: &Response) -> Result<()> {
+        self.pending.respond(self.fd.as_raw_fd(), event, response)
+    }
+
+    /// Allow a permission event
+    pub fn allow(&self, event: &Event) -> Result<()> {
+        self.respond(event, EventFlags::ALLOW)
+    }
+
+    /// Deny a permission event
+    pub fn deny(&self, event: &Event) -> Result<()> {
+        self.respond(event, EventFlags::DENY)
+    }
+
+    /// Number of permission events of the group that await a response
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// How long the oldest unanswered permission event has been waiting
+    pub fn oldest_pending(&self) -> Option<Duration> {
+        self.pending.oldest()
+    }
+
+    /// The group's record of unanswered permission events
+    pub(crate) fn permissions(&self) -> &PendingPermissions {
+        &self.pending
+    }
+
+    /// Answer the events that waited longer than `deadline` with `response`
+    ///
+    /// See [`PendingPermissions::expire`] for what is returned.
+    pub(crate) fn expire(&self, deadline: Duration, response: &Response) -> (usize, Result<()>) {
+        self.pending.expire(self.fd.as_raw_fd(), deadline, response)
+    }
+
+    /// Check that the group can be answered with `response`
+    pub(crate) fn check(&self, response: &Response) -> Result<()> {
+        sys::check_group_response(self.fd.as_raw_fd(), response)
+    }
+
+    /// Wrap `event` in a [`PermissionRequest`] answered through this responder
+    ///
+    /// Fails if `event` is not a permission event.
+    pub fn request(&self, event: Event, default: Decision) -> Result<PermissionRequest> {
+        if !event.is_permission() {
+            return Err(FanotifyError::invalid_event_data("Event is not a permission event"));
+        }
+
+        Ok(PermissionRequest {
+            event: Some(event),
+            responder: self.clone(),
+            default,
+        })
+    }
+
+    /// Sort `event` into a notification or a permission request
+    pub fn incoming(&self, event: Event, default: Decision) -> Incoming {
+        if event.is_permission() {
+            Incoming::Permission(PermissionRequest {
+                event: Some(event),
+                responder: self.clone(),
+                default,
+            })
+        } else {
+            Incoming::Event(event)
+        }
+    }
+}
+
+impl AsRawFd for Responder {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl fmt::Debug for Responder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Responder")
+            .field("fd", &self.fd.as_raw_fd())
+            .field("pending", &self.pending.len())
+            .finish()
+    }
+}
+
+/// An event read from a group, sorted by whether it needs an answer
+#[derive(Debug)]
+pub enum Incoming {
+    /// A notification event; nothing has to be done with it
+    Event(Event),
+    /// A permission event that has to be answered
+    Permission(PermissionRequest),
+}
+
+/// A permission event that is answered exactly once
+///
+/// The request owns the event's file descriptor and closes it once the
+/// event has been answered. If the request is dropped without an answer it
+/// responds with its [default decision](Self::set_default) and logs a
+/// warning.
+pub struct PermissionRequest {
+    /// The event; `None` once it has been answered
+    event: Option<Event>,
+    responder: Responder,
+    default: Decision,
+}
+
+impl PermissionRequest {
+    /// The permission event
+    pub fn event(&self) -> &Event {
+        self.event.as_ref().expect("permission request already answered")
+    }
+
+    /// The decision used if the request is dropped unanswered
+    pub fn default_decision(&self) -> Decision {
+        self.default
+    }
+
+    /// Change the decision used if the request is dropped unanswered
+    pub fn set_default(&mut self, default: Decision) {
+        self.default = default;
+    }
+
+    /// Let the operation proceed
+    pub fn allow(self) -> Result<()> {
+        self.decide(Decision::Allow)
+    }
+
+    /// Fail the operation with `EPERM`
+    pub fn deny(self) -> Result<()> {
+        self.decide(Decision::Deny)
+    }
+
+    /// Answer the request with `decision`
+    pub fn decide(self, decision: Decision) -> Result<()> {
+        self.respond(decision.response())
+    }
+
+    /// Answer the request with raw response flags
+    pub fn respond(self, response: EventFlags) -> Result<()> {
+        self.respond_with(Response::new(response))
+    }
+
+    /// Answer the request with an extended [`Response`]
+    pub fn respond_with(mut self, response: Response) -> Result<()> {
+        self.answer(&response)
+    }
+
+    /// Write the response and close the event file descriptor
+    fn answer(&mut self, response: &Response) -> Result<()> {
+        let Some(event) = self.event.take() else {
+            return Ok(());
+        };
+
+        let result = self.responder.respond_with(&event, response);
+        if let Some(fd) = event.info.fd {
+            sys::close_fd(fd);
+        }
+
+        result
+    }
+}
+
+impl fmt::Debug for PermissionRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PermissionRequest")
+            .field("event", &self.event)
+            .field("default", &self.default)
+            .finish()
+    }
+}
+
+impl Drop for PermissionRequest {
+    fn drop(&mut self) {
+        let Some(event) = &self.event else {
+            return;
+        };
+
+        log::warn!(
+            "permission request for {:?} from pid {} dropped unanswered, responding {:?}",
+            event.info.path,
+            event.info.pid,
+            self.default
+        );
+
+        if let Err(e) = self.answer(&self.default.into()) {
+            log::error!("failed to answer dropped permission request: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_denied, next_request, open_in_thread, permission_event, watched_file};
+    use crate::{Fanotify, MaskFlags};
+
+    #[test]
+    fn test_deny_consumes_request() {
+        let (mut fanotify, _dir, test_file) = watched_file("deny.txt", "deny", MaskFlags::OPEN_PERM);
+        let opener = open_in_thread(&test_file);
+
+        let request = next_request(&mut fanotify);
+        assert!(request.event().is_permission());
+        assert_eq!(fanotify.pending_permissions(), 1);
+        request.deny().unwrap();
+        assert_eq!(fanotify.pending_permissions(), 0);
+        assert_denied(opener);
+    }
+
+    #[test]
+    fn test_dropped_request_uses_default() {
+        let (mut fanotify, _dir, test_file) = watched_file("dropped.txt", "dropped", MaskFlags::OPEN_PERM);
+        fanotify.set_default_decision(Decision::Deny);
+        let opener = open_in_thread(&test_file);
+
+        let request = next_request(&mut fanotify);
+        assert_eq!(request.default_decision(), Decision::Deny);
+        drop(request);
+        assert_denied(opener);
+    }
+
+    #[test]
+    fn test_request_rejects_notification_events() {
+        let fanotify = Fanotify::new().unwrap();
+        let responder = fanotify.responder().unwrap();
+        let event = permission_event(MaskFlags::MODIFY, "/srv/file");
+
+        assert!(responder.request(event.clone(), Decision::Allow).is_err());
+        assert!(matches!(responder.incoming(event, Decision::Allow), Incoming::Event(_)));
+    }
+}
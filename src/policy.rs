@@ -0,0 +1,703 @@
+//! Rule-based answers to permission events
+//!
+//! A [`Policy`] is an ordered list of [`Rule`]s plus a default [`Action`].
+//! The first rule that matches an event decides it; if none does, the
+//! default applies. A policy implements [`Handler`], so it can be handed
+//! straight to [`Fanotify::run`](crate::Fanotify::run):
+//!
+//! ```no_run
+//! use fanotify_rs::policy::{Action, EventKind, PathPattern, Policy, Rule};
+//! use fanotify_rs::{Fanotify, FanotifyFlags, MaskFlags};
+//!
+//! # fn main() -> fanotify_rs::Result<()> {
+//! let policy = Policy::new(Action::Allow)
+//!     .rule(Rule::allow().path_prefix("/etc/shadow").exe(PathPattern::prefix("/usr/sbin")))
+//!     .rule(Rule::deny().path(PathPattern::glob("/etc/*shadow")?))
+//!     .rule(Rule::audit().kind(EventKind::Exec).path_prefix("/tmp"));
+//!
+//! let mut fanotify = Fanotify::with_flags(FanotifyFlags::CLASS_CONTENT)?;
+//! fanotify.add_watch("/etc", MaskFlags::OPEN_PERM | MaskFlags::EVENT_ON_CHILD)?;
+//! fanotify.run(policy)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Process attributes (uid, gid, executable, parent) are read from `/proc`
+//! only when a rule needs them.
+
+use std::cell::OnceCell;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use glob::{MatchOptions, Pattern};
+
+use crate::{
+    error::{FanotifyError, Result},
+    event::Event,
+    flags::MaskFlags,
+    handler::{Decision, Handler},
+    permission::PermissionRequest,
+    response::Response,
+};
+
+/// What a policy does with a matching event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "policy-file", derive(serde::Deserialize), serde(rename_all = "lowercase"))]
+pub enum Action {
+    /// Let the operation proceed
+    Allow,
+    /// Fail the operation with `EPERM`
+    Deny,
+    /// Let the operation proceed, but log it
+    Audit,
+}
+
+impl Action {
+    /// The decision written to the kernel for this action
+    pub fn decision(self) -> Decision {
+        match self {
+            Action::Allow | Action::Audit => Decision::Allow,
+            Action::Deny => Decision::Deny,
+        }
+    }
+}
+
+/// The kind of access a permission event asks about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "policy-file", derive(serde::Deserialize), serde(rename_all = "lowercase"))]
+pub enum EventKind {
+    /// A file is being opened (`OPEN_PERM`)
+    Open,
+    /// A file is being read (`ACCESS_PERM`)
+    Access,
+    /// A file is being opened for execution (`OPEN_EXEC_PERM`)
+    Exec,
+}
+
+impl EventKind {
+    /// The kind of the permission event `event`, if it is one
+    pub fn of(event: &Event) -> Option<Self> {
+        Self::from_mask(event.info.mask)
+    }
+
+    /// The kind of permission event `mask` describes, if any
+    pub fn from_mask(mask: MaskFlags) -> Option<Self> {
+        if mask.contains(MaskFlags::OPEN_EXEC_PERM) {
+            Some(EventKind::Exec)
+        } else if mask.contains(MaskFlags::OPEN_PERM) {
+            Some(EventKind::Open)
+        } else if mask.contains(MaskFlags::ACCESS_PERM) {
+            Some(EventKind::Access)
+        } else {
+            None
+        }
+    }
+}
+
+/// A pattern matched against file or executable paths
+#[derive(Clone, PartialEq, Eq)]
+pub enum PathPattern {
+    /// The path itself or anything below it
+    Prefix(PathBuf),
+    /// A shell glob; `*` does not cross `/`, `**` does
+    Glob(Pattern),
+}
+
+impl PathPattern {
+    /// Match `prefix` and everything below it
+    pub fn prefix<P: AsRef<Path>>(prefix: P) -> Self {
+        PathPattern::Prefix(prefix.as_ref().to_path_buf())
+    }
+
+    /// Match a shell glob such as `/home/*/.ssh/**`
+    ///
+    /// Brace groups expand to several patterns; use [`PathPattern::globs`]
+    /// for those.
+    pub fn glob(pattern: &str) -> Result<Self> {
+        let patterns = expand_braces(pattern);
+        if patterns.len() > 1 {
+            return Err(FanotifyError::invalid_policy(format!(
+                "glob '{}' expands to several patterns, use PathPattern::globs",
+                pattern
+            )));
+        }
+
+        compile(&patterns[0]).map(PathPattern::Glob)
+    }
+
+    /// Compile a glob that may contain one `{a,b,...}` group into patterns
+    pub fn globs(pattern: &str) -> Result<Vec<Self>> {
+        expand_braces(pattern)
+            .iter()
+            .map(|p| compile(p).map(PathPattern::Glob))
+            .collect()
+    }
+
+    /// Check whether `path` matches
+    pub fn matches(&self, path: &Path) -> bool {
+        match self {
+            PathPattern::Prefix(prefix) => path.starts_with(prefix),
+            PathPattern::Glob(pattern) => pattern.matches_path_with(path, glob_options()),
+        }
+    }
+}
+
+impl fmt::Debug for PathPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathPattern::Prefix(prefix) => f.debug_tuple("Prefix").field(prefix).finish(),
+            PathPattern::Glob(pattern) => f.debug_tuple("Glob").field(&pattern.as_str()).finish(),
+        }
+    }
+}
+
+fn glob_options() -> MatchOptions {
+    MatchOptions {
+        case_sensitive: true,
+        require_literal_separator: true,
+        require_literal_leading_dot: false,
+    }
+}
+
+fn compile(pattern: &str) -> Result<Pattern> {
+    Pattern::new(pattern)
+        .map_err(|e| FanotifyError::invalid_policy(format!("invalid glob '{}': {}", pattern, e)))
+}
+
+/// Expand a single `{a,b,...}` group; the `glob` crate has no brace support
+fn expand_braces(pattern: &str) -> Vec<String> {
+    let (Some(open), Some(close)) = (pattern.find('{'), pattern.find('}')) else {
+        return vec![pattern.to_string()];
+    };
+    if close < open {
+        return vec![pattern.to_string()];
+    }
+
+    let (head, tail) = (&pattern[..open], &pattern[close + 1..]);
+    pattern[open + 1..close]
+        .split(',')
+        .map(|alternative| format!("{}{}{}", head, alternative, tail))
+        .collect()
+}
+
+/// One rule of a [`Policy`]
+///
+/// Each condition lists alternatives: the rule matches if, for every
+/// condition that was set, at least one alternative matches. A rule without
+/// conditions matches every permission event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    action: Action,
+    name: Option<String>,
+    paths: Vec<PathPattern>,
+    kinds: Vec<EventKind>,
+    pids: Vec<u32>,
+    uids: Vec<u32>,
+    gids: Vec<u32>,
+    exes: Vec<PathPattern>,
+    parent_pids: Vec<u32>,
+    parent_exes: Vec<PathPattern>,
+}
+
+impl Rule {
+    /// A rule that applies `action`
+    pub fn new(action: Action) -> Self {
+        Self {
+            action,
+            name: None,
+            paths: Vec::new(),
+            kinds: Vec::new(),
+            pids: Vec::new(),
+            uids: Vec::new(),
+            gids: Vec::new(),
+            exes: Vec::new(),
+            parent_pids: Vec::new(),
+            parent_exes: Vec::new(),
+        }
+    }
+
+    /// A rule that allows matching events
+    pub fn allow() -> Self {
+        Self::new(Action::Allow)
+    }
+
+    /// A rule that denies matching events
+    pub fn deny() -> Self {
+        Self::new(Action::Deny)
+    }
+
+    /// A rule that allows and logs matching events
+    pub fn audit() -> Self {
+        Self::new(Action::Audit)
+    }
+
+    /// Give the rule a name used in logs and verdicts
+    pub fn named(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Match files whose path matches `pattern`
+    pub fn path(mut self, pattern: PathPattern) -> Self {
+        self.paths.push(pattern);
+        self
+    }
+
+    /// Match files at or below `prefix`
+    pub fn path_prefix<P: AsRef<Path>>(self, prefix: P) -> Self {
+        self.path(PathPattern::prefix(prefix))
+    }
+
+    /// Match events of `kind`
+    pub fn kind(mut self, kind: EventKind) -> Self {
+        self.kinds.push(kind);
+        self
+    }
+
+    /// Match events triggered by process `pid`
+    pub fn pid(mut self, pid: u32) -> Self {
+        self.pids.push(pid);
+        self
+    }
+
+    /// Match events triggered by processes with effective user id `uid`
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.uids.push(uid);
+        self
+    }
+
+    /// Match events triggered by processes with effective group id `gid`
+    pub fn gid(mut self, gid: u32) -> Self {
+        self.gids.push(gid);
+        self
+    }
+
+    /// Match events triggered by processes running an executable matching `pattern`
+    pub fn exe(mut self, pattern: PathPattern) -> Self {
+        self.exes.push(pattern);
+        self
+    }
+
+    /// Match events triggered by children of process `pid`
+    pub fn parent_pid(mut self, pid: u32) -> Self {
+        self.parent_pids.push(pid);
+        self
+    }
+
+    /// Match events triggered by processes whose parent runs an executable matching `pattern`
+    pub fn parent_exe(mut self, pattern: PathPattern) -> Self {
+        self.parent_exes.push(pattern);
+        self
+    }
+
+    /// The action applied to matching events
+    pub fn action(&self) -> Action {
+        self.action
+    }
+
+    /// The rule's name, if it has one
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Check whether the rule matches `event`
+    pub fn matches(&self, event: &Event) -> bool {
+        self.matches_process(event, &ProcessInfo::new(event.info.pid))
+    }
+
+    fn matches_process(&self, event: &Event, process: &ProcessInfo) -> bool {
+        if !self.kinds.is_empty()
+            && !EventKind::of(event).is_some_and(|kind| self.kinds.contains(&kind))
+        {
+            return false;
+        }
+        if !self.paths.is_empty() && !any_path(&self.paths, event.info.path.as_deref()) {
+            return false;
+        }
+        if !self.pids.is_empty() && !self.pids.contains(&event.info.pid) {
+            return false;
+        }
+        if !self.uids.is_empty() && !process.uid().is_some_and(|uid| self.uids.contains(&uid)) {
+            return false;
+        }
+        if !self.gids.is_empty() && !process.gid().is_some_and(|gid| self.gids.contains(&gid)) {
+            return false;
+        }
+        if !self.exes.is_empty() && !any_path(&self.exes, process.exe()) {
+            return false;
+        }
+        if !self.parent_pids.is_empty()
+            && !process.ppid().is_some_and(|ppid| self.parent_pids.contains(&ppid))
+        {
+            return false;
+        }
+        if !self.parent_exes.is_empty() && !any_path(&self.parent_exes, process.parent_exe()) {
+            return false;
+        }
+
+        true
+    }
+}
+
+fn any_path(patterns: &[PathPattern], path: Option<&Path>) -> bool {
+    path.is_some_and(|path| patterns.iter().any(|pattern| pattern.matches(path)))
+}
+
+/// Attributes of the process behind an event, read from `/proc` on demand
+pub(crate) struct ProcessInfo {
+    pid: u32,
+    status: OnceCell<Option<Status>>,
+    exe: OnceCell<Option<PathBuf>>,
+    parent_exe: OnceCell<Option<PathBuf>>,
+}
+
+/// The fields of `/proc/<pid>/status` the policy looks at
+struct Status {
+    ppid: u32,
+    uid: u32,
+    gid: u32,
+}
+
+impl ProcessInfo {
+    pub(crate) fn new(pid: u32) -> Self {
+        Self {
+            pid,
+            status: OnceCell::new(),
+            exe: OnceCell::new(),
+            parent_exe: OnceCell::new(),
+        }
+    }
+
+    fn status(&self) -> Option<&Status> {
+        self.status.get_or_init(|| read_status(self.pid)).as_ref()
+    }
+
+    /// Effective user id
+    pub(crate) fn uid(&self) -> Option<u32> {
+        self.status().map(|s| s.uid)
+    }
+
+    /// Effective group id
+    pub(crate) fn gid(&self) -> Option<u32> {
+        self.status().map(|s| s.gid)
+    }
+
+    /// Parent process id
+    pub(crate) fn ppid(&self) -> Option<u32> {
+        self.status().map(|s| s.ppid)
+    }
+
+    /// Path of the running executable
+    pub(crate) fn exe(&self) -> Option<&Path> {
+        self.exe.get_or_init(|| read_exe(self.pid)).as_deref()
+    }
+
+    /// Path of the parent's running executable
+    pub(crate) fn parent_exe(&self) -> Option<&Path> {
+        self.parent_exe
+            .get_or_init(|| self.ppid().and_then(read_exe))
+            .as_deref()
+    }
+}
+
+fn read_exe(pid: u32) -> Option<PathBuf> {
+    std::fs::read_link(format!("/proc/{}/exe", pid)).ok()
+}
+
+fn read_status(pid: u32) -> Option<Status> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let mut ppid = None;
+    let mut uid = None;
+    let mut gid = None;
+
+    for line in status.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        // Uid and Gid list the real, effective, saved and filesystem ids
+        let mut fields = value.split_whitespace();
+        match key {
+            "PPid" => ppid = fields.next().and_then(|v| v.parse().ok()),
+            "Uid" => uid = fields.nth(1).and_then(|v| v.parse().ok()),
+            "Gid" => gid = fields.nth(1).and_then(|v| v.parse().ok()),
+            _ => {}
+        }
+    }
+
+    Some(Status {
+        ppid: ppid?,
+        uid: uid?,
+        gid: gid?,
+    })
+}
+
+/// The outcome of evaluating a [`Policy`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Verdict {
+    /// The action to take
+    pub action: Action,
+    /// Index of the rule that matched, or `None` if the default applied
+    pub rule: Option<usize>,
+}
+
+/// An ordered list of rules with a default action
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Policy {
+    rules: Vec<Rule>,
+    exclusions: Vec<PathPattern>,
+    default: Action,
+    kernel_audit: bool,
+}
+
+impl Policy {
+    /// An empty policy that applies `default` to every event
+    pub fn new(default: Action) -> Self {
+        Self {
+            rules: Vec::new(),
+            exclusions: Vec::new(),
+            default,
+            kernel_audit: false,
+        }
+    }
+
+    /// Append a rule
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Append a rule in place
+    pub fn push(&mut self, rule: Rule) {
+        self.rules.push(rule);
+    }
+
+    /// Exempt files matching `pattern` from the policy
+    ///
+    /// Events on excluded files are allowed without consulting any rule.
+    pub fn exclude(mut self, pattern: PathPattern) -> Self {
+        self.exclusions.push(pattern);
+        self
+    }
+
+    /// The rules in evaluation order
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// The excluded path patterns
+    pub fn exclusions(&self) -> &[PathPattern] {
+        &self.exclusions
+    }
+
+    /// The action applied when no rule matches
+    pub fn default_action(&self) -> Action {
+        self.default
+    }
+
+    /// Change the action applied when no rule matches
+    pub fn set_default_action(&mut self, action: Action) {
+        self.default = action;
+    }
+
+    /// Whether denials and audited events are also logged by the kernel
+    pub fn kernel_audit(&self) -> bool {
+        self.kernel_audit
+    }
+
+    /// Log denials and audited events to the audit subsystem
+    ///
+    /// Responses then carry `FAN_AUDIT` and the number of the matching rule,
+    /// counted from 1 (0 when the default applied). The group must have been
+    /// created with [`FanotifyFlags::ENABLE_AUDIT`](crate::FanotifyFlags::ENABLE_AUDIT).
+    pub fn set_kernel_audit(&mut self, enabled: bool) {
+        self.kernel_audit = enabled;
+    }
+
+    /// The response written to the kernel for `verdict`
+    pub fn response(&self, verdict: Verdict) -> Response {
+        let response = Response::from(verdict.action.decision());
+
+        if !self.kernel_audit || verdict.action == Action::Allow {
+            return response;
+        }
+
+        let rule_number = verdict.rule.map_or(0, |index| index as u32 + 1);
+        response.audit_rule(rule_number)
+    }
+
+    /// [`response`](Self::response) naming the matching rule for the decision log
+    fn logged_response(&self, verdict: Verdict) -> Response {
+        let response = self.response(verdict);
+        match verdict.rule {
+            Some(index) => response.rule(index as u32 + 1),
+            None => response,
+        }
+    }
+
+    /// Find the first rule matching `event`
+    ///
+    /// Excluded files are allowed with `rule` set to `None`.
+    pub fn evaluate(&self, event: &Event) -> Verdict {
+        if any_path(&self.exclusions, event.info.path.as_deref()) {
+            return Verdict {
+                action: Action::Allow,
+                rule: None,
+            };
+        }
+
+        let process = ProcessInfo::new(event.info.pid);
+
+        for (index, rule) in self.rules.iter().enumerate() {
+            if rule.matches_process(event, &process) {
+                return Verdict {
+                    action: rule.action,
+                    rule: Some(index),
+                };
+            }
+        }
+
+        Verdict {
+            action: self.default,
+            rule: None,
+        }
+    }
+
+    /// Evaluate `request` and answer it
+    pub fn answer(&self, request: PermissionRequest) -> Result<Verdict> {
+        let verdict = self.evaluate(request.event());
+        self.log(request.event(), verdict);
+        request.respond_with(self.logged_response(verdict))?;
+        Ok(verdict)
+    }
+
+    /// Log audited events
+    pub(crate) fn log(&self, event: &Event, verdict: Verdict) {
+        if verdict.action != Action::Audit {
+            return;
+        }
+
+        let rule = verdict
+            .rule
+            .and_then(|index| self.rules[index].name())
+            .unwrap_or("default");
+        log::info!(
+            "audit: pid {} {:?} {:?} (rule {})",
+            event.info.pid,
+            EventKind::of(event),
+            event.info.path,
+            rule
+        );
+    }
+}
+
+impl Handler for Policy {
+    fn on_event(&mut self, _event: &Event) {}
+
+    fn on_permission(&mut self, event: &Event) -> Decision {
+        let verdict = self.evaluate(event);
+        self.log(event, verdict);
+        verdict.action.decision()
+    }
+
+    fn on_permission_response(&mut self, event: &Event) -> Response {
+        let verdict = self.evaluate(event);
+        self.log(event, verdict);
+        self.logged_response(verdict)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_denied, open_in_thread, permission_event, watched_file};
+
+    #[test]
+    fn test_first_matching_rule_wins() {
+        let policy = Policy::new(Action::Allow)
+            .rule(Rule::allow().path_prefix("/etc/shadow").pid(1))
+            .rule(Rule::deny().path(PathPattern::glob("/etc/*shadow").unwrap()))
+            .rule(Rule::audit().kind(EventKind::Exec));
+
+        let shadow = permission_event(MaskFlags::OPEN_PERM, "/etc/shadow");
+        assert_eq!(policy.evaluate(&shadow), Verdict { action: Action::Deny, rule: Some(1) });
+
+        let exec = permission_event(MaskFlags::OPEN_EXEC_PERM, "/usr/bin/true");
+        assert_eq!(policy.evaluate(&exec), Verdict { action: Action::Audit, rule: Some(2) });
+
+        let other = permission_event(MaskFlags::ACCESS_PERM, "/etc/hosts");
+        assert_eq!(policy.evaluate(&other), Verdict { action: Action::Allow, rule: None });
+
+        let policy = policy.exclude(PathPattern::prefix("/etc/gshadow"));
+        let excluded = permission_event(MaskFlags::OPEN_PERM, "/etc/gshadow");
+        assert_eq!(policy.evaluate(&excluded), Verdict { action: Action::Allow, rule: None });
+    }
+
+    #[test]
+    fn test_process_conditions() {
+        let me = ProcessInfo::new(std::process::id());
+        let uid = me.uid().unwrap();
+        let exe = me.exe().unwrap().to_path_buf();
+        let parent = me.ppid().unwrap();
+
+        let event = permission_event(MaskFlags::OPEN_PERM, "/srv/data");
+        assert!(Rule::deny().uid(uid).exe(PathPattern::prefix(&exe)).matches(&event));
+        assert!(Rule::deny().parent_pid(parent).matches(&event));
+        assert!(!Rule::deny().uid(uid.wrapping_add(1)).matches(&event));
+        assert!(!Rule::deny().exe(PathPattern::prefix("/nonexistent")).matches(&event));
+    }
+
+    #[test]
+    fn test_glob_patterns() {
+        let patterns = PathPattern::globs("/home/*/.{ssh,gnupg}/**").unwrap();
+        assert_eq!(patterns.len(), 2);
+        assert!(patterns[0].matches(Path::new("/home/alice/.ssh/id_ed25519")));
+        assert!(patterns[1].matches(Path::new("/home/bob/.gnupg/private/key")));
+        assert!(!patterns[0].matches(Path::new("/home/alice/nested/.ssh/key")));
+        assert!(PathPattern::glob("/etc/{a,b}").is_err());
+        assert!(PathPattern::glob("/etc/[").is_err());
+    }
+
+    #[test]
+    fn test_policy_as_handler() {
+        let (mut fanotify, _dir, secret) = watched_file("secret.key", "secret", MaskFlags::OPEN_PERM);
+        let opener = open_in_thread(&secret);
+
+        struct Once(Policy, bool);
+        impl Handler for Once {
+            fn on_event(&mut self, _event: &Event) {}
+            fn on_permission(&mut self, event: &Event) -> Decision {
+                self.1 = true;
+                self.0.on_permission(event)
+            }
+            fn should_stop(&self) -> bool {
+                self.1
+            }
+        }
+
+        let policy = Policy::new(Action::Allow)
+            .rule(Rule::deny().path(PathPattern::glob("/**/*.key").unwrap()));
+        fanotify.run(Once(policy, false)).unwrap();
+        assert_denied(opener);
+    }
+
+    #[test]
+    fn test_kernel_audit_responses() {
+        let mut policy = Policy::new(Action::Deny)
+            .rule(Rule::allow().path_prefix("/srv"))
+            .rule(Rule::audit().path_prefix("/etc"));
+
+        let audited = Verdict { action: Action::Audit, rule: Some(1) };
+        assert_eq!(policy.response(audited), Response::allow());
+
+        policy.set_kernel_audit(true);
+        assert_eq!(policy.response(audited), Response::allow().audit_rule(2));
+        assert_eq!(
+            policy.response(Verdict { action: Action::Deny, rule: None }),
+            Response::deny().audit_rule(0)
+        );
+        assert_eq!(
+            policy.response(Verdict { action: Action::Allow, rule: Some(0) }),
+            Response::allow()
+        );
+    }
+}
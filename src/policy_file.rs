@@ -0,0 +1,625 @@
+//! Loading policies from TOML files, with hot reload
+//!
+//! A policy file lists a default action, paths excluded from the policy and
+//! the rules in evaluation order:
+//!
+//! ```toml
+//! default = "allow"
+//! exclude = ["/usr/lib", "/var/cache/**"]
+//!
+//! [[rule]]
+//! name = "shadow"
+//! action = "deny"
+//! paths = ["/etc/*shadow"]
+//! kinds = ["open"]
+//!
+//! [[rule]]
+//! action = "audit"
+//! exes = ["/usr/bin/python3*"]
+//! parent_exes = ["/usr/sbin/sshd"]
+//! ```
+//!
+//! Rules accept `name`, `action` (`allow`, `deny` or `audit`), `paths`,
+//! `kinds` (`open`, `access`, `exec`), `pids`, `uids`, `gids`, `exes`,
+//! `parent_pids` and `parent_exes`, with the meaning of the [`Rule`] builder
+//! methods of the same name. A path containing `*`, `?`, `[` or `{` is a
+//! glob, anything else a prefix. Errors point at the offending line and
+//! column.
+//!
+//! [`ReloadingPolicy`] keeps a policy in sync with its file: a background
+//! thread reloads it on `SIGHUP` and when the file is replaced or rewritten.
+
+use std::fs;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use serde::Deserialize;
+use toml::Spanned;
+
+use crate::{
+    error::{FanotifyError, Result},
+    event::Event,
+    fanotify::Fanotify,
+    flags::MaskFlags,
+    handler::{Decision, Handler},
+    linux::errno,
+    policy::{Action, EventKind, PathPattern, Policy, Rule},
+    response::Response,
+    sys,
+};
+
+/// The top level of a policy file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    #[serde(default = "default_action")]
+    default: Action,
+    #[serde(default)]
+    exclude: Vec<Spanned<String>>,
+    #[serde(default, rename = "rule")]
+    rules: Vec<RuleEntry>,
+}
+
+/// One `[[rule]]` table
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleEntry {
+    name: Option<String>,
+    action: Action,
+    #[serde(default)]
+    paths: Vec<Spanned<String>>,
+    #[serde(default)]
+    kinds: Vec<EventKind>,
+    #[serde(default)]
+    pids: Vec<u32>,
+    #[serde(default)]
+    uids: Vec<u32>,
+    #[serde(default)]
+    gids: Vec<u32>,
+    #[serde(default)]
+    exes: Vec<Spanned<String>>,
+    #[serde(default)]
+    parent_pids: Vec<u32>,
+    #[serde(default)]
+    parent_exes: Vec<Spanned<String>>,
+}
+
+fn default_action() -> Action {
+    Action::Allow
+}
+
+impl Policy {
+    /// Parse a policy from the contents of a TOML policy file
+    pub fn from_toml(text: &str) -> Result<Self> {
+        let file: PolicyFile = toml::from_str(text).map_err(|e| {
+            let message = e.message().to_string();
+            match e.span() {
+                Some(span) => located(text, span.start, &message),
+                None => FanotifyError::invalid_policy(message),
+            }
+        })?;
+
+        let mut policy = Policy::new(file.default);
+        for pattern in &file.exclude {
+            for pattern in patterns(text, pattern)? {
+                policy = policy.exclude(pattern);
+            }
+        }
+
+        for entry in file.rules {
+            policy.push(rule(text, entry)?);
+        }
+
+        Ok(policy)
+    }
+
+    /// Load a policy from a TOML policy file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+
+        Policy::from_toml(&text).map_err(|e| match e {
+            FanotifyError::InvalidPolicy { message } => {
+                FanotifyError::invalid_policy(format!("{}: {}", path.display(), message))
+            }
+            other => other,
+        })
+    }
+}
+
+/// Build a [`Rule`] from its table
+fn rule(text: &str, entry: RuleEntry) -> Result<Rule> {
+    let mut rule = Rule::new(entry.action);
+
+    if let Some(name) = entry.name {
+        rule = rule.named(name);
+    }
+    for pattern in &entry.paths {
+        rule = patterns(text, pattern)?.into_iter().fold(rule, Rule::path);
+    }
+    rule = entry.kinds.into_iter().fold(rule, Rule::kind);
+    rule = entry.pids.into_iter().fold(rule, Rule::pid);
+    rule = entry.uids.into_iter().fold(rule, Rule::uid);
+    rule = entry.gids.into_iter().fold(rule, Rule::gid);
+    for pattern in &entry.exes {
+        rule = patterns(text, pattern)?.into_iter().fold(rule, Rule::exe);
+    }
+    rule = entry.parent_pids.into_iter().fold(rule, Rule::parent_pid);
+    for pattern in &entry.parent_exes {
+        rule = patterns(text, pattern)?.into_iter().fold(rule, Rule::parent_exe);
+    }
+
+    Ok(rule)
+}
+
+/// Turn a path string into patterns: a glob if it has glob syntax, else a prefix
+fn patterns(text: &str, pattern: &Spanned<String>) -> Result<Vec<PathPattern>> {
+    let value = pattern.get_ref();
+
+    if !value.contains(['*', '?', '[', '{']) {
+        return Ok(vec![PathPattern::prefix(value)]);
+    }
+
+    PathPattern::globs(value).map_err(|e| {
+        let message = match e {
+            FanotifyError::InvalidPolicy { message } => message,
+            other => other.to_string(),
+        };
+        located(text, pattern.span().start, &message)
+    })
+}
+
+/// An [`InvalidPolicy`](FanotifyError::InvalidPolicy) error pointing at `offset`
+fn located(text: &str, offset: usize, message: &str) -> FanotifyError {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+
+    FanotifyError::invalid_policy(format!("line {}, column {}: {}", line, column, message))
+}
+
+/// Identifies one version of the policy file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+impl Stamp {
+    fn of(path: &Path) -> Option<Self> {
+        let metadata = fs::metadata(path).ok()?;
+        Some(Self {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            size: metadata.size(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+        })
+    }
+}
+
+/// How often the background thread compares the policy file's metadata
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A [`Policy`] that follows its file
+///
+/// Used as a [`Handler`], it answers permission events with the current
+/// policy. A background thread reloads the file
+///
+/// - when the process receives `SIGHUP` or [`request_reload`](Self::request_reload)
+///   is called, and
+/// - when the file's inode, size or modification time changed, which it
+///   checks every second. This catches editors that save by renaming a
+///   temporary file into place.
+///
+/// If [`watch`](Self::watch) added its mark, a file in the policy's
+/// directory closed after writing triggers the same check right away.
+///
+/// The new policy replaces the old one between two decisions, so no
+/// permission event is dropped or answered twice. If the new file does not
+/// parse, the previous policy stays in force and the error is logged.
+///
+/// Keep the policy file outside the paths watched for permission events:
+/// reading it would otherwise wait on a permission event of its own.
+pub struct ReloadingPolicy {
+    shared: Arc<Shared>,
+    /// eventfd used to wake the reload thread up
+    wake_fd: OwnedFd,
+    signal: signal_hook::SigId,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// State shared with the reload thread
+struct Shared {
+    path: PathBuf,
+    policy: Mutex<Policy>,
+    stamp: Mutex<Option<Stamp>>,
+    reloads: AtomicU64,
+    failures: AtomicU64,
+    stop: AtomicBool,
+}
+
+impl Shared {
+    fn reload(&self) -> Result<()> {
+        let stamp = Stamp::of(&self.path);
+        *self.stamp.lock().unwrap() = stamp;
+        self.load()
+    }
+
+    /// Replace the policy with the file's current contents
+    fn load(&self) -> Result<()> {
+        let mut policy = Policy::load(&self.path)?;
+
+        let mut current = self.policy.lock().unwrap();
+        // Kernel auditing depends on how the group was created, not on the file
+        policy.set_kernel_audit(current.kernel_audit());
+        *current = policy;
+        drop(current);
+
+        self.reloads.fetch_add(1, Ordering::Relaxed);
+        log::info!("reloaded policy from {}", self.path.display());
+        Ok(())
+    }
+
+    /// Reload now, logging instead of returning failures
+    fn reload_logged(&self) {
+        if let Err(e) = self.reload() {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+            log::error!("keeping previous policy: {}", e);
+        }
+    }
+
+    /// Reload if the file changed since it was last loaded
+    ///
+    /// A version that fails to load is not tried again until it changes.
+    fn reload_if_changed(&self) {
+        {
+            let mut stamp = self.stamp.lock().unwrap();
+            let current = Stamp::of(&self.path);
+            if current == *stamp {
+                return;
+            }
+            // Claim this version so the handler and the thread load it once
+            *stamp = current;
+        }
+
+        if let Err(e) = self.load() {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+            log::error!("keeping previous policy: {}", e);
+        }
+    }
+}
+
+impl ReloadingPolicy {
+    /// The mark [`watch`](Self::watch) adds on the policy file's directory
+    pub const WATCH_MASK: MaskFlags = MaskFlags::CLOSE_WRITE.union(MaskFlags::EVENT_ON_CHILD);
+
+    /// Load the policy at `path`, start listening for `SIGHUP` and start the
+    /// reload thread
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let stamp = Stamp::of(&path);
+        let policy = Policy::load(&path)?;
+
+        let raw = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if raw < 0 {
+            return Err(FanotifyError::from(errno()));
+        }
+        // SAFETY: eventfd returned a new descriptor that nobody else owns
+        let wake_fd = unsafe { OwnedFd::from_raw_fd(raw) };
+
+        // SAFETY: write(2) is async-signal-safe, and the handler is
+        // unregistered before the eventfd is closed
+        let signal = unsafe {
+            signal_hook::low_level::register(signal_hook::consts::SIGHUP, move || wake(raw))
+        }?;
+
+        let shared = Arc::new(Shared {
+            path,
+            policy: Mutex::new(policy),
+            stamp: Mutex::new(stamp),
+            reloads: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            stop: AtomicBool::new(false),
+        });
+
+        let thread_shared = Arc::clone(&shared);
+        let thread = thread::Builder::new()
+            .name("fanotify-policy".to_string())
+            .spawn(move || follow(&thread_shared, raw));
+        let thread = match thread {
+            Ok(thread) => thread,
+            Err(e) => {
+                signal_hook::low_level::unregister(signal);
+                return Err(e.into());
+            }
+        };
+
+        Ok(Self {
+            shared,
+            wake_fd,
+            signal,
+            thread: Some(thread),
+        })
+    }
+
+    /// Watch the policy file's directory through `fanotify`
+    ///
+    /// For asynchronous groups, add [`WATCH_MASK`](Self::WATCH_MASK) on
+    /// [`directory`](Self::directory) instead.
+    pub fn watch(&self, fanotify: &mut Fanotify) -> Result<()> {
+        fanotify.add_watch(self.directory(), Self::WATCH_MASK)
+    }
+
+    /// The directory containing the policy file
+    pub fn directory(&self) -> &Path {
+        match self.shared.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        }
+    }
+
+    /// The policy file
+    pub fn path(&self) -> &Path {
+        &self.shared.path
+    }
+
+    /// The policy currently in force
+    ///
+    /// Decisions wait while the guard is held.
+    pub fn policy(&self) -> MutexGuard<'_, Policy> {
+        self.shared.policy.lock().unwrap()
+    }
+
+    /// See [`Policy::set_kernel_audit`]; the setting survives reloads
+    pub fn set_kernel_audit(&self, enabled: bool) {
+        self.policy().set_kernel_audit(enabled);
+    }
+
+    /// Number of successful reloads
+    pub fn reload_count(&self) -> u64 {
+        self.shared.reloads.load(Ordering::Relaxed)
+    }
+
+    /// Number of reloads that failed and kept the previous policy
+    pub fn failed_reload_count(&self) -> u64 {
+        self.shared.failures.load(Ordering::Relaxed)
+    }
+
+    /// Reload the policy file now
+    ///
+    /// On error the current policy stays in force.
+    pub fn reload(&self) -> Result<()> {
+        self.shared.reload()
+    }
+
+    /// Have the reload thread reload the policy file, as `SIGHUP` does
+    pub fn request_reload(&self) {
+        wake(self.wake_fd.as_raw_fd());
+    }
+
+    /// Check whether `event` may have rewritten the policy file
+    fn touches_policy(&self, event: &Event) -> bool {
+        if !event.info.mask.contains(MaskFlags::CLOSE_WRITE) {
+            return false;
+        }
+
+        // The file may be written under another name and renamed into place
+        // later, which the reload thread notices; this only speeds up the
+        // common case of writing it in place
+        event
+            .info
+            .path
+            .as_deref()
+            .and_then(Path::parent)
+            .is_some_and(|dir| same_dir(dir, self.directory()))
+    }
+}
+
+fn same_dir(a: &Path, b: &Path) -> bool {
+    a == b || fs::canonicalize(a).ok() == fs::canonicalize(b).ok()
+}
+
+/// Add one to the eventfd `fd`
+fn wake(fd: RawFd) {
+    let value: u64 = 1;
+    // EAGAIN means the counter is already non-zero, i.e. a wake-up is pending
+    unsafe {
+        libc::write(fd, &value as *const u64 as *const libc::c_void, std::mem::size_of::<u64>());
+    }
+}
+
+/// Body of the reload thread
+fn follow(shared: &Shared, wake_fd: RawFd) {
+    loop {
+        let woken = match sys::poll_readable(wake_fd, Some(CHECK_INTERVAL)) {
+            Ok(woken) => woken,
+            Err(e) => {
+                log::error!("policy reload thread failed: {}", e);
+                return;
+            }
+        };
+        if shared.stop.load(Ordering::Acquire) {
+            return;
+        }
+
+        if woken {
+            // Reset the counter so the next wake-up is seen
+            let mut counter = [0u8; 8];
+            let _ = sys::read_fd(wake_fd, &mut counter);
+            shared.reload_logged();
+        } else {
+            shared.reload_if_changed();
+        }
+    }
+}
+
+impl Handler for ReloadingPolicy {
+    fn on_event(&mut self, event: &Event) {
+        if self.touches_policy(event) {
+            self.shared.reload_if_changed();
+        }
+    }
+
+    fn on_permission(&mut self, event: &Event) -> Decision {
+        self.policy().on_permission(event)
+    }
+
+    fn on_permission_response(&mut self, event: &Event) -> Response {
+        self.policy().on_permission_response(event)
+    }
+}
+
+impl Drop for ReloadingPolicy {
+    fn drop(&mut self) {
+        signal_hook::low_level::unregister(self.signal);
+        self.shared.stop.store(true, Ordering::Release);
+        wake(self.wake_fd.as_raw_fd());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_denied, open_in_thread, permission_event, watched_file};
+    use std::thread;
+    use tempfile::tempdir;
+
+    const POLICY: &str = r#"
+default = "deny"
+exclude = ["/usr/lib"]
+
+[[rule]]
+name = "keys"
+action = "deny"
+paths = ["/home/*/.{ssh,gnupg}/**"]
+kinds = ["open", "exec"]
+
+[[rule]]
+action = "allow"
+paths = ["/srv"]
+uids = [0, 1000]
+"#;
+
+    #[test]
+    fn test_parse_policy_file() {
+        let policy = Policy::from_toml(POLICY).unwrap();
+
+        assert_eq!(policy.default_action(), Action::Deny);
+        assert_eq!(policy.exclusions(), &[PathPattern::prefix("/usr/lib")]);
+        assert_eq!(policy.rules().len(), 2);
+        assert_eq!(policy.rules()[0].name(), Some("keys"));
+        assert_eq!(policy.rules()[0].action(), Action::Deny);
+        assert_eq!(
+            policy.rules()[1],
+            Rule::allow().path_prefix("/srv").uid(0).uid(1000)
+        );
+    }
+
+    #[test]
+    fn test_errors_point_at_line() {
+        let unknown = "default = \"allow\"\n\n[[rule]]\naction = \"deny\"\nowner = 3\n";
+        let message = Policy::from_toml(unknown).unwrap_err().to_string();
+        assert!(message.contains("line 5"), "{}", message);
+
+        let bad_action = "[[rule]]\naction = \"maybe\"\n";
+        let message = Policy::from_toml(bad_action).unwrap_err().to_string();
+        assert!(message.contains("line 2"), "{}", message);
+
+        let bad_glob = "[[rule]]\naction = \"deny\"\npaths = [\"/ok\",\n  \"/etc/[\"]\n";
+        let message = Policy::from_toml(bad_glob).unwrap_err().to_string();
+        assert!(message.contains("line 4, column 3"), "{}", message);
+    }
+
+    #[test]
+    fn test_reload_on_file_change() {
+        let config_dir = tempdir().unwrap();
+        let policy_path = config_dir.path().join("policy.toml");
+        fs::write(&policy_path, "default = \"allow\"\n").unwrap();
+
+        let (mut fanotify, _data_dir, data) = watched_file("data.txt", "data", MaskFlags::OPEN_PERM);
+        let mut policy = ReloadingPolicy::load(&policy_path).unwrap();
+        policy.watch(&mut fanotify).unwrap();
+
+        // Rewrite the policy, then open the data file once it is in force
+        fs::write(&policy_path, "default = \"deny\"\n").unwrap();
+        let opener = open_in_thread(&data);
+
+        struct UntilPermission<'a>(&'a mut ReloadingPolicy, bool);
+        impl Handler for UntilPermission<'_> {
+            fn on_event(&mut self, event: &Event) {
+                self.0.on_event(event)
+            }
+            fn on_permission(&mut self, event: &Event) -> Decision {
+                self.1 = true;
+                self.0.on_permission(event)
+            }
+            fn should_stop(&self) -> bool {
+                self.1
+            }
+        }
+
+        fanotify.run(UntilPermission(&mut policy, false)).unwrap();
+        assert_eq!(policy.reload_count(), 1);
+        assert_eq!(policy.policy().default_action(), Action::Deny);
+        assert_denied(opener);
+    }
+
+    /// Wait until `done` holds, for a few seconds at most
+    fn wait_for(done: impl Fn() -> bool) {
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(std::time::Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_requested_reload_keeps_policy_on_error() {
+        let config_dir = tempdir().unwrap();
+        let policy_path = config_dir.path().join("policy.toml");
+        fs::write(&policy_path, "default = \"audit\"\n").unwrap();
+        let mut policy = ReloadingPolicy::load(&policy_path).unwrap();
+
+        fs::write(&policy_path, "default = \"sometimes\"\n").unwrap();
+        policy.request_reload();
+        wait_for(|| policy.failed_reload_count() == 1);
+
+        let event = permission_event(MaskFlags::OPEN_PERM, "/srv/file");
+        assert_eq!(policy.on_permission(&event), Decision::Allow);
+        assert_eq!(policy.reload_count(), 0);
+        assert_eq!(policy.policy().default_action(), Action::Audit);
+
+        // No event needed: the thread reloads while the group is idle
+        fs::write(&policy_path, "default = \"deny\"\n").unwrap();
+        policy.request_reload();
+        wait_for(|| policy.reload_count() == 1);
+        assert_eq!(policy.on_permission(&event), Decision::Deny);
+    }
+
+    #[test]
+    fn test_reload_on_rename_into_place() {
+        let config_dir = tempdir().unwrap();
+        let policy_path = config_dir.path().join("policy.toml");
+        fs::write(&policy_path, "default = \"allow\"\n").unwrap();
+        let policy = ReloadingPolicy::load(&policy_path).unwrap();
+
+        let temp_path = config_dir.path().join(".policy.toml.swp");
+        fs::write(&temp_path, "default = \"deny\"\n").unwrap();
+        fs::rename(&temp_path, &policy_path).unwrap();
+
+        wait_for(|| policy.reload_count() == 1);
+        assert_eq!(policy.policy().default_action(), Action::Deny);
+    }
+}
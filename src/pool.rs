@@ -0,0 +1,564 @@
+//! Deciding permission events on several threads
+//!
+//! A [`WorkerPool`] reads a group on one thread and hands every event to one
+//! of N worker threads, each running its own clone of a [`Handler`]. Workers
+//! answer permission events through a shared [`Responder`], so slow
+//! decisions (hashing a file, asking a scanner) no longer queue up behind
+//! each other.
+//!
+//! Events are routed by the file they concern (device and inode), so all
+//! events for one file are handled by the same worker, in the order the
+//! kernel reported them. Each worker has a bounded queue; when it is full
+//! the reader waits, which leaves further events queued in the kernel.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::{
+    error::{FanotifyError, Result},
+    event::Event,
+    fanotify::Fanotify,
+    handler::{self, Handler},
+    linux::errno,
+    permission::Responder,
+    sys,
+};
+
+/// Configuration of a [`WorkerPool`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolConfig {
+    workers: usize,
+    queue_capacity: usize,
+}
+
+impl PoolConfig {
+    /// A pool of `workers` threads, each queueing up to 64 events
+    ///
+    /// # Panics
+    ///
+    /// Panics if `workers` is zero.
+    pub fn new(workers: usize) -> Self {
+        assert!(workers > 0, "worker count must be non-zero");
+
+        Self {
+            workers,
+            queue_capacity: 64,
+        }
+    }
+
+    /// Set how many events may wait for each worker
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn with_queue_capacity(mut self, capacity: usize) -> Self {
+        assert!(capacity > 0, "queue capacity must be non-zero");
+        self.queue_capacity = capacity;
+        self
+    }
+
+    /// Number of worker threads
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    /// Maximum number of events waiting for each worker
+    pub fn queue_capacity(&self) -> usize {
+        self.queue_capacity
+    }
+}
+
+impl Default for PoolConfig {
+    /// One worker per available CPU
+    fn default() -> Self {
+        Self::new(thread::available_parallelism().map_or(4, |n| n.get()))
+    }
+}
+
+/// Upper bounds of the latency histogram buckets, in microseconds
+const BUCKET_BOUNDS_US: [u64; 14] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000,
+    1_000_000, 5_000_000,
+];
+
+/// A snapshot of a latency distribution
+///
+/// `counts[i]` is the number of samples no longer than `bounds[i]`, and not
+/// counted in an earlier bucket; the last count holds the samples longer
+/// than every bound.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// Upper bounds of the buckets
+    pub bounds: Vec<Duration>,
+    /// Samples per bucket, with one extra bucket for longer samples
+    pub counts: Vec<u64>,
+    /// Sum of all samples
+    pub total: Duration,
+}
+
+impl LatencyHistogram {
+    /// Number of samples
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Average sample, if there are any
+    pub fn mean(&self) -> Option<Duration> {
+        let count = self.count();
+        (count > 0).then(|| Duration::from_micros((self.total.as_micros() / count as u128) as u64))
+    }
+
+    /// Upper bound of the bucket holding the `q` quantile (`0.0..=1.0`)
+    ///
+    /// `None` if there are no samples or the quantile falls beyond the last
+    /// bound.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+
+        let target = ((count as f64) * q.clamp(0.0, 1.0)).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, samples) in self.counts.iter().enumerate() {
+            seen += samples;
+            if seen >= target {
+                return self.bounds.get(bucket).copied();
+            }
+        }
+        None
+    }
+}
+
+/// Lock-free recorder behind a [`LatencyHistogram`]
+#[derive(Default)]
+struct Histogram {
+    counts: [AtomicU64; BUCKET_BOUNDS_US.len() + 1],
+    total_us: AtomicU64,
+}
+
+impl Histogram {
+    fn record(&self, latency: Duration) {
+        let micros = latency.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = BUCKET_BOUNDS_US
+            .iter()
+            .position(|&bound| micros <= bound)
+            .unwrap_or(BUCKET_BOUNDS_US.len());
+
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.total_us.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> LatencyHistogram {
+        LatencyHistogram {
+            bounds: BUCKET_BOUNDS_US.iter().map(|&us| Duration::from_micros(us)).collect(),
+            counts: self.counts.iter().map(|c| c.load(Ordering::Relaxed)).collect(),
+            total: Duration::from_micros(self.total_us.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// A snapshot of the pool's counters
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolMetrics {
+    /// Events currently queued, per worker
+    pub depths: Vec<usize>,
+    /// Highest total queue depth seen so far
+    pub high_watermark: usize,
+    /// Events handled by the workers
+    pub processed: u64,
+    /// Time events spent queued before a worker picked them up
+    pub queue_latency: LatencyHistogram,
+    /// Time the handler took to decide permission events
+    pub decision_latency: LatencyHistogram,
+}
+
+impl PoolMetrics {
+    /// Events currently queued across all workers
+    pub fn depth(&self) -> usize {
+        self.depths.iter().sum()
+    }
+}
+
+/// Counters shared by the reader and the workers
+struct Shared {
+    depths: Vec<AtomicUsize>,
+    high_watermark: AtomicUsize,
+    processed: AtomicU64,
+    queue_latency: Histogram,
+    decision_latency: Histogram,
+}
+
+impl Shared {
+    fn queued(&self, worker: usize) {
+        self.depths[worker].fetch_add(1, Ordering::Relaxed);
+        let depth = self.depths.iter().map(|d| d.load(Ordering::Relaxed)).sum();
+        self.high_watermark.fetch_max(depth, Ordering::Relaxed);
+    }
+}
+
+/// An event on its way to a worker
+struct Job {
+    event: Event,
+    queued: Instant,
+}
+
+/// Handle to a running worker pool
+///
+/// Dropping the pool stops all threads and closes the group; the kernel
+/// allows permission events nobody answered.
+pub struct WorkerPool {
+    config: PoolConfig,
+    shared: Arc<Shared>,
+    responder: Responder,
+    /// eventfd used to wake the reader thread up
+    stop_fd: OwnedFd,
+    /// The reader thread, which hands the group back when it exits
+    reader: Option<JoinHandle<Fanotify>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    /// Read `fanotify` on a new thread and handle its events on
+    /// `config.workers()` threads, each with its own clone of `handler`
+    ///
+    /// Errors returned while answering events are passed to
+    /// [`Handler::on_error`]; they do not stop the pool.
+    pub fn spawn<H>(fanotify: Fanotify, config: PoolConfig, handler: H) -> Result<Self>
+    where
+        H: Handler + Clone + Send + 'static,
+    {
+        let raw = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if raw < 0 {
+            return Err(FanotifyError::from(errno()));
+        }
+        // SAFETY: eventfd returned a new descriptor that nobody else owns
+        let stop_fd = unsafe { OwnedFd::from_raw_fd(raw) };
+        let thread_stop_fd = stop_fd.try_clone()?;
+
+        let responder = fanotify.responder()?;
+        let shared = Arc::new(Shared {
+            depths: (0..config.workers).map(|_| AtomicUsize::new(0)).collect(),
+            high_watermark: AtomicUsize::new(0),
+            processed: AtomicU64::new(0),
+            queue_latency: Histogram::default(),
+            decision_latency: Histogram::default(),
+        });
+
+        let mut senders = Vec::with_capacity(config.workers);
+        let mut workers = Vec::with_capacity(config.workers);
+        for index in 0..config.workers {
+            let (sender, jobs) = mpsc::sync_channel(config.queue_capacity);
+            let handler = handler.clone();
+            let responder = responder.clone();
+            let shared = Arc::clone(&shared);
+
+            let worker = thread::Builder::new()
+                .name(format!("fanotify-worker-{}", index))
+                .spawn(move || work(index, handler, responder, jobs, &shared))?;
+
+            senders.push(sender);
+            workers.push(worker);
+        }
+
+        let reader_shared = Arc::clone(&shared);
+        let reader = thread::Builder::new()
+            .name("fanotify-pool-reader".to_string())
+            .spawn(move || read(fanotify, thread_stop_fd, senders, &reader_shared))?;
+
+        Ok(Self {
+            config,
+            shared,
+            responder,
+            stop_fd,
+            reader: Some(reader),
+            workers,
+        })
+    }
+
+    /// The pool's configuration
+    pub fn config(&self) -> &PoolConfig {
+        &self.config
+    }
+
+    /// A snapshot of the queue depths and latencies
+    pub fn metrics(&self) -> PoolMetrics {
+        PoolMetrics {
+            depths: self.shared.depths.iter().map(|d| d.load(Ordering::Relaxed)).collect(),
+            high_watermark: self.shared.high_watermark.load(Ordering::Relaxed),
+            processed: self.shared.processed.load(Ordering::Relaxed),
+            queue_latency: self.shared.queue_latency.snapshot(),
+            decision_latency: self.shared.decision_latency.snapshot(),
+        }
+    }
+
+    /// The responder the workers answer through
+    pub fn responder(&self) -> &Responder {
+        &self.responder
+    }
+
+    /// Check whether the reader thread is still running
+    pub fn is_running(&self) -> bool {
+        self.reader.as_ref().is_some_and(|t| !t.is_finished())
+    }
+
+    /// Stop reading, let the workers finish the queued events and hand the
+    /// group back
+    pub fn stop(mut self) -> Result<Fanotify> {
+        self.signal_stop()?;
+        let reader = self.reader.take().expect("pool reader already joined");
+        let fanotify = reader
+            .join()
+            .map_err(|_| FanotifyError::invalid_event_data("Pool reader thread panicked"))?;
+
+        for worker in self.workers.drain(..) {
+            worker
+                .join()
+                .map_err(|_| FanotifyError::invalid_event_data("Pool worker thread panicked"))?;
+        }
+
+        Ok(fanotify)
+    }
+
+    /// Wake the reader thread and ask it to exit
+    fn signal_stop(&self) -> Result<()> {
+        let value: u64 = 1;
+        let result = unsafe {
+            libc::write(
+                self.stop_fd.as_raw_fd(),
+                &value as *const u64 as *const libc::c_void,
+                std::mem::size_of::<u64>(),
+            )
+        };
+
+        // EAGAIN means the counter is already non-zero, i.e. a stop is pending
+        if result < 0 && errno() != libc::EAGAIN {
+            return Err(FanotifyError::from(errno()));
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        if let Some(reader) = self.reader.take() {
+            let _ = self.signal_stop();
+            let _ = reader.join();
+        }
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// The worker responsible for `event`
+///
+/// Events for the same file always map to the same worker.
+fn worker_for(event: &Event, workers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+
+    match event.info.fd.and_then(|fd| sys::fstat(fd).ok()) {
+        Some(stat) => (stat.st_dev, stat.st_ino).hash(&mut hasher),
+        None => event.info.path.hash(&mut hasher),
+    }
+
+    (hasher.finish() % workers as u64) as usize
+}
+
+/// Body of the reader thread
+///
+/// Returning drops the senders, which lets the workers drain their queues
+/// and exit.
+fn read(
+    mut fanotify: Fanotify,
+    stop_fd: OwnedFd,
+    senders: Vec<SyncSender<Job>>,
+    shared: &Shared,
+) -> Fanotify {
+    loop {
+        let mut fds = [
+            libc::pollfd {
+                fd: fanotify.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: stop_fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+
+        let result = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+        if result < 0 {
+            let err = errno();
+            if err == libc::EINTR {
+                continue;
+            }
+            log::error!("worker pool stopped reading: {}", FanotifyError::from(err));
+            return fanotify;
+        }
+
+        if fds[1].revents != 0 {
+            return fanotify;
+        }
+
+        if fds[0].revents == 0 {
+            continue;
+        }
+
+        let events = match fanotify.read_available() {
+            Ok(events) => events,
+            Err(e) => {
+                log::error!("worker pool stopped reading: {}", e);
+                return fanotify;
+            }
+        };
+
+        for event in events {
+            let worker = worker_for(&event, senders.len());
+            shared.queued(worker);
+            let job = Job {
+                event,
+                queued: Instant::now(),
+            };
+
+            if senders[worker].send(job).is_err() {
+                // The worker is gone; the group closing allows its events
+                log::error!("pool worker {} exited early", worker);
+                return fanotify;
+            }
+        }
+    }
+}
+
+/// Body of a worker thread
+fn work<H: Handler>(
+    index: usize,
+    mut handler: H,
+    responder: Responder,
+    jobs: Receiver<Job>,
+    shared: &Shared,
+) {
+    for job in jobs {
+        shared.depths[index].fetch_sub(1, Ordering::Relaxed);
+        shared.queue_latency.record(job.queued.elapsed());
+
+        let permission = job.event.is_permission();
+        let started = Instant::now();
+        let result = handler::dispatch_batch(
+            &mut handler,
+            responder.as_raw_fd(),
+            responder.permissions(),
+            [job.event],
+        );
+        if permission {
+            shared.decision_latency.record(started.elapsed());
+        }
+        shared.processed.fetch_add(1, Ordering::Relaxed);
+
+        if let Err(e) = result {
+            log::error!("pool worker {}: {}", index, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_denied, open_in_thread, permission_event, watched_file};
+    use crate::{Decision, FanotifyFlags, MaskFlags};
+    use std::fs::File;
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    #[derive(Clone)]
+    struct SlowDeny {
+        active: Arc<AtomicUsize>,
+        most_active: Arc<AtomicUsize>,
+    }
+
+    impl Handler for SlowDeny {
+        fn on_event(&mut self, _event: &Event) {}
+
+        fn on_permission(&mut self, _event: &Event) -> Decision {
+            let active = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most_active.fetch_max(active, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(100));
+            self.active.fetch_sub(1, Ordering::SeqCst);
+            Decision::Deny
+        }
+    }
+
+    #[test]
+    fn test_pool_decides_in_parallel() {
+        let mut fanotify = Fanotify::with_flags(FanotifyFlags::CLASS_CONTENT).unwrap();
+        let temp_dir = tempdir().unwrap();
+        let files: Vec<PathBuf> = (0..8)
+            .map(|i| {
+                let path = temp_dir.path().join(format!("file{}.txt", i));
+                std::fs::write(&path, "data").unwrap();
+                fanotify.add_watch(&path, MaskFlags::OPEN_PERM).unwrap();
+                path
+            })
+            .collect();
+
+        let most_active = Arc::new(AtomicUsize::new(0));
+        let handler = SlowDeny {
+            active: Arc::new(AtomicUsize::new(0)),
+            most_active: Arc::clone(&most_active),
+        };
+        let pool = fanotify.spawn_pool(PoolConfig::new(4), handler).unwrap();
+
+        let openers: Vec<_> = files.iter().map(|path| open_in_thread(path)).collect();
+        openers.into_iter().for_each(assert_denied);
+
+        let fanotify = pool.stop().unwrap();
+        assert_eq!(fanotify.pending_permissions(), 0);
+        assert!(most_active.load(Ordering::SeqCst) > 1);
+    }
+
+    #[test]
+    fn test_pool_metrics() {
+        let (fanotify, _dir, test_file) = watched_file("metrics.txt", "data", MaskFlags::OPEN_PERM);
+
+        let handler = SlowDeny {
+            active: Arc::new(AtomicUsize::new(0)),
+            most_active: Arc::new(AtomicUsize::new(0)),
+        };
+        let pool = fanotify.spawn_pool(PoolConfig::new(2), handler).unwrap();
+
+        for _ in 0..3 {
+            assert!(File::open(&test_file).is_err());
+        }
+
+        // The counters are updated just after the answer is written
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.metrics().processed < 3 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let metrics = pool.metrics();
+        assert_eq!(metrics.processed, 3);
+        assert_eq!(metrics.depth(), 0);
+        assert_eq!(metrics.queue_latency.count(), 3);
+        assert_eq!(metrics.decision_latency.count(), 3);
+        assert!(metrics.decision_latency.quantile(0.5).unwrap() >= Duration::from_millis(100));
+        pool.stop().unwrap();
+    }
+
+    #[test]
+    fn test_same_file_same_worker() {
+        let event = permission_event(MaskFlags::OPEN_PERM, "/srv/a");
+        let worker = worker_for(&event, 8);
+        assert!(worker < 8);
+        assert_eq!(worker_for(&event.clone(), 8), worker);
+    }
+}
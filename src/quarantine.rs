@@ -0,0 +1,573 @@
+//! Moving denied files aside
+//!
+//! A [`Quarantine`] keeps copies of files in a private directory together
+//! with what is known about them: where they came from, their SHA-256, when
+//! and why they were quarantined. The copy is made through the permission
+//! event's file descriptor, so it is exactly the content that was denied.
+//! The original can then be left alone, truncated or renamed (see
+//! [`Disposal`]), and a quarantined file can be [restored](Quarantine::restore)
+//! later.
+//!
+//! Truncating through the event needs event file descriptors opened for
+//! writing:
+//!
+//! ```no_run
+//! use fanotify_rs::quarantine::{Disposal, Quarantine};
+//! use fanotify_rs::scan::{Scanning, SignatureScanner};
+//! use fanotify_rs::{Fanotify, FanotifyFlags, MaskFlags};
+//!
+//! # fn main() -> fanotify_rs::Result<()> {
+//! let mut fanotify = Fanotify::with_event_flags(FanotifyFlags::CLASS_CONTENT, libc::O_RDWR)?;
+//! fanotify.add_mount_watch("/srv/uploads", MaskFlags::OPEN_PERM)?;
+//!
+//! let quarantine = Quarantine::open("/var/lib/fanotify/quarantine")?;
+//! let scanner = SignatureScanner::new().hex_signature("Dropper", "4d5a9000")?;
+//! fanotify.run(Scanning::new(scanner).with_quarantine(quarantine, Disposal::Truncate))?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Each quarantined file is stored as `<id>.bin` next to an `<id>.json`
+//! metadata file.
+
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::io::Write;
+use std::os::fd::{AsRawFd, BorrowedFd, RawFd};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+
+use crate::{
+    allowlist::Digest,
+    error::{FanotifyError, Result},
+    event::Event,
+    json::{path_from_hex, path_to_hex},
+    sys,
+};
+
+/// Tells apart concurrent copies into the quarantine
+static STAGING: AtomicU64 = AtomicU64::new(0);
+
+/// Suffix given to originals by [`Disposal::Rename`]
+pub const RENAMED_SUFFIX: &str = ".quarantined";
+
+/// What happens to the original file once it has been copied
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Disposal {
+    /// Leave it as it is
+    #[default]
+    Keep,
+    /// Truncate it to zero bytes through the event's file descriptor
+    ///
+    /// Needs a group created with `O_RDWR` event flags.
+    Truncate,
+    /// Rename it by appending [`RENAMED_SUFFIX`]
+    Rename,
+}
+
+impl Disposal {
+    fn name(self) -> &'static str {
+        match self {
+            Disposal::Keep => "keep",
+            Disposal::Truncate => "truncate",
+            Disposal::Rename => "rename",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "keep" => Some(Disposal::Keep),
+            "truncate" => Some(Disposal::Truncate),
+            "rename" => Some(Disposal::Rename),
+            _ => None,
+        }
+    }
+}
+
+/// A quarantined file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuarantineEntry {
+    /// Identifies the entry within its quarantine
+    pub id: String,
+    /// Where the file was found
+    pub original_path: Option<PathBuf>,
+    /// SHA-256 of the content
+    pub sha256: Digest,
+    /// Size of the content in bytes
+    pub size: u64,
+    /// Permission bits of the original
+    pub mode: u32,
+    /// When it was quarantined
+    pub time: DateTime<Utc>,
+    /// Why it was quarantined
+    pub reason: String,
+    /// What was done to the original
+    pub disposal: Disposal,
+}
+
+impl QuarantineEntry {
+    fn to_json(&self) -> String {
+        let original_path = self.original_path.as_deref();
+        let metadata = Metadata {
+            id: self.id.clone(),
+            original_path: original_path.map(|path| path.to_string_lossy().into_owned()),
+            // A lossy string cannot be restored to, so keep the raw bytes as well
+            original_path_bytes: original_path.filter(|path| path.to_str().is_none()).map(path_to_hex),
+            sha256: self.sha256.to_string(),
+            size: self.size,
+            mode: self.mode,
+            time: self.time,
+            reason: self.reason.clone(),
+            disposal: self.disposal.name().to_string(),
+        };
+
+        let mut out = serde_json::to_string(&metadata).expect("metadata is plain data");
+        out.push('\n');
+        out
+    }
+
+    fn from_json(text: &str) -> std::result::Result<Self, String> {
+        let metadata: Metadata = serde_json::from_str(text).map_err(|e| e.to_string())?;
+
+        Ok(Self {
+            id: metadata.id,
+            original_path: match metadata.original_path_bytes {
+                Some(hex) => Some(path_from_hex(&hex).ok_or("invalid original_path_bytes")?),
+                None => metadata.original_path.map(PathBuf::from),
+            },
+            sha256: metadata.sha256.parse().map_err(|_| "invalid sha256".to_string())?,
+            size: metadata.size,
+            mode: metadata.mode,
+            time: metadata.time,
+            reason: metadata.reason,
+            disposal: Disposal::from_name(&metadata.disposal).ok_or("invalid disposal")?,
+        })
+    }
+}
+
+/// The metadata file of an entry
+#[derive(Serialize, Deserialize)]
+struct Metadata {
+    id: String,
+    original_path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    original_path_bytes: Option<String>,
+    sha256: String,
+    size: u64,
+    mode: u32,
+    time: DateTime<Utc>,
+    reason: String,
+    disposal: String,
+}
+
+/// A directory of quarantined files
+#[derive(Debug, Clone)]
+pub struct Quarantine {
+    dir: PathBuf,
+}
+
+impl Quarantine {
+    /// Use the directory `dir`, creating it readable by its owner only if it
+    /// does not exist
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// The quarantine directory
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Quarantine the file behind the permission event `event`
+    pub fn quarantine(&self, event: &Event, reason: &str, disposal: Disposal) -> Result<QuarantineEntry> {
+        let fd = event.info.fd.ok_or_else(|| {
+            FanotifyError::invalid_event_data("Permission event has no file descriptor")
+        })?;
+        self.quarantine_fd(fd, event.info.path.as_deref(), reason, disposal)
+    }
+
+    /// Quarantine the file open as `fd`, found at `path`
+    pub fn quarantine_fd(
+        &self,
+        fd: RawFd,
+        path: Option<&Path>,
+        reason: &str,
+        disposal: Disposal,
+    ) -> Result<QuarantineEntry> {
+        // Check before copying so a misconfigured group fails without side effects
+        match disposal {
+            Disposal::Truncate if !sys::writable(fd)? => {
+                return Err(FanotifyError::invalid_flags(
+                    "truncating needs event file descriptors opened with O_RDWR, see Fanotify::with_event_flags",
+                ));
+            }
+            Disposal::Rename if path.is_none() => {
+                return Err(FanotifyError::invalid_event_data("Cannot rename a file without a path"));
+            }
+            _ => {}
+        }
+
+        let time = Utc::now();
+        let stat = sys::fstat(fd)?;
+
+        let staging = self.dir.join(format!(
+            ".incoming-{}-{}",
+            std::process::id(),
+            STAGING.fetch_add(1, Ordering::Relaxed)
+        ));
+        let (sha256, size) = copy_out(unsafe { BorrowedFd::borrow_raw(fd) }, &staging)?;
+        let id = format!("{}-{}", time.format("%Y%m%dT%H%M%S%.9fZ"), &sha256.to_string()[..16]);
+        if let Err(err) = std::fs::rename(&staging, self.content_path(&id)) {
+            let _ = std::fs::remove_file(&staging);
+            return Err(err.into());
+        }
+
+        let entry = QuarantineEntry {
+            id,
+            original_path: path.map(Path::to_path_buf),
+            sha256,
+            size,
+            mode: stat.st_mode & 0o7777,
+            time,
+            reason: reason.to_string(),
+            disposal,
+        };
+        let result = write_private(&self.metadata_path(&entry.id), entry.to_json().as_bytes())
+            .and_then(|()| dispose(fd, path, &stat, disposal));
+        if let Err(err) = result {
+            // Leave no half-made entry behind for a file that was not disposed of
+            let _ = std::fs::remove_file(self.content_path(&entry.id));
+            let _ = std::fs::remove_file(self.metadata_path(&entry.id));
+            return Err(err);
+        }
+
+        log::warn!("quarantined {:?} as {}: {}", entry.original_path, entry.id, reason);
+        Ok(entry)
+    }
+
+    /// Every quarantined file, oldest first
+    pub fn list(&self) -> Result<Vec<QuarantineEntry>> {
+        let mut entries = Vec::new();
+
+        for dirent in std::fs::read_dir(&self.dir)? {
+            let path = dirent?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                let text = std::fs::read_to_string(&path)?;
+                let entry = QuarantineEntry::from_json(&text).map_err(|message| {
+                    FanotifyError::invalid_quarantine(format!("{}: {}", path.display(), message))
+                })?;
+                entries.push(entry);
+            }
+        }
+
+        entries.sort_by(|a, b| a.time.cmp(&b.time).then_with(|| a.id.cmp(&b.id)));
+        Ok(entries)
+    }
+
+    /// The entry `id`
+    pub fn get(&self, id: &str) -> Result<QuarantineEntry> {
+        check_id(id)?;
+        let path = self.metadata_path(id);
+        let text = std::fs::read_to_string(&path)?;
+        QuarantineEntry::from_json(&text)
+            .map_err(|message| FanotifyError::invalid_quarantine(format!("{}: {}", path.display(), message)))
+    }
+
+    /// Put the quarantined file `id` back and drop it from the quarantine
+    ///
+    /// The content is written to `to`, or to its original path if `to` is
+    /// `None`, replacing whatever is there. Its hash is checked first, so a
+    /// copy that was altered in quarantine is not restored. Returns the
+    /// path written.
+    ///
+    /// Restoring into a watched location triggers permission events like
+    /// any other write.
+    pub fn restore(&self, id: &str, to: Option<&Path>) -> Result<PathBuf> {
+        let entry = self.get(id)?;
+        let target = match to.or(entry.original_path.as_deref()) {
+            Some(target) => target.to_path_buf(),
+            None => {
+                return Err(FanotifyError::invalid_path(format!("quarantine entry {} has no original path", id)))
+            }
+        };
+
+        let content = std::fs::read(self.content_path(id))?;
+        if Digest::of_bytes(&content) != entry.sha256 {
+            return Err(FanotifyError::invalid_quarantine(format!(
+                "quarantined copy of {} does not match its hash",
+                id
+            )));
+        }
+
+        let mut staging = target.as_os_str().to_os_string();
+        staging.push(".restoring");
+        let staging = PathBuf::from(staging);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(entry.mode)
+            .open(&staging)?;
+        file.write_all(&content)?;
+        file.set_permissions(std::fs::Permissions::from_mode(entry.mode))?;
+        file.sync_all()?;
+        std::fs::rename(&staging, &target)?;
+
+        self.delete(id)?;
+        Ok(target)
+    }
+
+    /// Drop the quarantined file `id` for good
+    pub fn delete(&self, id: &str) -> Result<()> {
+        check_id(id)?;
+        std::fs::remove_file(self.content_path(id))?;
+        std::fs::remove_file(self.metadata_path(id))?;
+        Ok(())
+    }
+
+    fn content_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.bin", id))
+    }
+
+    fn metadata_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+}
+
+/// Reject ids that would name a file outside the quarantine directory
+fn check_id(id: &str) -> Result<()> {
+    if id.is_empty() || id.starts_with('.') || id.contains('/') {
+        return Err(FanotifyError::invalid_quarantine(format!("invalid entry id {:?}", id)));
+    }
+    Ok(())
+}
+
+/// Do `disposal` to the original open as `fd`, found at `path`
+fn dispose(fd: RawFd, path: Option<&Path>, stat: &libc::stat, disposal: Disposal) -> Result<()> {
+    match disposal {
+        Disposal::Keep => {}
+        Disposal::Truncate => {
+            if unsafe { libc::ftruncate(fd, 0) } < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+        }
+        Disposal::Rename => {
+            let path = path.expect("checked by quarantine_fd");
+            // The path comes from /proc and may name another file by now
+            let current = std::fs::symlink_metadata(path)?;
+            if (current.dev(), current.ino()) != (stat.st_dev, stat.st_ino) {
+                return Err(FanotifyError::invalid_event_data(format!(
+                    "{} no longer names the quarantined file",
+                    path.display()
+                )));
+            }
+            let mut renamed = path.as_os_str().to_os_string();
+            renamed.push(RENAMED_SUFFIX);
+            std::fs::rename(path, renamed)?;
+        }
+    }
+    Ok(())
+}
+
+/// Create `path` readable by its owner only and write `data` to it
+fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Copy the content behind `fd` to `path`, hashing it on the way
+fn copy_out(fd: BorrowedFd<'_>, path: &Path) -> Result<(Digest, u64)> {
+    let mut file: File = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+    let mut hasher = Sha256::new();
+
+    let copied = sys::read_chunks(fd.as_raw_fd(), 64 * 1024, |chunk| {
+        hasher.update(chunk);
+        file.write_all(chunk)?;
+        Ok(true)
+    })
+    .and_then(|size| file.sync_all().map(|()| size));
+
+    match copied {
+        Ok(size) => Ok((Digest::from_bytes(hasher.finalize().into()), size)),
+        Err(e) => {
+            let _ = std::fs::remove_file(path);
+            Err(e.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan::{Scanning, SignatureScanner};
+    use std::os::unix::ffi::OsStrExt;
+    use crate::test_support::{decide_next, open_in_thread};
+    use crate::{Fanotify, FanotifyFlags, MaskFlags};
+    use tempfile::tempdir;
+
+    const MARKER: &[u8] = b"X5O!P%@AP-QUARANTINE-TEST";
+
+    #[test]
+    fn test_quarantine_rename_and_restore() {
+        let temp_dir = tempdir().unwrap();
+        let quarantine = Quarantine::open(temp_dir.path().join("q")).unwrap();
+        let original = temp_dir.path().join("bad file.txt");
+        std::fs::write(&original, "bad content").unwrap();
+        std::fs::set_permissions(&original, std::fs::Permissions::from_mode(0o640)).unwrap();
+
+        let file = File::open(&original).unwrap();
+        let entry = quarantine
+            .quarantine_fd(file.as_raw_fd(), Some(&original), "test \"reason\"", Disposal::Rename)
+            .unwrap();
+        drop(file);
+
+        assert!(!original.exists());
+        let mut renamed = original.as_os_str().to_os_string();
+        renamed.push(RENAMED_SUFFIX);
+        assert!(Path::new(&renamed).exists());
+
+        assert_eq!(entry.sha256, Digest::of_bytes(b"bad content"));
+        assert_eq!((entry.size, entry.mode), (11, 0o640));
+        assert_eq!(quarantine.list().unwrap(), vec![entry.clone()]);
+
+        // Truncating needs a writable descriptor
+        let file = File::open(&renamed).unwrap();
+        let err = quarantine.quarantine_fd(file.as_raw_fd(), None, "x", Disposal::Truncate).unwrap_err();
+        assert!(err.to_string().contains("O_RDWR"), "{}", err);
+
+        let restored = quarantine.restore(&entry.id, None).unwrap();
+        assert_eq!(restored, original);
+        assert_eq!(std::fs::read(&original).unwrap(), b"bad content");
+        assert_eq!(std::fs::metadata(&original).unwrap().permissions().mode() & 0o7777, 0o640);
+        assert!(quarantine.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_tampered_copy_is_not_restored() {
+        let temp_dir = tempdir().unwrap();
+        let quarantine = Quarantine::open(temp_dir.path().join("q")).unwrap();
+        let original = temp_dir.path().join("file.txt");
+        std::fs::write(&original, "content").unwrap();
+
+        let file = File::open(&original).unwrap();
+        let entry = quarantine.quarantine_fd(file.as_raw_fd(), Some(&original), "x", Disposal::Keep).unwrap();
+        std::fs::write(quarantine.content_path(&entry.id), "altered").unwrap();
+
+        let result = quarantine.restore(&entry.id, None);
+        assert!(matches!(result, Err(FanotifyError::InvalidQuarantine { .. })));
+        assert_eq!(quarantine.list().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_ids_stay_inside_the_quarantine() {
+        let temp_dir = tempdir().unwrap();
+        let quarantine = Quarantine::open(temp_dir.path().join("q")).unwrap();
+        let outside = temp_dir.path().join("outside");
+        std::fs::write(outside.with_extension("json"), "{}").unwrap();
+        std::fs::write(outside.with_extension("bin"), "keep").unwrap();
+
+        for id in ["../outside", ".hidden", "", "a/b"] {
+            assert!(matches!(quarantine.get(id), Err(FanotifyError::InvalidQuarantine { .. })));
+            assert!(matches!(quarantine.restore(id, None), Err(FanotifyError::InvalidQuarantine { .. })));
+            assert!(matches!(quarantine.delete(id), Err(FanotifyError::InvalidQuarantine { .. })));
+        }
+        assert!(outside.with_extension("bin").exists());
+    }
+
+    #[test]
+    fn test_rename_refuses_a_swapped_path() {
+        let temp_dir = tempdir().unwrap();
+        let quarantine = Quarantine::open(temp_dir.path().join("q")).unwrap();
+        let original = temp_dir.path().join("file.txt");
+        std::fs::write(&original, "bad content").unwrap();
+
+        let file = File::open(&original).unwrap();
+        std::fs::rename(&original, temp_dir.path().join("moved.txt")).unwrap();
+        std::fs::write(&original, "innocent").unwrap();
+
+        let err = quarantine
+            .quarantine_fd(file.as_raw_fd(), Some(&original), "x", Disposal::Rename)
+            .unwrap_err();
+        assert!(err.to_string().contains("no longer names"), "{}", err);
+        assert_eq!(std::fs::read(&original).unwrap(), b"innocent");
+        assert_eq!(std::fs::read_dir(quarantine.dir()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_non_utf8_original_path_is_kept() {
+        let temp_dir = tempdir().unwrap();
+        let quarantine = Quarantine::open(temp_dir.path().join("q")).unwrap();
+        let original = temp_dir.path().join(std::ffi::OsStr::from_bytes(b"caf\xe9.txt"));
+        std::fs::write(&original, "content").unwrap();
+
+        let file = File::open(&original).unwrap();
+        let entry = quarantine.quarantine_fd(file.as_raw_fd(), Some(&original), "x", Disposal::Keep).unwrap();
+        assert_eq!(quarantine.get(&entry.id).unwrap().original_path.as_deref(), Some(original.as_path()));
+
+        std::fs::remove_file(&original).unwrap();
+        assert_eq!(quarantine.restore(&entry.id, None).unwrap(), original);
+        assert_eq!(std::fs::read(&original).unwrap(), b"content");
+    }
+
+    #[test]
+    fn test_scanner_quarantines_infected_file() {
+        let temp_dir = tempdir().unwrap();
+        let infected = temp_dir.path().join("infected.bin");
+        std::fs::write(&infected, [b"payload ", MARKER].concat()).unwrap();
+
+        let mut fanotify = Fanotify::with_event_flags(FanotifyFlags::CLASS_CONTENT, libc::O_RDWR).unwrap();
+        fanotify.add_watch(&infected, MaskFlags::OPEN_PERM).unwrap();
+
+        let quarantine = Quarantine::open(temp_dir.path().join("q")).unwrap();
+        let mut handler = Scanning::new(SignatureScanner::new().signature("Test-Marker", MARKER))
+            .with_quarantine(quarantine.clone(), Disposal::Truncate);
+
+        let opener = open_in_thread(&infected);
+        decide_next(&mut fanotify, &mut handler);
+        assert!(opener.join().unwrap().is_err());
+
+        fanotify.remove_watch(&infected).unwrap();
+        assert_eq!(std::fs::metadata(&infected).unwrap().len(), 0);
+
+        let entries = quarantine.list().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].original_path.as_deref(), Some(infected.as_path()));
+        assert!(entries[0].reason.contains("Test-Marker"), "{}", entries[0].reason);
+
+        let elsewhere = temp_dir.path().join("restored.bin");
+        quarantine.restore(&entries[0].id, Some(&elsewhere)).unwrap();
+        assert_eq!(std::fs::read(&elsewhere).unwrap(), [b"payload ", MARKER].concat());
+    }
+
+    #[test]
+    fn test_cached_infected_verdict_is_not_quarantined_again() {
+        let temp_dir = tempdir().unwrap();
+        let infected = temp_dir.path().join("infected.bin");
+        std::fs::write(&infected, [b"payload ", MARKER].concat()).unwrap();
+
+        let mut fanotify = Fanotify::with_flags(FanotifyFlags::CLASS_CONTENT).unwrap();
+        fanotify.add_watch(&infected, MaskFlags::OPEN_PERM).unwrap();
+
+        let quarantine = Quarantine::open(temp_dir.path().join("q")).unwrap();
+        let mut handler = Scanning::new(SignatureScanner::new().signature("Test-Marker", MARKER))
+            .with_quarantine(quarantine.clone(), Disposal::Keep);
+
+        for _ in 0..2 {
+            let opener = open_in_thread(&infected);
+            decide_next(&mut fanotify, &mut handler);
+            assert!(opener.join().unwrap().is_err());
+        }
+
+        fanotify.remove_watch(&infected).unwrap();
+        assert_eq!(handler.stats().cache_hits, 1);
+        assert_eq!(handler.stats().quarantined, 1);
+        assert_eq!(quarantine.list().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,243 @@
+//! Extended permission responses
+//!
+//! Besides allowing or denying, a response can ask the kernel to log the
+//! decision to the audit subsystem (`FAN_AUDIT`) and attach the number of
+//! the rule that made it (`FAN_INFO` with a `fanotify_response_info_audit_rule`
+//! record), so the decision shows up in auditd next to the matching rule.
+//!
+//! ```
+//! use fanotify_rs::response::{Response, Trust};
+//!
+//! let response = Response::deny()
+//!     .audit_rule(12)
+//!     .trust(Trust::No, Trust::Unknown);
+//! ```
+//!
+//! Audited responses need a group created with
+//! [`FanotifyFlags::ENABLE_AUDIT`](crate::FanotifyFlags::ENABLE_AUDIT); the
+//! kernel rejects them otherwise. Information records need Linux 6.3 or
+//! newer.
+
+use std::mem::size_of;
+use std::os::fd::RawFd;
+
+use crate::{
+    flags::EventFlags,
+    handler::Decision,
+    linux::{
+        fanotify_response, fanotify_response_info_audit_rule, fanotify_response_info_header,
+        FAN_RESPONSE_INFO_AUDIT_RULE,
+    },
+};
+
+/// Whether the subject or object of an audited decision is trusted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Trust {
+    /// Not trusted
+    No = 0,
+    /// Trusted
+    Yes = 1,
+    /// Trust is not known
+    #[default]
+    Unknown = 2,
+}
+
+/// The rule reported to the audit subsystem with a decision
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AuditRule {
+    /// Number of the rule that made the decision
+    pub rule_number: u32,
+    /// Trust in the process making the access
+    pub subject_trust: Trust,
+    /// Trust in the file being accessed
+    pub object_trust: Trust,
+}
+
+impl AuditRule {
+    /// A rule record for `rule_number`, with unknown trust
+    pub fn new(rule_number: u32) -> Self {
+        Self {
+            rule_number,
+            subject_trust: Trust::Unknown,
+            object_trust: Trust::Unknown,
+        }
+    }
+}
+
+/// A complete answer to a permission event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Response {
+    flags: EventFlags,
+    audit_rule: Option<AuditRule>,
+    rule: Option<u32>,
+}
+
+impl Response {
+    /// A response with the raw response flags `flags`
+    pub fn new(flags: EventFlags) -> Self {
+        Self {
+            flags,
+            audit_rule: None,
+            rule: None,
+        }
+    }
+
+    /// Let the operation proceed
+    pub fn allow() -> Self {
+        Self::new(EventFlags::ALLOW)
+    }
+
+    /// Fail the operation with `EPERM`
+    pub fn deny() -> Self {
+        Self::new(EventFlags::DENY)
+    }
+
+    /// Log the decision to the audit subsystem
+    pub fn audit(mut self) -> Self {
+        self.flags |= EventFlags::AUDIT;
+        self
+    }
+
+    /// Log the decision together with the rule that made it
+    pub fn audit_rule(mut self, rule_number: u32) -> Self {
+        self.flags |= EventFlags::AUDIT | EventFlags::INFO;
+        self.audit_rule = Some(AuditRule::new(rule_number));
+        self
+    }
+
+    /// Set the trust reported with the audit rule
+    ///
+    /// Has no effect unless [`audit_rule`](Self::audit_rule) was called.
+    pub fn trust(mut self, subject: Trust, object: Trust) -> Self {
+        if let Some(rule) = &mut self.audit_rule {
+            rule.subject_trust = subject;
+            rule.object_trust = object;
+        }
+        self
+    }
+
+    /// Record the number of the rule behind the response in the
+    /// [decision log](crate::decision_log)
+    ///
+    /// Unlike [`audit_rule`](Self::audit_rule) this is not sent to the kernel.
+    pub fn rule(mut self, rule_number: u32) -> Self {
+        self.rule = Some(rule_number);
+        self
+    }
+
+    /// The number of the rule behind the response, if known
+    pub fn rule_number(&self) -> Option<u32> {
+        self.rule.or(self.audit_rule.map(|rule| rule.rule_number))
+    }
+
+    /// The response flags
+    pub fn flags(&self) -> EventFlags {
+        self.flags
+    }
+
+    /// The audit rule record, if any
+    pub fn audit_rule_info(&self) -> Option<&AuditRule> {
+        self.audit_rule.as_ref()
+    }
+
+    /// Serialise the response for the event file descriptor `event_fd`
+    ///
+    /// This is the buffer written to the group: a `fanotify_response`
+    /// followed by the information record, if any.
+    pub fn to_bytes(&self, event_fd: RawFd) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(
+            size_of::<fanotify_response>() + size_of::<fanotify_response_info_audit_rule>(),
+        );
+
+        let response = fanotify_response {
+            fd: event_fd,
+            response: self.flags.bits(),
+        };
+        bytes.extend_from_slice(&response.fd.to_ne_bytes());
+        bytes.extend_from_slice(&response.response.to_ne_bytes());
+
+        if let Some(rule) = &self.audit_rule {
+            let record = fanotify_response_info_audit_rule {
+                hdr: fanotify_response_info_header {
+                    type_: FAN_RESPONSE_INFO_AUDIT_RULE,
+                    pad: 0,
+                    len: size_of::<fanotify_response_info_audit_rule>() as u16,
+                },
+                rule_number: rule.rule_number,
+                subj_trust: rule.subject_trust as u32,
+                obj_trust: rule.object_trust as u32,
+            };
+            bytes.push(record.hdr.type_);
+            bytes.push(record.hdr.pad);
+            bytes.extend_from_slice(&record.hdr.len.to_ne_bytes());
+            bytes.extend_from_slice(&record.rule_number.to_ne_bytes());
+            bytes.extend_from_slice(&record.subj_trust.to_ne_bytes());
+            bytes.extend_from_slice(&record.obj_trust.to_ne_bytes());
+        }
+
+        bytes
+    }
+}
+
+impl Default for Response {
+    fn default() -> Self {
+        Self::allow()
+    }
+}
+
+impl From<EventFlags> for Response {
+    fn from(flags: EventFlags) -> Self {
+        Self::new(flags)
+    }
+}
+
+impl From<Decision> for Response {
+    fn from(decision: Decision) -> Self {
+        Self::new(decision.response())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_denied, next_request, open_in_thread};
+    use crate::{Fanotify, FanotifyFlags, MaskFlags};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_serialise_plain_response() {
+        let bytes = Response::allow().to_bytes(7);
+        assert_eq!(bytes.len(), size_of::<fanotify_response>());
+        assert_eq!(&bytes[..4], &7i32.to_ne_bytes());
+        assert_eq!(&bytes[4..], &EventFlags::ALLOW.bits().to_ne_bytes());
+    }
+
+    #[test]
+    fn test_serialise_audit_rule() {
+        let response = Response::deny().audit_rule(42).trust(Trust::Yes, Trust::No);
+        assert_eq!(response.flags(), EventFlags::DENY | EventFlags::AUDIT | EventFlags::INFO);
+
+        let bytes = response.to_bytes(3);
+        assert_eq!(bytes.len(), 8 + 16);
+        assert_eq!(bytes[8], FAN_RESPONSE_INFO_AUDIT_RULE);
+        assert_eq!(&bytes[10..12], &16u16.to_ne_bytes());
+        assert_eq!(&bytes[12..16], &42u32.to_ne_bytes());
+        assert_eq!(&bytes[16..20], &1u32.to_ne_bytes());
+        assert_eq!(&bytes[20..24], &0u32.to_ne_bytes());
+    }
+
+    #[test]
+    fn test_audited_deny() {
+        let flags = FanotifyFlags::CLASS_CONTENT | FanotifyFlags::ENABLE_AUDIT;
+        let mut fanotify = Fanotify::with_flags(flags).unwrap();
+        let temp_dir = tempdir().unwrap();
+        let test_file = temp_dir.path().join("audited.txt");
+        std::fs::write(&test_file, "audited").unwrap();
+        fanotify.add_watch(&test_file, MaskFlags::OPEN_PERM).unwrap();
+
+        let opener = open_in_thread(&test_file);
+        let request = next_request(&mut fanotify);
+        request.respond_with(Response::deny().audit_rule(1)).unwrap();
+        assert_denied(opener);
+    }
+}
@@ -0,0 +1,597 @@
+//! Confining a process subtree to a set of directories
+//!
+//! A [`Sandbox`] answers `OPEN_PERM` and `OPEN_EXEC_PERM` events. Processes
+//! in the confined subtree, a root process and all of its descendants, may
+//! only open files below the allowed roots; every other process is allowed.
+//! Descendants are found by following `/proc/<pid>/stat` parent links up to
+//! the root, checking start times so a recycled pid is not mistaken for a
+//! member. The answer is cached either way, so each process is looked up
+//! once.
+//!
+//! [`Sandbox::launch`] starts a command confined from its first `exec` and
+//! answers events on a background thread until it and everything it started
+//! exit, which makes for hermetic build checks:
+//!
+//! ```no_run
+//! use std::process::Command;
+//! use fanotify_rs::sandbox::Sandbox;
+//!
+//! # fn main() -> fanotify_rs::Result<()> {
+//! let mut fanotify = Sandbox::group()?;
+//! Sandbox::watch(&mut fanotify, "/")?;
+//!
+//! let sandbox = Sandbox::new()
+//!     .allow("/usr")
+//!     .allow("/lib")
+//!     .allow("/etc/ld.so.cache")
+//!     .allow("/src/project");
+//!
+//! let mut make = Command::new("make");
+//! make.current_dir("/src/project");
+//! let (status, sandbox) = sandbox.launch(fanotify, make)?.wait()?;
+//! for access in sandbox.violations() {
+//!     println!("{:?} opened {:?}", access.exe, access.path);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A launched command runs below a small reaper process, which is the root
+//! of the subtree and a child subreaper (see `PR_SET_CHILD_SUBREAPER` in
+//! `prctl(2)`): descendants whose parents exit, such as double-forked
+//! daemons, are reparented to it and stay confined. With
+//! [`Sandbox::confine`] there is no such reaper, so descendants of a
+//! process that exits before them leave the subtree unless the root is a
+//! subreaper itself.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::{
+    dry_run::DeniedAccess,
+    error::{FanotifyError, Result},
+    event::Event,
+    fanotify::Fanotify,
+    flags::{FanotifyFlags, MaskFlags},
+    handler::{Decision, Handler},
+    linux::errno,
+    policy::{EventKind, PathPattern, ProcessInfo},
+    sys,
+};
+
+/// Violations kept by a [`Sandbox`]; later ones are only counted
+const MAX_VIOLATIONS: usize = 1024;
+
+/// How often the launch thread checks whether it should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The process whose subtree is confined
+enum Root {
+    /// Nothing is confined
+    None,
+    /// A launched child reports its pid through this pipe before `exec`
+    Pending(File),
+    /// The root process and its start time
+    Process { pid: u32, start: u64 },
+}
+
+/// Counters kept by a [`Sandbox`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SandboxStats {
+    /// Accesses by confined processes that were allowed
+    pub allowed: u64,
+    /// Accesses by confined processes that were denied
+    pub denied: u64,
+    /// Accesses by processes outside the subtree
+    pub unconfined: u64,
+}
+
+/// A [`Handler`] confining a process subtree to allowed directories
+pub struct Sandbox {
+    allowed: Vec<PathPattern>,
+    root: Root,
+    /// Known members of the subtree and their start times
+    members: HashMap<u32, u64>,
+    /// Processes known to be outside the subtree and their start times
+    outsiders: HashMap<u32, u64>,
+    violations: Vec<DeniedAccess>,
+    stats: SandboxStats,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sandbox {
+    /// A sandbox that allows nothing and confines nobody yet
+    pub fn new() -> Self {
+        Self {
+            allowed: Vec::new(),
+            root: Root::None,
+            members: HashMap::new(),
+            outsiders: HashMap::new(),
+            violations: Vec::new(),
+            stats: SandboxStats::default(),
+        }
+    }
+
+    /// Allow `path` and everything below it
+    pub fn allow<P: AsRef<Path>>(self, path: P) -> Self {
+        self.allow_pattern(PathPattern::prefix(path))
+    }
+
+    /// Allow the paths matching `pattern`
+    pub fn allow_pattern(mut self, pattern: PathPattern) -> Self {
+        self.allowed.push(pattern);
+        self
+    }
+
+    /// Create a group for [`launch`](Self::launch)
+    ///
+    /// The group is `CLASS_CONTENT` and its event file descriptors are
+    /// opened `O_CLOEXEC`, so a launched command cannot inherit them.
+    pub fn group() -> Result<Fanotify> {
+        Fanotify::with_event_flags(
+            FanotifyFlags::CLASS_CONTENT | FanotifyFlags::CLOEXEC,
+            libc::O_RDONLY | libc::O_CLOEXEC,
+        )
+    }
+
+    /// Mark the mount containing `path` for `OPEN_PERM` and `OPEN_EXEC_PERM`
+    ///
+    /// Only files on marked mounts are confined. The group must be created
+    /// with `CLASS_CONTENT` or `CLASS_PRE_CONTENT`.
+    pub fn watch<P: AsRef<Path>>(fanotify: &mut Fanotify, path: P) -> Result<()> {
+        fanotify.add_mount_watch(path, MaskFlags::OPEN_PERM | MaskFlags::OPEN_EXEC_PERM)
+    }
+
+    /// Confine the running process `pid` and its descendants
+    pub fn confine(&mut self, pid: u32) -> Result<()> {
+        let (_, start) = proc_stat(pid).ok_or_else(|| FanotifyError::from(libc::ESRCH))?;
+        self.root = Root::Process { pid, start };
+        self.members.clear();
+        self.outsiders.clear();
+        Ok(())
+    }
+
+    /// The root of the confined subtree, once known
+    pub fn root(&self) -> Option<u32> {
+        match self.root {
+            Root::Process { pid, .. } => Some(pid),
+            _ => None,
+        }
+    }
+
+    /// Whether `pid` is the root or one of its descendants
+    pub fn contains(&mut self, pid: u32) -> bool {
+        self.resolve_root();
+        let Root::Process { pid: root, start: root_start } = self.root else {
+            return false;
+        };
+
+        let mut chain = Vec::new();
+        let mut current = pid;
+        loop {
+            let Some((ppid, start)) = proc_stat(current) else {
+                return false;
+            };
+            if (current == root && start == root_start) || self.members.get(&current) == Some(&start) {
+                self.members.extend(chain);
+                return true;
+            }
+            // Processes never move into the subtree, so this answer holds too
+            if current <= 1 || self.outsiders.get(&current) == Some(&start) {
+                chain.push((current, start));
+                self.outsiders.extend(chain);
+                return false;
+            }
+            chain.push((current, start));
+            current = ppid;
+        }
+    }
+
+    /// The accesses that were denied, up to the first 1024
+    pub fn violations(&self) -> &[DeniedAccess] {
+        &self.violations
+    }
+
+    /// The counters
+    pub fn stats(&self) -> SandboxStats {
+        self.stats
+    }
+
+    /// Start `command` confined, answering events on a background thread
+    ///
+    /// The thread answers the events of `fanotify`, which should already be
+    /// marked (see [`watch`](Self::watch)), until the child is waited for.
+    /// The child reports its pid and stays behind as the subtree's reaper
+    /// while a process of its own executes the command, so the command is
+    /// confined from its first access on and orphaned descendants remain
+    /// confined.
+    ///
+    /// The command must not get hold of the group, or it could answer its
+    /// own events: the group is marked close-on-exec here, and event file
+    /// descriptors must be opened `O_CLOEXEC` (see [`group`](Self::group)),
+    /// failing with [`FanotifyError::InvalidFlags`] otherwise.
+    pub fn launch(mut self, fanotify: Fanotify, mut command: Command) -> Result<SandboxedChild> {
+        if sys::group_flags(fanotify.as_raw_fd())?.1 & libc::O_CLOEXEC as u32 == 0 {
+            return Err(FanotifyError::invalid_flags(
+                "launching needs event file descriptors opened with O_CLOEXEC",
+            ));
+        }
+        sys::set_cloexec(fanotify.as_raw_fd())?;
+
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } < 0 {
+            return Err(FanotifyError::from(errno()));
+        }
+        // SAFETY: pipe2 returned two new descriptors that nobody else owns
+        let (reader, writer) = unsafe { (File::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        self.root = Root::Pending(reader);
+        self.members.clear();
+        self.outsiders.clear();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::Builder::new()
+            .name("fanotify-sandbox".to_string())
+            .spawn(move || serve(fanotify, self, &thread_stop))?;
+
+        let raw_writer = writer.as_raw_fd();
+        // SAFETY: only async-signal-safe calls happen between fork and exec
+        unsafe {
+            command.pre_exec(move || {
+                let pid = (libc::getpid() as u32).to_ne_bytes();
+                libc::write(raw_writer, pid.as_ptr() as *const libc::c_void, pid.len());
+
+                // Orphans in the subtree are reparented to this process
+                // instead of leaving it, and a process group lets Drop kill
+                // them all
+                if libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) < 0 || libc::setpgid(0, 0) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                match libc::fork() {
+                    -1 => Err(std::io::Error::last_os_error()),
+                    0 => {
+                        // The command goes down with its reaper
+                        libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL, 0, 0, 0);
+                        Ok(())
+                    }
+                    command => reap(command),
+                }
+            });
+        }
+
+        let spawned = command.spawn();
+        drop(writer);
+
+        let mut handle = SandboxedChild {
+            child: None,
+            stop,
+            thread: Some(thread),
+        };
+        match spawned {
+            Ok(child) => {
+                handle.child = Some(child);
+                Ok(handle)
+            }
+            Err(e) => {
+                handle.finish()?;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Take the root's pid from the launch pipe once the child wrote it
+    fn resolve_root(&mut self) {
+        let Root::Pending(reader) = &mut self.root else {
+            return;
+        };
+
+        let mut pid = [0u8; 4];
+        if reader.read_exact(&mut pid).is_ok() {
+            let pid = u32::from_ne_bytes(pid);
+            if let Err(e) = self.confine(pid) {
+                log::warn!("launched process {} is gone: {}", pid, e);
+            }
+        }
+    }
+}
+
+impl Handler for Sandbox {
+    fn on_event(&mut self, _event: &Event) {}
+
+    fn on_permission(&mut self, event: &Event) -> Decision {
+        if !self.contains(event.info.pid) {
+            self.stats.unconfined += 1;
+            return Decision::Allow;
+        }
+
+        let allowed = event
+            .info
+            .path
+            .as_deref()
+            .is_some_and(|path| self.allowed.iter().any(|pattern| pattern.matches(path)));
+        if allowed {
+            self.stats.allowed += 1;
+            return Decision::Allow;
+        }
+
+        self.stats.denied += 1;
+        let process = ProcessInfo::new(event.info.pid);
+        let access = DeniedAccess {
+            pid: event.info.pid,
+            exe: process.exe().map(PathBuf::from),
+            kind: EventKind::of(event),
+            path: event.info.path.clone(),
+        };
+        log::info!("sandbox denied {:?} opening {:?}", access.exe, access.path);
+        if self.violations.len() < MAX_VIOLATIONS {
+            self.violations.push(access);
+        }
+        Decision::Deny
+    }
+}
+
+/// A child started by [`Sandbox::launch`]
+///
+/// Dropping the handle kills the child, so it never runs unconfined.
+pub struct SandboxedChild {
+    child: Option<Child>,
+    stop: Arc<AtomicBool>,
+    /// The thread answering events, which hands the sandbox back
+    thread: Option<JoinHandle<Result<Sandbox>>>,
+}
+
+impl SandboxedChild {
+    /// The child's pid
+    pub fn id(&self) -> u32 {
+        self.child.as_ref().expect("child is running").id()
+    }
+
+    /// Wait for the child and all of its descendants to exit, then stop
+    /// answering events
+    ///
+    /// Returns the child's exit status and the sandbox with what it saw.
+    pub fn wait(mut self) -> Result<(ExitStatus, Sandbox)> {
+        let status = self.child.take().expect("child is running").wait();
+        let sandbox = self.finish();
+        Ok((status?, sandbox?))
+    }
+
+    /// Stop the event thread and shut its group down
+    fn finish(&mut self) -> Result<Sandbox> {
+        self.stop.store(true, Ordering::Release);
+        self.thread
+            .take()
+            .expect("sandbox thread is running")
+            .join()
+            .map_err(|_| FanotifyError::invalid_event_data("Sandbox thread panicked"))?
+    }
+}
+
+impl Drop for SandboxedChild {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            // The reaper leads the subtree's process group
+            unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+            let _ = child.wait();
+        }
+        if self.thread.is_some() {
+            let _ = self.finish();
+        }
+    }
+}
+
+/// Answer the events of `fanotify` with `sandbox` until `stop` is set
+///
+/// The group is shut down afterwards, allowing whatever is still queued.
+fn serve(mut fanotify: Fanotify, mut sandbox: Sandbox, stop: &AtomicBool) -> Result<Sandbox> {
+    let mut result = Ok(());
+
+    while result.is_ok() && !stop.load(Ordering::Acquire) {
+        result = match fanotify.poll_readable(Some(POLL_INTERVAL)) {
+            Ok(false) => Ok(()),
+            Ok(true) => fanotify
+                .read_available()
+                .and_then(|events| fanotify.dispatch(&mut sandbox, events)),
+            Err(e) => Err(e),
+        };
+    }
+
+    let shutdown = fanotify.shutdown(Decision::Allow);
+    result.and(shutdown).map(|()| sandbox)
+}
+
+/// Stay behind as the reaper of a launched subtree whose command is the
+/// process `command`
+///
+/// Waits for every descendant, then exits the way the command did. Runs
+/// between `fork` and `exec`, so it may only make async-signal-safe calls.
+unsafe fn reap(command: libc::pid_t) -> ! {
+    // Let Command::spawn see the command's exec rather than wait for us, and
+    // keep no pipes of the command's open
+    libc::syscall(libc::SYS_close_range, 0, libc::c_uint::MAX, 0);
+
+    let mut result = 0;
+    loop {
+        let mut status = 0;
+        let pid = libc::waitpid(-1, &mut status, 0);
+        if pid == command {
+            result = status;
+        } else if pid < 0 && errno() != libc::EINTR {
+            break;
+        }
+    }
+
+    if libc::WIFSIGNALED(result) {
+        let signal = libc::WTERMSIG(result);
+        libc::signal(signal, libc::SIG_DFL);
+        libc::kill(libc::getpid(), signal);
+    }
+    libc::_exit(libc::WEXITSTATUS(result))
+}
+
+/// The parent pid and start time of `pid`, from `/proc/<pid>/stat`
+fn proc_stat(pid: u32) -> Option<(u32, u64)> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name may contain anything, including ") ", so split after
+    // its last closing parenthesis
+    let (_, rest) = stat.rsplit_once(')')?;
+    let fields: Vec<&str> = rest.split_whitespace().collect();
+    let ppid = fields.get(1)?.parse().ok()?;
+    let start = fields.get(19)?.parse().ok()?;
+    Some((ppid, start))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_contains_descendants() {
+        let mut sleeper = Command::new("sleep").arg("5").spawn().unwrap();
+
+        let mut sandbox = Sandbox::new();
+        assert!(!sandbox.contains(std::process::id()));
+
+        sandbox.confine(std::process::id()).unwrap();
+        assert!(sandbox.contains(std::process::id()));
+        assert!(sandbox.contains(sleeper.id()));
+        assert!(!sandbox.contains(1));
+
+        sandbox.confine(sleeper.id()).unwrap();
+        assert!(sandbox.contains(sleeper.id()));
+        assert!(!sandbox.contains(std::process::id()));
+
+        sleeper.kill().unwrap();
+        sleeper.wait().unwrap();
+        assert!(!sandbox.contains(sleeper.id()));
+    }
+
+    #[test]
+    fn test_launch_denies_outside_allowed_roots() {
+        let temp_dir = tempdir().unwrap();
+        let allowed = temp_dir.path().join("allowed.txt");
+        let secret = temp_dir.path().join("secret.txt");
+        std::fs::write(&allowed, "fine\n").unwrap();
+        std::fs::write(&secret, "hidden\n").unwrap();
+
+        // Mark the directory rather than the mount to keep the test to it
+        let mut fanotify = Sandbox::group().unwrap();
+        fanotify
+            .add_watch(temp_dir.path(), MaskFlags::OPEN_PERM | MaskFlags::EVENT_ON_CHILD)
+            .unwrap();
+
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("cat {} && cat {}", allowed.display(), secret.display()))
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null());
+        let launched = Sandbox::new().allow(&allowed).launch(fanotify, command).unwrap();
+        let pid = launched.id();
+        let (status, sandbox) = launched.wait().unwrap();
+
+        assert!(!status.success());
+        assert_eq!(sandbox.root(), Some(pid));
+        assert_eq!(sandbox.stats().allowed, 1);
+        assert_eq!(sandbox.stats().denied, 1);
+        assert_eq!(sandbox.violations().len(), 1);
+        assert_eq!(sandbox.violations()[0].path.as_deref(), Some(secret.as_path()));
+        assert_eq!(sandbox.violations()[0].kind, Some(EventKind::Open));
+
+        // Outside the sandbox the file opens as usual
+        assert_eq!(std::fs::read_to_string(&secret).unwrap(), "hidden\n");
+    }
+
+    #[test]
+    fn test_launch_confines_orphans() {
+        let temp_dir = tempdir().unwrap();
+        let secret = temp_dir.path().join("secret.txt");
+        std::fs::write(&secret, "hidden\n").unwrap();
+
+        let mut fanotify = Sandbox::group().unwrap();
+        fanotify
+            .add_watch(temp_dir.path(), MaskFlags::OPEN_PERM | MaskFlags::EVENT_ON_CHILD)
+            .unwrap();
+
+        // The shell exits at once, leaving the subshell to be reparented
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("(sleep 0.2; cat {}) & exit 3", secret.display()))
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null());
+        let (status, sandbox) = Sandbox::new().launch(fanotify, command).unwrap().wait().unwrap();
+
+        assert_eq!(status.code(), Some(3));
+        assert_eq!(sandbox.stats().denied, 1);
+        assert_eq!(sandbox.violations()[0].path.as_deref(), Some(secret.as_path()));
+    }
+
+    #[test]
+    fn test_launched_commands_hold_no_fanotify_fds() {
+        let temp_dir = tempdir().unwrap();
+        let out_dir = tempdir().unwrap();
+        let fds = out_dir.path().join("fds.txt");
+        std::fs::write(temp_dir.path().join("file.txt"), "data\n").unwrap();
+
+        let mut fanotify = Sandbox::group().unwrap();
+        fanotify
+            .add_watch(temp_dir.path(), MaskFlags::OPEN_PERM | MaskFlags::EVENT_ON_CHILD)
+            .unwrap();
+
+        // The open leaves the serve thread holding an event fd for a while
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(format!(
+            "cat {}/file.txt; for fd in /proc/$$/fd/*; do readlink $fd; done > {}",
+            temp_dir.path().display(),
+            fds.display()
+        ));
+        command.stdout(std::process::Stdio::null()).stderr(std::process::Stdio::null());
+        let sandbox = Sandbox::new().allow(temp_dir.path()).allow(out_dir.path());
+        let (status, _) = sandbox.launch(fanotify, command).unwrap().wait().unwrap();
+
+        assert!(status.success());
+        let links = std::fs::read_to_string(&fds).unwrap();
+        assert!(!links.is_empty());
+        assert!(!links.contains("fanotify"), "{}", links);
+        assert!(!links.contains("file.txt"), "{}", links);
+    }
+
+    #[test]
+    fn test_launch_needs_cloexec_event_fds() {
+        let fanotify = Fanotify::with_flags(FanotifyFlags::CLASS_CONTENT).unwrap();
+        let result = Sandbox::new().launch(fanotify, Command::new("true"));
+        assert!(matches!(result, Err(FanotifyError::InvalidFlags { .. })));
+    }
+
+    #[test]
+    fn test_outsiders_are_cached() {
+        let mut sandbox = Sandbox::new();
+        let mut sleeper = Command::new("sleep").arg("5").spawn().unwrap();
+        sandbox.confine(sleeper.id()).unwrap();
+
+        let own_start = proc_stat(std::process::id()).map(|(_, start)| start);
+        assert!(!sandbox.contains(std::process::id()));
+        assert_eq!(sandbox.outsiders.get(&std::process::id()).copied(), own_start);
+        assert!(sandbox.outsiders.contains_key(&1));
+        assert!(!sandbox.contains(std::process::id()));
+
+        sleeper.kill().unwrap();
+        sleeper.wait().unwrap();
+    }
+}
@@ -0,0 +1,700 @@
+//! On-access content scanning
+//!
+//! A [`Scanner`] looks at the contents of a file and returns a [`Verdict`].
+//! Wrapping a scanner in [`Scanning`] turns it into a [`Handler`]: files are
+//! scanned through the permission event's file descriptor before the access
+//! is allowed, and infected files are denied.
+//!
+//! Two scanners are built in: [`SignatureScanner`] matches byte patterns
+//! itself, and [`ClamdScanner`] streams the file to a running clamd.
+//!
+//! ```no_run
+//! use fanotify_rs::scan::{ClamdScanner, Scanning};
+//! use fanotify_rs::{Fanotify, FanotifyFlags, MaskFlags};
+//!
+//! # fn main() -> fanotify_rs::Result<()> {
+//! let mut fanotify = Fanotify::with_flags(FanotifyFlags::CLASS_CONTENT)?;
+//! fanotify.add_mount_watch("/home", MaskFlags::OPEN_PERM)?;
+//! fanotify.run(Scanning::new(ClamdScanner::new("/run/clamav/clamd.ctl")))?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Scan results are cached by device, inode, modification time and size, so
+//! unchanged files are only scanned once.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, Metadata};
+use std::io::{Read, Write};
+use std::mem::ManuallyDrop;
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::{
+    error::{FanotifyError, Result},
+    event::Event,
+    handler::{Decision, Handler},
+    quarantine::{Disposal, Quarantine},
+    sys,
+};
+
+/// The outcome of scanning a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// Nothing was found
+    Clean,
+    /// The file matched the named signature
+    Infected(String),
+    /// The file could not be scanned
+    Error(String),
+}
+
+impl Verdict {
+    /// Whether the file was found infected
+    pub fn is_infected(&self) -> bool {
+        matches!(self, Verdict::Infected(_))
+    }
+}
+
+/// Something that decides whether a file's contents are acceptable
+///
+/// `fd` is open for reading. Scanners should read it with `pread(2)` or
+/// through a duplicate so the caller's file offset is left alone.
+pub trait Scanner {
+    /// Scan the file behind `fd`
+    fn scan(&mut self, fd: BorrowedFd<'_>, path: Option<&Path>, meta: &Metadata) -> Verdict;
+}
+
+impl<S: Scanner + ?Sized> Scanner for &mut S {
+    fn scan(&mut self, fd: BorrowedFd<'_>, path: Option<&Path>, meta: &Metadata) -> Verdict {
+        (**self).scan(fd, path, meta)
+    }
+}
+
+impl<S: Scanner + ?Sized> Scanner for Box<S> {
+    fn scan(&mut self, fd: BorrowedFd<'_>, path: Option<&Path>, meta: &Metadata) -> Verdict {
+        (**self).scan(fd, path, meta)
+    }
+}
+
+/// A named byte pattern
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Signature {
+    name: String,
+    pattern: Vec<u8>,
+}
+
+/// Finds files containing any of a set of byte patterns
+#[derive(Debug, Clone, Default)]
+pub struct SignatureScanner {
+    signatures: Vec<Signature>,
+    max_size: Option<u64>,
+}
+
+impl SignatureScanner {
+    /// A scanner without signatures
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Report files containing `pattern` as infected with `name`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pattern` is empty.
+    pub fn signature(mut self, name: impl Into<String>, pattern: impl Into<Vec<u8>>) -> Self {
+        let pattern = pattern.into();
+        assert!(!pattern.is_empty(), "signature pattern must not be empty");
+
+        self.signatures.push(Signature {
+            name: name.into(),
+            pattern,
+        });
+        self
+    }
+
+    /// Add a signature given in hex, e.g. `"4d5a9000"`
+    pub fn hex_signature(self, name: impl Into<String>, hex: &str) -> Result<Self> {
+        let hex: String = hex.chars().filter(|c| !c.is_whitespace()).collect();
+        let invalid = || FanotifyError::invalid_policy(format!("invalid hex signature: {:?}", hex));
+
+        if hex.is_empty() || !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+            return Err(invalid());
+        }
+
+        let pattern = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<std::result::Result<Vec<u8>, _>>()
+            .map_err(|_| invalid())?;
+        Ok(self.signature(name, pattern))
+    }
+
+    /// Only scan the first `bytes` of each file
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Number of signatures
+    pub fn len(&self) -> usize {
+        self.signatures.len()
+    }
+
+    /// Whether there are no signatures
+    pub fn is_empty(&self) -> bool {
+        self.signatures.is_empty()
+    }
+
+    /// The first signature found in `data`
+    pub fn find(&self, data: &[u8]) -> Option<&str> {
+        self.signatures
+            .iter()
+            .find(|signature| contains(data, &signature.pattern))
+            .map(|signature| signature.name.as_str())
+    }
+}
+
+/// Whether `pattern` occurs in `data`
+fn contains(data: &[u8], pattern: &[u8]) -> bool {
+    data.windows(pattern.len()).any(|window| window == pattern)
+}
+
+impl Scanner for SignatureScanner {
+    fn scan(&mut self, fd: BorrowedFd<'_>, _path: Option<&Path>, _meta: &Metadata) -> Verdict {
+        let Some(longest) = self.signatures.iter().map(|s| s.pattern.len()).max() else {
+            return Verdict::Clean;
+        };
+
+        // Keep the tail of the previous chunk so patterns spanning chunks match
+        let mut window: Vec<u8> = Vec::new();
+        let mut scanned: u64 = 0;
+        let mut found = None;
+
+        let result = sys::read_chunks(fd.as_raw_fd(), 64 * 1024, |chunk| {
+            let chunk = match self.max_size {
+                Some(max) => &chunk[..chunk.len().min(max.saturating_sub(scanned) as usize)],
+                None => chunk,
+            };
+            scanned += chunk.len() as u64;
+
+            window.extend_from_slice(chunk);
+            if let Some(name) = self.find(&window) {
+                found = Some(name.to_string());
+                return Ok(false);
+            }
+            let keep = window.len().min(longest - 1);
+            window.drain(..window.len() - keep);
+
+            Ok(!chunk.is_empty() && self.max_size.is_none_or(|max| scanned < max))
+        });
+
+        match (found, result) {
+            (Some(name), _) => Verdict::Infected(name),
+            (None, Ok(_)) => Verdict::Clean,
+            (None, Err(e)) => Verdict::Error(e.to_string()),
+        }
+    }
+}
+
+/// Streams files to clamd over its local UNIX socket
+///
+/// Uses the `INSTREAM` command of the clamd protocol, so clamd does not need
+/// access to the scanned file. Each scan opens a new connection.
+#[derive(Debug, Clone)]
+pub struct ClamdScanner {
+    socket: PathBuf,
+    timeout: Duration,
+    chunk_size: usize,
+}
+
+impl ClamdScanner {
+    /// Talk to clamd on the UNIX socket at `socket`
+    pub fn new<P: AsRef<Path>>(socket: P) -> Self {
+        Self {
+            socket: socket.as_ref().to_path_buf(),
+            timeout: Duration::from_secs(30),
+            chunk_size: 64 * 1024,
+        }
+    }
+
+    /// Give up on a scan when clamd does not answer within `timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The socket path
+    pub fn socket(&self) -> &Path {
+        &self.socket
+    }
+
+    /// Check that clamd is reachable
+    pub fn ping(&self) -> Result<()> {
+        let mut stream = self.connect()?;
+        stream.write_all(b"zPING\0")?;
+
+        match read_reply(&mut stream)?.as_str() {
+            "PONG" => Ok(()),
+            reply => Err(FanotifyError::invalid_event_data(format!("unexpected clamd reply: {}", reply))),
+        }
+    }
+
+    fn connect(&self) -> Result<UnixStream> {
+        let stream = UnixStream::connect(&self.socket)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        Ok(stream)
+    }
+
+    /// Send the file behind `fd` with `INSTREAM` and return clamd's reply
+    fn instream(&self, fd: BorrowedFd<'_>) -> Result<String> {
+        let mut stream = self.connect()?;
+        stream.write_all(b"zINSTREAM\0")?;
+
+        sys::read_chunks(fd.as_raw_fd(), self.chunk_size, |chunk| {
+            stream.write_all(&(chunk.len() as u32).to_be_bytes())?;
+            stream.write_all(chunk)?;
+            Ok(true)
+        })?;
+        stream.write_all(&0u32.to_be_bytes())?;
+
+        read_reply(&mut stream)
+    }
+}
+
+/// Read a reply terminated by a NUL byte or the end of the stream
+fn read_reply(stream: &mut UnixStream) -> Result<String> {
+    let mut reply = Vec::new();
+    let mut byte = [0u8; 1];
+
+    while stream.read(&mut byte)? == 1 && byte[0] != 0 {
+        reply.push(byte[0]);
+    }
+
+    Ok(String::from_utf8_lossy(&reply).trim().to_string())
+}
+
+/// Interpret an `INSTREAM` reply such as `stream: Eicar-Signature FOUND`
+fn parse_reply(reply: &str) -> Verdict {
+    let result = reply.strip_prefix("stream:").unwrap_or(reply).trim();
+
+    if result == "OK" {
+        Verdict::Clean
+    } else if let Some(name) = result.strip_suffix("FOUND") {
+        Verdict::Infected(name.trim().to_string())
+    } else {
+        Verdict::Error(result.to_string())
+    }
+}
+
+impl Scanner for ClamdScanner {
+    fn scan(&mut self, fd: BorrowedFd<'_>, _path: Option<&Path>, _meta: &Metadata) -> Verdict {
+        match self.instream(fd) {
+            Ok(reply) => parse_reply(&reply),
+            Err(e) => Verdict::Error(format!("clamd: {}", e)),
+        }
+    }
+}
+
+/// Identifies one version of a file
+type CacheKey = (u64, u64, (i64, i64), (i64, i64), u64);
+
+/// The cache key of `meta`
+///
+/// The change time is there because `utimensat(2)` can put the modification
+/// time back after a rewrite, but nothing can set the change time.
+fn cache_key(meta: &Metadata) -> CacheKey {
+    (
+        meta.dev(),
+        meta.ino(),
+        (meta.mtime(), meta.mtime_nsec()),
+        (meta.ctime(), meta.ctime_nsec()),
+        meta.size(),
+    )
+}
+
+/// Scan results by device, inode, modification and change time and size
+///
+/// Only clean and infected verdicts are cached; errors are retried. When
+/// full, the oldest result is dropped.
+#[derive(Debug, Clone)]
+pub struct ScanCache {
+    capacity: usize,
+    results: HashMap<CacheKey, Verdict>,
+    order: VecDeque<CacheKey>,
+}
+
+impl ScanCache {
+    /// A cache holding up to `capacity` results; zero disables caching
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            results: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// The cached verdict for the file described by `meta`
+    pub fn get(&self, meta: &Metadata) -> Option<&Verdict> {
+        self.results.get(&cache_key(meta))
+    }
+
+    /// Remember `verdict` for the file described by `meta`
+    pub fn insert(&mut self, meta: &Metadata, verdict: Verdict) {
+        if self.capacity == 0 || matches!(verdict, Verdict::Error(_)) {
+            return;
+        }
+
+        let key = cache_key(meta);
+        if self.results.insert(key, verdict).is_none() {
+            self.order.push_back(key);
+        }
+        while self.results.len() > self.capacity {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            self.results.remove(&oldest);
+        }
+    }
+
+    /// Forget every result
+    pub fn clear(&mut self) {
+        self.results.clear();
+        self.order.clear();
+    }
+
+    /// Number of cached results
+    pub fn len(&self) -> usize {
+        self.results.len()
+    }
+
+    /// Whether no result is cached
+    pub fn is_empty(&self) -> bool {
+        self.results.is_empty()
+    }
+}
+
+impl Default for ScanCache {
+    fn default() -> Self {
+        Self::new(4096)
+    }
+}
+
+/// Counters kept by [`Scanning`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ScanStats {
+    /// Files handed to the scanner
+    pub scanned: u64,
+    /// Events answered from the cache
+    pub cache_hits: u64,
+    /// Accesses denied because the file was infected
+    pub infected: u64,
+    /// Files that could not be scanned
+    pub errors: u64,
+    /// Infected files moved into quarantine
+    pub quarantined: u64,
+}
+
+/// A [`Handler`] that scans files before allowing access to them
+pub struct Scanning<S> {
+    scanner: S,
+    cache: ScanCache,
+    on_error: Decision,
+    quarantine: Option<(Quarantine, Disposal)>,
+    stats: ScanStats,
+}
+
+impl<S: Scanner> Scanning<S> {
+    /// Scan with `scanner`, caching results and allowing files that cannot
+    /// be scanned
+    pub fn new(scanner: S) -> Self {
+        Self {
+            scanner,
+            cache: ScanCache::default(),
+            on_error: Decision::Allow,
+            quarantine: None,
+            stats: ScanStats::default(),
+        }
+    }
+
+    /// Use `cache` for scan results
+    pub fn with_cache(mut self, cache: ScanCache) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// The decision for files the scanner failed on
+    ///
+    /// Defaults to `Allow`, so an unreachable scanner does not lock up the
+    /// system.
+    pub fn on_scan_error(mut self, decision: Decision) -> Self {
+        self.on_error = decision;
+        self
+    }
+
+    /// Move infected files into `quarantine`, then deal with the original
+    /// as `disposal` says
+    pub fn with_quarantine(mut self, quarantine: Quarantine, disposal: Disposal) -> Self {
+        self.quarantine = Some((quarantine, disposal));
+        self
+    }
+
+    /// The scanner
+    pub fn scanner(&self) -> &S {
+        &self.scanner
+    }
+
+    /// The result cache
+    pub fn cache(&self) -> &ScanCache {
+        &self.cache
+    }
+
+    /// The result cache, e.g. to clear it after a signature update
+    pub fn cache_mut(&mut self) -> &mut ScanCache {
+        &mut self.cache
+    }
+
+    /// A snapshot of the counters
+    pub fn stats(&self) -> ScanStats {
+        self.stats
+    }
+
+    /// Scan the file behind `event`, consulting the cache first
+    pub fn scan_event(&mut self, event: &Event) -> Verdict {
+        self.scan_or_lookup(event).0
+    }
+
+    /// Like [`scan_event`](Self::scan_event), also telling whether the
+    /// verdict came from the cache
+    fn scan_or_lookup(&mut self, event: &Event) -> (Verdict, bool) {
+        let Some(fd) = event.info.fd else {
+            return (Verdict::Error("event has no file descriptor".to_string()), false);
+        };
+
+        // SAFETY: the event owns fd for at least as long as this call
+        let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+        let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd.as_raw_fd()) });
+        let meta = match file.metadata() {
+            Ok(meta) => meta,
+            Err(e) => return (Verdict::Error(e.to_string()), false),
+        };
+        if !meta.is_file() {
+            return (Verdict::Clean, false);
+        }
+
+        if let Some(verdict) = self.cache.get(&meta) {
+            self.stats.cache_hits += 1;
+            return (verdict.clone(), true);
+        }
+
+        self.stats.scanned += 1;
+        let verdict = self.scanner.scan(fd, event.info.path.as_deref(), &meta);
+        self.cache.insert(&meta, verdict.clone());
+        (verdict, false)
+    }
+}
+
+impl<S: Scanner> Handler for Scanning<S> {
+    fn on_event(&mut self, _event: &Event) {}
+
+    fn on_permission(&mut self, event: &Event) -> Decision {
+        let (verdict, cached) = self.scan_or_lookup(event);
+        match verdict {
+            Verdict::Clean => Decision::Allow,
+            Verdict::Infected(name) => {
+                self.stats.infected += 1;
+                log::warn!("{:?} is infected with {}, denied pid {}", event.info.path, name, event.info.pid);
+
+                // A cached verdict means the file was quarantined when it was scanned
+                if let Some((quarantine, disposal)) = self.quarantine.as_ref().filter(|_| !cached) {
+                    match quarantine.quarantine(event, &format!("infected with {}", name), *disposal) {
+                        Ok(_) => self.stats.quarantined += 1,
+                        Err(e) => log::error!("failed to quarantine {:?}: {}", event.info.path, e),
+                    }
+                }
+                Decision::Deny
+            }
+            Verdict::Error(message) => {
+                self.stats.errors += 1;
+                log::error!("failed to scan {:?}: {}", event.info.path, message);
+                self.on_error
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{decide_next, open_in_thread};
+    use crate::{Fanotify, FanotifyFlags, MaskFlags};
+    use std::os::fd::AsFd;
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+    use tempfile::tempdir;
+
+    const MARKER: &[u8] = b"X5O!P%@AP-TEST-SIGNATURE";
+
+    fn scan_file<S: Scanner>(scanner: &mut S, path: &Path) -> Verdict {
+        let file = File::open(path).unwrap();
+        let meta = file.metadata().unwrap();
+        scanner.scan(file.as_fd(), Some(path), &meta)
+    }
+
+    #[test]
+    fn test_signature_scanner() {
+        let temp_dir = tempdir().unwrap();
+        let clean = temp_dir.path().join("clean.bin");
+        let infected = temp_dir.path().join("infected.bin");
+        std::fs::write(&clean, vec![b'a'; 200_000]).unwrap();
+
+        // Put the marker across the boundary between two read chunks
+        let mut data = vec![b'a'; 64 * 1024 - 5];
+        data.extend_from_slice(MARKER);
+        data.extend_from_slice(&[b'b'; 1000]);
+        std::fs::write(&infected, &data).unwrap();
+
+        let mut scanner = SignatureScanner::new()
+            .signature("Test-Marker", MARKER)
+            .hex_signature("Elf", "7f 45 4c 46 02")
+            .unwrap();
+        assert_eq!(scan_file(&mut scanner, &clean), Verdict::Clean);
+        assert_eq!(scan_file(&mut scanner, &infected), Verdict::Infected("Test-Marker".to_string()));
+
+        let mut limited = scanner.clone().max_size(1024);
+        assert_eq!(scan_file(&mut limited, &infected), Verdict::Clean);
+
+        assert!(SignatureScanner::new().hex_signature("Bad", "4d5").is_err());
+    }
+
+    /// A stand-in for clamd answering `connections` requests
+    fn fake_clamd(socket: &Path, connections: usize) -> thread::JoinHandle<Vec<String>> {
+        let listener = UnixListener::bind(socket).unwrap();
+
+        thread::spawn(move || {
+            let mut commands = Vec::new();
+            for _ in 0..connections {
+                let (mut stream, _) = listener.accept().unwrap();
+                let command = read_reply_bytes(&mut stream);
+                let reply: &[u8] = match command.as_str() {
+                    "zPING" => b"PONG\0",
+                    "zINSTREAM" => {
+                        let mut data = Vec::new();
+                        loop {
+                            let mut len = [0u8; 4];
+                            stream.read_exact(&mut len).unwrap();
+                            let len = u32::from_be_bytes(len) as usize;
+                            if len == 0 {
+                                break;
+                            }
+                            let start = data.len();
+                            data.resize(start + len, 0);
+                            stream.read_exact(&mut data[start..]).unwrap();
+                        }
+                        if contains(&data, MARKER) {
+                            b"stream: Test-Marker FOUND\0"
+                        } else {
+                            b"stream: OK\0"
+                        }
+                    }
+                    _ => b"UNKNOWN COMMAND\0",
+                };
+                stream.write_all(reply).unwrap();
+                commands.push(command);
+            }
+            commands
+        })
+    }
+
+    fn read_reply_bytes(stream: &mut UnixStream) -> String {
+        let mut command = Vec::new();
+        let mut byte = [0u8; 1];
+        while stream.read(&mut byte).unwrap() == 1 && byte[0] != 0 {
+            command.push(byte[0]);
+        }
+        String::from_utf8(command).unwrap()
+    }
+
+    #[test]
+    fn test_cache_sees_through_reset_mtime() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("file.bin");
+        std::fs::write(&path, "clean").unwrap();
+        let before = std::fs::metadata(&path).unwrap();
+        let mut cache = ScanCache::new(8);
+        cache.insert(&before, Verdict::Clean);
+
+        // Same size, and the modification time set back afterwards
+        thread::sleep(std::time::Duration::from_millis(20));
+        std::fs::write(&path, "dirty").unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(before.modified().unwrap())
+            .unwrap();
+        let after = std::fs::metadata(&path).unwrap();
+        assert_eq!((after.mtime(), after.mtime_nsec()), (before.mtime(), before.mtime_nsec()));
+
+        assert_eq!(cache.get(&before), Some(&Verdict::Clean));
+        assert_eq!(cache.get(&after), None);
+    }
+
+    #[test]
+    fn test_clamd_scanner() {
+        let temp_dir = tempdir().unwrap();
+        let socket = temp_dir.path().join("clamd.sock");
+        let server = fake_clamd(&socket, 3);
+
+        let clean = temp_dir.path().join("clean.txt");
+        let infected = temp_dir.path().join("infected.txt");
+        std::fs::write(&clean, "nothing to see").unwrap();
+        std::fs::write(&infected, [b"prefix ", MARKER].concat()).unwrap();
+
+        let mut scanner = ClamdScanner::new(&socket).with_timeout(Duration::from_secs(5));
+        scanner.ping().unwrap();
+        assert_eq!(scan_file(&mut scanner, &clean), Verdict::Clean);
+        assert_eq!(scan_file(&mut scanner, &infected), Verdict::Infected("Test-Marker".to_string()));
+        assert_eq!(server.join().unwrap(), ["zPING", "zINSTREAM", "zINSTREAM"]);
+
+        // Nobody listening any more
+        std::fs::remove_file(&socket).unwrap();
+        assert!(matches!(scan_file(&mut scanner, &clean), Verdict::Error(_)));
+        assert_eq!(parse_reply("stream: Size limit exceeded ERROR"), Verdict::Error("Size limit exceeded ERROR".to_string()));
+    }
+
+    #[test]
+    fn test_scanning_handler_caches_results() {
+        let mut fanotify = Fanotify::with_flags(FanotifyFlags::CLASS_CONTENT).unwrap();
+        let temp_dir = tempdir().unwrap();
+        let test_file = temp_dir.path().join("scanned.txt");
+        std::fs::write(&test_file, "clean").unwrap();
+        // Opened before the mark, as opening it later would wait for a decision
+        let writer = std::fs::OpenOptions::new().write(true).open(&test_file).unwrap();
+        fanotify.add_watch(&test_file, MaskFlags::OPEN_PERM).unwrap();
+
+        let mut handler = Scanning::new(SignatureScanner::new().signature("Test-Marker", MARKER));
+
+        let mut open = |handler: &mut Scanning<SignatureScanner>| {
+            let opener = open_in_thread(&test_file);
+            decide_next(&mut fanotify, handler);
+            opener.join().unwrap().is_ok()
+        };
+
+        assert!(open(&mut handler));
+        assert!(open(&mut handler));
+        assert_eq!(handler.stats().scanned, 1);
+        assert_eq!(handler.stats().cache_hits, 1);
+
+        // A changed file is scanned again
+        writer.write_all_at(&[b"now ", MARKER].concat(), 0).unwrap();
+        assert!(!open(&mut handler));
+        assert_eq!(handler.stats().scanned, 2);
+        assert_eq!(handler.stats().infected, 1);
+    }
+}
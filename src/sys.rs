@@ -0,0 +1,403 @@
+//! Safe helpers around the raw fanotify system calls
+//!
+//! These are shared by the synchronous and asynchronous front-ends so the
+//! mark and response handling only lives in one place.
+
+use std::ffi::CString;
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use crate::{
+    error::{FanotifyError, Result},
+    event::Event,
+    flags::{FanotifyFlags, MaskFlags},
+    linux::{errno, fanotify_init, fanotify_mark, FAN_MARK_FILESYSTEM, FAN_MARK_FLUSH, FAN_MARK_MOUNT},
+    response::Response,
+};
+
+/// Create a new fanotify group
+pub(crate) fn init(flags: FanotifyFlags, event_f_flags: u32) -> Result<OwnedFd> {
+    let result = unsafe { fanotify_init(flags.bits(), event_f_flags) };
+
+    if result < 0 {
+        return Err(FanotifyError::from(errno()));
+    }
+
+    // SAFETY: result is a valid file descriptor that nobody else owns
+    Ok(unsafe { OwnedFd::from_raw_fd(result) })
+}
+
+/// Add, remove or modify a mark on `path`
+pub(crate) fn mark_path(fd: RawFd, flags: u32, mask: MaskFlags, path: &Path) -> Result<()> {
+    // Convert path to C string
+    let path_cstr = match CString::new(path.to_string_lossy().as_bytes()) {
+        Ok(s) => s,
+        Err(_) => return Err(FanotifyError::invalid_path(path.to_string_lossy().to_string())),
+    };
+
+    let result = unsafe {
+        fanotify_mark(
+            fd,
+            flags,
+            mask.bits(),
+            libc::AT_SYMLINK_NOFOLLOW,
+            path_cstr.as_ptr(),
+        )
+    };
+
+    if result < 0 {
+        return Err(FanotifyError::from(errno()));
+    }
+
+    Ok(())
+}
+
+/// Add, remove or modify a mark on the object `target` refers to
+pub(crate) fn mark_fd(fd: RawFd, flags: u32, mask: MaskFlags, target: RawFd) -> Result<()> {
+    let result = unsafe { fanotify_mark(fd, flags, mask.bits(), target, std::ptr::null()) };
+
+    if result < 0 {
+        return Err(FanotifyError::from(errno()));
+    }
+
+    Ok(())
+}
+
+/// Write a permission response for `event` to the group
+pub(crate) fn write_response(fd: RawFd, event: &Event, response: &Response) -> Result<()> {
+    if !event.is_permission() {
+        return Err(FanotifyError::invalid_event_data("Event is not a permission event"));
+    }
+
+    let event_fd = event.info.fd.ok_or_else(|| {
+        FanotifyError::invalid_event_data("Permission event has no file descriptor")
+    })?;
+
+    write_raw_response(fd, event_fd, response)
+}
+
+/// Write a permission response for the event file descriptor `event_fd`
+pub(crate) fn write_raw_response(fd: RawFd, event_fd: RawFd, response: &Response) -> Result<()> {
+    check_response(response)?;
+
+    let bytes = response.to_bytes(event_fd);
+
+    let result = unsafe { libc::write(fd, bytes.as_ptr() as *const libc::c_void, bytes.len()) };
+
+    if result < 0 {
+        return Err(response_error(errno(), response));
+    }
+
+    Ok(())
+}
+
+/// Write several permission responses, answering them in order
+///
+/// The kernel takes one response per write but handles every segment of a
+/// `writev(2)` separately, so a run of responses costs a single system call.
+/// A response the kernel rejects ends the run; it is retried alone to
+/// collect its error and the responses after it are still written. The
+/// result for each response is returned in order.
+pub(crate) fn write_responses(fd: RawFd, responses: &[(RawFd, Response)]) -> Vec<Result<()>> {
+    let buffers: Vec<Vec<u8>> = responses
+        .iter()
+        .map(|(event_fd, response)| response.to_bytes(*event_fd))
+        .collect();
+    let mut results = Vec::with_capacity(responses.len());
+    let mut next = 0;
+
+    while next < responses.len() {
+        if let Err(e) = check_response(&responses[next].1) {
+            results.push(Err(e));
+            next += 1;
+            continue;
+        }
+
+        let mut end = next + 1;
+        while end < responses.len()
+            && end - next < IOV_MAX
+            && check_response(&responses[end].1).is_ok()
+        {
+            end += 1;
+        }
+
+        let iovecs: Vec<libc::iovec> = buffers[next..end]
+            .iter()
+            .map(|buffer| libc::iovec {
+                iov_base: buffer.as_ptr() as *mut libc::c_void,
+                iov_len: buffer.len(),
+            })
+            .collect();
+
+        let result = unsafe { libc::writev(fd, iovecs.as_ptr(), iovecs.len() as libc::c_int) };
+
+        if result < 0 {
+            // Nothing was written, so the first response is the one rejected
+            results.push(Err(response_error(errno(), &responses[next].1)));
+            next += 1;
+            continue;
+        }
+
+        let start = next;
+        let mut written = result as usize;
+        while next < end && written >= buffers[next].len() {
+            written -= buffers[next].len();
+            results.push(Ok(()));
+            next += 1;
+        }
+
+        if next == start {
+            results.push(Err(FanotifyError::invalid_event_data("Short permission response write")));
+            next += 1;
+        }
+    }
+
+    results
+}
+
+/// Maximum number of segments in one `writev(2)`
+const IOV_MAX: usize = 1024;
+
+/// Reject responses the running kernel cannot understand
+fn check_response(response: &Response) -> Result<()> {
+    if response.flags().errno().is_some() && !kernel_at_least(6, 14) {
+        return Err(FanotifyError::unsupported_feature("Denying with an errno", "6.14"));
+    }
+
+    Ok(())
+}
+
+/// The error for a response the kernel rejected with `err`
+fn response_error(err: i32, response: &Response) -> FanotifyError {
+    if err == libc::EINVAL && response.flags().errno().is_some() {
+        return FanotifyError::invalid_flags("denying with an errno needs a CLASS_PRE_CONTENT group");
+    }
+
+    FanotifyError::from(err)
+}
+
+/// Whether the running kernel is at least `major.minor`
+///
+/// Assumes a recent kernel if the release string cannot be parsed.
+pub(crate) fn kernel_at_least(major: u32, minor: u32) -> bool {
+    static VERSION: OnceLock<Option<(u32, u32)>> = OnceLock::new();
+
+    let version = *VERSION.get_or_init(|| {
+        let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+        if unsafe { libc::uname(&mut uts) } < 0 {
+            return None;
+        }
+        let release = unsafe { std::ffi::CStr::from_ptr(uts.release.as_ptr()) };
+        parse_release(release.to_str().ok()?)
+    });
+
+    version.is_none_or(|version| version >= (major, minor))
+}
+
+/// Parse the `major.minor` part of a kernel release such as `6.14.2-arch1`
+fn parse_release(release: &str) -> Option<(u32, u32)> {
+    let mut parts = release.split(['.', '-']);
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// Remove every mark of the group
+///
+/// Inode, mount and filesystem marks are flushed separately. Kernels without
+/// filesystem marks reject that last flush, which is not an error here.
+pub(crate) fn flush_marks(fd: RawFd) -> Result<()> {
+    let kinds = [
+        (FAN_MARK_FLUSH, false),
+        (FAN_MARK_FLUSH | FAN_MARK_MOUNT, false),
+        (FAN_MARK_FLUSH | FAN_MARK_FILESYSTEM, true),
+    ];
+
+    for (flags, optional) in kinds {
+        let result = unsafe { fanotify_mark(fd, flags, 0, libc::AT_FDCWD, std::ptr::null()) };
+
+        if result < 0 {
+            let err = errno();
+            if optional && err == libc::EINVAL {
+                continue;
+            }
+            return Err(FanotifyError::from(err));
+        }
+    }
+
+    Ok(())
+}
+
+/// Wait until `fd` is readable or `timeout` expires
+///
+/// `None` waits indefinitely. Returns `false` on timeout.
+pub(crate) fn poll_readable(fd: RawFd, timeout: Option<Duration>) -> Result<bool> {
+    let deadline = timeout.map(|t| Instant::now() + t);
+
+    loop {
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        let result = unsafe { libc::poll(&mut pollfd, 1, poll_timeout(deadline)) };
+
+        if result < 0 {
+            let err = errno();
+            if err == libc::EINTR {
+                continue;
+            }
+            return Err(FanotifyError::from(err));
+        }
+
+        return Ok(result > 0);
+    }
+}
+
+/// Milliseconds left until `deadline`, rounded up, in the form `poll(2)` expects
+pub(crate) fn poll_timeout(deadline: Option<Instant>) -> libc::c_int {
+    match deadline {
+        None => -1,
+        Some(deadline) => {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let millis = remaining.as_nanos().div_ceil(1_000_000);
+            millis.min(libc::c_int::MAX as u128) as libc::c_int
+        }
+    }
+}
+
+/// Close a file descriptor handed to us by the kernel with an event
+pub(crate) fn close_fd(fd: RawFd) {
+    unsafe {
+        libc::close(fd);
+    }
+}
+
+/// `fstat(2)` the file behind `fd`
+pub(crate) fn fstat(fd: RawFd) -> std::io::Result<libc::stat> {
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(stat)
+}
+
+/// Read the file behind `fd` from offset 0 in chunks of `chunk` bytes
+///
+/// Uses `pread(2)`, so the file offset of `fd` is left alone. Stops at the
+/// end of the file or early when `f` returns `false`, and returns the
+/// number of bytes handed to `f`.
+pub(crate) fn read_chunks<F>(fd: RawFd, chunk: usize, mut f: F) -> std::io::Result<u64>
+where
+    F: FnMut(&[u8]) -> std::io::Result<bool>,
+{
+    let mut buf = vec![0u8; chunk];
+    let mut offset: u64 = 0;
+
+    loop {
+        let result = unsafe {
+            libc::pread(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), offset as libc::off_t)
+        };
+
+        if result < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        if result == 0 {
+            return Ok(offset);
+        }
+
+        offset += result as u64;
+        if !f(&buf[..result as usize])? {
+            return Ok(offset);
+        }
+    }
+}
+
+/// Whether `fd` was opened for writing
+pub(crate) fn writable(fd: RawFd) -> Result<bool> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(matches!(flags & libc::O_ACCMODE, libc::O_RDWR | libc::O_WRONLY))
+}
+
+/// Plain `read(2)` into `buf`
+#[cfg(any(feature = "tokio", feature = "async-io", feature = "policy-file"))]
+pub(crate) fn read_fd(fd: RawFd, buf: &mut [u8]) -> std::io::Result<usize> {
+    let result = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(result as usize)
+}
+
+/// Put `fd` into non-blocking mode
+#[cfg(feature = "tokio")]
+pub(crate) fn set_nonblocking(fd: RawFd) -> Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(FanotifyError::from(errno()));
+    }
+
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(FanotifyError::from(errno()));
+    }
+
+    Ok(())
+}
+
+/// Set `FD_CLOEXEC` on `fd`
+pub(crate) fn set_cloexec(fd: RawFd) -> Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 {
+        return Err(FanotifyError::from(errno()));
+    }
+
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } < 0 {
+        return Err(FanotifyError::from(errno()));
+    }
+
+    Ok(())
+}
+
+/// The flags and `event_f_flags` the group `fd` was created with, from its
+/// fdinfo
+pub(crate) fn group_flags(fd: RawFd) -> Result<(u32, u32)> {
+    let info = std::fs::read_to_string(format!("/proc/self/fdinfo/{}", fd))?;
+    info.lines()
+        .find_map(|line| line.strip_prefix("fanotify flags:"))
+        .and_then(|line| line.split_once("event-flags:"))
+        .and_then(|(flags, event_flags)| {
+            let flags = u32::from_str_radix(flags.trim(), 16).ok()?;
+            Some((flags, u32::from_str_radix(event_flags.trim(), 16).ok()?))
+        })
+        .ok_or_else(|| FanotifyError::invalid_flags("not a fanotify group"))
+}
+
+/// Check up front that the group `fd` can be answered with `response`
+///
+/// Writing a response only checks the kernel version; this also rejects
+/// errno denials on groups that are not `CLASS_PRE_CONTENT`.
+pub(crate) fn check_group_response(fd: RawFd, response: &Response) -> Result<()> {
+    check_response(response)?;
+
+    if response.flags().errno().is_none() {
+        return Ok(());
+    }
+    let (flags, _) = group_flags(fd)?;
+    if !FanotifyFlags::from_bits_retain(flags).contains(FanotifyFlags::CLASS_PRE_CONTENT) {
+        return Err(FanotifyError::invalid_flags("denying with an errno needs a CLASS_PRE_CONTENT group"));
+    }
+
+    Ok(())
+}
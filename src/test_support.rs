@@ -0,0 +1,85 @@
+//! Setup shared by the unit tests
+//!
+//! Most permission tests need the same pieces: a `CLASS_CONTENT` group
+//! watching a file in a temporary directory, a thread that opens the file
+//! (an unanswered permission event from the test's own thread would hang
+//! it), and a way to answer the event and close its file descriptor.
+
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+
+use tempfile::{tempdir, TempDir};
+
+use crate::{
+    event::{Event, EventInfo},
+    fanotify::Fanotify,
+    flags::{FanotifyFlags, MaskFlags},
+    handler::Handler,
+    permission::{Incoming, PermissionRequest},
+    response::Response,
+    sys,
+};
+
+/// A thread opening a watched file, see [`open_in_thread`]
+pub(crate) type Opener = JoinHandle<io::Result<()>>;
+
+/// A `CLASS_CONTENT` group watching `name`, created with `contents` in a
+/// fresh temporary directory, for `mask`
+pub(crate) fn watched_file(name: &str, contents: &str, mask: MaskFlags) -> (Fanotify, TempDir, PathBuf) {
+    let mut fanotify = Fanotify::with_flags(FanotifyFlags::CLASS_CONTENT).unwrap();
+    let temp_dir = tempdir().unwrap();
+    let path = temp_dir.path().join(name);
+    std::fs::write(&path, contents).unwrap();
+    fanotify.add_watch(&path, mask).unwrap();
+    (fanotify, temp_dir, path)
+}
+
+/// Open `path` on another thread, which blocks until the permission event
+/// is answered
+pub(crate) fn open_in_thread(path: &Path) -> Opener {
+    let path = path.to_path_buf();
+    thread::spawn(move || File::open(path).map(|_| ()))
+}
+
+/// Wait for `opener` and check that its open was denied
+pub(crate) fn assert_denied(opener: Opener) {
+    let result = opener.join().unwrap();
+    assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+}
+
+/// Answer `event` with `response` and close its file descriptor
+pub(crate) fn respond(fanotify: &Fanotify, event: &Event, response: &Response) {
+    fanotify.respond_with(event, response).unwrap();
+    sys::close_fd(event.info.fd.unwrap());
+}
+
+/// Answer `event` with what `handler` decides and close its file descriptor
+pub(crate) fn decide<H: Handler>(fanotify: &Fanotify, handler: &mut H, event: &Event) {
+    let response = handler.on_permission_response(event);
+    respond(fanotify, event, &response);
+}
+
+/// Read the next event and answer it with what `handler` decides
+pub(crate) fn decide_next<H: Handler>(fanotify: &mut Fanotify, handler: &mut H) {
+    let event = fanotify.read_blocking().unwrap();
+    decide(fanotify, handler, &event);
+}
+
+/// Read the next event as a [`PermissionRequest`]
+pub(crate) fn next_request(fanotify: &mut Fanotify) -> PermissionRequest {
+    match fanotify.read_incoming().unwrap() {
+        Incoming::Permission(request) => request,
+        Incoming::Event(event) => panic!("expected a permission request, got {:?}", event),
+    }
+}
+
+/// An event from this process for `path` that no group produced, a
+/// permission event unless `mask` says otherwise
+pub(crate) fn permission_event(mask: MaskFlags, path: &str) -> Event {
+    Event {
+        info: EventInfo::new(mask, std::process::id()).with_path(PathBuf::from(path)),
+        raw_data: Vec::new(),
+    }
+}
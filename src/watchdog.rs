@@ -0,0 +1,239 @@
+//! Deadlines for permission decisions
+//!
+//! A process that triggers a permission event stays blocked until the event
+//! is answered, so a decision handler that hangs freezes every process
+//! touching the watched files. A [`Watchdog`] runs next to the handler and
+//! answers every permission event that has been waiting longer than its
+//! deadline with a default decision, counting each such timeout.
+//!
+//! The age of an event is measured from when it was read from the group.
+//! When the handler finally answers an event the watchdog already answered,
+//! the late answer is dropped.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::{
+    error::{FanotifyError, Result},
+    handler::Decision,
+    permission::Responder,
+};
+
+/// Shortest and longest interval between two checks
+const MIN_TICK: Duration = Duration::from_millis(1);
+const MAX_TICK: Duration = Duration::from_secs(1);
+
+/// State shared with the watchdog thread
+struct Shared {
+    responder: Responder,
+    deadline: Duration,
+    default: Decision,
+    timeouts: AtomicU64,
+    stopped: Mutex<bool>,
+    wake: Condvar,
+}
+
+/// Handle to a running watchdog thread
+///
+/// Dropping the handle stops the thread and waits for it to exit.
+pub struct Watchdog {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    /// Answer the permission events of `responder`'s group with `default`
+    /// once they have waited longer than `deadline`
+    ///
+    /// Works with any group: pass the [`responder`](crate::Fanotify::responder)
+    /// of a synchronous or asynchronous group. Fails if the group cannot be
+    /// answered with `default`, such as [`Decision::DenyWith`] on a group
+    /// that is not `CLASS_PRE_CONTENT`.
+    pub fn spawn(responder: Responder, deadline: Duration, default: Decision) -> Result<Self> {
+        if deadline.is_zero() {
+            return Err(FanotifyError::invalid_flags("Watchdog deadline must not be zero"));
+        }
+        responder.check(&default.into())?;
+
+        let shared = Arc::new(Shared {
+            responder,
+            deadline,
+            default,
+            timeouts: AtomicU64::new(0),
+            stopped: Mutex::new(false),
+            wake: Condvar::new(),
+        });
+
+        let thread_shared = Arc::clone(&shared);
+        let thread = thread::Builder::new()
+            .name("fanotify-watchdog".to_string())
+            .spawn(move || watch(&thread_shared))?;
+
+        Ok(Self {
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    /// How long a permission event may wait for an answer
+    pub fn deadline(&self) -> Duration {
+        self.shared.deadline
+    }
+
+    /// The decision given to events that miss the deadline
+    pub fn default_decision(&self) -> Decision {
+        self.shared.default
+    }
+
+    /// Number of permission events answered because they missed the deadline
+    pub fn timeouts(&self) -> u64 {
+        self.shared.timeouts.load(Ordering::Relaxed)
+    }
+
+    /// How long the oldest unanswered permission event has been waiting
+    pub fn oldest_pending(&self) -> Option<Duration> {
+        self.shared.responder.oldest_pending()
+    }
+
+    /// Check whether the watchdog thread is still running
+    pub fn is_running(&self) -> bool {
+        self.thread.as_ref().is_some_and(|t| !t.is_finished())
+    }
+
+    /// Stop the watchdog thread
+    pub fn stop(mut self) -> Result<()> {
+        self.signal_stop();
+        let thread = self.thread.take().expect("watchdog thread already joined");
+        thread
+            .join()
+            .map_err(|_| FanotifyError::invalid_event_data("Watchdog thread panicked"))
+    }
+
+    /// Ask the watchdog thread to exit
+    fn signal_stop(&self) {
+        *self.shared.stopped.lock().unwrap() = true;
+        self.shared.wake.notify_all();
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.signal_stop();
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Body of the watchdog thread
+fn watch(shared: &Shared) {
+    let tick = (shared.deadline / 4).clamp(MIN_TICK, MAX_TICK);
+    let response = shared.default.into();
+    let mut stopped = shared.stopped.lock().unwrap();
+
+    while !*stopped {
+        stopped = shared.wake.wait_timeout(stopped, tick).unwrap().0;
+
+        // Events whose response failed stay pending for their owner
+        let (expired, result) = shared.responder.expire(shared.deadline, &response);
+        if expired > 0 {
+            shared.timeouts.fetch_add(expired as u64, Ordering::Relaxed);
+            log::warn!(
+                "{} permission event(s) missed the {:?} deadline, answered {:?}",
+                expired,
+                shared.deadline,
+                shared.default
+            );
+        }
+        if let Err(e) = result {
+            log::error!("failed to answer an expired permission event: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{assert_denied, next_request, open_in_thread, permission_event, watched_file};
+    use crate::{Errno, Event, Fanotify, FanotifyFlags, MaskFlags};
+    use std::fs::File;
+    use std::os::fd::AsRawFd;
+    use std::time::Instant;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_watchdog_answers_stuck_events() {
+        let (mut fanotify, _dir, test_file) = watched_file("stuck.txt", "stuck", MaskFlags::OPEN_PERM);
+
+        let watchdog = fanotify
+            .spawn_watchdog(Duration::from_millis(50), Decision::Deny)
+            .unwrap();
+        let opener = open_in_thread(&test_file);
+
+        // Hold on to the request, like a hung scanner would
+        let request = next_request(&mut fanotify);
+        assert_denied(opener);
+
+        // The opener is released before the watchdog counts the timeout
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while watchdog.timeouts() == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(watchdog.timeouts(), 1);
+        assert_eq!(fanotify.pending_permissions(), 0);
+        assert_eq!(watchdog.oldest_pending(), None);
+
+        // The late answer is dropped
+        request.allow().unwrap();
+        watchdog.stop().unwrap();
+    }
+
+    #[test]
+    fn test_failed_expiries_stay_pending() {
+        let fanotify = Fanotify::with_flags(FanotifyFlags::CLASS_CONTENT).unwrap();
+        let temp_dir = tempdir().unwrap();
+        let responder = fanotify.responder().unwrap();
+
+        // Descriptors the kernel has no permission event for
+        let strays: Vec<File> = (0..3)
+            .map(|i| File::create(temp_dir.path().join(format!("stray{}", i))).unwrap())
+            .collect();
+        let events: Vec<Event> = strays
+            .iter()
+            .map(|file| {
+                let mut event = permission_event(MaskFlags::OPEN_PERM, "stray");
+                event.info.fd = Some(file.as_raw_fd());
+                event
+            })
+            .collect();
+        responder.permissions().track(&events);
+
+        // Every tick tries them again, and none counts as a timeout
+        let watchdog = fanotify
+            .spawn_watchdog(Duration::from_millis(10), Decision::Deny)
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        assert_eq!(fanotify.pending_permissions(), 3);
+        assert_eq!(watchdog.timeouts(), 0);
+        watchdog.stop().unwrap();
+    }
+
+    #[test]
+    fn test_errno_default_needs_pre_content_group() {
+        let fanotify = Fanotify::with_flags(FanotifyFlags::CLASS_CONTENT).unwrap();
+        let result = fanotify.spawn_watchdog(Duration::from_secs(1), Decision::DenyWith(Errno::EAGAIN));
+        assert!(matches!(
+            result,
+            Err(FanotifyError::InvalidFlags { .. } | FanotifyError::UnsupportedFeature { .. })
+        ));
+    }
+
+    #[test]
+    fn test_zero_deadline_rejected() {
+        let fanotify = Fanotify::new().unwrap();
+        assert!(fanotify.spawn_watchdog(Duration::ZERO, Decision::Allow).is_err());
+    }
+}
@@ -0,0 +1,220 @@
+//! Background watcher thread delivering events over a channel
+//!
+//! [`Fanotify::spawn_watcher`] moves a group onto a dedicated thread that
+//! waits for events and forwards them through an [`mpsc`] channel. The
+//! thread sleeps in `poll(2)` on both the group and an eventfd, so
+//! [`WatcherHandle::stop`] can wake it up immediately.
+
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::{
+    error::{FanotifyError, Result},
+    event::Event,
+    fanotify::Fanotify,
+    linux::errno,
+};
+
+/// Handle to a running watcher thread
+///
+/// Dropping the handle stops the thread and waits for it to exit.
+pub struct WatcherHandle {
+    /// Receiving end of the event channel
+    events: Receiver<Result<Event>>,
+    /// eventfd used to wake the thread up
+    stop_fd: OwnedFd,
+    /// The watcher thread, which hands the group back when it exits
+    thread: Option<JoinHandle<Fanotify>>,
+}
+
+impl WatcherHandle {
+    /// Move `fanotify` onto a new watcher thread
+    pub(crate) fn spawn(fanotify: Fanotify) -> Result<Self> {
+        let raw = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if raw < 0 {
+            return Err(FanotifyError::from(errno()));
+        }
+        // SAFETY: eventfd returned a new descriptor that nobody else owns
+        let stop_fd = unsafe { OwnedFd::from_raw_fd(raw) };
+        let thread_stop_fd = stop_fd.try_clone()?;
+
+        let (sender, events) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("fanotify-watcher".to_string())
+            .spawn(move || watch(fanotify, thread_stop_fd, sender))?;
+
+        Ok(Self {
+            events,
+            stop_fd,
+            thread: Some(thread),
+        })
+    }
+
+    /// The channel events are delivered on
+    ///
+    /// A read error is delivered as an `Err` item, after which the watcher
+    /// thread exits and the channel disconnects.
+    pub fn receiver(&self) -> &Receiver<Result<Event>> {
+        &self.events
+    }
+
+    /// Wait for the next event
+    ///
+    /// Returns `None` once the watcher thread has exited.
+    pub fn recv(&self) -> Option<Result<Event>> {
+        self.events.recv().ok()
+    }
+
+    /// Return the next event if one has been delivered, without blocking
+    pub fn try_recv(&self) -> Option<Result<Event>> {
+        match self.events.try_recv() {
+            Ok(item) => Some(item),
+            Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => None,
+        }
+    }
+
+    /// Wait up to `timeout` for the next event
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Result<Event>> {
+        match self.events.recv_timeout(timeout) {
+            Ok(item) => Some(item),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+
+    /// Check whether the watcher thread is still running
+    pub fn is_running(&self) -> bool {
+        self.thread.as_ref().is_some_and(|t| !t.is_finished())
+    }
+
+    /// Stop the watcher thread and hand the group back
+    ///
+    /// Events that were already delivered stay in the channel but can no
+    /// longer be received once the handle is consumed; drain them first if
+    /// they matter.
+    pub fn stop(mut self) -> Result<Fanotify> {
+        self.signal_stop()?;
+        let thread = self.thread.take().expect("watcher thread already joined");
+        thread
+            .join()
+            .map_err(|_| FanotifyError::invalid_event_data("Watcher thread panicked"))
+    }
+
+    /// Wake the watcher thread and ask it to exit
+    fn signal_stop(&self) -> Result<()> {
+        let value: u64 = 1;
+        let result = unsafe {
+            libc::write(
+                self.stop_fd.as_raw_fd(),
+                &value as *const u64 as *const libc::c_void,
+                std::mem::size_of::<u64>(),
+            )
+        };
+
+        // EAGAIN means the counter is already non-zero, i.e. a stop is pending
+        if result < 0 && errno() != libc::EAGAIN {
+            return Err(FanotifyError::from(errno()));
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for WatcherHandle {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = self.signal_stop();
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Body of the watcher thread
+fn watch(mut fanotify: Fanotify, stop_fd: OwnedFd, sender: Sender<Result<Event>>) -> Fanotify {
+    loop {
+        let mut fds = [
+            libc::pollfd {
+                fd: fanotify.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: stop_fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+
+        let result = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+        if result < 0 {
+            let err = errno();
+            if err == libc::EINTR {
+                continue;
+            }
+            let _ = sender.send(Err(FanotifyError::from(err)));
+            return fanotify;
+        }
+
+        if fds[1].revents != 0 {
+            return fanotify;
+        }
+
+        if fds[0].revents == 0 {
+            continue;
+        }
+
+        match fanotify.read_available() {
+            Ok(events) => {
+                for event in events {
+                    if sender.send(Ok(event)).is_err() {
+                        // Nobody is listening any more
+                        return fanotify;
+                    }
+                }
+            }
+            Err(e) => {
+                let _ = sender.send(Err(e));
+                return fanotify;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MaskFlags;
+    use std::time::Instant;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_watcher_delivers_events_and_stops() {
+        let mut fanotify = Fanotify::new().unwrap();
+        let temp_dir = tempdir().unwrap();
+        let test_file = temp_dir.path().join("watched.txt");
+        std::fs::write(&test_file, "").unwrap();
+        fanotify.add_watch(&test_file, MaskFlags::MODIFY).unwrap();
+
+        let watcher = fanotify.spawn_watcher().unwrap();
+        assert!(watcher.is_running());
+
+        std::fs::write(&test_file, "watched").unwrap();
+        let event = watcher.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+        assert!(event.is_modify());
+
+        // The thread is parked in poll(); stopping must not wait for an event
+        let start = Instant::now();
+        let fanotify = watcher.stop().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(fanotify.is_watched(&test_file));
+    }
+
+    #[test]
+    fn test_watcher_drop_joins_thread() {
+        let fanotify = Fanotify::new().unwrap();
+        let watcher = fanotify.spawn_watcher().unwrap();
+        assert!(watcher.try_recv().is_none());
+        drop(watcher);
+    }
+}
@@ -3,7 +3,7 @@ use tempfile::tempdir;
 use std::fs;
 use std::path::Path;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 #[test]
 fn test_basic_functionality() {
@@ -14,8 +14,9 @@ fn test_basic_functionality() {
     // Create a fanotify instance
     let mut fanotify = Fanotify::new().unwrap();
     
-    // Add a watch for the temporary directory
-    let result = fanotify.add_watch(temp_dir.path(), MaskFlags::ACCESS | MaskFlags::MODIFY);
+    // Add a watch for the temporary directory and the files inside it
+    let mask = MaskFlags::ACCESS | MaskFlags::MODIFY | MaskFlags::EVENT_ON_CHILD;
+    let result = fanotify.add_watch(temp_dir.path(), mask);
     assert!(result.is_ok(), "add_watch failed: {:?}", result.err());
     
     // Verify the watch was added
@@ -37,9 +38,10 @@ fn test_basic_functionality() {
     }
     assert!(!events.is_empty(), "No events received after file creation");
     
-    // Check that we have a create event
-    let has_create_event = events.iter().any(|event| event.is_create());
-    assert!(has_create_event, "Expected to find a CREATE event");
+    // Creation itself is only reported to FID groups; writing the new file
+    // content shows up as a modification
+    let has_modify_event = events.iter().any(|event| event.is_modify());
+    assert!(has_modify_event, "Expected to find a MODIFY event");
     
     // Remove the watch
     fanotify.remove_watch(temp_dir.path()).unwrap();
@@ -52,7 +54,8 @@ fn test_event_types() {
     let test_file = temp_dir.path().join("test.txt");
     
     let mut fanotify = Fanotify::new().unwrap();
-    let result = fanotify.add_watch(temp_dir.path(), MaskFlags::ACCESS | MaskFlags::MODIFY);
+    let mask = MaskFlags::ACCESS | MaskFlags::MODIFY | MaskFlags::EVENT_ON_CHILD;
+    let result = fanotify.add_watch(temp_dir.path(), mask);
     assert!(result.is_ok(), "add_watch failed: {:?}", result.err());
     
     // Create a file
@@ -111,4 +114,37 @@ fn test_watched_paths() {
     // Test getting mask for a watched path
     let mask = fanotify.get_mask(temp_dir.path());
     assert_eq!(mask, Some(MaskFlags::ACCESS | MaskFlags::MODIFY));
-} 
\ No newline at end of file
+}
+
+#[test]
+fn test_read_modes_on_blocking_group() {
+    let temp_dir = tempdir().unwrap();
+    let test_file = temp_dir.path().join("test.txt");
+    fs::write(&test_file, "").unwrap();
+
+    // The default flags do not include NONBLOCK
+    let mut fanotify = Fanotify::new().unwrap();
+    fanotify.add_watch(&test_file, MaskFlags::MODIFY).unwrap();
+
+    assert!(fanotify.try_read().unwrap().is_none());
+    assert!(fanotify.read_events().unwrap().is_empty());
+    assert_eq!(fanotify.events().count(), 0);
+
+    let start = Instant::now();
+    assert!(fanotify.read_timeout(Duration::from_millis(50)).unwrap().is_none());
+    assert!(start.elapsed() >= Duration::from_millis(50));
+
+    fs::write(&test_file, "first").unwrap();
+    let event = fanotify.read_blocking().unwrap();
+    assert!(event.is_modify());
+
+    fs::write(&test_file, "second").unwrap();
+    let event = fanotify.read_timeout(Duration::from_secs(5)).unwrap();
+    assert!(event.is_some_and(|e| e.is_modify()));
+
+    // Drain whatever else the writes produced; the iterator must terminate
+    for event in fanotify.events() {
+        assert!(event.unwrap().is_modify());
+    }
+    assert!(fanotify.try_read().unwrap().is_none());
+}